@@ -0,0 +1,34 @@
+//! Make the features of the tiles in an MBTiles file valid, in place, e.g. for tiles made by an
+//! older version.
+#[macro_use]
+extern crate clap;
+
+extern crate failure;
+
+extern crate tileigi;
+
+use std::path::Path;
+
+use clap::{Arg, App};
+
+use tileigi::*;
+
+fn main() -> Result<(), failure::Error> {
+    let matches = App::new("fix-tiles")
+        .version(crate_version!())
+        .about("Make the features of the tiles in an MBTiles file valid, changing the file in place")
+        .arg(Arg::with_name("verbose").short("v").long("verbose").multiple(true).help("Show more log messages. -v for info, -vv for debug, -vvv for trace"))
+        .arg(Arg::with_name("quiet").short("q").long("quiet").conflicts_with("verbose").help("Don't print how many tiles were repaired, and only log errors"))
+        .arg(Arg::with_name("mbtiles").long("mbtiles").takes_value(true).value_name("FILENAME").required(true).help("MBTiles file to fix"))
+        .get_matches();
+
+    init_logging(matches.occurrences_of("verbose"), matches.is_present("quiet"));
+
+    let (num_checked, num_repaired) = fix_mbtiles(Path::new(matches.value_of("mbtiles").unwrap()))?;
+
+    if ! matches.is_present("quiet") {
+        println!("Checked {} tiles, repaired {}", num_checked, num_repaired);
+    }
+
+    Ok(())
+}
@@ -2,64 +2,43 @@ extern crate slippy_map_tiles;
 #[macro_use]
 extern crate clap;
 
-extern crate log;
-extern crate env_logger;
 extern crate failure;
 
 extern crate tileigi;
 
 use std::path::{PathBuf, Path};
-use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use clap::{Arg, App, AppSettings, ArgGroup};
 use slippy_map_tiles::BBox;
-use env_logger::{Color};
-use log::Level;
 
 use tileigi::*;
 
 fn main() -> Result<(), failure::Error> {
-    env_logger::Builder::from_default_env()
-        .format(|buf, record| {
-            let level = record.level();
-            let mut level_style = buf.style();
-
-            match level {
-                Level::Trace => level_style.set_color(Color::White),
-                Level::Debug => level_style.set_color(Color::Blue),
-                Level::Info => level_style.set_color(Color::Green),
-                Level::Warn => level_style.set_color(Color::Yellow),
-                Level::Error => level_style.set_color(Color::Red).set_bold(true),
-            };
-
-            write!(buf, "{:>5} ", level_style.value(level)).unwrap();
-
-            let ts = buf.timestamp();
-            write!(buf, "{}: ", ts).unwrap();
-            write!(buf, "{}", record.module_path().unwrap_or("UNKNOWN_MOD")).unwrap();
-            write!(buf, ":L{} ", record.line().map(|l| format!("{}", l)).unwrap_or("UNKNOWN_LINE".to_string())).unwrap();
-
-            writeln!(buf, "{}", record.args()).unwrap();
-
-            Ok(())
-        })
-        .init();
-
     let matches = App::new("tileigi")
         .version(crate_version!())
         .about("Generate vector tiles from a yml file")
         .setting(AppSettings::AllowLeadingHyphen)
-        .arg(Arg::with_name("data_yml").long("data-yml").takes_value(true).value_name("FILENAME").required(true).help("Filename of the .yml file"))
+        .arg(Arg::with_name("verbose").short("v").long("verbose").multiple(true).help("Show more log messages. -v for info, -vv for debug, -vvv for trace"))
+        .arg(Arg::with_name("quiet").short("q").long("quiet").conflicts_with("verbose").help("Don't print progress, and only log errors"))
+
+        .arg(Arg::with_name("data_yml").long("data-yml").takes_value(true).value_name("FILENAME").required(true).multiple(true).number_of_values(1).help("Filename of the .yml file. Can be given more than once, to use the layers from all of them"))
+        .arg(Arg::with_name("allow_override").long("allow-override").help("With several --data-yml, a layer in a later file replaces one with the same name in an earlier file, rather than being an error"))
 
         .arg(Arg::with_name("dest_dir").long("dest-dir").takes_value(true).value_name("DIR").help("Save tiles to this mbtiles file"))
         .arg(Arg::with_name("dest_mbtiles").long("dest-mbtiles").takes_value(true).value_name("FILENAME").help("Save tiles to this TileStash directory path"))
         .arg(Arg::with_name("dest_modtile").long("dest-modtile").takes_value(true).value_name("DIR").help("Save tiles to this mod_tile directory path"))
-        .group(ArgGroup::with_name("dest").args(&["dest_dir", "dest_mbtiles", "dest_modtile"]).required(true))
+        .arg(Arg::with_name("no_write").long("no-write").help("Generate the tiles, but don't save them anywhere. For benchmarking"))
+        .arg(Arg::with_name("describe_layers").long("describe-layers").help("Print the TileJSON vector_layers (names, zooms & field types) of the layers, from the database's column details, and don't generate any tiles"))
+        .arg(Arg::with_name("validate_source").long("validate-source").help("Don't generate any tiles, but check the source features in a random sample of tiles (see --sample), and print how many were invalid, and how many of those could be repaired"))
+        .arg(Arg::with_name("sample").long("sample").takes_value(true).value_name("NUMBER").requires("validate_source").help("With --validate-source, how many random tiles to check. Default is 100"))
+        .group(ArgGroup::with_name("dest").args(&["dest_dir", "dest_mbtiles", "dest_modtile", "no_write", "describe_layers", "validate_source"]).required(true))
 
         .arg(Arg::with_name("minzoom").long("minzoom").value_name("ZOOM").default_value("0").help("Minimum zoom to generate"))
         .arg(Arg::with_name("maxzoom").long("maxzoom").value_name("ZOOM").default_value("14").help("Maximum zoom to generate"))
 
         .arg(Arg::with_name("zoom").long("zoom").value_name("ZOOM").conflicts_with_all(&["minzoom", "maxzoom"]).help("Only generate for this zoom"))
+        .arg(Arg::with_name("zooms").long("zooms").value_name("ZOOM,ZOOM,...").conflicts_with_all(&["minzoom", "maxzoom", "zoom", "tile_list"]).help("Only generate these zooms, e.g. 0,5,10"))
 
         .arg(Arg::with_name("bbox").long("bbox").takes_value(true).value_name("MINLON,MINLAT,MAXLON,MAXLAT").help("Only generate tiles inside this bbox. 'planet' for planet, or minlon,minlat,maxlon,maxlat"))
 
@@ -75,8 +54,55 @@ fn main() -> Result<(), failure::Error> {
         .arg(Arg::with_name("if_not_exists").long("if-not-exists").help("Do not generate a tile if the file already exists. Doesn't work with mbtiles (yet)"))
         .arg(Arg::with_name("no_compress").long("no-compress").help("Do not compress the pbf files"))
 
+        .arg(Arg::with_name("scheme").long("scheme").takes_value(true).value_name("SCHEME").possible_values(&["xyz", "tms"]).help("How to number tile rows. Default is tms for MBTiles, and xyz for directories"))
+
+        .arg(Arg::with_name("validity_level").long("validity-level").takes_value(true).value_name("LEVEL").possible_values(&["full", "skip-expensive", "none"]).default_value("full").help("How much to check & repair geometries. skip-expensive skips the slow self-intersection check, none trusts the source data entirely"))
+
+        .arg(Arg::with_name("make_valid_vertex_budget").long("make-valid-vertex-budget").takes_value(true).value_name("NUMBER").help("Simplify polygon rings with more vertices than this before making them valid, so very dense, invalid rings don't take too long. Less exact"))
+        .arg(Arg::with_name("make_valid_vertex_budget_max_zoom").long("make-valid-vertex-budget-max-zoom").takes_value(true).value_name("ZOOM").requires("make_valid_vertex_budget").help("Only use --make-valid-vertex-budget up to this zoom. Default 10"))
+
+        .arg(Arg::with_name("simplify_stage").long("simplify-stage").takes_value(true).value_name("STAGE").possible_values(&["before-clip", "after-clip"]).default_value("before-clip").help("Simplify geometries before clipping them to the tile (no seams between tiles), or after (faster)"))
+
+        .arg(Arg::with_name("coordinate_rounding").long("coordinate-rounding").takes_value(true).value_name("MODE").possible_values(&["round", "floor", "round-half-even"]).default_value("round").help("How to round source coordinates to whole tile units"))
+
+        .arg(Arg::with_name("clamp_coordinates").long("clamp-coordinates").help("Move any vertices just outside the tile & buffer (e.g. from rounding) onto its edge, and log how many were moved"))
+
+        .arg(Arg::with_name("fail_on_encode_error").long("fail-on-encode-error").help("Stop if a feature can't be encoded (e.g. a column type which can't be in a vector tile), rather than leaving it out"))
+
+        .arg(Arg::with_name("keep_zero_length_lines").long("keep-zero-length-lines").help("Keep lines whose points are all in the same place (e.g. connectors in a routing graph), rather than dropping them"))
+
+        .arg(Arg::with_name("check_interior_nesting").long("check-interior-nesting").help("Check that all polygon interiors are in the right place after making polygons valid. Slow"))
+
+        .arg(Arg::with_name("flush_every").long("flush-every").takes_value(true).value_name("NUMBER").help("For directory output, sync files to disk after this many tiles. 0 (default) = never"))
+
+        .arg(Arg::with_name("sorted_writes").long("sorted-writes").help("Insert each metatile's tiles in zoom, column, row order, which SQLite writes faster. MBTiles only"))
+        .arg(Arg::with_name("tile_hashes").long("tile-hashes").help("Store a SHA-256 hash of each tile's uncompressed contents in the tile_hashes table. MBTiles only"))
+
+        .arg(Arg::with_name("buffer_only_counts_as_empty").long("buffer-only-counts-as-empty").requires("prune_empty_descendants").help("With --prune-empty-descendants, count tiles whose features are all only in the buffer as empty"))
+        .arg(Arg::with_name("prune_empty_descendants").long("prune-empty-descendants").help("Don't generate tiles if the tile containing them (on the zoom before) was empty. Layers can turn this off with prune-empty-descendants: false"))
+
+        .arg(Arg::with_name("max_zoom_per_layer").long("max-zoom-per-layer").takes_value(true).value_name("LAYER=ZOOM,...").help("Never generate (or query) these layers above these zooms, whatever their maxzoom in the data.yml is, e.g. hillshade_labels=10"))
+        .arg(Arg::with_name("split_zoom_at").long("split-zoom-at").takes_value(true).value_name("ZOOM,ZOOM,...").help("Split MBTiles output into one file per zoom band, with a new file starting at each of these zooms, e.g. 8 gives file.z0-7.mbtiles & file.z8-14.mbtiles"))
+
+        .arg(Arg::with_name("debug_density").long("debug-density").takes_value(true).value_name("CELLS").help("Add a _density layer to every tile, with the number of features in each cell of a CELLS×CELLS grid. For debugging"))
+
+        .arg(Arg::with_name("against").long("against").takes_value(true).value_name("FILENAME").help("Compare each tile to the one in this (previously generated) MBTiles file, and only write the tiles which have changed"))
+        .arg(Arg::with_name("compression_level").long("compression-level").takes_value(true).value_name("LEVEL").help("How much to gzip tiles, from 0 (fastest) to 9 (smallest). Default is 6"))
+        .arg(Arg::with_name("compress_min_bytes").long("compress-min-bytes").takes_value(true).value_name("BYTES").help("For --dest-dir, don't compress tiles smaller than this, since gzip would make them bigger"))
+        .arg(Arg::with_name("path_template").long("path-template").takes_value(true).value_name("TEMPLATE").help("For --dest-dir, save tiles at paths from this template, e.g. '{z}/{x}/{y}.mvt'. {x_hi} & {y_hi} (x or y / 1024) can be used for more subdirectories"))
+        .arg(Arg::with_name("attribution").long("attribution").takes_value(true).value_name("TEXT").help("Attribution (e.g. '© OpenStreetMap contributors') to store in the MBTiles/directory metadata"))
+        .arg(Arg::with_name("copy_unchanged").long("copy-unchanged").requires("against").help("With --against, write the previous version of tiles which haven't changed, rather than leaving them out"))
+
+        .arg(Arg::with_name("db_retries").long("db-retries").takes_value(true).value_name("NUMBER").default_value("3").help("Retry database queries which fail for temporary reasons (e.g. deadlocks, lock timeouts, connection resets) this many times. Queries which hit the statement timeout aren't retried"))
+        .arg(Arg::with_name("dump_sql").long("dump-sql").help("Log (at info level, so with -v) the SQL of each layer's query, with the parameters' values, for the first tile. At trace level (-vvv), for every tile"))
+        .arg(Arg::with_name("dump_invalid").long("dump-invalid").takes_value(true).value_name("FILENAME").help("Save every geometry which couldn't be made valid (and so was left out), with its layer & tile, to this GeoJSON file"))
+        .arg(Arg::with_name("db_retry_backoff").long("db-retry-backoff").takes_value(true).value_name("MILLISECONDS").default_value("500").help("Wait this long before retrying a failed database query. Doubles for each retry"))
+
         .arg(Arg::with_name("file-writer-buffer").long("file-writer-buffer").help("Size of buffer for the file writer thread").takes_value(true))
 
+        .arg(Arg::with_name("max_runtime").long("max-runtime").takes_value(true).value_name("DURATION").help("Stop starting new metatiles after this long, e.g. 90s, 30m, 2h. The ones already started are finished"))
+        .arg(Arg::with_name("remaining_tiles").long("remaining-tiles").takes_value(true).value_name("FILENAME").requires("max_runtime").help("With --max-runtime, write the metatiles which weren't generated to this file, for --tile-list"))
+
         .arg(Arg::with_name("tile_list")
              .long("tile-list").alias("list")
              .takes_value(true).required(false).value_name("FILENAME")
@@ -84,9 +110,12 @@ fn main() -> Result<(), failure::Error> {
              .help("Generate tiles from a list of tiles, one metatile per line 'SCALE Z/X/Y'"))
         .get_matches();
 
-    let data_yml = matches.value_of("data_yml").unwrap();
+    init_logging(matches.occurrences_of("verbose"), matches.is_present("quiet"));
+
+    let data_ymls: Vec<&str> = matches.values_of("data_yml").unwrap().collect();
 
     let dest = match (matches.value_of("dest_dir"), matches.value_of("dest_mbtiles"), matches.value_of("dest_modtile")) {
+        _ if matches.is_present("no_write") || matches.is_present("describe_layers") || matches.is_present("validate_source") => TileDestinationType::Null,
         (Some(dest_dir), None, None) => TileDestinationType::TileStashDirectory(PathBuf::from(dest_dir)),
         (None, Some(mbtiles_filename), None) => TileDestinationType::MBTiles(PathBuf::from(mbtiles_filename)),
         (None, None, Some(modtile_dir)) => TileDestinationType::ModTileDirectory(PathBuf::from(modtile_dir)),
@@ -94,9 +123,15 @@ fn main() -> Result<(), failure::Error> {
         _ => panic!("Can't provide >1 dest"),
     };
 
+    let zooms: Option<Vec<u8>> = match matches.value_of("zooms") {
+        None => None,
+        Some(zooms) => Some(zooms.split(",").map(|z| z.trim().parse()).collect::<std::result::Result<_, _>>()?),
+    };
     let (minzoom, maxzoom): (u8, u8) = if let Some(z) = matches.value_of("zoom") {
         let z: u8 = z.parse().unwrap();
         (z, z)
+    } else if let Some(ref zooms) = zooms {
+        (zooms.iter().cloned().min().unwrap_or(0), zooms.iter().cloned().max().unwrap_or(0))
     } else {
         ( matches.value_of("minzoom").unwrap().parse().unwrap(),
           matches.value_of("maxzoom").unwrap().parse().unwrap() )
@@ -129,7 +164,93 @@ fn main() -> Result<(), failure::Error> {
 
     let file_writer_buffer: usize = matches.value_of("file-writer-buffer").map(|s| s.parse().unwrap()).unwrap_or(5_000);
 
-    generate_all(&data_yml, minzoom, maxzoom, &bbox, &dest, if_not_exists, compress, metatile_scale, num_threads, tile_list, file_writer_buffer, matches.is_present("quiet"))?;
+    let mut options = GenerateOptions::default();
+    options.scheme = match matches.value_of("scheme") {
+        None => None,
+        Some(s) => Some(s.parse()?),
+    };
+    options.validity_level = matches.value_of("validity_level").unwrap().parse()?;
+    options.simplify_stage = matches.value_of("simplify_stage").unwrap().parse()?;
+    options.coordinate_rounding = matches.value_of("coordinate_rounding").unwrap().parse()?;
+    options.check_interior_nesting = matches.is_present("check_interior_nesting");
+    options.clamp_coordinates = matches.is_present("clamp_coordinates");
+    options.prune_empty_descendants = matches.is_present("prune_empty_descendants");
+    options.zooms = zooms;
+    options.dump_sql = matches.is_present("dump_sql");
+    options.dump_invalid = matches.value_of("dump_invalid").map(PathBuf::from);
+    options.buffer_only_counts_as_empty = matches.is_present("buffer_only_counts_as_empty");
+    options.keep_zero_length_lines = matches.is_present("keep_zero_length_lines");
+    options.fail_on_encode_error = matches.is_present("fail_on_encode_error");
+    options.flush_every = matches.value_of("flush_every").map(|s| s.parse()).unwrap_or(Ok(0))?;
+    options.store_tile_hashes = matches.is_present("tile_hashes");
+    options.sorted_writes = matches.is_present("sorted_writes");
+    options.split_zoom_at = match matches.value_of("split_zoom_at") {
+        None => Vec::new(),
+        Some(zooms) => zooms.split(",").map(|z| z.trim().parse()).collect::<std::result::Result<_, _>>()?,
+    };
+    options.max_zoom_per_layer = match matches.value_of("max_zoom_per_layer") {
+        None => Vec::new(),
+        Some(caps) => caps.split(",").map(|cap| {
+            let mut parts = cap.trim().splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(layer), Some(zoom)) => Ok((layer.to_string(), zoom.parse()?)),
+                _ => Err(failure::err_msg(format!("Invalid layer zoom cap {:?}, it should be LAYER=ZOOM", cap))),
+            }
+        }).collect::<Result<_, failure::Error>>()?,
+    };
+    options.debug_density = match matches.value_of("debug_density") {
+        None => None,
+        Some(cells) => Some(cells.parse()?),
+    };
+    options.against = matches.value_of("against").map(PathBuf::from);
+    options.copy_unchanged = matches.is_present("copy_unchanged");
+    options.allow_layer_override = matches.is_present("allow_override");
+    options.path_template = matches.value_of("path_template").map(|t| t.to_string());
+    options.compress_min_bytes = match matches.value_of("compress_min_bytes") {
+        None => None,
+        Some(bytes) => Some(bytes.parse()?),
+    };
+    options.compression_level = match matches.value_of("compression_level") {
+        None => None,
+        Some(level) => Some(level.parse()?),
+    };
+    options.make_valid_vertex_budget = match matches.value_of("make_valid_vertex_budget") {
+        None => None,
+        Some(budget) => Some(budget.parse()?),
+    };
+    options.make_valid_vertex_budget_max_zoom = match matches.value_of("make_valid_vertex_budget_max_zoom") {
+        None => None,
+        Some(zoom) => Some(zoom.parse()?),
+    };
+    options.max_runtime = match matches.value_of("max_runtime") {
+        None => None,
+        Some(max_runtime) => Some(parse_duration(max_runtime)?),
+    };
+    options.remaining_tiles = matches.value_of("remaining_tiles").map(PathBuf::from);
+    options.attribution = matches.value_of("attribution").map(|a| a.to_string());
+    options.db_retry = RetryPolicy{
+        retries: matches.value_of("db_retries").unwrap().parse()?,
+        backoff: Duration::from_millis(matches.value_of("db_retry_backoff").unwrap().parse()?),
+    };
+
+    if matches.is_present("describe_layers") {
+        println!("{}", describe_layers(&data_ymls, &options)?);
+        return Ok(());
+    }
+
+    if matches.is_present("validate_source") {
+        let sample: usize = matches.value_of("sample").unwrap_or("100").parse()?;
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        for layer in validate_source(&data_ymls, minzoom, maxzoom, &bbox, sample, seed, &options)? {
+            println!("{}", layer);
+        }
+        return Ok(());
+    }
+
+    let stats = generate_all(&data_ymls, minzoom, maxzoom, &bbox, &dest, if_not_exists, compress, metatile_scale, num_threads, tile_list, file_writer_buffer, matches.is_present("quiet"), &options)?;
+    if ! matches.is_present("quiet") {
+        println!("{}", stats);
+    }
 
     Ok(())
 }
@@ -0,0 +1,46 @@
+//! Check that the tiles in an MBTiles file have the layers we expect, e.g. for CI. Exits with an
+//! error if any don't.
+#[macro_use]
+extern crate clap;
+
+extern crate failure;
+
+extern crate tileigi;
+
+use std::path::Path;
+
+use clap::{Arg, App};
+
+use tileigi::*;
+
+fn main() -> Result<(), failure::Error> {
+    let matches = App::new("validate-layers")
+        .version(crate_version!())
+        .about("Check that the tiles in an MBTiles file have the expected layers for their zoom")
+        .arg(Arg::with_name("verbose").short("v").long("verbose").multiple(true).help("Show more log messages. -v for info, -vv for debug, -vvv for trace"))
+        .arg(Arg::with_name("quiet").short("q").long("quiet").conflicts_with("verbose").help("Only print the tiles which don't match, and log errors"))
+        .arg(Arg::with_name("mbtiles").long("mbtiles").takes_value(true).value_name("FILENAME").required(true).help("MBTiles file to check"))
+        .arg(Arg::with_name("expect").long("expect").takes_value(true).value_name("FILENAME").required(true).help("JSON file of the layers to expect for each band of zooms"))
+        .arg(Arg::with_name("sample").long("sample").takes_value(true).value_name("NUMBER").default_value("1").help("Only check every NUMBER'th tile"))
+        .get_matches();
+
+    init_logging(matches.occurrences_of("verbose"), matches.is_present("quiet"));
+
+    let expected = ExpectedLayers::from_file(Path::new(matches.value_of("expect").unwrap()))?;
+    let sample: usize = matches.value_of("sample").unwrap().parse()?;
+
+    let (num_checked, mismatches) = validate_mbtiles_layers(Path::new(matches.value_of("mbtiles").unwrap()), &expected, sample)?;
+
+    for mismatch in mismatches.iter() {
+        println!("{}", mismatch);
+    }
+    if ! matches.is_present("quiet") {
+        println!("Checked {} tiles, {} didn't have the expected layers", num_checked, mismatches.len());
+    }
+
+    if ! mismatches.is_empty() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
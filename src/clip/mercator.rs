@@ -0,0 +1,227 @@
+//! Clip source geometries (in EPSG:3857 metres) to the area that web mercator can show.
+//!
+//! Geometries which cross the antimeridian, or go up to the poles, can have coordinates way
+//! outside the world. Converting them to tile coordinates gives huge numbers which overflow the
+//! integer pipeline, or a polygon that stretches all the way across the world. So before
+//! converting, we cut them to the world, and move any part which is over the antimeridian to the
+//! other side of the world.
+
+use geo::*;
+
+use super::{Border, is_inside};
+use super::cohen_sutherland;
+use ::MERCATOR_HALF_WIDTH;
+use ::geometry_bbox;
+
+/// The largest number of 'world copies' on either side we will move back into the world.
+/// Anything further out than this is just dropped.
+const MAX_WORLD_COPIES: i64 = 2;
+
+/// Clip this geometry to the web mercator world. Parts over the antimeridian are cut off, and
+/// moved to the other side. Returns None if nothing is left.
+pub fn clip_to_mercator_bounds(geom: Geometry<f64>) -> Option<Geometry<f64>> {
+    let h = MERCATOR_HALF_WIDTH;
+
+    let bbox = match geometry_bbox(&geom) {
+        None => { return None; },
+        Some(b) => b,
+    };
+
+    if bbox.xmin >= -h && bbox.xmax <= h && bbox.ymin >= -h && bbox.ymax <= h {
+        // Everything inside, which is the usual case
+        return Some(geom);
+    }
+
+    match geom {
+        Geometry::Point(p) => wrap_point(p).map(Geometry::Point),
+        Geometry::MultiPoint(mp) => {
+            let points: Vec<_> = mp.0.into_iter().filter_map(wrap_point).collect();
+            match points.len() {
+                0 => None,
+                _ => Some(Geometry::MultiPoint(MultiPoint(points))),
+            }
+        },
+        Geometry::LineString(ls) => lines_to_geom(clip_linestring(&ls, &bbox)),
+        Geometry::MultiLineString(mls) => {
+            let lines = mls.0.iter().flat_map(|ls| clip_linestring(ls, &bbox).into_iter()).collect();
+            lines_to_geom(lines)
+        },
+        Geometry::Polygon(p) => polygons_to_geom(clip_polygon(&p, &bbox)),
+        Geometry::MultiPolygon(mp) => {
+            let polygons = mp.0.iter().flat_map(|p| clip_polygon(p, &bbox).into_iter()).collect();
+            polygons_to_geom(polygons)
+        },
+        Geometry::GeometryCollection(gc) => {
+            let geoms: Vec<_> = gc.0.into_iter().filter_map(clip_to_mercator_bounds).collect();
+            match geoms.len() {
+                0 => None,
+                _ => Some(Geometry::GeometryCollection(GeometryCollection(geoms))),
+            }
+        },
+        Geometry::Line(l) => lines_to_geom(clip_linestring(&LineString(vec![l.start, l.end]), &bbox)),
+    }
+}
+
+/// Which copies of the world (0 is the real one, 1 is the one to the east etc.) this bbox
+/// overlaps.
+fn world_copies(bbox: &Bbox<f64>) -> ::std::ops::Range<i64> {
+    let h = MERCATOR_HALF_WIDTH;
+    let copy_for_x = |x: f64| {
+        let k = ((x + h) / (2. * h)).floor();
+        // Points exactly on the east edge of the world are in this world.
+        let k = if k > 0. && (x - h) % (2. * h) == 0. { k - 1. } else { k };
+        (k as i64).max(-MAX_WORLD_COPIES).min(MAX_WORLD_COPIES)
+    };
+
+    copy_for_x(bbox.xmin)..(copy_for_x(bbox.xmax)+1)
+}
+
+fn world_copy_bbox(copy: i64) -> Bbox<f64> {
+    let h = MERCATOR_HALF_WIDTH;
+    let offset = 2. * h * (copy as f64);
+    Bbox{ xmin: offset - h, xmax: offset + h, ymin: -h, ymax: h }
+}
+
+fn shift(p: Point<f64>, copy: i64) -> Point<f64> {
+    Point::new(p.x() - 2. * MERCATOR_HALF_WIDTH * (copy as f64), p.y())
+}
+
+fn wrap_point(p: Point<f64>) -> Option<Point<f64>> {
+    let h = MERCATOR_HALF_WIDTH;
+    if p.y() < -h || p.y() > h {
+        return None;
+    }
+
+    let bbox = Bbox{ xmin: p.x(), xmax: p.x(), ymin: p.y(), ymax: p.y() };
+    let copy = world_copies(&bbox).start;
+    let p = shift(p, copy);
+    if p.x() < -h || p.x() > h {
+        None
+    } else {
+        Some(p)
+    }
+}
+
+fn clip_linestring(ls: &LineString<f64>, bbox: &Bbox<f64>) -> Vec<LineString<f64>> {
+    let mut results = Vec::new();
+
+    for copy in world_copies(bbox) {
+        let world = world_copy_bbox(copy);
+        let mut current: Vec<Point<f64>> = Vec::new();
+
+        for seg in ls.0.windows(2) {
+            match cohen_sutherland::clip(&seg[0], &seg[1], &world) {
+                None => {
+                    if current.len() >= 2 {
+                        results.push(LineString(current.drain(..).map(|p| shift(p, copy)).collect()));
+                    }
+                    current.clear();
+                },
+                Some((start, end)) => {
+                    let start: Point<f64> = start.into();
+                    let end: Point<f64> = end.into();
+                    if current.last() != Some(&start) {
+                        if current.len() >= 2 {
+                            results.push(LineString(current.drain(..).map(|p| shift(p, copy)).collect()));
+                        }
+                        current.clear();
+                        current.push(start);
+                    }
+                    current.push(end);
+                },
+            }
+        }
+
+        if current.len() >= 2 {
+            results.push(LineString(current.into_iter().map(|p| shift(p, copy)).collect()));
+        }
+    }
+
+    results
+}
+
+fn clip_polygon(p: &Polygon<f64>, bbox: &Bbox<f64>) -> Vec<Polygon<f64>> {
+    world_copies(bbox).filter_map(|copy| {
+        let world = world_copy_bbox(copy);
+        let exterior = match clip_ring(&p.exterior, &world) {
+            None => { return None; },
+            Some(e) => e,
+        };
+        let interiors = p.interiors.iter().filter_map(|i| clip_ring(i, &world)).collect::<Vec<_>>();
+
+        let exterior = LineString(exterior.0.into_iter().map(|p| shift(p, copy)).collect());
+        let interiors = interiors.into_iter().map(|i| LineString(i.0.into_iter().map(|p| shift(p, copy)).collect())).collect();
+        Some(Polygon::new(exterior, interiors))
+    }).collect()
+}
+
+/// Sutherland-Hodgman clip of a (closed) ring to a bbox.
+fn clip_ring(ring: &LineString<f64>, bbox: &Bbox<f64>) -> Option<LineString<f64>> {
+    let mut points = ring.0.clone();
+    for border in [Border::XMin(bbox.xmin), Border::XMax(bbox.xmax), Border::YMin(bbox.ymin), Border::YMax(bbox.ymax)].iter() {
+        points = clip_ring_to_border(&points, border);
+        if points.is_empty() {
+            return None;
+        }
+    }
+
+    // first, 2 others & last
+    if points.len() < 4 {
+        None
+    } else {
+        Some(LineString(points))
+    }
+}
+
+fn clip_ring_to_border(points: &[Point<f64>], border: &Border<f64>) -> Vec<Point<f64>> {
+    let mut new_points = Vec::with_capacity(points.len());
+
+    for seg in points.windows(2) {
+        let p1 = seg[0];
+        let p2 = seg[1];
+        if is_inside(&p1, border) {
+            new_points.push(p1);
+            if ! is_inside(&p2, border) {
+                new_points.push(intersection(&p1, &p2, border));
+            }
+        } else if is_inside(&p2, border) {
+            new_points.push(intersection(&p1, &p2, border));
+        }
+    }
+
+    if let Some(first) = new_points.first().cloned() {
+        new_points.push(first);
+    }
+
+    new_points
+}
+
+/// Where the segment p1-p2 crosses this border. One point must be inside, and one outside.
+fn intersection(p1: &Point<f64>, p2: &Point<f64>, border: &Border<f64>) -> Point<f64> {
+    match *border {
+        Border::XMin(x) | Border::XMax(x) => {
+            let t = (x - p1.x()) / (p2.x() - p1.x());
+            Point::new(x, p1.y() + t * (p2.y() - p1.y()))
+        },
+        Border::YMin(y) | Border::YMax(y) => {
+            let t = (y - p1.y()) / (p2.y() - p1.y());
+            Point::new(p1.x() + t * (p2.x() - p1.x()), y)
+        },
+    }
+}
+
+fn lines_to_geom(mut lines: Vec<LineString<f64>>) -> Option<Geometry<f64>> {
+    match lines.len() {
+        0 => None,
+        1 => Some(Geometry::LineString(lines.remove(0))),
+        _ => Some(Geometry::MultiLineString(MultiLineString(lines))),
+    }
+}
+
+fn polygons_to_geom(mut polygons: Vec<Polygon<f64>>) -> Option<Geometry<f64>> {
+    match polygons.len() {
+        0 => None,
+        1 => Some(Geometry::Polygon(polygons.remove(0))),
+        _ => Some(Geometry::MultiPolygon(MultiPolygon(polygons))),
+    }
+}
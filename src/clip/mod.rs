@@ -1,6 +1,7 @@
 use std::collections::{HashSet, HashMap};
 use std::fmt::Debug;
 use std::borrow::{Cow, Borrow};
+use std::cell::Cell;
 
 use yaml_rust::{YamlLoader, Yaml};
 
@@ -19,6 +20,8 @@ use ::simplify::remove_duplicate_points;
 // local stuff
 mod cohen_sutherland;
 mod sutherland_hodgeman;
+mod mercator;
+pub use self::mercator::clip_to_mercator_bounds;
 //mod weiler_atherton;
 
 #[cfg(test)]
@@ -84,102 +87,45 @@ enum IntersectionOption<T: CoordinateType> {
 
 
 /// given 2 points, and a border, return the (x, y) where the line between the two points crosses
-/// the border. This assumes that both points are on different sizes of the border
+/// the border. This assumes that both points are on different sizes of the border.
+///
+/// The crossing is rounded to the nearest integer, and is the same whichever way along the line
+/// we go, so clipping a line to two tiles which share an edge gives exactly the same point on
+/// that edge for both.
 fn intersection(p1: &Point<i32>, p2: &Point<i32>, border: &Border<i32>) -> (i32, i32) {
+    // Always calculate from the same end of the segment
+    let (p1, p2) = if (p1.x(), p1.y()) <= (p2.x(), p2.y()) { (p1, p2) } else { (p2, p1) };
     let x1 = p1.x() as i64;
     let y1 = p1.y() as i64;
     let x2 = p2.x() as i64;
     let y2 = p2.y() as i64;
 
-    // The algorithms are rearranged to reduce the risk of an i32 integer overflow
     match *border {
-        Border::XMin(xmin) => {
-            let x = xmin;
+        Border::XMin(x) | Border::XMax(x) => {
             assert!(x1 != x2);
-
-            let a = y2 - y1;
-            let b = (x as i64) - x1;
-            let c = x2 - x1;
-            let mut delta_y = 0;
-            delta_y = (a/c)*b;
-            if delta_y == 0 {
-                delta_y = (b/c)*a;
-                if delta_y == 0 {
-                    // might be overflow, but we presume not.
-                    delta_y = (a*b)/c;
-                }
-            }
-            let y = y1 + delta_y;
-            let y = y as i32;
-
-            (x, y)
-        },
-        Border::XMax(xmax) => {
-            let x = xmax;
-            assert!(x1 != x2);
-
-            let a = y2 - y1;
-            let b = (x as i64) - x1;
-            let c = x2 - x1;
-            let mut delta_y = 0;
-            delta_y = (a/c)*b;
-            if delta_y == 0 {
-                delta_y = (b/c)*a;
-                if delta_y == 0 {
-                    // might be overflow, but we presume not.
-                    delta_y = (a*b)/c;
-                }
-            }
-            let y = y1 + delta_y;
-            let y = y as i32;
-
-            (x, y)
+            let y = y1 + mul_div_round(y2 - y1, (x as i64) - x1, x2 - x1);
+            (x, y as i32)
         },
-        Border::YMin(ymin) => {
-            let y = ymin;
+        Border::YMin(y) | Border::YMax(y) => {
             assert!(y1 != y2);
-
-            let a = x2 - x1;
-            let b = (y as i64) - y1;
-            let c = y2 - y1;
-            let mut delta_x = 0;
-            delta_x = (a/c)*b;
-            if delta_x == 0 {
-                delta_x = (b/c)*a;
-                if delta_x == 0 {
-                    // might be overflow, but we presume not.
-                    delta_x = (a*b)/c;
-                }
-            }
-            let x = x1 + delta_x;
-            let x = x as i32;
-
-            (x, y)
-        },
-        Border::YMax(ymax) => {
-            let y = ymax;
-            assert!(y1 != y2);
-
-            let a = x2 - x1;
-            let b = (y as i64) - y1;
-            let c = y2 - y1;
-            let mut delta_x = 0;
-            delta_x = (a/c)*b;
-            if delta_x == 0 {
-                delta_x = (b/c)*a;
-                if delta_x == 0 {
-                    // might be overflow, but we presume not.
-                    delta_x = (a*b)/c;
-                }
-            }
-            let x = x1 + delta_x;
-            let x = x as i32;
-
-            (x, y)
+            let x = x1 + mul_div_round(x2 - x1, (y as i64) - y1, y2 - y1);
+            (x as i32, y)
         },
     }
 }
 
+/// a × b ÷ c, rounded to the nearest integer (halves are rounded up). Done with 128 bit integers
+/// so it can't overflow.
+fn mul_div_round(a: i64, b: i64, c: i64) -> i64 {
+    let (num, denom) = if c < 0 { (-(a as i128) * (b as i128), -(c as i128)) } else { ((a as i128) * (b as i128), c as i128) };
+    // floor((num + denom/2) / denom), without losing the half
+    let num = 2*num + denom;
+    let denom = 2*denom;
+    let quotient = num / denom;
+    let quotient = if num % denom < 0 { quotient - 1 } else { quotient };
+    quotient as i64
+}
+
 
 fn calculate_intersections(linestring: &LineString<i32>, border: &Border<i32>) -> LineBorderIntersection<i32> {
     // First we look if everything is all inside or all outside, and early return then, Then we
@@ -400,12 +346,105 @@ fn clip_to_border(geom: Cow<Geometry<i32>>, border: &Border<i32>) -> Option<Geom
 
 
 pub fn clip_to_bbox(geom: Cow<Geometry<i32>>, bbox: &Bbox<i32>) -> Option<Geometry<i32>> {
+    // Each polygon of a MultiPolygon is clipped on it's own, so ones outside the bbox can be
+    // skipped
+    let geom = match geom {
+        Cow::Owned(Geometry::MultiPolygon(mp)) => {
+            return sutherland_hodgeman::clip_multipolygon_to_bbox(Cow::Owned(mp), bbox).map(Geometry::MultiPolygon);
+        },
+        Cow::Borrowed(&Geometry::MultiPolygon(ref mp)) => {
+            return sutherland_hodgeman::clip_multipolygon_to_bbox(Cow::Borrowed(mp), bbox).map(Geometry::MultiPolygon);
+        },
+        geom => geom,
+    };
+
     clip_to_border(geom, &Border::XMin(bbox.xmin))
        .and_then(|geom| clip_to_border(Cow::Owned(geom), &Border::XMax(bbox.xmax)))
        .and_then(|geom| clip_to_border(Cow::Owned(geom), &Border::YMin(bbox.ymin)))
        .and_then(|geom| clip_to_border(Cow::Owned(geom), &Border::YMax(bbox.ymax)))
 }
 
+/// Is this polygon all of `bbox`? Clipping a polygon which is bigger than the tile leaves the
+/// tile's rectangle, but maybe with extra vertices along its edges, or a corner visited twice,
+/// which `make_valid` can split up oddly. Every vertex must be on the edge of `bbox`, and the
+/// ring must enclose all of it.
+pub fn covers_whole_bbox(geom: &Geometry<i32>, bbox: &Bbox<i32>) -> bool {
+    let ring = match *geom {
+        Geometry::Polygon(ref p) if p.interiors.is_empty() => &p.exterior,
+        Geometry::MultiPolygon(ref mp) if mp.0.len() == 1 && mp.0[0].interiors.is_empty() => &mp.0[0].exterior,
+        _ => { return false; },
+    };
+    if ring.0.len() < 4 {
+        return false;
+    }
+
+    let on_edge = |p: &Point<i32>| {
+        let (x, y) = (p.x(), p.y());
+        x >= bbox.xmin && x <= bbox.xmax && y >= bbox.ymin && y <= bbox.ymax
+            && (x == bbox.xmin || x == bbox.xmax || y == bbox.ymin || y == bbox.ymax)
+    };
+    if ! ring.0.iter().all(on_edge) {
+        return false;
+    }
+
+    let twice_area: i64 = ring.0.windows(2).map(|w| (w[0].x() as i64) * (w[1].y() as i64) - (w[1].x() as i64) * (w[0].y() as i64)).sum();
+    twice_area.abs() == 2 * ((bbox.xmax - bbox.xmin) as i64) * ((bbox.ymax - bbox.ymin) as i64)
+}
+
+/// A polygon of exactly `bbox`, with its 4 corners, going the right way around when the y axis
+/// goes this way.
+pub fn bbox_polygon(bbox: &Bbox<i32>, y_axis: ::validity::YAxis) -> Geometry<i32> {
+    let ring: LineString<i32> = vec![(bbox.xmin, bbox.ymin), (bbox.xmin, bbox.ymax), (bbox.xmax, bbox.ymax), (bbox.xmax, bbox.ymin), (bbox.xmin, bbox.ymin)].into();
+    let mut geom: Geometry<i32> = Polygon::new(ring, vec![]).into();
+    ::validity::ensure_polygon_orientation(&mut geom, y_axis);
+    geom
+}
+
+/// Move any vertices outside `bbox` onto the nearest edge of it. Returns how many were moved.
+/// This can make polygons invalid, so check them again if anything was clamped.
+pub fn clamp_to_bbox(geom: &mut Geometry<i32>, bbox: &Bbox<i32>) -> usize {
+    let clamped = Cell::new(0);
+    geom.map_coords_inplace(&|&(x, y)| {
+        let new_x = x.max(bbox.xmin).min(bbox.xmax);
+        let new_y = y.max(bbox.ymin).min(bbox.ymax);
+        if (new_x, new_y) != (x, y) {
+            clamped.set(clamped.get() + 1);
+        }
+        (new_x, new_y)
+    });
+
+    clamped.get()
+}
+
+/// Move any vertices within `distance` of an edge of the tile (`0`..`extent` on both axes) onto
+/// that edge, and round where they are along it to a multiple of `distance`. Neighbouring tiles
+/// are rounded independently, so this makes the vertices on both sides of a shared edge agree.
+/// Returns how many were moved. Like `clamp_to_bbox`, this can make polygons invalid.
+pub fn snap_to_tile_edges(geom: &mut Geometry<i32>, extent: i32, distance: i32) -> usize {
+    debug_assert!(distance > 0);
+    let snapped = Cell::new(0);
+    let edge = |v: i32| if v.abs() <= distance { Some(0) } else if (v - extent).abs() <= distance { Some(extent) } else { None };
+    // To the nearest multiple, rounding halves up (integer division rounds towards 0)
+    let grid = |v: i32| {
+        let v = v + distance / 2;
+        (if v >= 0 { v / distance } else { (v - distance + 1) / distance }) * distance
+    };
+    geom.map_coords_inplace(&|&(x, y)| {
+        let new = match (edge(x), edge(y)) {
+            (Some(x), Some(y)) => (x, y),
+            (Some(x), None) => (x, grid(y)),
+            (None, Some(y)) => (grid(x), y),
+            (None, None) => (x, y),
+        };
+        if new != (x, y) {
+            snapped.set(snapped.get() + 1);
+        }
+        new
+    });
+
+    snapped.get()
+}
+
 /// geom - The geometry
 /// metatile_scale - e.g. 8 for an 8x8 tile. This could be a 2x2 metatile, so that'd be 2
 /// zoom - of the tile
@@ -561,11 +600,13 @@ pub fn clip_point_to_tiles(metatile: &Metatile, point: Point<i32>, buffer: i32)
     }
 }
 
-pub fn clip_geometry_to_tiles(metatile: &Metatile, geom: Geometry<i32>, buffer: i32) -> Vec<(slippy_map_tiles::Tile, Option<Geometry<i32>>)> {
+/// Cut this geometry (in metatile coordinates) into the tiles of the metatile. Each tile is
+/// `tile_size` wide & high, usually 4096.
+pub fn clip_geometry_to_tiles(metatile: &Metatile, geom: Geometry<i32>, buffer: i32, tile_size: i32) -> Vec<(slippy_map_tiles::Tile, Option<Geometry<i32>>)> {
     // TODO somehow in this method, it's making invalid polygons where the last point != first
     // point
     // Simple approach for now
-    let mut res = slice_box(Cow::Owned(geom), metatile.size(), metatile.zoom(), metatile.x(), metatile.y(), 0, 0, metatile.size() as i32*4096, buffer);
+    let mut res = slice_box(Cow::Owned(geom), metatile.size(), metatile.zoom(), metatile.x(), metatile.y(), 0, 0, metatile.size() as i32*tile_size, buffer);
 
     // TODO make the slice_box etc not produce geoms with this result
     for &mut (tile, ref mut geom_opt) in res.iter_mut() {
@@ -117,17 +117,40 @@ pub fn clip_multipolygon_to_border(mp: Cow<MultiPolygon<i32>>, border: &Border<i
     }
 }
 
+/// How many polygons have been clipped to a bbox, to check that polygons outside it are skipped
+#[cfg(test)]
+thread_local!(pub static POLYGONS_CLIPPED: ::std::cell::Cell<usize> = ::std::cell::Cell::new(0));
+
+/// Could this polygon overlap `bbox`? i.e. does it's exterior's bbox overlap (or touch) it.
+fn polygon_may_overlap_bbox(poly: &Polygon<i32>, bbox: &Bbox<i32>) -> bool {
+    let points = &poly.exterior.0;
+    if points.is_empty() {
+        return false;
+    }
+    let xmin = points.iter().map(|p| p.x()).min().unwrap();
+    let xmax = points.iter().map(|p| p.x()).max().unwrap();
+    let ymin = points.iter().map(|p| p.y()).min().unwrap();
+    let ymax = points.iter().map(|p| p.y()).max().unwrap();
+
+    xmax >= bbox.xmin && xmin <= bbox.xmax && ymax >= bbox.ymin && ymin <= bbox.ymax
+}
+
 pub fn clip_polygon_to_bbox(poly: Cow<Polygon<i32>>, bbox: &Bbox<i32>) -> Option<Polygon<i32>> {
+    #[cfg(test)]
+    POLYGONS_CLIPPED.with(|c| c.set(c.get()+1));
+
     clip_polygon_to_border(poly, &Border::XMin(bbox.xmin))
            .and_then(|p| clip_polygon_to_border(Cow::Owned(p), &Border::XMax(bbox.xmax)))
            .and_then(|p| clip_polygon_to_border(Cow::Owned(p), &Border::YMin(bbox.ymin)))
            .and_then(|p| clip_polygon_to_border(Cow::Owned(p), &Border::YMax(bbox.ymax)))
 }
 
+/// Clip each polygon on it's own. Polygons which are nowhere near the bbox are dropped before any
+/// clipping.
 pub fn clip_multipolygon_to_bbox(mp: Cow<MultiPolygon<i32>>, bbox: &Bbox<i32>) -> Option<MultiPolygon<i32>> {
     let polys: Vec<_> = match mp {
-        Cow::Owned(mp) => mp.0.into_iter().filter_map(|p| clip_polygon_to_bbox(Cow::Owned(p), bbox)).collect(),
-        Cow::Borrowed(mp) => mp.0.iter().filter_map(|p| clip_polygon_to_bbox(Cow::Borrowed(p), bbox)).collect(),
+        Cow::Owned(mp) => mp.0.into_iter().filter(|p| polygon_may_overlap_bbox(p, bbox)).filter_map(|p| clip_polygon_to_bbox(Cow::Owned(p), bbox)).collect(),
+        Cow::Borrowed(mp) => mp.0.iter().filter(|p| polygon_may_overlap_bbox(p, bbox)).filter_map(|p| clip_polygon_to_bbox(Cow::Borrowed(p), bbox)).collect(),
     };
 
     if polys.len() == 0 {
@@ -154,7 +154,7 @@ fn result_valid_geom() {
     assert!(validity::is_valid(&geom));
     let metatile =  Metatile::new(8, 4, 0, 0).unwrap();
     let buffer = 0;
-    for (t, g) in clip_geometry_to_tiles(&metatile, geom, buffer).into_iter() {
+    for (t, g) in clip_geometry_to_tiles(&metatile, geom, buffer, 4096).into_iter() {
         match g {
             Some(mut g) => {
                 assert!(validity::is_valid(&g), "Invalid geometry {:?}", g);
@@ -170,6 +170,151 @@ fn intersect1() {
     let border = Border::XMax(4096);
     let p1 = Point::new(4823, 3340);
     let p2 = Point::new(3962, 3222);
-    assert_eq!(intersection(&p1, &p2, &border), (4096, 3241));
+    assert_eq!(intersection(&p1, &p2, &border), (4096, 3240));
+    assert_eq!(intersection(&p2, &p1, &border), (4096, 3240));
 
 }
+
+#[test]
+fn clip_to_mercator_bounds_inside() {
+    let geom: Geometry<f64> = Polygon::new(vec![(0., 0.), (0., 10.), (10., 10.), (10., 0.), (0., 0.)].into(), vec![]).into();
+    assert_eq!(clip_to_mercator_bounds(geom.clone()), Some(geom));
+}
+
+#[test]
+fn clip_to_mercator_bounds_antimeridian() {
+    // A square from 179.1° to 180.9°, i.e. over the antimeridian.
+    let h = ::MERCATOR_HALF_WIDTH;
+    let geom: Geometry<f64> = Polygon::new(vec![(h-100000., 0.), (h-100000., 100000.), (h+100000., 100000.), (h+100000., 0.), (h-100000., 0.)].into(), vec![]).into();
+
+    let result = match clip_to_mercator_bounds(geom) {
+        Some(Geometry::MultiPolygon(mp)) => mp,
+        x => panic!("Expected a multipolygon, got {:?}", x),
+    };
+    assert_eq!(result.0.len(), 2);
+
+    // The part on the east edge of the world
+    let east = &result.0[0];
+    assert!(east.exterior.0.iter().all(|p| p.x() >= h-100000. && p.x() <= h));
+    // and the part moved over to the west edge
+    let west = &result.0[1];
+    assert!(west.exterior.0.iter().all(|p| p.x() >= -h && p.x() <= -h+100000.+1e-6));
+
+    // At zoom 1, each piece is in its own tile, not stretched across the world
+    assert_eq!(::lonlat_to_tile(179.5, 0.5, 1).0, 1);
+    assert_eq!(::lonlat_to_tile(-179.5, 0.5, 1).0, 0);
+    assert!(east.exterior.0.iter().all(|p| p.x() >= 0.));
+    assert!(west.exterior.0.iter().all(|p| p.x() <= 0.));
+}
+
+#[test]
+fn clip_to_mercator_bounds_poles() {
+    // A line going up past the top of the world gets cut at the top
+    let h = ::MERCATOR_HALF_WIDTH;
+    let geom: Geometry<f64> = LineString(vec![Point::new(0., 0.), Point::new(0., 2.*h)]).into();
+    assert_eq!(clip_to_mercator_bounds(geom), Some(LineString(vec![Point::new(0., 0.), Point::new(0., h)]).into()));
+
+    // Points off the top are dropped
+    assert_eq!(clip_to_mercator_bounds(Point::new(0., 2.*h).into()), None);
+}
+
+#[test]
+fn clamp_to_bbox1() {
+    let bbox = Bbox{ xmin: -64, xmax: 4096+64, ymin: -64, ymax: 4096+64 };
+
+    // One vertex is one unit over the edge
+    let mut geom: Geometry<i32> = Polygon::new(vec![(0, 0), (0, 2000), (4096+65, 2000), (2000, 0), (0, 0)].into(), vec![]).into();
+    assert!(validity::is_valid(&geom));
+    assert_eq!(clamp_to_bbox(&mut geom, &bbox), 1);
+    assert_eq!(geom, Polygon::new(vec![(0, 0), (0, 2000), (4096+64, 2000), (2000, 0), (0, 0)].into(), vec![]).into());
+    assert!(validity::is_valid(&geom));
+
+    // Nothing to do
+    assert_eq!(clamp_to_bbox(&mut geom, &bbox), 0);
+}
+
+#[test]
+fn intersect_steep() {
+    // Exactly half way up
+    assert_eq!(intersection(&Point::new(0, 0), &Point::new(2000, 7000), &Border::XMax(1000)), (1000, 3500));
+    assert_eq!(intersection(&Point::new(0, 0), &Point::new(-2000, 7000), &Border::XMin(-1000)), (-1000, 3500));
+    assert_eq!(intersection(&Point::new(0, 0), &Point::new(7000, -2000), &Border::YMin(-1000)), (3500, -1000));
+}
+
+#[test]
+fn shared_edge_crossing() {
+    // A diagonal line across the edge between 2 tiles (x = 4096)
+    let left = Bbox{ xmin: 0, xmax: 4096, ymin: 0, ymax: 4096 };
+    let right = Bbox{ xmin: 4096, xmax: 8192, ymin: 0, ymax: 4096 };
+    let line: Geometry<i32> = LineString::from(vec![(100, 100), (8000, 3000)]).into();
+    let reversed: Geometry<i32> = LineString::from(vec![(8000, 3000), (100, 100)]).into();
+
+    let crossing = |geom: &Geometry<i32>, bbox: &Bbox<i32>| -> Vec<(i32, i32)> {
+        match clip_to_bbox(Cow::Borrowed(geom), bbox) {
+            Some(Geometry::LineString(ls)) => ls.0.iter().filter(|p| p.x() == 4096).map(|p| (p.x(), p.y())).collect(),
+            x => panic!("Unexpected clip result {:?}", x),
+        }
+    };
+
+    // 100 + 2900 × 3996 ÷ 7900 = 1566.89
+    let expected = vec![(4096, 1567)];
+    assert_eq!(crossing(&line, &left), expected);
+    assert_eq!(crossing(&line, &right), expected);
+    assert_eq!(crossing(&reversed, &left), expected);
+    assert_eq!(crossing(&reversed, &right), expected);
+}
+
+#[test]
+fn multipolygon_clipped_per_polygon() {
+    // 50 small squares in a row, and only the first 2 are in the tile
+    let polygons: Vec<Polygon<i32>> = (0..50).map(|i| {
+        let x = i*3000 + 100;
+        Polygon::new(vec![(x, 100), (x, 200), (x+100, 200), (x+100, 100), (x, 100)].into(), vec![])
+    }).collect();
+    let mp: Geometry<i32> = MultiPolygon(polygons.clone()).into();
+    let bbox = Bbox{ xmin: -64, xmax: 4096+64, ymin: -64, ymax: 4096+64 };
+
+    let clipped = || sutherland_hodgeman::POLYGONS_CLIPPED.with(|c| c.get());
+    let before = clipped();
+    let result = clip_to_bbox(Cow::Borrowed(&mp), &bbox);
+    assert_eq!(clipped() - before, 2);
+    assert_eq!(result, Some(MultiPolygon(polygons[0..2].to_vec()).into()));
+
+    // Making it valid only clips those 2 as well
+    let before = clipped();
+    let result = validity::make_valid_within(mp, &bbox, &validity::ValidityOptions::default());
+    assert_eq!(clipped() - before, 2);
+    assert_eq!(result, Some(MultiPolygon(polygons[0..2].to_vec()).into()));
+}
+
+#[test]
+fn whole_bbox_polygon() {
+    let bbox = Bbox{ xmin: -64, xmax: 4096+64, ymin: -64, ymax: 4096+64 };
+
+    // Bigger than the tile, so clipping leaves the tile's rectangle
+    let big: Geometry<i32> = Polygon::new(vec![(-9000, -9000), (-9000, 9000), (9000, 9000), (9000, -9000), (-9000, -9000)].into(), vec![]).into();
+    let clipped = clip_to_bbox(Cow::Borrowed(&big), &bbox).unwrap();
+    assert!(covers_whole_bbox(&clipped, &bbox));
+
+    // Extra vertices along the edges, and a corner visited twice, are the same area
+    let messy: Geometry<i32> = Polygon::new(vec![(-64, -64), (-64, 2000), (-64, 4160), (4160, 4160), (4160, 1000), (4160, -64), (-64, -64), (-64, -64)].into(), vec![]).into();
+    assert!(covers_whole_bbox(&messy, &bbox));
+
+    let rectangle = bbox_polygon(&bbox, validity::YAxis::Down);
+    assert!(covers_whole_bbox(&rectangle, &bbox));
+    assert!(validity::is_valid(&rectangle));
+    match rectangle {
+        Geometry::Polygon(ref p) => assert_eq!(p.exterior.0.len(), 5),
+        ref x => panic!("{:?}", x),
+    }
+
+    // Only part of the tile, even though every vertex is on the edge
+    let triangle: Geometry<i32> = Polygon::new(vec![(-64, -64), (-64, 4160), (4160, 4160), (-64, -64)].into(), vec![]).into();
+    assert!(!covers_whole_bbox(&triangle, &bbox));
+    // Inside the tile
+    let inner: Geometry<i32> = Polygon::new(vec![(0, 0), (0, 4096), (4096, 4096), (4096, 0), (0, 0)].into(), vec![]).into();
+    assert!(!covers_whole_bbox(&inner, &bbox));
+    // A hole
+    let donut: Geometry<i32> = Polygon::new(vec![(-64, -64), (-64, 4160), (4160, 4160), (4160, -64), (-64, -64)].into(), vec![vec![(10, 10), (20, 10), (20, 20), (10, 10)].into()]).into();
+    assert!(!covers_whole_bbox(&donut, &bbox));
+}
@@ -0,0 +1,451 @@
+//! Just enough vector tile decoding to look inside generated tiles, e.g. to check which layers
+//! they have, or to compare them.
+
+use std::borrow::Cow;
+use std::io::Read;
+use std::rc::Rc;
+
+use flate2::read::GzDecoder;
+use geo::{Geometry, Point, LineString, Polygon, MultiPoint, MultiLineString, MultiPolygon};
+use mapbox_vector_tile;
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+/// The uncompressed tile. Tiles are usually gzipped, but don't have to be.
+pub fn decompress_tile(bytes: &[u8]) -> Result<Cow<[u8]>> {
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        let mut uncompressed = Vec::new();
+        GzDecoder::new(bytes).read_to_end(&mut uncompressed)?;
+        Ok(Cow::Owned(uncompressed))
+    } else {
+        Ok(Cow::Borrowed(bytes))
+    }
+}
+
+/// The value of one protobuf field.
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum FieldValue<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+fn varint(bytes: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut result = 0;
+    let mut shift = 0;
+    loop {
+        let b = match bytes.get(*pos) {
+            None => { return Err(format_err!("protobuf ends in the middle of a varint")); },
+            Some(b) => *b,
+        };
+        *pos += 1;
+        if shift >= 64 {
+            return Err(format_err!("protobuf varint is too long"));
+        }
+        result |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(result);
+        }
+        shift += 7;
+    }
+}
+
+/// The field number & value of every field in this protobuf message.
+fn protobuf_message(bytes: &[u8]) -> Result<Vec<(u64, FieldValue)>> {
+    fn fixed(bytes: &[u8], pos: &mut usize, len: usize) -> Result<u64> {
+        if *pos + len > bytes.len() {
+            return Err(format_err!("protobuf ends in the middle of a field"));
+        }
+        let value = bytes[*pos..*pos+len].iter().rev().fold(0, |acc, &b| (acc << 8) | (b as u64));
+        *pos += len;
+        Ok(value)
+    }
+
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let key = varint(bytes, &mut pos)?;
+        let value = match key & 0x7 {
+            0 => FieldValue::Varint(varint(bytes, &mut pos)?),
+            1 => FieldValue::Fixed64(fixed(bytes, &mut pos, 8)?),
+            2 => {
+                let len = varint(bytes, &mut pos)? as usize;
+                if pos + len > bytes.len() {
+                    return Err(format_err!("protobuf field {} is longer than the message", key >> 3));
+                }
+                pos += len;
+                FieldValue::Bytes(&bytes[pos-len..pos])
+            },
+            5 => FieldValue::Fixed32(fixed(bytes, &mut pos, 4)? as u32),
+            x => { return Err(format_err!("Unknown protobuf wire type {}", x)); },
+        };
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+/// The field number & contents of every length delimited field in this protobuf message. Other
+/// fields are skipped.
+pub fn protobuf_fields(bytes: &[u8]) -> Result<Vec<(u64, &[u8])>> {
+    Ok(protobuf_message(bytes)?.into_iter().filter_map(|(field, value)| match value {
+        FieldValue::Bytes(b) => Some((field, b)),
+        _ => None,
+    }).collect())
+}
+
+/// The names of the layers in this (possibly gzipped) vector tile, in the order they are in the
+/// tile.
+pub fn tile_layer_names(bytes: &[u8]) -> Result<Vec<String>> {
+    let bytes = decompress_tile(bytes)?;
+
+    // Tile.layers is field 3, and Layer.name is 1
+    protobuf_fields(&bytes)?.into_iter()
+        .filter(|&(field, _)| field == 3)
+        .map(|(_, layer)| {
+            let name = protobuf_fields(layer)?.into_iter().find(|&(field, _)| field == 1).map(|(_, name)| name);
+            match name {
+                None => Err(format_err!("Layer has no name")),
+                Some(name) => Ok(String::from_utf8(name.to_vec())?),
+            }
+        })
+        .collect()
+}
+
+/// An attribute value of a decoded feature.
+#[derive(Debug,Clone,PartialEq)]
+pub enum DecodedValue {
+    String(String),
+    Float(f32),
+    Double(f64),
+    Int(i64),
+    UInt(u64),
+    SInt(i64),
+    Bool(bool),
+}
+
+/// A feature from a vector tile, with its geometry decoded into parts (each a point, line or
+/// ring), and its attributes sorted by key.
+#[derive(Debug,Clone,PartialEq)]
+pub struct DecodedFeature {
+    /// 1 for points, 2 for lines & 3 for polygons
+    pub geom_type: u64,
+    pub parts: Vec<Vec<(i32, i32)>>,
+    pub properties: Vec<(String, DecodedValue)>,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct DecodedLayer {
+    pub name: String,
+    pub extent: u64,
+    pub features: Vec<DecodedFeature>,
+}
+
+impl DecodedValue {
+    /// The value to encode again. Unsigned & zigzag encoded ints become plain ints.
+    pub fn to_value(&self) -> mapbox_vector_tile::Value {
+        match *self {
+            DecodedValue::String(ref s) => mapbox_vector_tile::Value::String(Rc::new(s.clone())),
+            DecodedValue::Float(f) => mapbox_vector_tile::Value::Float(f),
+            DecodedValue::Double(d) => mapbox_vector_tile::Value::Double(d),
+            DecodedValue::Int(i) | DecodedValue::SInt(i) => mapbox_vector_tile::Value::Int(i),
+            DecodedValue::UInt(u) => mapbox_vector_tile::Value::Int(u as i64),
+            DecodedValue::Bool(b) => mapbox_vector_tile::Value::Boolean(b),
+        }
+    }
+}
+
+impl DecodedFeature {
+    /// The feature's geometry. Polygon rings are grouped into polygons by their winding: a ring
+    /// which winds the same way as the first one starts a new polygon, and the others are holes
+    /// in the polygon before them.
+    pub fn geometry(&self) -> Result<Geometry<i32>> {
+        fn linestring(part: &[(i32, i32)]) -> LineString<i32> {
+            LineString(part.iter().map(|&(x, y)| Point::new(x, y)).collect())
+        }
+        fn signed_area(part: &[(i32, i32)]) -> i64 {
+            part.windows(2).map(|w| (w[0].0 as i64)*(w[1].1 as i64) - (w[1].0 as i64)*(w[0].1 as i64)).sum()
+        }
+
+        if self.parts.is_empty() {
+            return Err(format_err!("Feature has no geometry"));
+        }
+        match self.geom_type {
+            1 => {
+                let mut points: Vec<Point<i32>> = self.parts.iter().flat_map(|part| part.iter().map(|&(x, y)| Point::new(x, y))).collect();
+                Ok(if points.len() == 1 { Geometry::Point(points.remove(0)) } else { Geometry::MultiPoint(MultiPoint(points)) })
+            },
+            2 => {
+                let mut lines: Vec<LineString<i32>> = self.parts.iter().map(|part| linestring(part)).collect();
+                Ok(if lines.len() == 1 { Geometry::LineString(lines.remove(0)) } else { Geometry::MultiLineString(MultiLineString(lines)) })
+            },
+            3 => {
+                let exterior_is_positive = signed_area(&self.parts[0]) > 0;
+                let mut polygons: Vec<Polygon<i32>> = Vec::new();
+                for part in self.parts.iter() {
+                    if (signed_area(part) > 0) == exterior_is_positive || polygons.is_empty() {
+                        polygons.push(Polygon::new(linestring(part), vec![]));
+                    } else {
+                        polygons.last_mut().unwrap().interiors.push(linestring(part));
+                    }
+                }
+                Ok(if polygons.len() == 1 { Geometry::Polygon(polygons.remove(0)) } else { Geometry::MultiPolygon(MultiPolygon(polygons)) })
+            },
+            x => Err(format_err!("Unknown geometry type {}", x)),
+        }
+    }
+
+    /// The feature's attributes, to encode again.
+    pub fn mvt_properties(&self) -> mapbox_vector_tile::Properties {
+        let mut properties = mapbox_vector_tile::Properties::new();
+        for &(ref key, ref value) in self.properties.iter() {
+            properties.insert(Rc::new(key.clone()), value.to_value());
+        }
+        properties
+    }
+}
+
+/// The numbers in a packed repeated field (or a single, unpacked, one).
+fn packed_varints(value: FieldValue) -> Result<Vec<u64>> {
+    match value {
+        FieldValue::Varint(v) => Ok(vec![v]),
+        FieldValue::Bytes(bytes) => {
+            let mut values = Vec::new();
+            let mut pos = 0;
+            while pos < bytes.len() {
+                values.push(varint(bytes, &mut pos)?);
+            }
+            Ok(values)
+        },
+        _ => Err(format_err!("Expected a packed field")),
+    }
+}
+
+fn zigzag(n: u64) -> i64 {
+    ((n >> 1) as i64) ^ -((n & 1) as i64)
+}
+
+fn decode_value(bytes: &[u8]) -> Result<DecodedValue> {
+    let value = match protobuf_message(bytes)?.into_iter().next() {
+        Some((1, FieldValue::Bytes(s))) => DecodedValue::String(String::from_utf8(s.to_vec())?),
+        Some((2, FieldValue::Fixed32(f))) => DecodedValue::Float(f32::from_bits(f)),
+        Some((3, FieldValue::Fixed64(d))) => DecodedValue::Double(f64::from_bits(d)),
+        Some((4, FieldValue::Varint(i))) => DecodedValue::Int(i as i64),
+        Some((5, FieldValue::Varint(u))) => DecodedValue::UInt(u),
+        Some((6, FieldValue::Varint(s))) => DecodedValue::SInt(zigzag(s)),
+        Some((7, FieldValue::Varint(b))) => DecodedValue::Bool(b != 0),
+        x => { return Err(format_err!("Unknown value {:?}", x)); },
+    };
+    Ok(value)
+}
+
+/// Decode the MoveTo/LineTo/ClosePath commands of a feature's geometry into its parts.
+fn decode_geometry(commands: &[u64]) -> Result<Vec<Vec<(i32, i32)>>> {
+    let mut parts: Vec<Vec<(i32, i32)>> = Vec::new();
+    let (mut x, mut y) = (0i64, 0i64);
+    let mut i = 0;
+    while i < commands.len() {
+        let (command, count) = (commands[i] & 0x7, (commands[i] >> 3) as usize);
+        i += 1;
+        match command {
+            1 | 2 => {
+                if i + 2*count > commands.len() {
+                    return Err(format_err!("Geometry ends in the middle of a command"));
+                }
+                for _ in 0..count {
+                    x += zigzag(commands[i]);
+                    y += zigzag(commands[i+1]);
+                    i += 2;
+                    let point = (x as i32, y as i32);
+                    if command == 1 {
+                        parts.push(vec![point]);
+                        continue;
+                    }
+                    match parts.last_mut() {
+                        Some(part) => part.push(point),
+                        None => { return Err(format_err!("LineTo before a MoveTo")); },
+                    }
+                }
+            },
+            7 => match parts.last_mut() {
+                Some(part) => { let first = part[0]; part.push(first); },
+                None => { return Err(format_err!("ClosePath before a MoveTo")); },
+            },
+            c => { return Err(format_err!("Unknown geometry command {}", c)); },
+        }
+    }
+    Ok(parts)
+}
+
+/// Decode one layer (the bytes of a tile's `layers` field).
+pub fn decode_layer(bytes: &[u8]) -> Result<DecodedLayer> {
+    let fields = protobuf_message(bytes)?;
+    let mut name = None;
+    let mut extent = 4096;
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+    for &(field, value) in fields.iter() {
+        match (field, value) {
+            (1, FieldValue::Bytes(n)) => { name = Some(String::from_utf8(n.to_vec())?); },
+            (3, FieldValue::Bytes(k)) => { keys.push(String::from_utf8(k.to_vec())?); },
+            (4, FieldValue::Bytes(v)) => { values.push(decode_value(v)?); },
+            (5, FieldValue::Varint(e)) => { extent = e; },
+            _ => {},
+        }
+    }
+
+    let mut features = Vec::new();
+    for &(field, value) in fields.iter() {
+        let bytes = match (field, value) {
+            (2, FieldValue::Bytes(b)) => b,
+            _ => { continue; },
+        };
+        let mut geom_type = 0;
+        let mut tags = Vec::new();
+        let mut commands = Vec::new();
+        for (field, value) in protobuf_message(bytes)? {
+            match field {
+                2 => tags.extend(packed_varints(value)?),
+                3 => if let FieldValue::Varint(t) = value { geom_type = t; },
+                4 => commands.extend(packed_varints(value)?),
+                _ => {},
+            }
+        }
+
+        let mut properties = Vec::with_capacity(tags.len()/2);
+        for tag in tags.chunks(2) {
+            match (tag.get(0).and_then(|&k| keys.get(k as usize)), tag.get(1).and_then(|&v| values.get(v as usize))) {
+                (Some(k), Some(v)) => properties.push((k.clone(), v.clone())),
+                _ => { return Err(format_err!("Feature has an unknown key or value")); },
+            }
+        }
+        properties.sort_by(|a, b| a.0.cmp(&b.0));
+
+        features.push(DecodedFeature{ geom_type, parts: decode_geometry(&commands)?, properties });
+    }
+
+    match name {
+        None => Err(format_err!("Layer has no name")),
+        Some(name) => Ok(DecodedLayer{ name, extent, features }),
+    }
+}
+
+/// Decode all the layers in this (possibly gzipped) vector tile, in the order they are in the
+/// tile.
+pub fn decode_tile_layers(bytes: &[u8]) -> Result<Vec<DecodedLayer>> {
+    let bytes = decompress_tile(bytes)?;
+    protobuf_fields(&bytes)?.into_iter()
+        .filter(|&(field, _)| field == 3)
+        .map(|(_, layer)| decode_layer(layer))
+        .collect()
+}
+
+/// Decode all the layers in this (possibly gzipped) vector tile, sorted by name.
+fn decode_tile(bytes: &[u8]) -> Result<Vec<DecodedLayer>> {
+    let mut layers = decode_tile_layers(bytes)?;
+    layers.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(layers)
+}
+
+/// Are these features the same, with every vertex within `tolerance` (in x & y)?
+fn features_equal(a: &DecodedFeature, b: &DecodedFeature, tolerance: i32) -> bool {
+    a.geom_type == b.geom_type && a.properties == b.properties && a.parts.len() == b.parts.len()
+        && a.parts.iter().zip(b.parts.iter()).all(|(a, b)| {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(p, q)| (p.0 - q.0).abs() <= tolerance && (p.1 - q.1).abs() <= tolerance)
+        })
+}
+
+/// Do these 2 (possibly gzipped) vector tiles have the same layers, with the same features? The
+/// order of layers & features, and how they're encoded, don't matter, but every feature must have
+/// the same attributes, and its vertices must be within `tolerance` of the other's. Tiles which
+/// can't be decoded aren't equal to anything.
+pub fn tiles_geometrically_equal(a: &[u8], b: &[u8], tolerance: i32) -> bool {
+    let (a, b) = match (decode_tile(a), decode_tile(b)) {
+        (Ok(a), Ok(b)) => (a, b),
+        _ => { return false; },
+    };
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).all(|(a, b)| {
+        if a.name != b.name || a.extent != b.extent || a.features.len() != b.features.len() {
+            return false;
+        }
+        let mut matched = vec![false; b.features.len()];
+        a.features.iter().all(|f| {
+            let found = b.features.iter().enumerate().position(|(i, g)| !matched[i] && features_equal(f, g, tolerance));
+            match found {
+                None => false,
+                Some(i) => { matched[i] = true; true },
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapbox_vector_tile;
+
+    #[test]
+    fn layer_names() {
+        let mut tile = mapbox_vector_tile::Tile::new();
+        tile.add_layer(mapbox_vector_tile::Layer::new("water".to_string()));
+        tile.add_layer(mapbox_vector_tile::Layer::new("roads".to_string()));
+
+        let expected = vec!["water".to_string(), "roads".to_string()];
+        assert_eq!(tile_layer_names(&tile.to_bytes()).unwrap(), expected);
+        assert_eq!(tile_layer_names(&tile.to_compressed_bytes()).unwrap(), expected);
+
+        assert!(tile_layer_names(&[0x1a, 0x10, 0x0a]).is_err());
+    }
+
+    #[test]
+    fn geometrically_equal() {
+        use std::rc::Rc;
+        use geo::{Point, LineString, Geometry};
+
+        fn properties(name: &str, lanes: i64) -> Rc<mapbox_vector_tile::Properties> {
+            let mut properties = mapbox_vector_tile::Properties::new();
+            properties.insert(Rc::new("name".to_string()), mapbox_vector_tile::Value::String(Rc::new(name.to_string())));
+            properties.insert(Rc::new("lanes".to_string()), mapbox_vector_tile::Value::Int(lanes));
+            Rc::new(properties)
+        }
+        fn tile(features: Vec<(Geometry<i32>, Rc<mapbox_vector_tile::Properties>)>) -> mapbox_vector_tile::Tile {
+            let mut layer = mapbox_vector_tile::Layer::new("roads".to_string());
+            for (geom, properties) in features {
+                layer.add_feature(mapbox_vector_tile::Feature::new(geom, properties));
+            }
+            let mut tile = mapbox_vector_tile::Tile::new();
+            tile.add_layer(layer);
+            tile
+        }
+
+        let main_street: Geometry<i32> = LineString(vec![Point::new(0, 0), Point::new(100, 100)]).into();
+        let high_street: Geometry<i32> = LineString(vec![Point::new(50, 0), Point::new(50, 300)]).into();
+        let a = tile(vec![(main_street.clone(), properties("Main Street", 2)), (high_street.clone(), properties("High Street", 1))]);
+        let b = tile(vec![(high_street.clone(), properties("High Street", 1)), (main_street.clone(), properties("Main Street", 2))]);
+
+        // Different order (incl. the keys & values), but the same features
+        assert_ne!(a.to_bytes(), b.to_bytes());
+        assert!(tiles_geometrically_equal(&a.to_bytes(), &b.to_bytes(), 0));
+        assert!(tiles_geometrically_equal(&a.to_compressed_bytes(), &b.to_bytes(), 0));
+
+        // A vertex moved by 1
+        let moved: Geometry<i32> = LineString(vec![Point::new(0, 0), Point::new(101, 100)]).into();
+        let c = tile(vec![(moved, properties("Main Street", 2)), (high_street.clone(), properties("High Street", 1))]);
+        assert!(!tiles_geometrically_equal(&a.to_bytes(), &c.to_bytes(), 0));
+        assert!(tiles_geometrically_equal(&a.to_bytes(), &c.to_bytes(), 1));
+
+        // Different attributes
+        let d = tile(vec![(main_street.clone(), properties("Main Street", 3)), (high_street.clone(), properties("High Street", 1))]);
+        assert!(!tiles_geometrically_equal(&a.to_bytes(), &d.to_bytes(), 10));
+
+        // Missing a feature
+        let e = tile(vec![(main_street, properties("Main Street", 2))]);
+        assert!(!tiles_geometrically_equal(&a.to_bytes(), &e.to_bytes(), 10));
+
+        assert!(!tiles_geometrically_equal(&a.to_bytes(), &[0x1a, 0x10, 0x0a], 10));
+    }
+}
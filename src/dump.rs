@@ -0,0 +1,96 @@
+//! Saving the geometries which couldn't be made valid (and so were left out of the tiles) to a
+//! GeoJSON file, so the source data can be looked at & fixed.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use geo::Geometry;
+use geo::algorithm::map_coords::MapCoords;
+use slippy_map_tiles::Metatile;
+
+use ::geometry_as_geojson;
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+/// A GeoJSON FeatureCollection, which the geometries are added to as they're dropped (from any
+/// thread). `finish` closes it, and if it isn't called (e.g. generating stopped with an error),
+/// it's closed when dropped.
+pub struct InvalidGeometryDump {
+    file: Mutex<DumpFile>,
+}
+
+struct DumpFile {
+    writer: BufWriter<File>,
+    num_features: usize,
+    finished: bool,
+}
+
+impl InvalidGeometryDump {
+    /// Start a new (empty) dump file, replacing any which is there.
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path).map_err(|e| format_err!("Couldn't create {}: {}", path.display(), e))?);
+        write!(writer, "{{\"type\":\"FeatureCollection\",\"features\":[\n")?;
+        Ok(InvalidGeometryDump{ file: Mutex::new(DumpFile{ writer: writer, num_features: 0, finished: false }) })
+    }
+
+    /// Add this geometry, which is in the coordinates of this metatile (where each tile is
+    /// `4096 * oversample` units wide), from this layer. `x` & `y` in the properties are of the
+    /// top left tile of the metatile.
+    pub fn add(&self, layer: &str, metatile: &Metatile, oversample: i32, geom: &Geometry<i32>, reason: &str) {
+        // geom_as_geojson needs coordinates in the whole world
+        let size = (4096 * oversample) as f64;
+        let (x0, y0) = (metatile.x() as f64 * size, metatile.y() as f64 * size);
+        let world_geom: Geometry<f64> = geom.map_coords(&|&(x, y)| (x as f64 + x0, y as f64 + y0));
+        let extent = size * 2f64.powi(metatile.zoom() as i32);
+
+        self.write_feature(layer, metatile, &geometry_as_geojson(&world_geom, extent), reason);
+    }
+
+    /// Add a feature whose geometry couldn't be read, so there's only a `null` geometry.
+    pub fn add_unreadable(&self, layer: &str, metatile: &Metatile, reason: &str) {
+        self.write_feature(layer, metatile, "null", reason);
+    }
+
+    fn write_feature(&self, layer: &str, metatile: &Metatile, geometry: &str, reason: &str) {
+        let properties = json!({ "layer": layer, "zoom": metatile.zoom(), "x": metatile.x(), "y": metatile.y(), "reason": reason });
+        let feature = format!("{{\"type\":\"Feature\",\"properties\":{},\"geometry\":{}}}", properties, geometry);
+
+        let mut file = self.file.lock().unwrap();
+        let separator = if file.num_features == 0 { "" } else { ",\n" };
+        if let Err(e) = write!(file.writer, "{}{}", separator, feature) {
+            error!("Couldn't write invalid geometry to the dump file: {}", e);
+        }
+        file.num_features += 1;
+    }
+
+    /// Close the FeatureCollection. Returns how many geometries are in it.
+    pub fn finish(&self) -> Result<usize> {
+        let mut file = self.file.lock().unwrap();
+        file.close()?;
+        Ok(file.num_features)
+    }
+}
+
+impl DumpFile {
+    fn close(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        write!(self.writer, "\n]}}\n")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for InvalidGeometryDump {
+    fn drop(&mut self) {
+        if let Ok(file) = self.file.get_mut() {
+            if let Err(e) = file.close() {
+                error!("Couldn't close the invalid geometry dump file: {}", e);
+            }
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use std::{thread, time};
 use std::sync::mpsc::Receiver;
-use std::io::{Write, BufWriter};
-use std::path::PathBuf;
+use std::io::{self, Write, BufWriter};
+use std::path::{Path, PathBuf};
 use slippy_map_tiles;
 use std::fs;
 use std::fs::File;
@@ -11,6 +11,11 @@ use md5;
 use byteorder::{LittleEndian, WriteBytesExt};
 use serde_json;
 
+use ::TileScheme;
+use ::MAX_ZOOM;
+
+type Result<T> = ::std::result::Result<T, ::failure::Error>;
+
 #[derive(Debug,Eq,PartialEq)]
 pub enum FileIOMessage {
     // Time to quit
@@ -21,6 +26,9 @@ pub enum FileIOMessage {
     SaveMetaTile(slippy_map_tiles::Metatile, Vec<(slippy_map_tiles::Tile, Vec<u8>)>),
 
     AppendToTile(slippy_map_tiles::Tile, Vec<u8>),
+
+    // Hashes of the uncompressed tile contents
+    SaveTileHashes(Vec<(slippy_map_tiles::Tile, String)>),
     // EnsureAllCompressed,
 }
 
@@ -40,12 +48,53 @@ pub fn fileio_thread<D: TileDestination+Sized>(rx: Receiver<FileIOMessage>, mut
             FileIOMessage::AppendToTile(tile, bytes) => {
                 dest.append_bytes_to_tile(tile, bytes);
             },
+            FileIOMessage::SaveTileHashes(hashes) => {
+                dest.save_tile_hashes(hashes);
+            },
         }
     }
 
     dest.finish();
 }
 
+/// The destination can't be written to. This is found before any tiles are generated.
+#[derive(Debug, Fail)]
+#[fail(display = "Can't write to destination {:?}: {}", path, cause)]
+pub struct DestinationError {
+    /// The directory or MBTiles file
+    pub path: PathBuf,
+    #[cause]
+    pub cause: io::Error,
+}
+
+/// Error if tiles can't be written in this directory, e.g. it can't be created, or is read only.
+/// The directory is created if needed.
+pub fn check_dir_writable(dir: &Path) -> ::std::result::Result<(), DestinationError> {
+    let error = |cause| DestinationError{ path: dir.to_path_buf(), cause };
+    fs::create_dir_all(dir).map_err(&error)?;
+
+    let probe = dir.join(format!(".tileigi-write-test-{}", ::std::process::id()));
+    File::create(&probe).map_err(&error)?;
+    fs::remove_file(&probe).map_err(&error)?;
+
+    Ok(())
+}
+
+/// Error if this MBTiles file can't be created (or written to, if it already exists).
+pub fn check_mbtiles_writable(filename: &Path) -> ::std::result::Result<(), DestinationError> {
+    let dir = match filename.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    check_dir_writable(dir)?;
+
+    if filename.exists() {
+        fs::OpenOptions::new().write(true).open(filename).map_err(|cause| DestinationError{ path: filename.to_path_buf(), cause })?;
+    }
+
+    Ok(())
+}
+
 pub trait TileDestination {
     fn new(dest_dir: &PathBuf) -> Self;
 
@@ -59,42 +108,160 @@ pub trait TileDestination {
 
     fn finish(&mut self) {}
 
-    fn does_metatile_exist(dest: &PathBuf, metatile: &slippy_map_tiles::Metatile) -> bool {
-        metatile.tiles().iter().all(|t| Self::does_tile_exist(dest, t))
+    /// Store these hashes of the tile contents. Not every destination can, so the default is to
+    /// ignore them.
+    fn save_tile_hashes(&mut self, hashes: Vec<(slippy_map_tiles::Tile, String)>) {}
+
+    fn does_metatile_exist(dest: &PathBuf, scheme: TileScheme, metatile: &slippy_map_tiles::Metatile) -> bool {
+        metatile.tiles().iter().all(|t| Self::does_tile_exist(dest, scheme, t))
     }
 
-    fn does_tile_exist(dest: &PathBuf, tile: &slippy_map_tiles::Tile) -> bool;
+    fn does_tile_exist(dest: &PathBuf, scheme: TileScheme, tile: &slippy_map_tiles::Tile) -> bool;
 
     fn append_bytes_to_tile(&mut self, tile: slippy_map_tiles::Tile, bytes: Vec<u8>) {
         unimplemented!();
     }
 }
 
+/// Keeps track of files that have been written, but not synced to disk yet, so that we can sync
+/// them every so often. We don't keep the files open, so we don't run out of file descriptors.
+struct UnsyncedFiles {
+    /// Sync after this many files. 0 = never sync (the OS will do it eventually).
+    flush_every: usize,
+    paths: Vec<PathBuf>,
+}
+
+impl UnsyncedFiles {
+    fn new() -> Self {
+        UnsyncedFiles{ flush_every: 0, paths: Vec::new() }
+    }
+
+    /// This file has been written (and closed).
+    fn written(&mut self, path: PathBuf) {
+        if self.flush_every == 0 {
+            return;
+        }
+        self.paths.push(path);
+        if self.paths.len() >= self.flush_every {
+            self.sync();
+        }
+    }
+
+    /// Sync all the files written since last time to disk.
+    fn sync(&mut self) {
+        for path in self.paths.drain(..) {
+            File::open(&path).and_then(|f| f.sync_all()).unwrap();
+        }
+    }
+}
+
+/// Check this is a template for tile paths which `templated_tile_path` can use. It must have
+/// `{z}`, `{x}` & `{y}`, so that every tile has its own file, and no other `{...}`s.
+pub fn check_path_template(template: &str) -> Result<()> {
+    for placeholder in ["{z}", "{x}", "{y}"].iter() {
+        if ! template.contains(placeholder) {
+            return Err(format_err!("Path template {:?} doesn't have {}", template, placeholder));
+        }
+    }
+    let rest = ["{z}", "{x}", "{y}", "{x_hi}", "{y_hi}"].iter().fold(template.to_string(), |t, placeholder| t.replace(placeholder, ""));
+    if rest.contains('{') || rest.contains('}') {
+        return Err(format_err!("Path template {:?} has an unknown {{...}}, only {{z}}, {{x}}, {{y}}, {{x_hi}} & {{y_hi}} can be used", template));
+    }
+    if template.starts_with('/') {
+        return Err(format_err!("Path template {:?} must be relative to the directory", template));
+    }
+
+    Ok(())
+}
+
+/// The path of this tile from a template (see `check_path_template`). `{x_hi}` & `{y_hi}` are
+/// x & y / 1024, to spread the tiles of high zooms over more directories.
+pub fn templated_tile_path(template: &str, zoom: u8, x: u32, y: u32) -> String {
+    template
+        .replace("{z}", &zoom.to_string())
+        .replace("{x_hi}", &(x / 1024).to_string())
+        .replace("{y_hi}", &(y / 1024).to_string())
+        .replace("{x}", &x.to_string())
+        .replace("{y}", &y.to_string())
+}
+
 pub struct TileStashDirectory {
     dest_dir: PathBuf,
+    scheme: TileScheme,
+    path_template: Option<String>,
+    unsynced: UnsyncedFiles,
+}
+
+impl TileStashDirectory {
+    pub fn set_scheme(&mut self, scheme: TileScheme) {
+        self.scheme = scheme;
+    }
+
+    /// Save tiles at this path (see `templated_tile_path`), rather than the usual TileStash
+    /// 'safe' layout.
+    pub fn set_path_template(&mut self, path_template: Option<String>) {
+        self.path_template = path_template;
+    }
+
+    /// Does this metatile exist, with tiles saved at paths from this template?
+    pub fn does_metatile_exist_with_template(dest: &PathBuf, scheme: TileScheme, path_template: Option<&str>, metatile: &slippy_map_tiles::Metatile) -> bool {
+        metatile.tiles().iter().all(|t| Self::templated_path(dest, scheme, path_template, t).exists())
+    }
+
+    /// Sync tiles to disk after every `flush_every` tiles. 0 (the default) means never.
+    pub fn set_flush_every(&mut self, flush_every: usize) {
+        self.unsynced.flush_every = flush_every;
+    }
+
+    /// Filename for this tile, with the y flipped if needed.
+    fn tile_path(dest: &PathBuf, scheme: TileScheme, tile: &slippy_map_tiles::Tile) -> PathBuf {
+        Self::templated_path(dest, scheme, None, tile)
+    }
+
+    /// Filename for this tile, from the template if there is one, with the y flipped if needed.
+    fn templated_path(dest: &PathBuf, scheme: TileScheme, path_template: Option<&str>, tile: &slippy_map_tiles::Tile) -> PathBuf {
+        let row = scheme.row(tile.zoom(), tile.y());
+        match path_template {
+            None => {
+                let tile = slippy_map_tiles::Tile::new(tile.zoom(), tile.x(), row).unwrap();
+                dest.join(tile.ts_path("pbf"))
+            },
+            Some(template) => dest.join(templated_tile_path(template, tile.zoom(), tile.x(), row)),
+        }
+    }
 }
 
 impl TileDestination for TileStashDirectory {
     fn new(dest_dir: &PathBuf) -> Self {
         fs::create_dir_all(&dest_dir).unwrap();
-        TileStashDirectory{ dest_dir: dest_dir.clone() }
+        TileStashDirectory{ dest_dir: dest_dir.clone(), scheme: TileScheme::Xyz, path_template: None, unsynced: UnsyncedFiles::new() }
     }
 
     fn save_tile(&mut self, tile: slippy_map_tiles::Tile, bytes: Vec<u8>) {
-        let filename = self.dest_dir.join(tile.ts_path("pbf"));
+        let filename = Self::templated_path(&self.dest_dir, self.scheme, self.path_template.as_ref().map(|t| t.as_str()), &tile);
         fs::create_dir_all(filename.parent().unwrap()).unwrap();
 
-        let mut file = BufWriter::new(File::create(filename).unwrap());
-        file.write_all(&bytes).unwrap();
+        {
+            let mut file = BufWriter::new(File::create(&filename).unwrap());
+            file.write_all(&bytes).unwrap();
+            file.flush().unwrap();
+        }
+        self.unsynced.written(filename);
+    }
+
+    fn finish(&mut self) {
+        self.unsynced.sync();
     }
 
-    fn does_tile_exist(dest: &PathBuf, tile: &slippy_map_tiles::Tile) -> bool {
-        dest.join(tile.ts_path("pbf")).exists()
+    fn does_tile_exist(dest: &PathBuf, scheme: TileScheme, tile: &slippy_map_tiles::Tile) -> bool {
+        Self::tile_path(dest, scheme, tile).exists()
     }
 }
 
 pub struct MBTiles {
     conn: rusqlite::Connection,
+    scheme: TileScheme,
+    sorted_writes: bool,
 }
 
 impl TileDestination for MBTiles {
@@ -120,7 +287,7 @@ impl TileDestination for MBTiles {
         conn.execute( "INSERT OR REPLACE INTO metadata (name, value) VALUES ('center', '0.0,0.0,0');", &[]).unwrap();
         conn.execute( "INSERT OR REPLACE INTO metadata (name, value) VALUES ('bounds', '-180.0,-85,180,85');", &[]).unwrap();
         conn.execute( "INSERT OR REPLACE INTO metadata (name, value) VALUES ('type', 'basemap');", &[]).unwrap();
-        conn.execute( "INSERT OR REPLACE INTO metadata (name, value) VALUES ('scheme', 'tms');", &[]).unwrap();
+        conn.execute( "INSERT OR REPLACE INTO metadata (name, value) VALUES ('scheme', ?1);", &[&TileScheme::Tms.as_str()]).unwrap();
 
         // sqlite is *much* faster if everything happens in one transaction, rather than each
         // statement each in it's own transaction. But we can't easily carry a
@@ -128,14 +295,24 @@ impl TileDestination for MBTiles {
         // transaction (in this connection), at the start
         conn.execute("BEGIN TRANSACTION;", &[]).unwrap();
 
-        MBTiles{ conn: conn }
+        MBTiles{ conn: conn, scheme: TileScheme::Tms, sorted_writes: false }
+    }
+
+    fn save_metatile(&mut self, _metatile: slippy_map_tiles::Metatile, mut tiles: Vec<(slippy_map_tiles::Tile, Vec<u8>)>) {
+        if self.sorted_writes {
+            let scheme = self.scheme;
+            tiles.sort_by_key(|&(ref tile, _)| (tile.zoom(), tile.x(), scheme.row(tile.zoom(), tile.y())));
+        }
+        for (tile, bytes) in tiles.into_iter() {
+            self.save_tile(tile, bytes);
+        }
     }
 
 
     fn save_tile(&mut self, tile: slippy_map_tiles::Tile, bytes: Vec<u8>) {
         let digest = format!("{:x}", md5::compute(&bytes));
 
-        let row: u32 = 2u32.pow(tile.zoom() as u32) - tile.y() - 1;
+        let row: u32 = self.scheme.row(tile.zoom(), tile.y());
 
         self.conn.execute(
             "INSERT OR REPLACE INTO map (zoom_level, tile_column, tile_row, tile_id) VALUES (?1, ?2, ?3, ?4);",
@@ -151,20 +328,30 @@ impl TileDestination for MBTiles {
 
     }
 
+    fn save_tile_hashes(&mut self, hashes: Vec<(slippy_map_tiles::Tile, String)>) {
+        for (tile, hash) in hashes.into_iter() {
+            let row: u32 = self.scheme.row(tile.zoom(), tile.y());
+            self.conn.execute(
+                "INSERT OR REPLACE INTO tile_hashes (zoom_level, tile_column, tile_row, hash) VALUES (?1, ?2, ?3, ?4);",
+                &[&tile.zoom(), &tile.x(), &row, &hash]
+                ).unwrap();
+        }
+    }
+
     fn finish(&mut self) {
         //self.txn().commit();
         self.conn.execute("COMMIT;", &[]).unwrap();
     }
 
-    fn does_tile_exist(dest: &PathBuf, tile: &slippy_map_tiles::Tile) -> bool {
-        // Not implemented yet
-        false
+    /// The scheme is read from the file.
+    fn does_tile_exist(dest: &PathBuf, _scheme: TileScheme, tile: &slippy_map_tiles::Tile) -> bool {
+        ExistingMBTiles::open(dest).does_tile_exist(tile)
     }
 
     fn append_bytes_to_tile(&mut self, tile: slippy_map_tiles::Tile, bytes: Vec<u8>) {
         let digest = format!("{}/{}/{}", tile.zoom(), tile.x(), tile.y());
 
-        let row: u32 = 2u32.pow(tile.zoom() as u32) - tile.y() - 1;
+        let row: u32 = self.scheme.row(tile.zoom(), tile.y());
 
         let num_changed = self.conn.execute(
             "INSERT OR IGNORE INTO map (zoom_level, tile_column, tile_row, tile_id) VALUES (?1, ?2, ?3, ?4);",
@@ -187,6 +374,32 @@ impl TileDestination for MBTiles {
 }
 
 impl MBTiles {
+    /// Number the rows with this scheme. The MBTiles spec says TMS, which is the default.
+    pub fn set_scheme(&mut self, scheme: TileScheme) {
+        self.scheme = scheme;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO metadata (name, value) VALUES ('scheme', ?1);",
+            &[&scheme.as_str()]
+            ).unwrap();
+    }
+
+    /// Insert the tiles of each metatile in the order of the `map` index (zoom, column, row),
+    /// rather than the order they were generated in, so SQLite's B-tree pages are filled in
+    /// order, and not split & rewritten as often.
+    pub fn set_sorted_writes(&mut self, sorted_writes: bool) {
+        self.sorted_writes = sorted_writes;
+    }
+
+    /// Create the `tile_hashes` table, which stores a hash of each tile's uncompressed contents.
+    pub fn set_store_tile_hashes(&mut self, store_tile_hashes: bool) {
+        if store_tile_hashes {
+            self.conn.execute_batch("
+                CREATE TABLE IF NOT EXISTS tile_hashes (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, hash TEXT);
+                CREATE UNIQUE INDEX IF NOT EXISTS tile_hashes_index ON tile_hashes (zoom_level, tile_column, tile_row);
+                ").unwrap();
+        }
+    }
+
     pub fn set_tilejson_vector_layers(&mut self, vector_layers: serde_json::Value) {
         let vector_layers_string = vector_layers.to_string();
         self.conn.execute(
@@ -194,19 +407,298 @@ impl MBTiles {
             &[&vector_layers_string]
             ).unwrap();
     }
+
+    /// Set the `minzoom` & `maxzoom` metadata to the zooms in this file.
+    pub fn set_zoom_range(&mut self, minzoom: u8, maxzoom: u8) {
+        self.conn.execute("INSERT OR REPLACE INTO metadata (name, value) VALUES ('minzoom', ?1);", &[&minzoom.to_string()]).unwrap();
+        self.conn.execute("INSERT OR REPLACE INTO metadata (name, value) VALUES ('maxzoom', ?1);", &[&maxzoom.to_string()]).unwrap();
+    }
+
+    /// Set the `bounds` metadata, as `[minlon, minlat, maxlon, maxlat]`.
+    pub fn set_bounds(&mut self, bounds: [f64; 4]) {
+        let bounds_string = format!("{},{},{},{}", bounds[0], bounds[1], bounds[2], bounds[3]);
+        self.conn.execute("INSERT OR REPLACE INTO metadata (name, value) VALUES ('bounds', ?1);", &[&bounds_string]).unwrap();
+    }
+
+    /// Set any other metadata row, e.g. `attribution`.
+    pub fn set_metadata(&mut self, name: &str, value: &str) {
+        self.conn.execute("INSERT OR REPLACE INTO metadata (name, value) VALUES (?1, ?2);", &[&name, &value]).unwrap();
+    }
+}
+
+/// An existing MBTiles file, which tiles are read from, e.g. to compare new tiles to.
+pub struct MBTilesReader {
+    conn: rusqlite::Connection,
+    scheme: TileScheme,
+}
+
+impl MBTilesReader {
+    pub fn open(filename: &PathBuf) -> Result<Self> {
+        if ! filename.is_file() {
+            return Err(format_err!("MBTiles file {} doesn't exist", filename.display()));
+        }
+        let conn = rusqlite::Connection::open_with_flags(filename, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let scheme: String = conn.query_row("SELECT value FROM metadata WHERE name = 'scheme';", &[], |r| r.get(0)).unwrap_or_else(|_| "tms".to_string());
+        let scheme = if scheme == "xyz" { TileScheme::Xyz } else { TileScheme::Tms };
+        Ok(MBTilesReader{ conn, scheme })
+    }
+
+    /// The (as stored, i.e. usually compressed) bytes of this tile, or None if it's not in the
+    /// file.
+    pub fn tile_data(&self, tile: &slippy_map_tiles::Tile) -> Result<Option<Vec<u8>>> {
+        let row: u32 = self.scheme.row(tile.zoom(), tile.y());
+        match self.conn.query_row("SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3;", &[&tile.zoom(), &tile.x(), &row], |r| r.get(0)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// The tiles already in an MBTiles output (or in the band files of a split one), for only
+/// generating the tiles which don't exist. The files are opened once, and files which don't exist
+/// (yet) have no tiles.
+pub struct ExistingMBTiles {
+    /// The files, and the (inclusive) zooms each has
+    bands: Vec<(u8, u8, MBTilesReader)>,
+}
+
+impl ExistingMBTiles {
+    /// The tiles in the MBTiles file `filename`.
+    pub fn open(filename: &PathBuf) -> Self {
+        ExistingMBTiles{ bands: MBTilesReader::open(filename).ok().into_iter().map(|reader| (0, MAX_ZOOM, reader)).collect() }
+    }
+
+    /// The tiles in the band files of `filename`, when it's split (see `SplitMBTiles`).
+    pub fn open_split(filename: &PathBuf) -> Self {
+        let bands = SplitMBTiles::existing_band_files(filename).into_iter()
+            .filter_map(|(minzoom, maxzoom, path)| MBTilesReader::open(&path).ok().map(|reader| (minzoom, maxzoom, reader)))
+            .collect();
+        ExistingMBTiles{ bands }
+    }
+
+    pub fn does_tile_exist(&self, tile: &slippy_map_tiles::Tile) -> bool {
+        self.bands.iter()
+            .filter(|&&(minzoom, maxzoom, _)| minzoom <= tile.zoom() && tile.zoom() <= maxzoom)
+            .any(|&(_, _, ref reader)| match reader.tile_data(tile) {
+                Ok(Some(_)) => true,
+                _ => false,
+            })
+    }
+
+    pub fn does_metatile_exist(&self, metatile: &slippy_map_tiles::Metatile) -> bool {
+        metatile.tiles().iter().all(|t| self.does_tile_exist(t))
+    }
+}
+
+/// Several MBTiles files, each covering a contiguous band of zooms, so that one huge file is
+/// easier to distribute. Each tile is saved in the file for its zoom. A band's file is only
+/// created when the first tile for it is saved.
+pub struct SplitMBTiles {
+    filename: PathBuf,
+    bands: Vec<(u8, u8, Option<MBTiles>)>,
+    scheme: TileScheme,
+    store_tile_hashes: bool,
+    sorted_writes: bool,
+    vector_layers: Option<serde_json::Value>,
+    bounds: Option<[f64; 4]>,
+    metadata: Vec<(String, String)>,
+}
+
+impl SplitMBTiles {
+    /// Split `min_zoom`…`max_zoom` into bands starting at each zoom in `split_at`. The files are
+    /// named after `filename`, with the zooms added, e.g. `planet.z0-7.mbtiles`.
+    pub fn new_split(filename: &PathBuf, min_zoom: u8, max_zoom: u8, split_at: &[u8]) -> Self {
+        let bands = Self::zoom_bands(min_zoom, max_zoom, split_at).into_iter().map(|(minzoom, maxzoom)| (minzoom, maxzoom, None)).collect();
+
+        SplitMBTiles{
+            filename: filename.clone(), bands: bands, scheme: TileScheme::Tms, store_tile_hashes: false,
+            sorted_writes: false, vector_layers: None, bounds: None, metadata: Vec::new(),
+        }
+    }
+
+    /// The (inclusive) zoom ranges when `min_zoom`…`max_zoom` is split at these zooms. The splits
+    /// must be in increasing order.
+    pub fn zoom_bands(min_zoom: u8, max_zoom: u8, split_at: &[u8]) -> Vec<(u8, u8)> {
+        let mut bands = Vec::with_capacity(split_at.len()+1);
+        let mut start = min_zoom;
+        for &split in split_at.iter().filter(|&&z| z > min_zoom && z <= max_zoom) {
+            bands.push((start, split-1));
+            start = split;
+        }
+        bands.push((start, max_zoom));
+        bands
+    }
+
+    /// Filename of the file for this band, e.g. `planet.mbtiles` → `planet.z0-7.mbtiles`
+    pub fn filename_for_band(filename: &PathBuf, minzoom: u8, maxzoom: u8) -> PathBuf {
+        let stem = filename.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        let extension = filename.extension().map(|e| e.to_string_lossy().into_owned()).unwrap_or("mbtiles".to_string());
+        filename.with_file_name(format!("{}.z{}-{}.{}", stem, minzoom, maxzoom, extension))
+    }
+
+    /// The band files for `filename` which already exist, and their zoom ranges.
+    fn existing_band_files(filename: &PathBuf) -> Vec<(u8, u8, PathBuf)> {
+        let stem = filename.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        let extension = filename.extension().map(|e| e.to_string_lossy().into_owned()).unwrap_or("mbtiles".to_string());
+        let prefix = format!("{}.z", stem);
+        let suffix = format!(".{}", extension);
+        let dir = match filename.parent() {
+            Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => { return Vec::new(); },
+        };
+
+        entries.filter_map(|entry| entry.ok()).filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if ! (name.starts_with(&prefix) && name.ends_with(&suffix)) || name.len() < prefix.len() + suffix.len() {
+                return None;
+            }
+            let mut zooms = name[prefix.len()..name.len()-suffix.len()].splitn(2, '-');
+            let minzoom = zooms.next().and_then(|z| z.parse().ok());
+            let maxzoom = zooms.next().and_then(|z| z.parse().ok());
+            match (minzoom, maxzoom) {
+                (Some(minzoom), Some(maxzoom)) => Some((minzoom, maxzoom, entry.path())),
+                _ => None,
+            }
+        }).collect()
+    }
+
+    /// The file for the band this zoom is in, which is created if this is the first tile for it.
+    fn for_zoom(&mut self, zoom: u8) -> Result<&mut MBTiles> {
+        let filename = &self.filename;
+        let (scheme, store_tile_hashes, sorted_writes) = (self.scheme, self.store_tile_hashes, self.sorted_writes);
+        let (vector_layers, bounds, metadata) = (&self.vector_layers, &self.bounds, &self.metadata);
+        let num_bands = self.bands.len();
+        let (min_zoom, max_zoom) = (self.bands[0].0, self.bands[num_bands-1].1);
+
+        match self.bands.iter_mut().find(|&&mut (minzoom, maxzoom, _)| minzoom <= zoom && zoom <= maxzoom) {
+            Some(&mut (minzoom, maxzoom, ref mut mbtiles)) => {
+                if mbtiles.is_none() {
+                    let mut new_mbtiles = MBTiles::new(&Self::filename_for_band(filename, minzoom, maxzoom));
+                    new_mbtiles.set_scheme(scheme);
+                    new_mbtiles.set_store_tile_hashes(store_tile_hashes);
+                    new_mbtiles.set_sorted_writes(sorted_writes);
+                    new_mbtiles.set_zoom_range(minzoom, maxzoom);
+                    if let Some(ref vector_layers) = *vector_layers {
+                        new_mbtiles.set_tilejson_vector_layers(vector_layers.clone());
+                    }
+                    if let Some(bounds) = *bounds {
+                        new_mbtiles.set_bounds(bounds);
+                    }
+                    for &(ref name, ref value) in metadata.iter() {
+                        new_mbtiles.set_metadata(name, value);
+                    }
+                    *mbtiles = Some(new_mbtiles);
+                }
+                Ok(mbtiles.as_mut().unwrap())
+            },
+            None => Err(format_err!("No MBTiles file for zoom {}, only zooms {}-{} are being written", zoom, min_zoom, max_zoom)),
+        }
+    }
+
+    pub fn set_scheme(&mut self, scheme: TileScheme) {
+        self.scheme = scheme;
+    }
+
+    pub fn set_store_tile_hashes(&mut self, store_tile_hashes: bool) {
+        self.store_tile_hashes = store_tile_hashes;
+    }
+
+    pub fn set_sorted_writes(&mut self, sorted_writes: bool) {
+        self.sorted_writes = sorted_writes;
+    }
+
+    pub fn set_tilejson_vector_layers(&mut self, vector_layers: serde_json::Value) {
+        self.vector_layers = Some(vector_layers);
+    }
+
+    pub fn set_bounds(&mut self, bounds: [f64; 4]) {
+        self.bounds = Some(bounds);
+    }
+
+    pub fn set_metadata(&mut self, name: &str, value: &str) {
+        self.metadata.push((name.to_string(), value.to_string()));
+    }
+}
+
+impl TileDestination for SplitMBTiles {
+    fn new(filename: &PathBuf) -> Self {
+        SplitMBTiles::new_split(filename, 0, ::MAX_ZOOM, &[])
+    }
+
+    fn save_tile(&mut self, tile: slippy_map_tiles::Tile, bytes: Vec<u8>) {
+        match self.for_zoom(tile.zoom()) {
+            Ok(mbtiles) => mbtiles.save_tile(tile, bytes),
+            Err(e) => error!("Tile {:?} not saved: {}", tile, e),
+        }
+    }
+
+    fn save_metatile(&mut self, metatile: slippy_map_tiles::Metatile, tiles: Vec<(slippy_map_tiles::Tile, Vec<u8>)>) {
+        // All the tiles of a metatile are on the same zoom
+        match self.for_zoom(metatile.zoom()) {
+            Ok(mbtiles) => mbtiles.save_metatile(metatile, tiles),
+            Err(e) => error!("Metatile {:?} not saved: {}", metatile, e),
+        }
+    }
+
+    fn save_tile_hashes(&mut self, hashes: Vec<(slippy_map_tiles::Tile, String)>) {
+        for (tile, hash) in hashes.into_iter() {
+            match self.for_zoom(tile.zoom()) {
+                Ok(mbtiles) => mbtiles.save_tile_hashes(vec![(tile, hash)]),
+                Err(e) => error!("Hash for tile {:?} not saved: {}", tile, e),
+            }
+        }
+    }
+
+    fn append_bytes_to_tile(&mut self, tile: slippy_map_tiles::Tile, bytes: Vec<u8>) {
+        match self.for_zoom(tile.zoom()) {
+            Ok(mbtiles) => mbtiles.append_bytes_to_tile(tile, bytes),
+            Err(e) => error!("Tile {:?} not appended to: {}", tile, e),
+        }
+    }
+
+    fn finish(&mut self) {
+        for &mut (_, _, ref mut mbtiles) in self.bands.iter_mut() {
+            if let Some(ref mut mbtiles) = *mbtiles {
+                mbtiles.finish();
+            }
+        }
+    }
+
+    /// Looks in the band file for this tile's zoom. `dest` is the unsplit filename. The scheme is
+    /// read from the file.
+    fn does_tile_exist(dest: &PathBuf, _scheme: TileScheme, tile: &slippy_map_tiles::Tile) -> bool {
+        ExistingMBTiles::open_split(dest).does_tile_exist(tile)
+    }
 }
 
 pub struct ModTileMetatileDirectory {
     dest_dir: PathBuf,
+    unsynced: UnsyncedFiles,
+}
+
+impl ModTileMetatileDirectory {
+    /// Sync metatiles to disk after every `flush_every` metatiles. 0 (the default) means never.
+    pub fn set_flush_every(&mut self, flush_every: usize) {
+        self.unsynced.flush_every = flush_every;
+    }
 }
 
 impl TileDestination for ModTileMetatileDirectory {
     fn new(dest_dir: &PathBuf) -> Self {
         fs::create_dir_all(&dest_dir).unwrap();
-        ModTileMetatileDirectory{ dest_dir: dest_dir.clone() }
+        ModTileMetatileDirectory{ dest_dir: dest_dir.clone(), unsynced: UnsyncedFiles::new() }
+    }
+
+    fn finish(&mut self) {
+        self.unsynced.sync();
     }
 
-    fn does_tile_exist(dest: &PathBuf, tile: &slippy_map_tiles::Tile) -> bool {
+    fn does_tile_exist(dest: &PathBuf, scheme: TileScheme, tile: &slippy_map_tiles::Tile) -> bool {
         // Not implemented yet
         false
     }
@@ -265,7 +757,7 @@ impl TileDestination for ModTileMetatileDirectory {
         let filename = self.dest_dir.join(xyz_to_mt(metatile.zoom(), metatile.x(), metatile.y(), "meta"));
         fs::create_dir_all(filename.parent().unwrap()).unwrap();
 
-        let mut file = BufWriter::new(File::create(filename).unwrap());
+        let mut file = BufWriter::new(File::create(&filename).unwrap());
         file.write_all(&[0x4d, 0x45, 0x54, 0x41]).unwrap(); // 'META' magic string
         file.write_u32::<LittleEndian>((size*size) as u32).unwrap();
         file.write_u32::<LittleEndian>(x).unwrap();
@@ -281,13 +773,17 @@ impl TileDestination for ModTileMetatileDirectory {
             file.write_all(&tiles[i]).unwrap();
         }
 
+        file.flush().unwrap();
+        drop(file);
+        self.unsynced.written(filename);
+
     }
 
     fn save_tile(&mut self, tile: slippy_map_tiles::Tile, bytes: Vec<u8>) {
         panic!("Use save_metatile instead");
     }
 
-    fn does_metatile_exist(dest: &PathBuf, metatile: &slippy_map_tiles::Metatile) -> bool {
+    fn does_metatile_exist(dest: &PathBuf, scheme: TileScheme, metatile: &slippy_map_tiles::Metatile) -> bool {
         dest.join(xyz_to_mt(metatile.zoom(), metatile.x(), metatile.y(), "meta")).exists()
         
     }
@@ -324,3 +820,291 @@ fn xyz_to_mt(z: u8, x: u32, y: u32, ext: &str) -> String {
     format!("{}/{}/{}/{}/{}/{}.{}", z, a, b, c, d, e, ext)
 }
 
+/// Throws away all the tiles, e.g. to measure how fast tiles are generated without any disk
+/// I/O. Only counts what it's been given.
+pub struct NullDestination {
+    tiles: usize,
+    bytes: usize,
+}
+
+impl TileDestination for NullDestination {
+    fn new(_dest: &PathBuf) -> Self {
+        NullDestination{ tiles: 0, bytes: 0 }
+    }
+
+    fn save_tile(&mut self, _tile: slippy_map_tiles::Tile, bytes: Vec<u8>) {
+        self.tiles += 1;
+        self.bytes += bytes.len();
+    }
+
+    fn append_bytes_to_tile(&mut self, _tile: slippy_map_tiles::Tile, bytes: Vec<u8>) {
+        self.bytes += bytes.len();
+    }
+
+    fn finish(&mut self) {
+        info!("Discarded {} tiles ({} bytes)", self.tiles, self.bytes);
+    }
+
+    fn does_tile_exist(_dest: &PathBuf, _scheme: TileScheme, _tile: &slippy_map_tiles::Tile) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_util::temp_path;
+
+    #[test]
+    fn mbtiles_tile_row() {
+        let path = temp_path("mbtiles_tile_row.mbtiles");
+        let _ = fs::remove_file(&path);
+
+        let mut mbtiles = MBTiles::new(&path);
+        mbtiles.save_tile(slippy_map_tiles::Tile::new(3, 2, 1).unwrap(), vec![1, 2, 3]);
+
+        // TMS is flipped, 2³ - 1 - 1 = 6
+        let row: i64 = mbtiles.conn.query_row("SELECT tile_row FROM map WHERE zoom_level = 3 AND tile_column = 2;", &[], |r| r.get(0)).unwrap();
+        assert_eq!(row, 6);
+        let scheme: String = mbtiles.conn.query_row("SELECT value FROM metadata WHERE name = 'scheme';", &[], |r| r.get(0)).unwrap();
+        assert_eq!(scheme, "tms");
+
+        // and with XYZ, it's not
+        mbtiles.set_scheme(TileScheme::Xyz);
+        mbtiles.save_tile(slippy_map_tiles::Tile::new(3, 5, 1).unwrap(), vec![1, 2, 3]);
+        let row: i64 = mbtiles.conn.query_row("SELECT tile_row FROM map WHERE zoom_level = 3 AND tile_column = 5;", &[], |r| r.get(0)).unwrap();
+        assert_eq!(row, 1);
+
+        mbtiles.finish();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn mbtiles_tile_hashes() {
+        let path = temp_path("mbtiles_tile_hashes.mbtiles");
+        let _ = fs::remove_file(&path);
+
+        let mut mbtiles = MBTiles::new(&path);
+        mbtiles.set_store_tile_hashes(true);
+        mbtiles.save_tile_hashes(vec![(slippy_map_tiles::Tile::new(3, 2, 1).unwrap(), "abc".to_string())]);
+        mbtiles.finish();
+
+        // Stored in the TMS scheme, like the tiles
+        let hash: String = mbtiles.conn.query_row("SELECT hash FROM tile_hashes WHERE zoom_level = 3 AND tile_column = 2 AND tile_row = 6;", &[], |r| r.get(0)).unwrap();
+        assert_eq!(hash, "abc");
+        drop(mbtiles);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn mbtiles_sorted_writes() {
+        let metatile = slippy_map_tiles::Metatile::new(2, 3, 2, 4).unwrap();
+        let mut tiles: Vec<_> = metatile.tiles().into_iter().enumerate().map(|(i, t)| (t, vec![i as u8])).collect();
+        tiles.reverse();
+
+        // The order the rows were inserted in, and what's in the file
+        let write = |sorted_writes: bool| -> (Vec<(u8, u32, u32)>, Vec<(u8, u32, u32, Vec<u8>)>) {
+            let path = temp_path(&format!("mbtiles_sorted_writes-{}.mbtiles", sorted_writes));
+            let _ = fs::remove_file(&path);
+
+            let mut mbtiles = MBTiles::new(&path);
+            mbtiles.set_sorted_writes(sorted_writes);
+            mbtiles.save_metatile(metatile.clone(), tiles.clone());
+            mbtiles.finish();
+
+            let inserted = {
+                let mut stmt = mbtiles.conn.prepare("SELECT zoom_level, tile_column, tile_row FROM map ORDER BY rowid;").unwrap();
+                let inserted = stmt.query_map(&[], |r| (r.get::<_, i64>(0) as u8, r.get::<_, i64>(1) as u32, r.get::<_, i64>(2) as u32)).unwrap().map(|r| r.unwrap()).collect();
+                inserted
+            };
+            let contents = {
+                let mut stmt = mbtiles.conn.prepare("SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles ORDER BY zoom_level, tile_column, tile_row;").unwrap();
+                let contents = stmt.query_map(&[], |r| (r.get::<_, i64>(0) as u8, r.get::<_, i64>(1) as u32, r.get::<_, i64>(2) as u32, r.get(3))).unwrap().map(|r| r.unwrap()).collect();
+                contents
+            };
+            drop(mbtiles);
+            fs::remove_file(&path).unwrap();
+            (inserted, contents)
+        };
+
+        let (unsorted_order, unsorted_contents) = write(false);
+        let (sorted_order, sorted_contents) = write(true);
+        assert_eq!(sorted_contents, unsorted_contents);
+        assert_eq!(sorted_contents.len(), 4);
+
+        let mut expected_order = sorted_order.clone();
+        expected_order.sort();
+        assert_eq!(sorted_order, expected_order);
+        assert!(unsorted_order != expected_order);
+    }
+
+    #[test]
+    fn mbtiles_reader() {
+        let path = temp_path("mbtiles_reader.mbtiles");
+        let _ = fs::remove_file(&path);
+        assert!(MBTilesReader::open(&path).is_err());
+
+        let mut mbtiles = MBTiles::new(&path);
+        mbtiles.set_scheme(TileScheme::Xyz);
+        mbtiles.save_tile(slippy_map_tiles::Tile::new(3, 2, 1).unwrap(), vec![1, 2, 3]);
+        mbtiles.finish();
+        drop(mbtiles);
+
+        let reader = MBTilesReader::open(&path).unwrap();
+        assert_eq!(reader.tile_data(&slippy_map_tiles::Tile::new(3, 2, 1).unwrap()).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(reader.tile_data(&slippy_map_tiles::Tile::new(3, 2, 6).unwrap()).unwrap(), None);
+
+        drop(reader);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tilestash_path_template() {
+        assert!(check_path_template("{z}/{x}/{y}.mvt").is_ok());
+        assert!(check_path_template("{z}/{x_hi}/{x}/{y}.pbf").is_ok());
+        assert!(check_path_template("{z}/{x}.pbf").is_err());
+        assert!(check_path_template("{z}/{x}/{y}.{ext}").is_err());
+        assert!(check_path_template("/{z}/{x}/{y}.pbf").is_err());
+
+        let path = temp_path("tilestash_path_template");
+        let _ = fs::remove_dir_all(&path);
+
+        let mut tilestash = TileStashDirectory::new(&path);
+        tilestash.set_path_template(Some("{z}/{x}/{y}.mvt".to_string()));
+        tilestash.save_tile(slippy_map_tiles::Tile::new(3, 2, 1).unwrap(), vec![1, 2, 3]);
+        tilestash.save_tile(slippy_map_tiles::Tile::new(3, 5, 7).unwrap(), vec![4, 5]);
+        tilestash.finish();
+        assert_eq!(fs::read(path.join("3/2/1.mvt")).unwrap(), vec![1, 2, 3]);
+        assert_eq!(fs::read(path.join("3/5/7.mvt")).unwrap(), vec![4, 5]);
+
+        // Subdirectories for high zooms, and the scheme is used for the y
+        tilestash.set_path_template(Some("{z}/{x_hi}/{x}/{y}.pbf".to_string()));
+        tilestash.set_scheme(TileScheme::Tms);
+        tilestash.save_tile(slippy_map_tiles::Tile::new(14, 8000, 100).unwrap(), vec![6]);
+        tilestash.finish();
+        assert_eq!(fs::read(path.join("14/7/8000/16283.pbf")).unwrap(), vec![6]);
+        assert!(TileStashDirectory::does_metatile_exist_with_template(&path, TileScheme::Xyz, Some("{z}/{x}/{y}.mvt"), &slippy_map_tiles::Metatile::new(1, 3, 2, 1).unwrap()));
+        assert!(!TileStashDirectory::does_metatile_exist(&path, TileScheme::Xyz, &slippy_map_tiles::Metatile::new(1, 3, 2, 1).unwrap()));
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn tilestash_flush_every() {
+        let path = temp_path("tilestash_flush_every");
+        let _ = fs::remove_dir_all(&path);
+
+        // Lots more tiles than the usual limit on open files (1024)
+        let mut dest = TileStashDirectory::new(&path);
+        dest.set_flush_every(100);
+        for x in 0..64 {
+            for y in 0..64 {
+                dest.save_tile(slippy_map_tiles::Tile::new(6, x, y).unwrap(), vec![0; 10]);
+            }
+            assert!(dest.unsynced.paths.len() < 100);
+        }
+        dest.finish();
+        assert!(dest.unsynced.paths.is_empty());
+
+        for x in 0..64 {
+            for y in 0..64 {
+                assert!(TileStashDirectory::does_tile_exist(&path, TileScheme::Xyz, &slippy_map_tiles::Tile::new(6, x, y).unwrap()));
+            }
+        }
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn split_mbtiles() {
+        assert_eq!(SplitMBTiles::zoom_bands(0, 14, &[8]), vec![(0, 7), (8, 14)]);
+        assert_eq!(SplitMBTiles::zoom_bands(0, 14, &[]), vec![(0, 14)]);
+        assert_eq!(SplitMBTiles::zoom_bands(0, 14, &[5, 10]), vec![(0, 4), (5, 9), (10, 14)]);
+
+        let path = temp_path("split_mbtiles.mbtiles");
+        let low_path = SplitMBTiles::filename_for_band(&path, 0, 7);
+        let high_path = SplitMBTiles::filename_for_band(&path, 8, 14);
+        assert_eq!(high_path, temp_path("split_mbtiles.z8-14.mbtiles"));
+        let _ = fs::remove_file(&low_path);
+        let _ = fs::remove_file(&high_path);
+
+        let mut dest = SplitMBTiles::new_split(&path, 0, 14, &[8]);
+        for z in 0..8 {
+            dest.save_tile(slippy_map_tiles::Tile::new(z, 0, 0).unwrap(), vec![z]);
+        }
+        // Files are only created when there's a tile for them
+        assert!(low_path.exists());
+        assert!(!high_path.exists());
+        for z in 8..15 {
+            dest.save_tile(slippy_map_tiles::Tile::new(z, 0, 0).unwrap(), vec![z]);
+        }
+        // Outside all the bands, so not saved anywhere
+        dest.save_tile(slippy_map_tiles::Tile::new(15, 0, 0).unwrap(), vec![15]);
+        dest.finish();
+        drop(dest);
+        assert!(!path.exists());
+        assert!(!SplitMBTiles::filename_for_band(&path, 15, 15).exists());
+
+        for z in 0..15 {
+            assert!(SplitMBTiles::does_tile_exist(&path, TileScheme::Tms, &slippy_map_tiles::Tile::new(z, 0, 0).unwrap()));
+        }
+        for z in 1..15 {
+            assert!(!SplitMBTiles::does_tile_exist(&path, TileScheme::Tms, &slippy_map_tiles::Tile::new(z, 1, 1).unwrap()));
+        }
+        assert!(!SplitMBTiles::does_tile_exist(&path, TileScheme::Tms, &slippy_map_tiles::Tile::new(15, 0, 0).unwrap()));
+
+        let file_zooms = |path: &PathBuf| -> (Vec<u8>, String, String) {
+            let conn = rusqlite::Connection::open(path).unwrap();
+            let zooms = {
+                let mut stmt = conn.prepare("SELECT zoom_level FROM map ORDER BY zoom_level;").unwrap();
+                let zooms = stmt.query_map(&[], |r| r.get::<_, i64>(0) as u8).unwrap().map(|z| z.unwrap()).collect();
+                zooms
+            };
+            let minzoom: String = conn.query_row("SELECT value FROM metadata WHERE name = 'minzoom';", &[], |r| r.get(0)).unwrap();
+            let maxzoom: String = conn.query_row("SELECT value FROM metadata WHERE name = 'maxzoom';", &[], |r| r.get(0)).unwrap();
+            (zooms, minzoom, maxzoom)
+        };
+
+        assert_eq!(file_zooms(&low_path), ((0..8).collect(), "0".to_string(), "7".to_string()));
+        assert_eq!(file_zooms(&high_path), ((8..15).collect(), "8".to_string(), "14".to_string()));
+
+        fs::remove_file(&low_path).unwrap();
+        fs::remove_file(&high_path).unwrap();
+    }
+
+    #[test]
+    fn tile_scheme_row() {
+        assert_eq!(TileScheme::Xyz.row(0, 0), 0);
+        assert_eq!(TileScheme::Tms.row(0, 0), 0);
+        assert_eq!(TileScheme::Xyz.row(3, 1), 1);
+        assert_eq!(TileScheme::Tms.row(3, 1), 6);
+        assert_eq!(TileScheme::Tms.row(3, 7), 0);
+    }
+
+    #[test]
+    fn null_destination() {
+        let path = temp_path("null_destination.mbtiles");
+        let _ = fs::remove_file(&path);
+        let metatile = slippy_map_tiles::Metatile::new(2, 3, 0, 0).unwrap();
+        let tiles: Vec<_> = metatile.tiles().into_iter().map(|t| (t, vec![1, 2, 3])).collect();
+
+        // It's given the same tiles as a real destination, but doesn't write anything
+        let mut null = NullDestination::new(&path);
+        null.save_metatile(metatile.clone(), tiles.clone());
+        null.save_tile_hashes(vec![(tiles[0].0, "abc".to_string())]);
+        null.finish();
+        assert!(!path.exists());
+
+        let mut mbtiles = MBTiles::new(&path);
+        mbtiles.save_metatile(metatile, tiles.clone());
+        mbtiles.finish();
+        let num_tiles: i64 = mbtiles.conn.query_row("SELECT COUNT(*) FROM map;", &[], |r| r.get(0)).unwrap();
+        assert_eq!(num_tiles as usize, null.tiles);
+        assert_eq!(null.tiles, tiles.len());
+        assert_eq!(null.bytes, tiles.len() * 3);
+        assert!(!NullDestination::does_metatile_exist(&path, TileScheme::Xyz, &slippy_map_tiles::Metatile::new(2, 3, 0, 0).unwrap()));
+
+        drop(mbtiles);
+        fs::remove_file(&path).unwrap();
+    }
+}
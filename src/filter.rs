@@ -0,0 +1,348 @@
+//! A layer's `filter`, which decides which features are in the tiles, from their attributes & the
+//! zoom, e.g. `class = 'service' and zoom >= 14`. Only comparisons, `and`, `or` & brackets are
+//! supported, it's not a query language.
+
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+use mapbox_vector_tile::Value;
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    Comparison(Operand, Operator, Literal),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+/// The left hand side of a comparison.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operand {
+    /// The zoom of the tile
+    Zoom,
+    /// The value of this attribute (column)
+    Attribute(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// The right hand side of a comparison.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal {
+    String(String),
+    Number(f64),
+    Boolean(bool),
+}
+
+impl Filter {
+    /// Should a feature with these attributes be in a tile on this zoom? `value_of` gives the
+    /// value of an attribute, or `None` if it's NULL (or not there). Any comparison with a NULL, or
+    /// with a value of a different type (e.g. a string with a number), is false.
+    pub fn matches<'a, F>(&self, zoom: u8, value_of: &F) -> bool
+        where F: Fn(&str) -> Option<&'a Value>
+    {
+        match *self {
+            Filter::And(ref a, ref b) => a.matches(zoom, value_of) && b.matches(zoom, value_of),
+            Filter::Or(ref a, ref b) => a.matches(zoom, value_of) || b.matches(zoom, value_of),
+            Filter::Comparison(ref operand, operator, ref literal) => {
+                let ordering = match *operand {
+                    Operand::Zoom => compare_number(zoom as f64, literal),
+                    Operand::Attribute(ref name) => value_of(name).and_then(|value| compare_value(value, literal)),
+                };
+                match ordering {
+                    None => false,
+                    Some(ordering) => operator.holds(ordering),
+                }
+            },
+        }
+    }
+
+    /// The attributes which this filter uses.
+    pub fn attributes(&self) -> Vec<&str> {
+        match *self {
+            Filter::And(ref a, ref b) | Filter::Or(ref a, ref b) => {
+                let mut attributes = a.attributes();
+                attributes.extend(b.attributes());
+                attributes
+            },
+            Filter::Comparison(Operand::Attribute(ref name), _, _) => vec![name.as_str()],
+            Filter::Comparison(Operand::Zoom, _, _) => vec![],
+        }
+    }
+}
+
+impl Operator {
+    fn holds(&self, ordering: Ordering) -> bool {
+        match *self {
+            Operator::Equal => ordering == Ordering::Equal,
+            Operator::NotEqual => ordering != Ordering::Equal,
+            Operator::Less => ordering == Ordering::Less,
+            Operator::LessOrEqual => ordering != Ordering::Greater,
+            Operator::Greater => ordering == Ordering::Greater,
+            Operator::GreaterOrEqual => ordering != Ordering::Less,
+        }
+    }
+}
+
+fn compare_number(number: f64, literal: &Literal) -> Option<Ordering> {
+    match *literal {
+        Literal::Number(n) => number.partial_cmp(&n),
+        _ => None,
+    }
+}
+
+fn compare_value(value: &Value, literal: &Literal) -> Option<Ordering> {
+    match (value, literal) {
+        (&Value::String(ref s), &Literal::String(ref l)) => Some(s.as_str().cmp(l.as_str())),
+        (&Value::Boolean(b), &Literal::Boolean(l)) => Some(b.cmp(&l)),
+        (&Value::Int(i), _) => compare_number(i as f64, literal),
+        (&Value::Float(f), _) => compare_number(f as f64, literal),
+        (&Value::Double(f), _) => compare_number(f, literal),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Identifier(String),
+    Literal(Literal),
+    Operator(Operator),
+    And,
+    Or,
+    OpenBracket,
+    CloseBracket,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' {
+            tokens.push(Token::OpenBracket);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::CloseBracket);
+            i += 1;
+        } else if c == '=' || c == '!' || c == '<' || c == '>' {
+            let next = chars.get(i+1).cloned();
+            let (operator, len) = match (c, next) {
+                ('=', Some('=')) => (Operator::Equal, 2),
+                ('=', _) => (Operator::Equal, 1),
+                ('!', Some('=')) | ('<', Some('>')) => (Operator::NotEqual, 2),
+                ('<', Some('=')) => (Operator::LessOrEqual, 2),
+                ('<', _) => (Operator::Less, 1),
+                ('>', Some('=')) => (Operator::GreaterOrEqual, 2),
+                ('>', _) => (Operator::Greater, 1),
+                _ => { return Err(format_err!("unknown operator at {:?}", &s[s.char_indices().nth(i).unwrap().0..])); },
+            };
+            tokens.push(Token::Operator(operator));
+            i += len;
+        } else if c == '\'' || c == '"' {
+            // 'strings', or "quoted attribute names". The quote is doubled to include it.
+            let mut value = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => { return Err(format_err!("unterminated {}", c)); },
+                    Some(&q) if q == c => {
+                        if chars.get(i+1) == Some(&c) {
+                            value.push(c);
+                            i += 2;
+                        } else {
+                            i += 1;
+                            break;
+                        }
+                    },
+                    Some(&other) => {
+                        value.push(other);
+                        i += 1;
+                    },
+                }
+            }
+            tokens.push(if c == '\'' { Token::Literal(Literal::String(value)) } else { Token::Identifier(value) });
+        } else if c.is_digit(10) || c == '-' || c == '.' {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_digit(10) || chars[i] == '.') {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            let number = number.parse().map_err(|_| format_err!("invalid number {}", number))?;
+            tokens.push(Token::Literal(Literal::Number(number)));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == ':') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            tokens.push(match word.to_lowercase().as_str() {
+                "and" => Token::And,
+                "or" => Token::Or,
+                "true" => Token::Literal(Literal::Boolean(true)),
+                "false" => Token::Literal(Literal::Boolean(false)),
+                _ => Token::Identifier(word),
+            });
+        } else {
+            return Err(format_err!("unexpected {:?}", c));
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// A recursive descent parser. `or` binds less tightly than `and`.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn or(&mut self) -> Result<Filter> {
+        let mut filter = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.position += 1;
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter> {
+        let mut filter = self.term()?;
+        while self.peek() == Some(&Token::And) {
+            self.position += 1;
+            filter = Filter::And(Box::new(filter), Box::new(self.term()?));
+        }
+        Ok(filter)
+    }
+
+    fn term(&mut self) -> Result<Filter> {
+        match self.next() {
+            Some(Token::OpenBracket) => {
+                let filter = self.or()?;
+                match self.next() {
+                    Some(Token::CloseBracket) => Ok(filter),
+                    _ => Err(format_err!("missing )")),
+                }
+            },
+            Some(Token::Identifier(name)) => {
+                let operand = if name == "zoom" { Operand::Zoom } else { Operand::Attribute(name) };
+                let operator = match self.next() {
+                    Some(Token::Operator(operator)) => operator,
+                    x => { return Err(format_err!("expected a comparison after {:?}, not {:?}", operand, x)); },
+                };
+                let literal = match self.next() {
+                    Some(Token::Literal(literal)) => literal,
+                    x => { return Err(format_err!("expected a value to compare {:?} to, not {:?}", operand, x)); },
+                };
+                if operand == Operand::Zoom {
+                    if let Literal::Number(_) = literal {} else {
+                        return Err(format_err!("zoom can only be compared to a number"));
+                    }
+                }
+                Ok(Filter::Comparison(operand, operator, literal))
+            },
+            x => Err(format_err!("expected a comparison, not {:?}", x)),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parser = Parser{ tokens: tokenize(s).map_err(|e| format_err!("Invalid filter {:?}: {}", s, e))?, position: 0 };
+        let filter = parser.or().map_err(|e| format_err!("Invalid filter {:?}: {}", s, e))?;
+        if parser.position < parser.tokens.len() {
+            return Err(format_err!("Invalid filter {:?}: unexpected {:?}", s, parser.tokens[parser.position]));
+        }
+
+        Ok(filter)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rc::Rc;
+
+    fn matches(filter: &str, zoom: u8, attributes: &[(&str, Value)]) -> bool {
+        let filter: Filter = filter.parse().unwrap();
+        filter.matches(zoom, &|name: &str| attributes.iter().find(|&&(n, _)| n == name).map(|&(_, ref v)| v))
+    }
+
+    #[test]
+    fn parse() {
+        let filter: Filter = "class = 'service' and zoom >= 14".parse().unwrap();
+        assert_eq!(filter, Filter::And(
+                Box::new(Filter::Comparison(Operand::Attribute("class".to_string()), Operator::Equal, Literal::String("service".to_string()))),
+                Box::new(Filter::Comparison(Operand::Zoom, Operator::GreaterOrEqual, Literal::Number(14.)))));
+        assert_eq!(filter.attributes(), vec!["class"]);
+
+        // and binds tighter than or
+        let filter: Filter = "a = 1 or b = 2 and c = 3".parse().unwrap();
+        match filter {
+            Filter::Or(_, ref b) => match **b {
+                Filter::And(_, _) => {},
+                ref x => panic!("{:?}", x),
+            },
+            ref x => panic!("{:?}", x),
+        }
+        assert_eq!(filter.attributes(), vec!["a", "b", "c"]);
+
+        assert!("\"addr:street\" != 'it''s' AND (zoom < 10 OR oneway = true)".parse::<Filter>().is_ok());
+
+        assert!("".parse::<Filter>().is_err());
+        assert!("class".parse::<Filter>().is_err());
+        assert!("class = ".parse::<Filter>().is_err());
+        assert!("class = 'service".parse::<Filter>().is_err());
+        assert!("(zoom > 3".parse::<Filter>().is_err());
+        assert!("zoom > 3 zoom".parse::<Filter>().is_err());
+        assert!("zoom = 'high'".parse::<Filter>().is_err());
+        assert!("class like 'serv%'".parse::<Filter>().is_err());
+    }
+
+    #[test]
+    fn evaluate() {
+        let service = [("class", Value::String(Rc::new("service".to_string()))), ("lanes", Value::Int(1))];
+        let primary = [("class", Value::String(Rc::new("primary".to_string()))), ("lanes", Value::Int(2))];
+
+        assert!(!matches("class = 'service' and zoom >= 14", 13, &service));
+        assert!(matches("class = 'service' and zoom >= 14", 14, &service));
+        assert!(!matches("class = 'service' and zoom >= 14", 14, &primary));
+
+        assert!(matches("class != 'service' or zoom >= 14", 10, &primary));
+        assert!(!matches("class != 'service' or zoom >= 14", 10, &service));
+
+        assert!(matches("lanes > 1.5", 0, &primary));
+        assert!(matches("lanes <= 1", 0, &service));
+
+        // NULLs, and different types, never match
+        assert!(!matches("name = 'x'", 0, &service));
+        assert!(!matches("name != 'x'", 0, &service));
+        assert!(!matches("lanes = '1'", 0, &service));
+        assert!(!matches("class = 1", 0, &service));
+    }
+}
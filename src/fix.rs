@@ -0,0 +1,269 @@
+//! Repairing the invalid geometries in tiles which have already been generated (e.g. by an older
+//! version), without regenerating them from the database.
+
+use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
+
+use flate2::write::GzEncoder;
+use mapbox_vector_tile;
+use md5;
+use rusqlite;
+
+use ::decode::{decompress_tile, protobuf_fields, decode_layer};
+use ::validity::{YAxis, is_valid_with_y_axis, make_valid, ensure_polygon_orientation};
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+/// Make every feature in this (possibly gzipped) tile valid. Features which can't be made valid
+/// are left out. A layer with features of an unknown geometry type is left as it is, since they
+/// can't be encoded again. `None` if all the features were already valid, otherwise the fixed
+/// tile, gzipped if the original was. Tiles are always y down, whatever the global `y_axis` is.
+pub fn fix_tile(bytes: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut changed = false;
+    let mut fixed_bytes = Vec::with_capacity(bytes.len());
+    let uncompressed = decompress_tile(bytes)?;
+    for (field, layer_bytes) in protobuf_fields(&uncompressed)? {
+        if field != 3 {
+            continue;
+        }
+        let layer = decode_layer(layer_bytes)?;
+
+        if let Some(feature) = layer.features.iter().find(|f| f.geom_type < 1 || f.geom_type > 3) {
+            warn!("Layer {} has a feature with unknown geometry type {}, leaving the layer as it is", layer.name, feature.geom_type);
+            fixed_bytes.extend(raw_layer(layer_bytes));
+            continue;
+        }
+
+        let mut layer_changed = false;
+        let mut mvt_layer = mapbox_vector_tile::Layer::new(layer.name.clone());
+        mvt_layer.extent = layer.extent as _;
+        for feature in layer.features.iter() {
+            let geom = feature.geometry().map_err(|e| format_err!("Layer {}: {}", layer.name, e))?;
+            let geom = if is_valid_with_y_axis(&geom, YAxis::Down) {
+                Some(geom)
+            } else {
+                layer_changed = true;
+                make_valid(geom).map(|mut g| { ensure_polygon_orientation(&mut g, YAxis::Down); g })
+            };
+            if let Some(geom) = geom {
+                mvt_layer.add_feature(mapbox_vector_tile::Feature::new(geom, Rc::new(feature.mvt_properties())));
+            }
+        }
+
+        if layer_changed {
+            changed = true;
+            let mut tile = mapbox_vector_tile::Tile::new();
+            tile.add_layer(mvt_layer);
+            fixed_bytes.extend(tile.to_bytes());
+        } else {
+            fixed_bytes.extend(raw_layer(layer_bytes));
+        }
+    }
+
+    if ! changed {
+        return Ok(None);
+    }
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        let mut encoder = GzEncoder::new(Vec::with_capacity(fixed_bytes.len()), ::flate2::Compression::default());
+        encoder.write_all(&fixed_bytes)?;
+        Ok(Some(encoder.finish()?))
+    } else {
+        Ok(Some(fixed_bytes))
+    }
+}
+
+/// A tile with just this layer (i.e. the layer's bytes as a tile's `layers` field).
+fn raw_layer(layer_bytes: &[u8]) -> Vec<u8> {
+    // Field 3, length delimited
+    let mut bytes = vec![0x1a];
+    let mut len = layer_bytes.len() as u64;
+    while len >= 0x80 {
+        bytes.push((len as u8 & 0x7f) | 0x80);
+        len >>= 7;
+    }
+    bytes.push(len as u8);
+    bytes.extend_from_slice(layer_bytes);
+    bytes
+}
+
+/// Make the features of every tile in this MBTiles file valid, changing the file in place. Files
+/// where tiles share images (`map` & `images` tables), and with just a `tiles` table, both work.
+/// Returns how many tiles were checked, and how many were repaired.
+pub fn fix_mbtiles(filename: &Path) -> Result<(usize, usize)> {
+    let conn = rusqlite::Connection::open(filename)?;
+    let is_table = |name: &str| -> Result<bool> {
+        let count: i64 = conn.query_row("SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?1;", &[&name], |r| r.get(0))?;
+        Ok(count > 0)
+    };
+    let deduplicated = is_table("map")? && is_table("images")?;
+    if ! deduplicated && ! is_table("tiles")? {
+        return Err(format_err!("{} has no tiles table", filename.display()));
+    }
+
+    conn.execute("BEGIN;", &[])?;
+    let counts = if deduplicated { fix_images(&conn)? } else { fix_tiles_table(&conn)? };
+    conn.execute("COMMIT;", &[])?;
+    Ok(counts)
+}
+
+/// Fix the tiles of an MBTiles file with `map` & `images` tables. A fixed image gets a new
+/// `tile_id` (the hash of its contents), and every tile which used it gets the fix.
+fn fix_images(conn: &rusqlite::Connection) -> Result<(usize, usize)> {
+    let mut num_checked = 0;
+    let mut num_repaired = 0;
+    let mut fixed_images = Vec::new();
+    {
+        let mut stmt = conn.prepare("SELECT tile_id, tile_data FROM images;")?;
+        let mut rows = stmt.query(&[])?;
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let tile_id: String = row.get(0);
+            let bytes: Vec<u8> = row.get(1);
+
+            // The same image can be used by many tiles (e.g. all sea)
+            let num_tiles: i64 = conn.query_row("SELECT count(*) FROM map WHERE tile_id = ?1;", &[&tile_id], |r| r.get(0))?;
+            num_checked += num_tiles as usize;
+
+            if let Some(fixed) = fix_tile(&bytes).map_err(|e| format_err!("Tile image {}: {}", tile_id, e))? {
+                fixed_images.push((tile_id, fixed));
+                num_repaired += num_tiles as usize;
+            }
+        }
+    }
+
+    // Changed after reading, so new images aren't read again
+    for (tile_id, fixed) in fixed_images.into_iter() {
+        let new_tile_id = format!("{:x}", md5::compute(&fixed));
+        conn.execute("INSERT OR IGNORE INTO images (tile_id, tile_data) VALUES (?1, ?2);", &[&new_tile_id, &fixed])?;
+        conn.execute("UPDATE map SET tile_id = ?1 WHERE tile_id = ?2;", &[&new_tile_id, &tile_id])?;
+        if new_tile_id != tile_id {
+            conn.execute("DELETE FROM images WHERE tile_id = ?1;", &[&tile_id])?;
+        }
+    }
+
+    Ok((num_checked, num_repaired))
+}
+
+/// Fix the tiles of an MBTiles file with just a `tiles` table.
+fn fix_tiles_table(conn: &rusqlite::Connection) -> Result<(usize, usize)> {
+    let mut num_checked = 0;
+    let mut fixed_tiles = Vec::new();
+    {
+        let mut stmt = conn.prepare("SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles;")?;
+        let mut rows = stmt.query(&[])?;
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let (zoom, column, row_num): (i64, i64, i64) = (row.get(0), row.get(1), row.get(2));
+            let bytes: Vec<u8> = row.get(3);
+            num_checked += 1;
+
+            if let Some(fixed) = fix_tile(&bytes).map_err(|e| format_err!("Tile {}/{}/{}: {}", zoom, column, row_num, e))? {
+                fixed_tiles.push((zoom, column, row_num, fixed));
+            }
+        }
+    }
+
+    let num_repaired = fixed_tiles.len();
+    for (zoom, column, row_num, fixed) in fixed_tiles.into_iter() {
+        conn.execute("UPDATE tiles SET tile_data = ?1 WHERE zoom_level = ?2 AND tile_column = ?3 AND tile_row = ?4;", &[&fixed, &zoom, &column, &row_num])?;
+    }
+
+    Ok((num_checked, num_repaired))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_util::temp_path;
+    use std::fs;
+    use geo::{Geometry, Point, LineString, Polygon};
+    use slippy_map_tiles;
+    use fileio::{MBTiles, TileDestination};
+
+    #[test]
+    fn fix_invalid_polygon() {
+        let path = temp_path("fix_invalid_polygon.mbtiles");
+        let _ = fs::remove_file(&path);
+
+        let tile_with = |points: &[(i32, i32)]| {
+            let ring = LineString(points.iter().map(|&(x, y)| Point::new(x, y)).collect());
+            let mut layer = mapbox_vector_tile::Layer::new("parks".to_string());
+            layer.add_feature(mapbox_vector_tile::Feature::new(Geometry::Polygon(Polygon::new(ring, vec![])), Rc::new(mapbox_vector_tile::Properties::new())));
+            let mut tile = mapbox_vector_tile::Tile::new();
+            tile.add_layer(layer);
+            tile.to_compressed_bytes()
+        };
+        // A bowtie, which crosses itself
+        let invalid = tile_with(&[(0, 0), (100, 100), (100, 0), (0, 100), (0, 0)]);
+        let valid = tile_with(&[(0, 0), (0, 100), (100, 100), (100, 0), (0, 0)]);
+        assert!(fix_tile(&valid).unwrap().is_none());
+
+        let mut mbtiles = MBTiles::new(&path);
+        mbtiles.save_tile(slippy_map_tiles::Tile::new(1, 0, 0).unwrap(), invalid.clone());
+        mbtiles.save_tile(slippy_map_tiles::Tile::new(1, 1, 0).unwrap(), valid.clone());
+        mbtiles.finish();
+        drop(mbtiles);
+
+        assert_eq!(fix_mbtiles(&path).unwrap(), (2, 1));
+
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let tile_data = |x: i64| -> Vec<u8> {
+            conn.query_row("SELECT tile_data FROM tiles WHERE zoom_level = 1 AND tile_column = ?1;", &[&x], |r| r.get(0)).unwrap()
+        };
+        let fixed = tile_data(0);
+        assert_eq!(tile_data(1), valid);
+
+        let layers = ::decode::decode_tile_layers(&fixed).unwrap();
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].name, "parks");
+        assert_eq!(layers[0].features.len(), 1);
+        assert!(is_valid_with_y_axis(&layers[0].features[0].geometry().unwrap(), YAxis::Down));
+
+        // The fixed image has a new id
+        let tile_id: String = conn.query_row("SELECT tile_id FROM map WHERE zoom_level = 1 AND tile_column = 0;", &[], |r| r.get(0)).unwrap();
+        assert_eq!(tile_id, format!("{:x}", md5::compute(&fixed)));
+        let num_images: i64 = conn.query_row("SELECT count(*) FROM images;", &[], |r| r.get(0)).unwrap();
+        assert_eq!(num_images, 2);
+
+        // Nothing left to fix
+        assert_eq!(fix_mbtiles(&path).unwrap(), (2, 0));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fix_tiles_table_with_unknown_geometry_type() {
+        let path = temp_path("fix_tiles_table.mbtiles");
+        let _ = fs::remove_file(&path);
+
+        // A bowtie, which crosses itself
+        let ring = LineString(vec![(0, 0), (100, 100), (100, 0), (0, 100), (0, 0)].into_iter().map(|(x, y)| Point::new(x, y)).collect());
+        let mut layer = mapbox_vector_tile::Layer::new("parks".to_string());
+        layer.add_feature(mapbox_vector_tile::Feature::new(Geometry::Polygon(Polygon::new(ring, vec![])), Rc::new(mapbox_vector_tile::Properties::new())));
+        let mut tile = mapbox_vector_tile::Tile::new();
+        tile.add_layer(layer);
+        let mut bytes = tile.to_bytes();
+        // A layer "x", with a feature with no geometry type (i.e. UNKNOWN), a MoveTo(0, 0)
+        let unknown_layer = vec![0x0a, 1, b'x', 0x12, 5, 0x22, 3, 9, 0, 0, 0x78, 2];
+        bytes.extend(raw_layer(&unknown_layer));
+
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch("CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);").unwrap();
+        conn.execute("INSERT INTO tiles VALUES (1, 0, 0, ?1);", &[&bytes]).unwrap();
+
+        assert_eq!(fix_mbtiles(&path).unwrap(), (1, 1));
+
+        let fixed: Vec<u8> = conn.query_row("SELECT tile_data FROM tiles;", &[], |r| r.get(0)).unwrap();
+        let layers: Vec<_> = protobuf_fields(&fixed).unwrap().into_iter().filter(|&(f, _)| f == 3).map(|(_, l)| l).collect();
+        assert_eq!(layers.len(), 2);
+        let parks = decode_layer(layers[0]).unwrap();
+        assert_eq!(parks.name, "parks");
+        assert!(is_valid_with_y_axis(&parks.features[0].geometry().unwrap(), YAxis::Down));
+        assert_eq!(layers[1], &unknown_layer[..]);
+
+        assert_eq!(fix_mbtiles(&path).unwrap(), (1, 0));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
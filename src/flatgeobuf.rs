@@ -0,0 +1,622 @@
+//! Just enough FlatGeobuf (https://flatgeobuf.org/) reading to use a file as a layer's source.
+//! The whole file is read, the spatial index (if there is one) is skipped. Only 2D geometries are
+//! supported.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+use geo::{Geometry, Point, LineString, Polygon, MultiPoint, MultiLineString, MultiPolygon, GeometryCollection};
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+/// The start of every FlatGeobuf file (version 3). The next byte is the patch version.
+const MAGIC: &[u8] = b"fgb\x03fgb";
+
+/// Each node of the packed R-Tree index is a bbox (4 f64s) & an offset (u64)
+const INDEX_NODE_BYTES: u64 = 40;
+
+/// The type of a column's values.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ColumnType {
+    Byte, UByte, Bool, Short, UShort, Int, UInt, Long, ULong, Float, Double, String, Json, DateTime, Binary,
+}
+
+impl ColumnType {
+    fn from_u8(x: u8) -> Result<Self> {
+        use self::ColumnType::*;
+        let types = [Byte, UByte, Bool, Short, UShort, Int, UInt, Long, ULong, Float, Double, String, Json, DateTime, Binary];
+        types.get(x as usize).cloned().ok_or_else(|| format_err!("Unknown column type {}", x))
+    }
+
+    /// The TileJSON type of these values. `None` for binary, which can't be in a vector tile.
+    pub fn tilejson_type(&self) -> Option<&'static str> {
+        use self::ColumnType::*;
+        match *self {
+            Bool => Some("Boolean"),
+            Byte | UByte | Short | UShort | Int | UInt | Long | ULong | Float | Double => Some("Number"),
+            String | Json | DateTime => Some("String"),
+            Binary => None,
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub type_: ColumnType,
+}
+
+/// The value of one column of a feature.
+#[derive(Debug,Clone,PartialEq)]
+pub enum PropertyValue {
+    Int(i64),
+    UInt(u64),
+    Float(f32),
+    Double(f64),
+    Boolean(bool),
+    /// Also JSON & date/time columns
+    String(String),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Feature {
+    pub geometry: Geometry<f64>,
+    /// minx, miny, maxx, maxy of `geometry`
+    pub bbox: [f64; 4],
+    /// The index (in `FlatGeobuf::columns`) & value of each column which this feature has a value
+    /// for
+    pub properties: Vec<(usize, PropertyValue)>,
+}
+
+/// The contents of a FlatGeobuf file.
+#[derive(Debug,Clone,PartialEq)]
+pub struct FlatGeobuf {
+    pub columns: Vec<Column>,
+    /// The EPSG code of the CRS, if it says
+    pub epsg_code: Option<i32>,
+    pub features: Vec<Feature>,
+}
+
+/// Read the FlatGeobuf file at `path`.
+pub fn read_file(path: &Path) -> Result<FlatGeobuf> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    read(&bytes)
+}
+
+/// Read this FlatGeobuf file.
+pub fn read(bytes: &[u8]) -> Result<FlatGeobuf> {
+    if bytes.len() < 8 || &bytes[0..7] != MAGIC {
+        return Err(format_err!("Not a FlatGeobuf (version 3) file"));
+    }
+
+    let header_len = read_u32(bytes, 8)? as usize;
+    let header = Table::root(slice(bytes, 12, header_len)?)?;
+
+    // Fields of the Header table
+    let geometry_type = header.u8(2, 0)?;
+    if header.u8(3, 0)? != 0 || header.u8(4, 0)? != 0 || header.u8(5, 0)? != 0 || header.u8(6, 0)? != 0 {
+        return Err(format_err!("Geometries with z, m or t coordinates aren't supported"));
+    }
+    let mut columns = Vec::new();
+    for column in header.tables(7)? {
+        let name = column.string(0)?.ok_or_else(|| format_err!("Column has no name"))?;
+        columns.push(Column{ name, type_: ColumnType::from_u8(column.u8(1, 0)?)? });
+    }
+    let features_count = header.u64(8, 0)?;
+    let index_node_size = header.u16(9, 16)?;
+    let epsg_code = match header.table(10)? {
+        None => None,
+        Some(crs) => match crs.i32(1, 0)? {
+            0 => None,
+            code => Some(code),
+        },
+    };
+
+    let mut position = 12 + header_len;
+    if index_node_size > 0 && features_count > 0 {
+        position = position.checked_add(index_size(features_count, index_node_size) as usize)
+            .ok_or_else(|| format_err!("FlatGeobuf index is too big"))?;
+    }
+
+    let mut features = Vec::new();
+    while position < bytes.len() {
+        let feature_len = read_u32(bytes, position)? as usize;
+        let feature = Table::root(slice(bytes, position + 4, feature_len)?)?;
+        position += 4 + feature_len;
+
+        if feature.vector(2)?.is_some() {
+            return Err(format_err!("Features with their own columns aren't supported"));
+        }
+        // Features without a geometry can't be in a vector tile
+        let geometry = match feature.table(0)? {
+            None => { continue; },
+            Some(g) => g,
+        };
+        let mut bbox = [::std::f64::INFINITY, ::std::f64::INFINITY, ::std::f64::NEG_INFINITY, ::std::f64::NEG_INFINITY];
+        let geometry = read_geometry(&geometry, geometry_type, &mut bbox)?;
+        let properties = match feature.bytes(1)? {
+            None => Vec::new(),
+            Some(bytes) => read_properties(bytes, &columns)?,
+        };
+        features.push(Feature{ geometry, bbox, properties });
+    }
+
+    Ok(FlatGeobuf{ columns, epsg_code, features })
+}
+
+/// How many bytes the packed R-Tree index of this many features is.
+fn index_size(features_count: u64, node_size: u16) -> u64 {
+    let node_size = ::std::cmp::max(node_size as u64, 2);
+    let mut n = features_count;
+    let mut num_nodes = n;
+    loop {
+        n = (n + node_size - 1) / node_size;
+        num_nodes += n;
+        if n <= 1 {
+            break;
+        }
+    }
+    num_nodes * INDEX_NODE_BYTES
+}
+
+/// The geometry in this Geometry table. `geometry_type` is from the header, 0 (Unknown) means
+/// each geometry says what type it is. `bbox` is expanded to include every point.
+fn read_geometry(table: &Table, geometry_type: u8, bbox: &mut [f64; 4]) -> Result<Geometry<f64>> {
+    let geometry_type = if geometry_type == 0 { table.u8(6, 0)? } else { geometry_type };
+
+    let xy = table.f64s(1)?;
+    if xy.len() % 2 != 0 {
+        return Err(format_err!("Geometry has an odd number of coordinates"));
+    }
+    let points: Vec<Point<f64>> = xy.chunks(2).map(|c| Point::new(c[0], c[1])).collect();
+    for p in points.iter() {
+        bbox[0] = bbox[0].min(p.x());
+        bbox[1] = bbox[1].min(p.y());
+        bbox[2] = bbox[2].max(p.x());
+        bbox[3] = bbox[3].max(p.y());
+    }
+    // Where each ring/line ends (as a number of points). None means there's only one.
+    let ends = table.u32s(0)?;
+
+    match geometry_type {
+        1 => points.into_iter().next().map(Geometry::Point).ok_or_else(|| format_err!("Point has no coordinates")),
+        2 => Ok(Geometry::LineString(LineString(points))),
+        3 => {
+            let mut rings = split_at_ends(points, &ends)?.into_iter();
+            match rings.next() {
+                None => Err(format_err!("Polygon has no rings")),
+                Some(exterior) => Ok(Geometry::Polygon(Polygon::new(exterior, rings.collect()))),
+            }
+        },
+        4 => Ok(Geometry::MultiPoint(MultiPoint(points))),
+        5 => Ok(Geometry::MultiLineString(MultiLineString(split_at_ends(points, &ends)?))),
+        6 => {
+            let mut polygons = Vec::new();
+            for part in table.tables(7)? {
+                match read_geometry(&part, 3, bbox)? {
+                    Geometry::Polygon(p) => polygons.push(p),
+                    _ => unreachable!(),
+                }
+            }
+            Ok(Geometry::MultiPolygon(MultiPolygon(polygons)))
+        },
+        7 => {
+            let mut geoms = Vec::new();
+            for part in table.tables(7)? {
+                geoms.push(read_geometry(&part, 0, bbox)?);
+            }
+            Ok(Geometry::GeometryCollection(GeometryCollection(geoms)))
+        },
+        x => Err(format_err!("Geometry type {} isn't supported", x)),
+    }
+}
+
+/// Split these points into lines (or rings), which end at each of `ends`.
+fn split_at_ends(points: Vec<Point<f64>>, ends: &[u32]) -> Result<Vec<LineString<f64>>> {
+    if ends.is_empty() {
+        return Ok(vec![LineString(points)]);
+    }
+    let mut lines = Vec::with_capacity(ends.len());
+    let mut start = 0;
+    for &end in ends.iter() {
+        let end = end as usize;
+        if end < start || end > points.len() {
+            return Err(format_err!("Geometry has invalid ends"));
+        }
+        lines.push(LineString(points[start..end].to_vec()));
+        start = end;
+    }
+    Ok(lines)
+}
+
+/// The values in a feature's properties: each is the column index (u16), and then the value.
+fn read_properties(bytes: &[u8], columns: &[Column]) -> Result<Vec<(usize, PropertyValue)>> {
+    let mut properties = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let column = read_u16(bytes, i)? as usize;
+        i += 2;
+        let type_ = columns.get(column).ok_or_else(|| format_err!("Feature has a value for column {}, which doesn't exist", column))?.type_;
+        let (value, len) = match type_ {
+            ColumnType::Byte => (PropertyValue::Int(slice(bytes, i, 1)?[0] as i8 as i64), 1),
+            ColumnType::UByte => (PropertyValue::Int(slice(bytes, i, 1)?[0] as i64), 1),
+            ColumnType::Bool => (PropertyValue::Boolean(slice(bytes, i, 1)?[0] != 0), 1),
+            ColumnType::Short => (PropertyValue::Int(LittleEndian::read_i16(slice(bytes, i, 2)?) as i64), 2),
+            ColumnType::UShort => (PropertyValue::Int(read_u16(bytes, i)? as i64), 2),
+            ColumnType::Int => (PropertyValue::Int(LittleEndian::read_i32(slice(bytes, i, 4)?) as i64), 4),
+            ColumnType::UInt => (PropertyValue::Int(read_u32(bytes, i)? as i64), 4),
+            ColumnType::Long => (PropertyValue::Int(LittleEndian::read_i64(slice(bytes, i, 8)?)), 8),
+            ColumnType::ULong => (PropertyValue::UInt(LittleEndian::read_u64(slice(bytes, i, 8)?)), 8),
+            ColumnType::Float => (PropertyValue::Float(LittleEndian::read_f32(slice(bytes, i, 4)?)), 4),
+            ColumnType::Double => (PropertyValue::Double(LittleEndian::read_f64(slice(bytes, i, 8)?)), 8),
+            ColumnType::String | ColumnType::Json | ColumnType::DateTime | ColumnType::Binary => {
+                let len = read_u32(bytes, i)? as usize;
+                let value = slice(bytes, i + 4, len)?;
+                let value = if type_ == ColumnType::Binary {
+                    PropertyValue::Binary(value.to_vec())
+                } else {
+                    PropertyValue::String(String::from_utf8(value.to_vec()).map_err(|_| format_err!("Column {} has a value which isn't UTF-8", columns[column].name))?)
+                };
+                (value, 4 + len)
+            },
+        };
+        i += len;
+        properties.push((column, value));
+    }
+    Ok(properties)
+}
+
+/// `len` bytes from `start`, or an error if the file is too short.
+fn slice(bytes: &[u8], start: usize, len: usize) -> Result<&[u8]> {
+    match start.checked_add(len) {
+        Some(end) if end <= bytes.len() => Ok(&bytes[start..end]),
+        _ => Err(format_err!("FlatGeobuf is truncated")),
+    }
+}
+
+fn read_u16(bytes: &[u8], position: usize) -> Result<u16> {
+    slice(bytes, position, 2).map(LittleEndian::read_u16)
+}
+
+fn read_u32(bytes: &[u8], position: usize) -> Result<u32> {
+    slice(bytes, position, 4).map(LittleEndian::read_u32)
+}
+
+/// A table in a flatbuffer. Fields are read by their index in the schema, and every offset is
+/// checked, so a broken file is an error rather than a panic.
+struct Table<'a> {
+    buf: &'a [u8],
+    position: usize,
+    vtable: usize,
+}
+
+impl<'a> Table<'a> {
+    /// The root table of this flatbuffer.
+    fn root(buf: &'a [u8]) -> Result<Self> {
+        Table::at(buf, read_u32(buf, 0)? as usize)
+    }
+
+    fn at(buf: &'a [u8], position: usize) -> Result<Self> {
+        let vtable = position as i64 - LittleEndian::read_i32(slice(buf, position, 4)?) as i64;
+        if vtable < 0 {
+            return Err(format_err!("FlatGeobuf has an invalid table"));
+        }
+        Ok(Table{ buf, position, vtable: vtable as usize })
+    }
+
+    /// Where this field is, or None if it isn't set.
+    fn field(&self, index: usize) -> Result<Option<usize>> {
+        let vtable_len = read_u16(self.buf, self.vtable)? as usize;
+        let entry = 4 + 2 * index;
+        if entry + 2 > vtable_len {
+            return Ok(None);
+        }
+        match read_u16(self.buf, self.vtable + entry)? {
+            0 => Ok(None),
+            offset => Ok(Some(self.position + offset as usize)),
+        }
+    }
+
+    /// Where the thing this (offset) field points to is.
+    fn target(&self, index: usize) -> Result<Option<usize>> {
+        match self.field(index)? {
+            None => Ok(None),
+            Some(position) => Ok(Some(position + read_u32(self.buf, position)? as usize)),
+        }
+    }
+
+    fn u8(&self, index: usize, default: u8) -> Result<u8> {
+        match self.field(index)? {
+            None => Ok(default),
+            Some(position) => Ok(slice(self.buf, position, 1)?[0]),
+        }
+    }
+
+    fn u16(&self, index: usize, default: u16) -> Result<u16> {
+        match self.field(index)? {
+            None => Ok(default),
+            Some(position) => read_u16(self.buf, position),
+        }
+    }
+
+    fn i32(&self, index: usize, default: i32) -> Result<i32> {
+        match self.field(index)? {
+            None => Ok(default),
+            Some(position) => slice(self.buf, position, 4).map(LittleEndian::read_i32),
+        }
+    }
+
+    fn u64(&self, index: usize, default: u64) -> Result<u64> {
+        match self.field(index)? {
+            None => Ok(default),
+            Some(position) => slice(self.buf, position, 8).map(LittleEndian::read_u64),
+        }
+    }
+
+    /// Where the elements of this vector start, and how many there are.
+    fn vector(&self, index: usize) -> Result<Option<(usize, usize)>> {
+        match self.target(index)? {
+            None => Ok(None),
+            Some(position) => Ok(Some((position + 4, read_u32(self.buf, position)? as usize))),
+        }
+    }
+
+    /// The bytes of this `[ubyte]` (or string) field.
+    fn bytes(&self, index: usize) -> Result<Option<&'a [u8]>> {
+        match self.vector(index)? {
+            None => Ok(None),
+            Some((start, len)) => slice(self.buf, start, len).map(Some),
+        }
+    }
+
+    fn string(&self, index: usize) -> Result<Option<String>> {
+        match self.bytes(index)? {
+            None => Ok(None),
+            Some(bytes) => String::from_utf8(bytes.to_vec()).map(Some).map_err(|_| format_err!("FlatGeobuf has a string which isn't UTF-8")),
+        }
+    }
+
+    fn f64s(&self, index: usize) -> Result<Vec<f64>> {
+        match self.vector(index)? {
+            None => Ok(Vec::new()),
+            Some((start, len)) => Ok(slice(self.buf, start, len.saturating_mul(8))?.chunks(8).map(LittleEndian::read_f64).collect()),
+        }
+    }
+
+    fn u32s(&self, index: usize) -> Result<Vec<u32>> {
+        match self.vector(index)? {
+            None => Ok(Vec::new()),
+            Some((start, len)) => Ok(slice(self.buf, start, len.saturating_mul(4))?.chunks(4).map(LittleEndian::read_u32).collect()),
+        }
+    }
+
+    fn table(&self, index: usize) -> Result<Option<Table<'a>>> {
+        match self.target(index)? {
+            None => Ok(None),
+            Some(position) => Table::at(self.buf, position).map(Some),
+        }
+    }
+
+    /// The tables in this `[Table]` field.
+    fn tables(&self, index: usize) -> Result<Vec<Table<'a>>> {
+        let (start, len) = match self.vector(index)? {
+            None => { return Ok(Vec::new()); },
+            Some(v) => v,
+        };
+        slice(self.buf, start, len.saturating_mul(4))?;
+        (0..len).map(|i| {
+            let position = start + 4 * i;
+            Table::at(self.buf, position + read_u32(self.buf, position)? as usize)
+        }).collect()
+    }
+}
+
+/// Writing FlatGeobuf files, for tests. The flatbuffers aren't aligned, which `read` doesn't need.
+#[cfg(test)]
+pub mod write {
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use geo::Geometry;
+
+    use super::{MAGIC, ColumnType, PropertyValue};
+
+    /// A field of a flatbuffer table
+    pub enum Field {
+        U8(u8),
+        U16(u16),
+        I32(i32),
+        U64(u64),
+        Bytes(Vec<u8>),
+        F64s(Vec<f64>),
+        U32s(Vec<u32>),
+        Table(Vec<(usize, Field)>),
+        Tables(Vec<Vec<(usize, Field)>>),
+    }
+
+    /// This table (a field index & value for each field which is set) as a flatbuffer.
+    pub fn flatbuffer(fields: Vec<(usize, Field)>) -> Vec<u8> {
+        let mut out = vec![0; 4];
+        let position = write_table(&mut out, fields);
+        (&mut out[0..4]).write_u32::<LittleEndian>(position as u32).unwrap();
+        out
+    }
+
+    /// Write the vtable & then the table, and then what it points to. Returns where the table is.
+    fn write_table(out: &mut Vec<u8>, fields: Vec<(usize, Field)>) -> usize {
+        let num_fields = fields.iter().map(|&(i, _)| i + 1).max().unwrap_or(0);
+        let inline_len = |f: &Field| match *f { Field::U8(_) => 1, Field::U16(_) => 2, Field::I32(_) => 4, Field::U64(_) => 8, _ => 4 };
+
+        let mut offsets = vec![0u16; num_fields];
+        let mut table_len = 4;
+        for &(i, ref f) in fields.iter() {
+            offsets[i] = table_len as u16;
+            table_len += inline_len(f);
+        }
+        let vtable = out.len();
+        out.write_u16::<LittleEndian>(4 + 2 * num_fields as u16).unwrap();
+        out.write_u16::<LittleEndian>(table_len as u16).unwrap();
+        for o in offsets {
+            out.write_u16::<LittleEndian>(o).unwrap();
+        }
+
+        let table = out.len();
+        out.write_i32::<LittleEndian>((table - vtable) as i32).unwrap();
+        let mut pointers = Vec::new();
+        for (_, f) in fields {
+            match f {
+                Field::U8(x) => out.push(x),
+                Field::U16(x) => out.write_u16::<LittleEndian>(x).unwrap(),
+                Field::I32(x) => out.write_i32::<LittleEndian>(x).unwrap(),
+                Field::U64(x) => out.write_u64::<LittleEndian>(x).unwrap(),
+                f => {
+                    pointers.push((out.len(), f));
+                    out.extend_from_slice(&[0; 4]);
+                },
+            }
+        }
+
+        for (pointer, f) in pointers {
+            let target = out.len();
+            (&mut out[pointer..pointer+4]).write_u32::<LittleEndian>((target - pointer) as u32).unwrap();
+            match f {
+                Field::Bytes(bytes) => {
+                    out.write_u32::<LittleEndian>(bytes.len() as u32).unwrap();
+                    out.extend_from_slice(&bytes);
+                },
+                Field::F64s(xs) => {
+                    out.write_u32::<LittleEndian>(xs.len() as u32).unwrap();
+                    for x in xs { out.write_f64::<LittleEndian>(x).unwrap(); }
+                },
+                Field::U32s(xs) => {
+                    out.write_u32::<LittleEndian>(xs.len() as u32).unwrap();
+                    for x in xs { out.write_u32::<LittleEndian>(x).unwrap(); }
+                },
+                Field::Table(fields) => {
+                    // The pointer is to the table, not its vtable
+                    let table = write_table(out, fields);
+                    (&mut out[pointer..pointer+4]).write_u32::<LittleEndian>((table - pointer) as u32).unwrap();
+                },
+                Field::Tables(tables) => {
+                    out.write_u32::<LittleEndian>(tables.len() as u32).unwrap();
+                    let elements = out.len();
+                    out.extend(vec![0; 4 * tables.len()]);
+                    for (i, fields) in tables.into_iter().enumerate() {
+                        let table = write_table(out, fields);
+                        let element = elements + 4 * i;
+                        (&mut out[element..element+4]).write_u32::<LittleEndian>((table - element) as u32).unwrap();
+                    }
+                },
+                _ => unreachable!(),
+            }
+        }
+
+        table
+    }
+
+    /// The fields of a Geometry table for this geometry. Only (multi)points, lines & polygons.
+    fn geometry(geom: &Geometry<f64>) -> Vec<(usize, Field)> {
+        let xy = |points: &[::geo::Point<f64>]| Field::F64s(points.iter().flat_map(|p| vec![p.x(), p.y()]).collect());
+        match *geom {
+            Geometry::Point(ref p) => vec![(1, xy(&[p.clone()])), (6, Field::U8(1))],
+            Geometry::LineString(ref ls) => vec![(1, xy(&ls.0)), (6, Field::U8(2))],
+            Geometry::Polygon(ref p) => {
+                let rings: Vec<_> = Some(&p.exterior).into_iter().chain(p.interiors.iter()).collect();
+                let mut ends = Vec::new();
+                for r in rings.iter() {
+                    let end = ends.last().cloned().unwrap_or(0) + r.0.len() as u32;
+                    ends.push(end);
+                }
+                let points: Vec<_> = rings.iter().flat_map(|r| r.0.iter().cloned()).collect();
+                vec![(0, Field::U32s(ends)), (1, xy(&points)), (6, Field::U8(3))]
+            },
+            Geometry::MultiPoint(ref mp) => vec![(1, xy(&mp.0)), (6, Field::U8(4))],
+            Geometry::MultiPolygon(ref mp) => vec![(6, Field::U8(6)), (7, Field::Tables(mp.0.iter().map(|p| geometry(&Geometry::Polygon(p.clone()))).collect()))],
+            ref g => panic!("Can't write {:?}", g),
+        }
+    }
+
+    /// A FlatGeobuf file of these features (each geometry & value of each column), in web
+    /// mercator, with no index.
+    pub fn file(columns: &[(&str, ColumnType)], features: &[(Geometry<f64>, Vec<(usize, PropertyValue)>)]) -> Vec<u8> {
+        let columns = columns.iter().map(|&(name, type_)| vec![(0, Field::Bytes(name.as_bytes().to_vec())), (1, Field::U8(type_ as u8))]).collect();
+        // No geometry type (each feature says), 3857, no index
+        let header = flatbuffer(vec![(2, Field::U8(0)), (7, Field::Tables(columns)), (8, Field::U64(features.len() as u64)), (9, Field::U16(0)), (10, Field::Table(vec![(1, Field::I32(3857))]))]);
+
+        let mut out = MAGIC.to_vec();
+        out.push(0);
+        out.write_u32::<LittleEndian>(header.len() as u32).unwrap();
+        out.extend_from_slice(&header);
+        for &(ref geom, ref values) in features.iter() {
+            let mut properties = Vec::new();
+            for &(column, ref value) in values.iter() {
+                properties.write_u16::<LittleEndian>(column as u16).unwrap();
+                match *value {
+                    PropertyValue::Int(x) => properties.write_i64::<LittleEndian>(x).unwrap(),
+                    PropertyValue::UInt(x) => properties.write_u64::<LittleEndian>(x).unwrap(),
+                    PropertyValue::Float(x) => properties.write_f32::<LittleEndian>(x).unwrap(),
+                    PropertyValue::Double(x) => properties.write_f64::<LittleEndian>(x).unwrap(),
+                    PropertyValue::Boolean(b) => properties.push(b as u8),
+                    PropertyValue::String(ref s) => {
+                        properties.write_u32::<LittleEndian>(s.len() as u32).unwrap();
+                        properties.extend_from_slice(s.as_bytes());
+                    },
+                    PropertyValue::Binary(ref b) => {
+                        properties.write_u32::<LittleEndian>(b.len() as u32).unwrap();
+                        properties.extend_from_slice(b);
+                    },
+                }
+            }
+            let feature = flatbuffer(vec![(0, Field::Table(geometry(geom))), (1, Field::Bytes(properties))]);
+            out.write_u32::<LittleEndian>(feature.len() as u32).unwrap();
+            out.extend_from_slice(&feature);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn index_sizes() {
+        // The one feature, and the root
+        assert_eq!(index_size(1, 16), 2 * INDEX_NODE_BYTES);
+        assert_eq!(index_size(16, 16), 17 * INDEX_NODE_BYTES);
+        assert_eq!(index_size(100, 16), (100 + 7 + 1) * INDEX_NODE_BYTES);
+        assert_eq!(index_size(3, 0), (3 + 2 + 1) * INDEX_NODE_BYTES);
+    }
+
+    #[test]
+    fn read_features() {
+        let outer: LineString<f64> = vec![(0., 0.), (0., 10.), (10., 10.), (10., 0.), (0., 0.)].into();
+        let hole: LineString<f64> = vec![(2., 2.), (4., 2.), (4., 4.), (2., 2.)].into();
+        let donut = Polygon::new(outer.clone(), vec![hole]);
+        let columns = [("name", ColumnType::String), ("population", ColumnType::Long), ("capital", ColumnType::Bool)];
+        let features = vec![
+            (Geometry::Polygon(donut.clone()), vec![(0, PropertyValue::String("Dublin".to_string())), (1, PropertyValue::Int(1_173_179)), (2, PropertyValue::Boolean(true))]),
+            (Geometry::MultiPolygon(MultiPolygon(vec![donut, Polygon::new(vec![(20., -5.), (20., 5.), (30., 5.), (30., -5.), (20., -5.)].into(), vec![])])), vec![(1, PropertyValue::Int(-1))]),
+            (Geometry::Point(Point::new(1., 2.)), vec![]),
+        ];
+
+        let fgb = read(&write::file(&columns, &features)).unwrap();
+        assert_eq!(fgb.epsg_code, Some(3857));
+        assert_eq!(fgb.columns.iter().map(|c| (c.name.as_str(), c.type_)).collect::<Vec<_>>(), columns.to_vec());
+        assert_eq!(fgb.features.len(), 3);
+        for (feature, &(ref geom, ref properties)) in fgb.features.iter().zip(features.iter()) {
+            assert_eq!(&feature.geometry, geom);
+            assert_eq!(&feature.properties, properties);
+        }
+        assert_eq!(fgb.features[1].bbox, [0., -5., 30., 10.]);
+        assert_eq!(fgb.features[2].bbox, [1., 2., 1., 2.]);
+
+        assert!(read(b"fgb\x03fgb\x00").is_err());
+        assert!(read(b"not a flatgeobuf").is_err());
+        let mut truncated = write::file(&columns, &features);
+        truncated.pop();
+        assert!(read(&truncated).is_err());
+    }
+}
@@ -1,11 +1,13 @@
 use postgres::params::ConnectParams;
 use postgres::types::{Type, ToSql, IsNull};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io::prelude::*;
 use std::fs;
+use std::path::PathBuf;
 
 use LocalBBox;
+use filter::{Filter, Literal};
 
 type Result<T> = std::result::Result<T, failure::Error>;
 
@@ -29,26 +31,227 @@ pub struct Layer {
     pub maxzoom: u8,
     pub buffer: u16,
     pub id: String,
+    /// Name of the layer in the vector tiles. Defaults to the `id`
+    pub name: String,
+    /// For zooms less than this, only include point features (e.g. labels)
+    pub labels_only_below: Option<u8>,
+    /// When simplifying, never remove points at the ends of segments shorter than this (in
+    /// 'pixels', like `buffer`), so small details (e.g. for dashed lines) are kept.
+    pub preserve_detail_below_length: Option<u16>,
+    /// Remove polygon holes with an area less than this (in tile coordinate units, where a tile
+    /// is 4096×4096).
+    pub min_hole_area: Option<u32>,
+    /// With `--prune-empty-descendants`, can tiles be skipped if their parent tile was empty?
+    /// Turn off for layers which can have data where their lower zooms don't.
+    pub prune_empty_descendants: bool,
+    /// Only keep this many features (the largest ones) in each tile.
+    pub max_features: Option<u32>,
+    /// Features with more vertices than this in a tile are simplified more until they have few
+    /// enough, or dropped if they can't be.
+    pub max_vertices_per_feature: Option<u32>,
+    /// Only encode these attributes (columns) of the features. `None` means all of them.
+    pub include_attributes: Option<Vec<String>>,
+    /// Never encode these attributes (columns) of the features.
+    pub exclude_attributes: Vec<String>,
+    /// Only features which match this (e.g. `class = 'service' and zoom >= 14`) are in the
+    /// tiles.
+    pub filter: Option<Filter>,
+    /// Simplify all the features of the layer in a tile together, so boundaries shared by
+    /// polygons are simplified the same way in each, with no gaps or overlaps between them.
+    pub simplify_topology: bool,
+    /// Clip & make the features valid at this many times the tile's resolution, and only then
+    /// scale them down to the tile. 1 means no oversampling.
+    pub oversample: u8,
+    /// Move vertices within this many tile units of the tile's edges onto the edge (see
+    /// `clip::snap_to_tile_edges`), so neighbouring tiles line up exactly.
+    pub snap_tile_edges: Option<u32>,
+    /// Don't simplify features on this zoom or higher, only remove their repeated & collinear
+    /// points (which doesn't move anything). The global maxzoom is never simplified.
+    pub simplify_max_zoom: Option<u8>,
+    /// In tiles where this layer has no features (e.g. the open sea), add a polygon covering the
+    /// whole tile (and buffer), with these attributes.
+    pub background: Option<Vec<(String, Literal)>>,
+    /// Put the features in several vector tile layers (rather than one called `name`), by the
+    /// value of an attribute.
+    pub split_by: Option<SplitBy>,
     pub table: TableSQL,
     pub dbname: Option<String>,
+    /// Read the features from this GeoPackage table, rather than PostgreSQL (then `table` &
+    /// `dbname` aren't used).
+    pub geopackage: Option<GeoPackageTable>,
+    /// Read the features from this FlatGeobuf file, rather than PostgreSQL (then `table` &
+    /// `dbname` aren't used).
+    pub flatgeobuf: Option<PathBuf>,
+    /// The features of a `wkt` datasource, which are in the data.yml itself (then `table` &
+    /// `dbname` aren't used).
+    pub inline_features: Option<Vec<InlineFeature>>,
+}
+
+/// A table in a GeoPackage file, which a layer's features are read from.
+#[derive(Clone,Debug,PartialEq)]
+pub struct GeoPackageTable {
+    pub path: PathBuf,
+    pub table: String,
+}
+
+/// A feature given in the data.yml itself, e.g. to reproduce a bug without a database.
+#[derive(Clone,Debug,PartialEq)]
+pub struct InlineFeature {
+    /// The geometry as (2D) WKB, in web mercator
+    pub wkb: Vec<u8>,
+    /// Attributes whose value is NULL aren't included
+    pub attributes: Vec<(String, Literal)>,
+}
+
+/// Which vector tile layer each of a layer's features goes in, from the value of one attribute.
+#[derive(Clone,Debug,PartialEq)]
+pub struct SplitBy {
+    /// The attribute (column) whose value picks the layer
+    pub column: String,
+    /// The vector tile layer for each value (as text, so `3` or `true` match numbers & booleans)
+    pub mapping: BTreeMap<String, String>,
+    /// The vector tile layer for features whose value isn't in `mapping` (or is NULL). `None`
+    /// leaves them out.
+    pub default: Option<String>,
+}
+
+impl SplitBy {
+    /// The names of the vector tile layers, each once: those in `mapping` (in order of value),
+    /// then the `default`.
+    pub fn layer_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for name in self.mapping.values().chain(self.default.iter()) {
+            if ! names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        names
+    }
+
+    /// The vector tile layer for a feature with this value of `column`. `None` if it's left out.
+    pub fn layer_for(&self, value: Option<&::mapbox_vector_tile::Value>) -> Option<&str> {
+        use mapbox_vector_tile::Value;
+        let text = match value {
+            Some(&Value::String(ref s)) => Some(s.to_string()),
+            Some(&Value::Int(i)) => Some(i.to_string()),
+            Some(&Value::Float(f)) => Some(f.to_string()),
+            Some(&Value::Double(f)) => Some(f.to_string()),
+            Some(&Value::Boolean(b)) => Some(b.to_string()),
+            _ => None,
+        };
+        text.and_then(|t| self.mapping.get(&t)).or(self.default.as_ref()).map(|name| name.as_str())
+    }
+}
+
+impl Layer {
+    /// The names of the vector tile layers this layer's features are in: its `name`, or those
+    /// of `split_by`.
+    pub fn tile_layer_names(&self) -> Vec<String> {
+        self.split_by.as_ref().map_or_else(|| vec![self.name.clone()], |s| s.layer_names())
+    }
+
+    /// Should this attribute (column) be in the tiles? (See `include_attributes` &
+    /// `exclude_attributes`)
+    pub fn includes_attribute(&self, name: &str) -> bool {
+        self.include_attributes.as_ref().map_or(true, |names| names.iter().any(|n| n == name))
+            && ! self.exclude_attributes.iter().any(|n| n == name)
+    }
+
+    /// Does this attribute need to be read from the source? Either it's in the tiles, or the
+    /// `filter` or `split_by` uses it.
+    pub fn reads_attribute(&self, name: &str) -> bool {
+        self.includes_attribute(name) || self.filter.as_ref().map_or(false, |f| f.attributes().contains(&name))
+            || self.split_by.as_ref().map_or(false, |s| s.column == name)
+    }
 }
 
 impl Layers {
     pub fn from_file(filename: &str) -> Result<Self> {
-        Layers::from_tmsource_file(filename).or_else(|_| Layers::from_tegola_file(filename))
+        // If it's not a tegola file either, the tmsource error is the more helpful one
+        Layers::from_tmsource_file(filename).or_else(|e| Layers::from_tegola_file(filename).map_err(|_| e))
+    }
+
+    /// Read the layers from all these files, and merge them, in order (see `merge`).
+    pub fn from_files(filenames: &[&str], allow_override: bool) -> Result<Self> {
+        let mut filenames = filenames.iter();
+        let first = filenames.next().ok_or_else(|| format_err!("No data.yml files given"))?;
+        let mut layers = Layers::from_file(first)?;
+        for filename in filenames {
+            let other = Layers::from_file(filename)?;
+            layers = layers.merge(other, allow_override).map_err(|e| format_err!("{}: {}", filename, e))?;
+        }
+
+        Ok(layers)
+    }
+
+    /// Add the layers from `other` after these ones. The settings (zooms, bounds, etc.) of `other`
+    /// override these. A layer with the same id or name as one here is an error, unless
+    /// `allow_override`, when it replaces the existing layer (in the same place).
+    pub fn merge(mut self, other: Layers, allow_override: bool) -> Result<Self> {
+        let Layers{ layers, global_maxzoom, global_minzoom, bounds, center, name, description } = other;
+        for layer in layers {
+            let existing = self.layers.iter().position(|l| l.id == layer.id || l.name == layer.name);
+            match existing {
+                None => self.layers.push(layer),
+                Some(i) => {
+                    if ! allow_override {
+                        return Err(format_err!("Layer {} is already defined", layer.id));
+                    }
+                    self.layers[i] = layer;
+                },
+            }
+        }
+
+        self.global_maxzoom = global_maxzoom;
+        self.global_minzoom = global_minzoom;
+        self.bounds = bounds.or(self.bounds);
+        self.center = center.or(self.center);
+        self.name = name.or(self.name);
+        self.description = description.or(self.description);
+
+        Ok(self)
+    }
+
+    /// Never generate these layers (by id or name) above these zooms, whatever their `maxzoom`
+    /// is. A layer which isn't there is an error, since it's probably a typo.
+    pub fn cap_layer_zooms(&mut self, caps: &[(String, u8)]) -> Result<()> {
+        for &(ref layer_id, max_zoom) in caps.iter() {
+            let layer = self.layers.iter_mut().find(|l| &l.id == layer_id || &l.name == layer_id)
+                .ok_or_else(|| format_err!("Can't cap the zoom of layer {}, there is no such layer", layer_id))?;
+            if max_zoom < layer.maxzoom {
+                debug!("Layer {} is capped at zoom {} (instead of {})", layer.id, max_zoom, layer.maxzoom);
+                layer.maxzoom = max_zoom;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The layers which are on this zoom, i.e. which are queried for its tiles.
+    pub fn layers_for_zoom<'a>(&'a self, zoom: u8) -> impl Iterator<Item=&'a Layer> + 'a {
+        let global_maxzoom = self.global_maxzoom;
+        self.layers.iter().filter(move |layer| {
+            let maxzoom = if layer.maxzoom > global_maxzoom { global_maxzoom } else { layer.maxzoom };
+            zoom >= layer.minzoom && zoom <= maxzoom
+        })
     }
 
     pub fn from_tmsource_file(filename: &str) -> Result<Self> {
         tmsource::layers_from_file(filename)
     }
 
+    pub fn from_tmsource_str(contents: &str) -> Result<Self> {
+        tmsource::layers_from_str(contents)
+    }
+
     pub fn from_tegola_file(filename: &str) -> Result<Self> {
         tegola::layers_from_file(filename)
     }
 
     pub fn get_all_connections(&self) -> HashMap<ConnectParams, Vec<String>> {
         let mut conns = HashMap::new();
-        for layer in self.layers.iter() {
+        // GeoPackage, FlatGeobuf & WKT layers don't use the database
+        for layer in self.layers.iter().filter(|l| l.geopackage.is_none() && l.flatgeobuf.is_none() && l.inline_features.is_none()) {
 
             let mut conn_params = postgres::params::Builder::new();
             // TODO do others
@@ -75,18 +278,40 @@ impl Layers {
     }
 }
 
+/// The SRID of web (aka spherical, or google) mercator, which tiles are in.
+pub const WEB_MERCATOR_SRID: u32 = 3857;
+
 #[derive(Clone,Debug)]
 pub struct TableSQL {
     pub query: String,
+    /// SRID of the `way` column. If it's not web mercator, the database reprojects it.
+    pub srid: u32,
     pub has_pixel_width: bool,
     pub has_pixel_height: bool,
     pub has_scale_denominator: bool,
     pub has_zoom: bool,
+    /// Does the query use `!bbox!` itself?
+    pub has_bbox: bool,
+    /// Only fetch rows whose `way` overlaps the tile's bbox (& buffer). Without this, the whole
+    /// table is fetched for every tile, unless the query uses `!bbox!` itself.
+    pub bbox_filter: bool,
         
 }
 
 impl TableSQL {
     pub fn new(query: String) -> Self {
+        TableSQL::new_with_srid(query, WEB_MERCATOR_SRID)
+    }
+
+    /// A query for a table whose `way` column is in this SRID.
+    pub fn new_with_srid(query: String, srid: u32) -> Self {
+        TableSQL::new_with_bbox_filter(query, srid, true)
+    }
+
+    /// A query for a table whose `way` column is in this SRID. If `bbox_filter` is false, rows
+    /// aren't filtered to the tile's bbox.
+    pub fn new_with_bbox_filter(query: String, srid: u32, bbox_filter: bool) -> Self {
+        let has_bbox = query.contains("!bbox!") || query.contains("!BBOX!");
         let has_pixel_width = query.contains("!pixel_width!");
         let has_pixel_height = query.contains("!pixel_height!");
         let has_scale_denominator = query.contains("!scale_denominator!");
@@ -97,7 +322,8 @@ impl TableSQL {
         query = query.replace("!bbox!", "$1");
         query = query.replace("!BBOX!", "$1");
 
-        let mut param_num = 2;
+        // The bbox is always $1, if it's used
+        let mut param_num = if has_bbox || bbox_filter { 2 } else { 1 };
         if has_pixel_width {
             query = query.replace("!pixel_width!", &format!("${}", param_num));
             param_num += 1;
@@ -116,16 +342,31 @@ impl TableSQL {
             param_num += 1;
         }
                 
-        let query = format!("SELECT ST_AsBinary(way::geometry), * from {} where way && $1", query);
+        let query = if !bbox_filter {
+            if srid == WEB_MERCATOR_SRID {
+                format!("SELECT ST_AsBinary(way::geometry), * from {}", query)
+            } else {
+                format!("SELECT ST_AsBinary(ST_Transform(way::geometry, {mercator})), * from {query}",
+                        mercator=WEB_MERCATOR_SRID, query=query)
+            }
+        } else if srid == WEB_MERCATOR_SRID {
+            format!("SELECT ST_AsBinary(way::geometry), * from {} where way && $1", query)
+        } else {
+            // The bbox is in web mercator, so convert that to the source SRID (so the index on
+            // `way` can be used), and convert the geometries to web mercator
+            format!("SELECT ST_AsBinary(ST_Transform(way::geometry, {mercator})), * from {query} where way && ST_Transform(ST_SetSRID($1::geometry, {mercator}), {srid})",
+                    mercator=WEB_MERCATOR_SRID, query=query, srid=srid)
+        };
         TableSQL{
-            query, has_pixel_width, has_pixel_height, has_scale_denominator, has_zoom,
+            query, srid, has_pixel_width, has_pixel_height, has_scale_denominator, has_zoom, has_bbox, bbox_filter,
         }
     }
 
     pub fn params<'a, T: num_traits::Float+Into<f64>+'a+std::fmt::Debug>(&self, bbox: &'a LocalBBox<T>, pixel_width: &'a f32, pixel_height: &'a f32, zoom: &'a i32, scale_denominator: &'a f32) -> Vec<&'a postgres::types::ToSql> {
-        // we always have bbox
-        let mut results: Vec<&postgres::types::ToSql> = Vec::with_capacity(4);
-        results.push(bbox);    // bbox
+        let mut results: Vec<&postgres::types::ToSql> = Vec::with_capacity(5);
+        if self.has_bbox || self.bbox_filter {
+            results.push(bbox);
+        }
         if self.has_pixel_width {
             results.push(pixel_width);
         }
@@ -141,4 +382,33 @@ impl TableSQL {
 
         results
     }
+
+    /// The query with the values of `params` written in, for logging (`--dump-sql`). The bbox is an
+    /// `ST_MakeEnvelope`. Not for running, the values aren't escaped.
+    pub fn query_with_params<T: num_traits::Float+Into<f64>>(&self, bbox: &LocalBBox<T>, pixel_width: f32, pixel_height: f32, zoom: i32, scale_denominator: f32) -> String {
+        // In the same order as `params`
+        let mut values: Vec<String> = Vec::with_capacity(5);
+        if self.has_bbox || self.bbox_filter {
+            values.push(format!("ST_MakeEnvelope({}, {}, {}, {}, {})", bbox.0.into(), bbox.1.into(), bbox.2.into(), bbox.3.into(), WEB_MERCATOR_SRID));
+        }
+        if self.has_pixel_width {
+            values.push(pixel_width.to_string());
+        }
+        if self.has_pixel_height {
+            values.push(pixel_height.to_string());
+        }
+        if self.has_scale_denominator {
+            values.push(scale_denominator.to_string());
+        }
+        if self.has_zoom {
+            values.push(zoom.to_string());
+        }
+
+        // Backwards, so $1 doesn't replace the start of $10
+        let mut query = self.query.clone();
+        for (i, value) in values.iter().enumerate().rev() {
+            query = query.replace(&format!("${}", i+1), value);
+        }
+        query
+    }
 }
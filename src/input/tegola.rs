@@ -103,12 +103,32 @@ pub fn layers_from_file(filename: &str) -> Result<Layers> {
             };
             let provider = tegola_config.providers.iter().filter(|p| p.name == provider_name).nth(0).ok_or(format_err!("Missing provider: {}", l.provider_layer))?;
             let sql = &provider.layers.iter().filter(|l| l.name == provider_layer).nth(0).ok_or(format_err!("missing layer {}", l.provider_layer))?.sql;
+            let id = (l.name.to_owned()).ok_or(format_err!("Missing name"))?;
             Ok(Layer {
-                id: (l.name.to_owned()).ok_or(format_err!("Missing name"))?,
+                name: id.clone(),
+                id: id,
                 dbname: Some(provider.database.to_owned()),
+                geopackage: None,
+                flatgeobuf: None,
+                inline_features: None,
                 minzoom: l.min_zoom.unwrap_or(0),
                 maxzoom: l.max_zoom.unwrap_or(22),
                 buffer: 0,
+                labels_only_below: None,
+                preserve_detail_below_length: None,
+                min_hole_area: None,
+                prune_empty_descendants: true,
+                max_features: None,
+                max_vertices_per_feature: None,
+                simplify_topology: false,
+                oversample: 1,
+                snap_tile_edges: None,
+                simplify_max_zoom: None,
+                background: None,
+                split_by: None,
+                include_attributes: None,
+                exclude_attributes: Vec::new(),
+                filter: None,
                 table: TableSQL::new(format!("({}) as t", sql.to_owned())),
             })
         }).collect::<Result<Vec<Layer>>>()?,
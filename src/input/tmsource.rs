@@ -1,72 +1,614 @@
 //! Read tmsource (aka tm2source) data.yml files
 
-use yaml_rust::{YamlLoader, Yaml};
 use std::fs::File;
 use std::io::prelude::*;
 use std::fs;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 
-use super::{Layers, Layer, TableSQL};
+use serde_yaml;
+use wkb;
+
+use filter::Literal;
+use wkt::{parse_wkt, write_wkb};
+use super::{Layers, Layer, TableSQL, GeoPackageTable, InlineFeature, SplitBy, WEB_MERCATOR_SRID};
 
 type Result<T> = std::result::Result<T, failure::Error>;
 
+/// The data.yml file. Keys which other tm2source tools (e.g. Mapbox Studio) use, but we don't, are
+/// allowed. Other unknown keys end up in `extra`: they're an error if they look like a typo of a
+/// known key (see `check_unknown_keys`), and a warning otherwise.
+#[derive(Deserialize, Debug)]
+struct DataYml {
+    minzoom: u8,
+    maxzoom: u8,
+    bounds: Option<[f64; 4]>,
+    center: Option<[f64; 3]>,
+    name: Option<String>,
+    description: Option<String>,
+
+    /// SRID of all the layers, unless a layer says otherwise
+    srid: Option<u32>,
+
+    /// `buffer-size` of all the layers, unless a layer says otherwise
+    #[serde(rename="buffer-size")]
+    buffer_size: Option<u16>,
+
+    /// `simplify-max-zoom` of all the layers, unless a layer says otherwise
+    #[serde(rename="simplify-max-zoom")]
+    simplify_max_zoom: Option<u8>,
+
+    #[serde(rename="Layer")]
+    layers: Vec<LayerConfig>,
+
+    // Ignored
+    attribution: Option<serde_yaml::Value>,
+    pixel_scale: Option<serde_yaml::Value>,
+    #[serde(rename="_prefs")]
+    prefs: Option<serde_yaml::Value>,
+
+    #[serde(flatten)]
+    extra: BTreeMap<String, serde_yaml::Value>,
+}
+
+const DATA_YML_KEYS: &[&str] = &["minzoom", "maxzoom", "bounds", "center", "name", "description", "srid", "buffer-size", "simplify-max-zoom", "Layer", "attribution", "pixel_scale", "_prefs"];
+
+#[derive(Deserialize, Debug)]
+struct LayerConfig {
+    id: String,
+    name: Option<String>,
+
+    #[serde(rename="Datasource")]
+    datasource: Option<DatasourceConfig>,
+
+    /// Where the features come from, instead of a `Datasource`
+    source: Option<LayerSourceConfig>,
+
+    #[serde(default)]
+    properties: LayerProperties,
+
+    // Ignored
+    description: Option<serde_yaml::Value>,
+    fields: Option<serde_yaml::Value>,
+    srs: Option<serde_yaml::Value>,
+    class: Option<serde_yaml::Value>,
+    advanced: Option<serde_yaml::Value>,
+
+    #[serde(flatten)]
+    extra: BTreeMap<String, serde_yaml::Value>,
+}
+
+const LAYER_KEYS: &[&str] = &["id", "name", "Datasource", "source", "properties", "description", "fields", "srs", "class", "advanced"];
+
+#[derive(Deserialize, Debug, Default)]
+struct DatasourceConfig {
+    /// Only `postgis`, `geopackage` & `wkt` layers are used
+    #[serde(rename="type")]
+    type_: Option<String>,
+    dbname: Option<String>,
+    table: Option<String>,
+    /// Several tables, which are combined (with `UNION ALL`) into the layer, instead of `table`
+    sources: Option<Vec<SourceConfig>>,
+    srid: Option<u32>,
+
+    // Ignored
+    host: Option<serde_yaml::Value>,
+    port: Option<serde_yaml::Value>,
+    user: Option<serde_yaml::Value>,
+    password: Option<serde_yaml::Value>,
+    extent: Option<serde_yaml::Value>,
+    geometry_field: Option<serde_yaml::Value>,
+    geometry_table: Option<serde_yaml::Value>,
+    key_field: Option<serde_yaml::Value>,
+    key_field_as_attribute: Option<serde_yaml::Value>,
+    max_size: Option<serde_yaml::Value>,
+    layer: Option<serde_yaml::Value>,
+
+    /// For `geopackage`, the filename. (Ignored for other types)
+    file: Option<serde_yaml::Value>,
+
+    /// For `wkt`, the features
+    features: Option<Vec<InlineFeatureConfig>>,
+
+    #[serde(flatten)]
+    extra: BTreeMap<String, serde_yaml::Value>,
+}
+
+const DATASOURCE_KEYS: &[&str] = &["type", "dbname", "table", "sources", "srid", "host", "port", "user", "password", "extent", "geometry_field", "geometry_table", "key_field", "key_field_as_attribute", "max_size", "layer", "file", "features"];
+
+/// A layer's `source`, a file which isn't a tm2source datasource. Only `flatgeobuf` is supported.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct LayerSourceConfig {
+    #[serde(rename="type")]
+    type_: String,
+    path: String,
+}
+
+/// One of the `features` of a `wkt` datasource. The geometry is WKT, or hex encoded WKB.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct InlineFeatureConfig {
+    wkt: Option<String>,
+    wkb: Option<String>,
+    attrs: Option<BTreeMap<String, serde_yaml::Value>>,
+}
+
+/// One of the `sources` of a datasource
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct SourceConfig {
+    table: String,
+    /// Every feature from this table has a `_src` attribute with this value
+    source_tag: Option<String>,
+}
+
+/// A layer's `split-by`: which vector tile layer each feature goes in, by the value of `column`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct SplitByConfig {
+    column: String,
+    mapping: BTreeMap<String, String>,
+    default: Option<String>,
+}
+
+/// The name of the attribute which a source's `source_tag` is put in.
+const SOURCE_TAG_ATTRIBUTE: &str = "_src";
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all="kebab-case")]
+struct LayerProperties {
+    minzoom: Option<u8>,
+    maxzoom: Option<u8>,
+    buffer_size: Option<u16>,
+    labels_only_below: Option<u8>,
+    preserve_detail_below_length: Option<u16>,
+    min_hole_area: Option<u32>,
+    prune_empty_descendants: Option<bool>,
+    max_features: Option<u32>,
+    max_vertices_per_feature: Option<u32>,
+    simplify_topology: Option<bool>,
+    oversample: Option<u8>,
+    snap_tile_edges: Option<u32>,
+    simplify_max_zoom: Option<u8>,
+    background: Option<BTreeMap<String, serde_yaml::Value>>,
+    split_by: Option<SplitByConfig>,
+    include_attributes: Option<Vec<String>>,
+    exclude_attributes: Option<Vec<String>>,
+    bbox_filter: Option<bool>,
+    filter: Option<String>,
+
+    // Ignored
+    group_by: Option<serde_yaml::Value>,
+    cache_features: Option<serde_yaml::Value>,
+}
+
 pub fn layers_from_file(filename: &str) -> Result<Layers> {
     let mut file = File::open(filename)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
 
-    let mut data_yml = YamlLoader::load_from_str(&contents)?;
-    let data_yml = data_yml.remove(0);
-
-    let global_maxzoom = data_yml["maxzoom"].as_i64().ok_or(format_err!("maxzoom is not a i64"))? as u8;
-    let global_minzoom = data_yml["minzoom"].as_i64().ok_or(format_err!("minzoom is not a i64"))? as u8;
-    let bounds = data_yml["bounds"].as_vec().ok_or(format_err!("bounds is not an array"))?;
-    // '90' would be parsed as an int in yaml
-    let bounds: [f64; 4] = [
-        bounds[0].as_f64().or_else(|| bounds[0].as_i64().map(|i| i as f64)).ok_or(format_err!("bounds invalid"))?,
-        bounds[1].as_f64().or_else(|| bounds[1].as_i64().map(|i| i as f64)).ok_or(format_err!("bounds       invalid"))?,
-        bounds[2].as_f64().or_else(|| bounds[2].as_i64().map(|i| i as f64)).ok_or(format_err!("bounds       invalid"))?,
-        bounds[3].as_f64().or_else(|| bounds[3].as_i64().map(|i| i as f64)).ok_or(format_err!("bounds       invalid"))?,
-    ];
-
-    let center = data_yml["center"].as_vec().ok_or(format_err!("center is not an array"))?;
-    let center: [f64; 3] = [
-        center[0].as_f64().or_else(|| center[0].as_i64().map(|i| i as f64)).ok_or(format_err!("center invalid"))?,
-        center[1].as_f64().or_else(|| center[1].as_i64().map(|i| i as f64)).ok_or(format_err!("center       invalid"))?,
-        center[2].as_f64().or_else(|| center[2].as_i64().map(|i| i as f64)).ok_or(format_err!("center       invalid"))?,
-    ];
-
-    let name = data_yml["name"].as_str().ok_or(format_err!("name is not str"))?.to_string();
-    let description = data_yml["description"].as_str().ok_or(format_err!("description is not str"))?.to_string();
-
-    // rust-yaml really needs an into_hash (etc)
-    // clone all the things
-    let layers = data_yml.as_hash().ok_or(format_err!("yml file is not a hash"))?.clone().remove(&Yaml::String("Layer".to_string())).ok_or(format_err!("No Layer key"))?;
-    let layers = layers.as_vec().ok_or(format_err!("layers is not an array"))?;
-
-    let layers: Vec<Layer> = layers.into_iter().filter(
-        |l| l["Datasource"]
-            .as_hash()
-            .and_then(
-                |d| d.get(&Yaml::String("type".to_string()))
-                    .and_then(|t| Some(t.as_str() == Some("postgis")))
-            )
-            .unwrap_or(false)
-        )
+    layers_from_str(&contents).map_err(|e| format_err!("{}: {}", filename, e))
+}
+
+pub fn layers_from_str(contents: &str) -> Result<Layers> {
+    let data_yml: DataYml = serde_yaml::from_str(contents).map_err(|e| format_err!("Invalid data.yml: {}", e))?;
+    check_unknown_keys("data.yml", &data_yml.extra, DATA_YML_KEYS)?;
+    for layer in data_yml.layers.iter() {
+        check_unknown_keys(&format!("Layer {}", layer.id), &layer.extra, LAYER_KEYS)?;
+        if let Some(ref datasource) = layer.datasource {
+            check_unknown_keys(&format!("Layer {} Datasource", layer.id), &datasource.extra, DATASOURCE_KEYS)?;
+        }
+    }
+
+    let global_minzoom = data_yml.minzoom;
+    let global_maxzoom = data_yml.maxzoom;
+    let global_srid = data_yml.srid.unwrap_or(WEB_MERCATOR_SRID);
+    let global_buffer_size = data_yml.buffer_size.unwrap_or(0);
+    let global_simplify_max_zoom = data_yml.simplify_max_zoom;
+
+    let layers: Vec<Layer> = data_yml.layers.into_iter()
+        .filter(|l| match l.datasource {
+            // Layers with a source (or neither) are checked below
+            None => true,
+            Some(ref datasource) => match datasource.type_.as_ref().map(|t| t.as_str()) { Some("postgis") | Some("geopackage") | Some("wkt") => true, _ => false },
+        })
         .map(|layer| {
-            let table = layer["Datasource"]["table"].as_str().ok_or(format_err!("table is not a str"))?;
-            let table = TableSQL::new(table.to_owned());
-            
+            let LayerConfig{ id, name, datasource, source, properties, .. } = layer;
+            let (datasource, flatgeobuf) = match (datasource, source) {
+                (Some(datasource), None) => (datasource, None),
+                (None, Some(LayerSourceConfig{ ref type_, ref path })) if type_ == "flatgeobuf" => (DatasourceConfig::default(), Some(PathBuf::from(path))),
+                (None, Some(LayerSourceConfig{ ref type_, .. })) => { return Err(format_err!("Layer {}: Unknown source type {:?}, only flatgeobuf is supported", id, type_)); },
+                (Some(_), Some(_)) => { return Err(format_err!("Layer {}: has both a Datasource and a source", id)); },
+                (None, None) => { return Err(format_err!("Layer {}: has no Datasource or source", id)); },
+            };
+            let geopackage = if datasource.type_.as_ref().map(|t| t.as_str()) == Some("geopackage") {
+                let path = match datasource.file.as_ref().and_then(|f| f.as_str()) {
+                    Some(path) => path.into(),
+                    None => { return Err(format_err!("Layer {}: GeoPackage datasource has no file", id)); },
+                };
+                match datasource.table {
+                    Some(ref table) => Some(GeoPackageTable{ path, table: table.clone() }),
+                    None => { return Err(format_err!("Layer {}: Datasource has no table", id)); },
+                }
+            } else {
+                None
+            };
+            let inline_features = if datasource.type_.as_ref().map(|t| t.as_str()) == Some("wkt") {
+                match datasource.features {
+                    Some(ref features) => Some(features.iter().enumerate()
+                        .map(|(i, f)| inline_feature(f).map_err(|e| format_err!("Layer {}: feature {}: {}", id, i, e)))
+                        .collect::<Result<Vec<_>>>()?),
+                    None => { return Err(format_err!("Layer {}: WKT datasource has no features", id)); },
+                }
+            } else {
+                None
+            };
+            let table = match (datasource.table, datasource.sources) {
+                // Not used
+                _ if inline_features.is_some() || flatgeobuf.is_some() => String::new(),
+                (Some(table), None) => table,
+                (None, Some(ref sources)) if !sources.is_empty() => union_sources(sources),
+                (Some(_), Some(_)) => { return Err(format_err!("Layer {}: Datasource has both a table and sources", id)); },
+                (None, _) => { return Err(format_err!("Layer {}: Datasource has no table", id)); },
+            };
+            let table = TableSQL::new_with_bbox_filter(table, datasource.srid.unwrap_or(global_srid), properties.bbox_filter.unwrap_or(true));
+            let oversample = properties.oversample.unwrap_or(1);
+            if oversample == 0 {
+                return Err(format_err!("Layer {}: oversample must be at least 1", id));
+            }
+            if properties.snap_tile_edges == Some(0) {
+                return Err(format_err!("Layer {}: snap-tile-edges must be at least 1", id));
+            }
+            let background = match properties.background {
+                None => None,
+                Some(ref attributes) => Some(attributes.iter().map(|(name, value)| match literal(value) {
+                    Some(value) => Ok((name.clone(), value)),
+                    None => Err(format_err!("Layer {}: background attribute {} must be a string, number or boolean", id, name)),
+                }).collect::<Result<Vec<_>>>()?),
+            };
+            let split_by = match properties.split_by {
+                None => None,
+                Some(SplitByConfig{ ref mapping, default: None, .. }) if mapping.is_empty() => {
+                    return Err(format_err!("Layer {}: split-by has no mapping or default, so every feature would be left out", id));
+                },
+                Some(SplitByConfig{ column, mapping, default }) => Some(SplitBy{ column, mapping, default }),
+            };
+            let filter = match properties.filter {
+                None => None,
+                Some(ref filter) => Some(filter.parse().map_err(|e| format_err!("Layer {}: {}", id, e))?),
+            };
+
             Ok(Layer {
-                id: layer["id"].as_str().ok_or(format_err!("id for layer is not a str"))?.to_owned(),
-                dbname: layer["Datasource"]["dbname"].as_str().map(|x| x.to_owned()),
-                minzoom: layer["properties"]["minzoom"].as_i64().map(|x| x as u8).unwrap_or(global_minzoom) as u8,
-                maxzoom: layer["properties"]["maxzoom"].as_i64().map(|x| x as u8).unwrap_or(global_maxzoom) as u8,
-                buffer: layer["properties"]["buffer-size"].as_i64().map(|x| x as u16).unwrap_or(0) as u16,
+                name: name.unwrap_or_else(|| id.clone()),
+                id: id,
+                dbname: datasource.dbname,
+                geopackage: geopackage,
+                flatgeobuf: flatgeobuf,
+                inline_features: inline_features,
+                minzoom: properties.minzoom.unwrap_or(global_minzoom),
+                maxzoom: properties.maxzoom.unwrap_or(global_maxzoom),
+                buffer: properties.buffer_size.unwrap_or(global_buffer_size),
+                labels_only_below: properties.labels_only_below,
+                preserve_detail_below_length: properties.preserve_detail_below_length,
+                min_hole_area: properties.min_hole_area,
+                prune_empty_descendants: properties.prune_empty_descendants.unwrap_or(true),
+                max_features: properties.max_features,
+                max_vertices_per_feature: properties.max_vertices_per_feature,
+                simplify_topology: properties.simplify_topology.unwrap_or(false),
+                oversample: oversample,
+                snap_tile_edges: properties.snap_tile_edges,
+                simplify_max_zoom: properties.simplify_max_zoom.or(global_simplify_max_zoom),
+                background: background,
+                split_by: split_by,
+                include_attributes: properties.include_attributes,
+                exclude_attributes: properties.exclude_attributes.unwrap_or_else(Vec::new),
+                filter: filter,
                 table: table,
             })
         })
         .collect::<Result<Vec<Layer>>>()?;
 
-    Ok(Layers{ layers: layers, global_minzoom: global_minzoom, global_maxzoom: global_maxzoom, bounds: Some(bounds), center: Some(center), name: Some(name), description: Some(description) })
+    Ok(Layers{ layers: layers, global_minzoom: global_minzoom, global_maxzoom: global_maxzoom, bounds: data_yml.bounds, center: data_yml.center, name: data_yml.name, description: data_yml.description })
+
+}
+
+/// Unknown keys which are close to one of the `known` keys are probably a typo (e.g. `mizoom`), so
+/// are an error. Other unknown keys are only logged.
+fn check_unknown_keys(context: &str, extra: &BTreeMap<String, serde_yaml::Value>, known: &[&str]) -> Result<()> {
+    for key in extra.keys() {
+        // Allow 1 typo in short keys, 2 in longer ones
+        match known.iter().find(|k| edit_distance(key, k) <= if k.len() < 5 { 1 } else { 2 }) {
+            Some(k) => { return Err(format_err!("{}: unknown key {:?}, did you mean {:?}?", context, key, k)); },
+            None => { warn!("{}: ignoring unknown key {:?}", context, key); },
+        }
+    }
+    Ok(())
+}
+
+/// How many characters need to be inserted, deleted or changed to turn `a` into `b` (the
+/// Levenshtein distance).
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..b.len()+1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i+1; b.len()+1];
+        for (j, &cb) in b.iter().enumerate() {
+            let change = if ca == cb { prev[j] } else { prev[j] + 1 };
+            row[j+1] = change.min(prev[j+1] + 1).min(row[j] + 1);
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
+
+/// This YAML value as a `Literal`, if it's a string, number or boolean.
+fn literal(value: &serde_yaml::Value) -> Option<Literal> {
+    match *value {
+        serde_yaml::Value::String(ref s) => Some(Literal::String(s.clone())),
+        serde_yaml::Value::Bool(b) => Some(Literal::Boolean(b)),
+        serde_yaml::Value::Number(ref n) => n.as_f64().map(Literal::Number),
+        _ => None,
+    }
+}
+
+/// The geometry (as WKB) & attributes of one of the `features` of a `wkt` datasource.
+fn inline_feature(config: &InlineFeatureConfig) -> Result<InlineFeature> {
+    let wkb = match (&config.wkt, &config.wkb) {
+        (&Some(ref wkt), &None) => {
+            let mut wkb = Vec::new();
+            write_wkb(&parse_wkt(wkt)?, &mut wkb);
+            wkb
+        },
+        (&None, &Some(ref hex)) => {
+            let bytes = hex_bytes(hex).ok_or_else(|| format_err!("wkb must be hex encoded"))?;
+            wkb::wkb_to_geom(&mut bytes.as_slice()).map_err(|e| format_err!("Invalid WKB: {:?}", e))?;
+            bytes
+        },
+        (&Some(_), &Some(_)) => { return Err(format_err!("has both wkt and wkb")); },
+        (&None, &None) => { return Err(format_err!("has no wkt or wkb")); },
+    };
+
+    let mut attributes = Vec::new();
+    for (name, value) in config.attrs.iter().flat_map(|attrs| attrs.iter()) {
+        match (literal(value), value) {
+            (Some(value), _) => attributes.push((name.clone(), value)),
+            (None, &serde_yaml::Value::Null) => {},
+            (None, _) => { return Err(format_err!("attribute {} must be a string, number, boolean or null", name)); },
+        }
+    }
+
+    Ok(InlineFeature{ wkb, attributes })
+}
+
+/// The bytes in this hex string (e.g. `0101000000...`), or None if it isn't one.
+fn hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim();
+    if hex.len() % 2 != 0 || ! hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()/2).map(|i| u8::from_str_radix(&hex[2*i..2*i+2], 16).ok()).collect()
+}
+
+/// Combine these sources into one table, with each one's `source_tag` (or NULL) in the
+/// `_src` column.
+fn union_sources(sources: &[SourceConfig]) -> String {
+    let selects: Vec<String> = sources.iter().map(|source| {
+        let tag = match source.source_tag {
+            None => "NULL::text".to_string(),
+            Some(ref tag) => format!("'{}'::text", tag.replace("'", "''")),
+        };
+        format!("SELECT {} AS {}, * FROM {}", tag, SOURCE_TAG_ATTRIBUTE, source.table)
+    }).collect();
+
+    format!("({}) as sources", selects.join(" UNION ALL "))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn data_yml(layers: &str) -> String {
+        format!("minzoom: 0\nmaxzoom: 14\nbounds: [-180, -85.0511, 180, 85.0511]\ncenter: [0, 0, 2]\nname: test\ndescription: test\nLayer:\n{}", layers)
+    }
+
+    #[test]
+    fn layer_name() {
+        let layers = layers_from_str(&data_yml("
+- id: bldg
+  name: building
+  Datasource:
+    type: postgis
+    table: (select way from buildings) as t
+- id: water
+  Datasource:
+    type: postgis
+    table: (select way from water) as t
+")).unwrap();
+
+        assert_eq!(layers.layers.len(), 2);
+        assert_eq!(layers.layers[0].id, "bldg");
+        assert_eq!(layers.layers[0].name, "building");
+        assert_eq!(layers.layers[1].id, "water");
+        assert_eq!(layers.layers[1].name, "water");
+    }
+
+    #[test]
+    fn srid() {
+        let layers = layers_from_str(&data_yml("
+- id: roads
+  Datasource:
+    type: postgis
+    table: (select way from roads) as t
+    srid: 27700
+- id: water
+  Datasource:
+    type: postgis
+    table: (select way from water) as t
+")).unwrap();
+
+        assert_eq!(layers.layers[0].table.srid, 27700);
+        assert_eq!(layers.layers[0].table.query, "SELECT ST_AsBinary(ST_Transform(way::geometry, 3857)), * from (select way from roads) as t where way && ST_Transform(ST_SetSRID($1::geometry, 3857), 27700)");
+
+        // Mercator needs no transform
+        assert_eq!(layers.layers[1].table.srid, 3857);
+        assert_eq!(layers.layers[1].table.query, "SELECT ST_AsBinary(way::geometry), * from (select way from water) as t where way && $1");
+
+        // Can be set for all layers at once
+        let layers = layers_from_str(&format!("srid: 27700\n{}", data_yml("
+- id: water
+  Datasource:
+    type: postgis
+    table: (select way from water) as t
+"))).unwrap();
+        assert_eq!(layers.layers[0].table.srid, 27700);
+        assert!(layers.layers[0].table.query.contains("ST_Transform(way::geometry, 3857)"));
+    }
+
+    #[test]
+    fn preserve_detail_below_length() {
+        let layers = layers_from_str(&data_yml("
+- id: railway
+  Datasource:
+    type: postgis
+    table: (select way from railways) as t
+  properties:
+    preserve-detail-below-length: 2
+- id: water
+  Datasource:
+    type: postgis
+    table: (select way from water) as t
+")).unwrap();
+
+        assert_eq!(layers.layers[0].preserve_detail_below_length, Some(2));
+        assert_eq!(layers.layers[1].preserve_detail_below_length, None);
+    }
+
+    #[test]
+    fn source_tags() {
+        let layers = layers_from_str(&data_yml("
+- id: waterways
+  Datasource:
+    type: postgis
+    sources:
+      - table: (select way, name from rivers) as t
+        source_tag: rivers
+      - table: (select way, name from canals) as t
+        source_tag: canal's
+      - table: (select way, name from streams) as t
+")).unwrap();
+
+        // Each feature has the tag of the table it came from in _src
+        assert_eq!(layers.layers[0].table.query, "SELECT ST_AsBinary(way::geometry), * from (\
+SELECT 'rivers'::text AS _src, * FROM (select way, name from rivers) as t \
+UNION ALL SELECT 'canal''s'::text AS _src, * FROM (select way, name from canals) as t \
+UNION ALL SELECT NULL::text AS _src, * FROM (select way, name from streams) as t\
+) as sources where way && $1");
+
+        let err = layers_from_str(&data_yml("
+- id: waterways
+  Datasource:
+    type: postgis
+    table: (select way from rivers) as t
+    sources:
+      - table: (select way from canals) as t
+        source_tag: canals
+")).unwrap_err().to_string();
+        assert!(err.contains("waterways") && err.contains("both a table and sources"), "{}", err);
+    }
+
+    #[test]
+    fn flatgeobuf_source() {
+        let layers = layers_from_str(&data_yml("
+- id: parks
+  source:
+    type: flatgeobuf
+    path: /data/parks.fgb
+")).unwrap();
+        assert_eq!(layers.layers[0].flatgeobuf, Some(PathBuf::from("/data/parks.fgb")));
+        assert_eq!(layers.layers[0].geopackage, None);
+
+        let err = layers_from_str(&data_yml("
+- id: parks
+  source:
+    type: shapefile
+    path: /data/parks.shp
+")).unwrap_err().to_string();
+        assert!(err.contains("parks") && err.contains("Unknown source type"), "{}", err);
+
+        let err = layers_from_str(&data_yml("
+- id: parks
+  source:
+    type: flatgeobuf
+    path: /data/parks.fgb
+  Datasource:
+    type: postgis
+    table: (select way from parks) as t
+")).unwrap_err().to_string();
+        assert!(err.contains("both a Datasource and a source"), "{}", err);
+
+        let err = layers_from_str(&data_yml("
+- id: parks
+")).unwrap_err().to_string();
+        assert!(err.contains("no Datasource or source"), "{}", err);
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let err = layers_from_str(&data_yml("
+- id: water
+  Datasource:
+    type: postgis
+    table: (select way from water) as t
+  properties:
+    mizoom: 5
+")).unwrap_err().to_string();
+        assert!(err.contains("unknown field `mizoom`"), "{}", err);
+
+        let err = layers_from_str(&format!("maxzom: 14\n{}", data_yml(""))).unwrap_err().to_string();
+        assert!(err.contains("\"maxzom\", did you mean \"maxzoom\""), "{}", err);
+
+        let err = layers_from_str(&data_yml("
+- id: water
+  datasource:
+    type: postgis
+    table: (select way from water) as t
+")).unwrap_err().to_string();
+        assert!(err.contains("Layer water") && err.contains("did you mean \"Datasource\""), "{}", err);
+
+        let err = layers_from_str(&data_yml("
+- id: water
+  Datasource:
+    type: postgis
+    tabel: (select way from water) as t
+    table: (select way from water) as t
+")).unwrap_err().to_string();
+        assert!(err.contains("Layer water Datasource") && err.contains("did you mean \"table\""), "{}", err);
+
+        // Keys which don't look like a typo are only a warning, e.g. from other tools
+        let layers = layers_from_str(&format!("format: pbf\n{}", data_yml("
+- id: water
+  interactivity: false
+  Datasource:
+    type: postgis
+    table: (select way from water) as t
+    estimate_extent: false
+"))).unwrap();
+        assert_eq!(layers.layers.len(), 1);
+
+        // A postgis layer needs a table
+        let err = layers_from_str(&data_yml("
+- id: water
+  Datasource:
+    type: postgis
+")).unwrap_err().to_string();
+        assert!(err.contains("water") && err.contains("no table"), "{}", err);
 
+        // and an id
+        assert!(layers_from_str(&data_yml("
+- Datasource:
+    type: postgis
+    table: (select way from water) as t
+")).is_err());
+    }
 }
@@ -13,34 +13,45 @@ extern crate serde_json;
 
 #[macro_use]
 extern crate log;
+extern crate env_logger;
 
 extern crate users;
 extern crate num_traits;
 extern crate rusqlite;
 extern crate md5;
+extern crate sha2;
 extern crate byteorder;
 extern crate separator;
 extern crate procinfo;
 extern crate toml;
 #[macro_use] extern crate serde_derive;
 extern crate serde;
+extern crate serde_yaml;
+extern crate flate2;
 
 #[macro_use] extern crate failure;
+#[macro_use] extern crate failure_derive;
+
+#[cfg(test)]
+#[macro_use]
+extern crate quickcheck;
 
 use std::fs::File;
 use std::fs;
 use std::io::prelude::*;
 use std::io::{BufReader, Seek, SeekFrom};
-use std::path::PathBuf;
-use std::collections::{HashSet, HashMap};
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::collections::{HashSet, HashMap, BTreeMap};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::borrow::{Cow, Borrow};
 use std::rc::Rc;
+use std::cell::{RefCell, Ref};
 use std::fmt::Write;
 
 use std::thread;
 use std::sync::mpsc::{channel, sync_channel, Sender, SyncSender};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 
 use yaml_rust::{YamlLoader, Yaml};
 
@@ -58,10 +69,11 @@ use geo::algorithm::contains::Contains;
 use separator::Separatable;
 
 mod clip;
-use clip::{clip_to_bbox,clip_geometry_to_tiles};
+use clip::{clip_to_bbox,clip_geometry_to_tiles,clip_to_mercator_bounds};
 
 mod validity;
 use validity::{is_valid, is_valid_skip_expensive};
+pub use validity::{ValidityLevel, ValidityOptions, make_valid_rings, MakeValidOutcome, make_valid_outcome, union_touching_polygons, point_in_polygon, YAxis, make_valid_in_extent, make_valid_within};
 
 macro_rules! memory {
     () => (
@@ -84,6 +96,7 @@ macro_rules! memory {
 mod printer;
 mod fileio;
 mod simplify;
+pub use simplify::{SimplifyStage, remove_unneeded_points_keep_rings};
 
 mod input;
 use input::{Layers, Layer};
@@ -93,8 +106,47 @@ use fileio::{FileIOMessage,TileDestination};
 mod stringstore;
 use stringstore::StringStore;
 
+mod retry;
+pub use retry::RetryPolicy;
+
+mod logging;
+pub use logging::{init_logging, log_level_for_verbosity};
+
+mod prune;
+use prune::EmptyTiles;
+
+mod decode;
+pub use decode::{tile_layer_names, tiles_geometrically_equal};
+pub use fileio::DestinationError;
+
+mod validate;
+pub use validate::{ExpectedLayers, LayerMismatch, validate_mbtiles_layers};
+
+mod fix;
+pub use fix::{fix_tile, fix_mbtiles};
+
+mod tile_matrix;
+pub use tile_matrix::TileMatrix;
+
+mod source;
+use source::DataSource;
+
+mod flatgeobuf;
+
+mod filter;
+
+mod wkt;
+
+mod dump;
+use dump::InvalidGeometryDump;
+
+mod rounding;
+pub use rounding::CoordinateRounding;
+
 #[cfg(test)]
 mod test;
+#[cfg(test)]
+mod test_util;
 
 type Result<T> = std::result::Result<T, failure::Error>;
 
@@ -103,12 +155,401 @@ pub enum TileDestinationType {
     TileStashDirectory(PathBuf),
     MBTiles(PathBuf),
     ModTileDirectory(PathBuf),
+    /// Generate the tiles, but throw them away, e.g. to benchmark generation without any disk I/O
+    Null,
+}
+
+/// How the rows (y) of tiles are numbered.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum TileScheme {
+    /// y = 0 is the top (north) row. Used by most tile directories & 'slippy maps'
+    Xyz,
+    /// y = 0 is the bottom (south) row. The MBTiles spec uses this.
+    Tms,
+}
+
+impl TileScheme {
+    /// The usual scheme for this destination. TMS for MBTiles (per the spec), XYZ for everything
+    /// else.
+    pub fn default_for(dest: &TileDestinationType) -> Self {
+        match *dest {
+            TileDestinationType::MBTiles(_) => TileScheme::Tms,
+            _ => TileScheme::Xyz,
+        }
+    }
+
+    /// The row number to use for a tile with this (XYZ) y, at this zoom.
+    pub fn row(&self, zoom: u8, y: u32) -> u32 {
+        match *self {
+            TileScheme::Xyz => y,
+            TileScheme::Tms => 2u32.pow(zoom as u32) - y - 1,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            TileScheme::Xyz => "xyz",
+            TileScheme::Tms => "tms",
+        }
+    }
+}
+
+impl std::str::FromStr for TileScheme {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "xyz" => Ok(TileScheme::Xyz),
+            "tms" => Ok(TileScheme::Tms),
+            x => Err(format_err!("Unknown tile scheme {:?}, must be 'xyz' or 'tms'", x)),
+        }
+    }
+}
+
+/// Less common options for `generate_all`.
+#[derive(Clone,Debug,Default)]
+pub struct GenerateOptions {
+    /// How to number the tile rows. If `None`, use `TileScheme::default_for` the destination.
+    pub scheme: Option<TileScheme>,
+
+    /// Check, and log, any interiors that have been wrongly assigned when making polygons valid.
+    /// Always done in debug builds.
+    pub check_interior_nesting: bool,
+
+    /// For directory outputs, sync the files to disk after this many tiles (or metatiles for
+    /// mod_tile). 0 means never, and leave it to the OS.
+    pub flush_every: usize,
+
+    /// How much to check (and repair) geometries. Less checking is faster, but only safe if the
+    /// source data is known to be valid.
+    pub validity_level: ValidityLevel,
+
+    /// How to retry database queries which fail for temporary reasons (e.g. deadlocks, lock timeouts).
+    pub db_retry: RetryPolicy,
+
+    /// Store a hash of each tile's (uncompressed) contents, e.g. for ETags. Only MBTiles output
+    /// supports this.
+    pub store_tile_hashes: bool,
+
+    /// Called on every feature after it has been made valid, just before it is encoded.
+    pub per_feature_hook: Option<FeatureHook>,
+
+    /// Whether to simplify geometries before (the default, no seams) or after (faster) clipping
+    /// them.
+    pub simplify_stage: SimplifyStage,
+
+    /// As a final step, move any vertices which are outside the tile & buffer (e.g. from rounding)
+    /// onto its edge, since some strict decoders reject them. The number moved is logged.
+    pub clamp_coordinates: bool,
+
+    /// Skip tiles if the tile which contains them (on the zoom before) had no features.
+    pub prune_empty_descendants: bool,
+
+    /// With `prune_empty_descendants`, count a tile as empty when all its features are only in the
+    /// buffer, outside the tile itself. By default they count as features, which is always safe.
+    pub buffer_only_counts_as_empty: bool,
+
+    /// Stop generating tiles if any feature couldn't be encoded (e.g. an attribute value which can't
+    /// be in a vector tile). Otherwise they are left out, and logged.
+    pub fail_on_encode_error: bool,
+
+    /// Keep lines whose points are all in the same place (e.g. zero length connectors in a routing
+    /// graph) as a 2 point line, rather than dropping them as invalid.
+    pub keep_zero_length_lines: bool,
+
+    /// Split MBTiles output into several files, with a new file starting at each of these zooms
+    /// (in increasing order). Empty means one file.
+    pub split_zoom_at: Vec<u8>,
+
+    /// Add a `_density` layer to every tile, with the number of features in each cell of an N×N
+    /// grid over the tile. For tuning simplification & feature limits.
+    pub debug_density: Option<u32>,
+
+    /// Compare every tile to the one in this (previously generated) MBTiles file, and only write
+    /// it if it's changed.
+    pub against: Option<PathBuf>,
+
+    /// With `against`, write the previous tile for unchanged tiles, rather than leaving them out.
+    pub copy_unchanged: bool,
+
+    /// For directory output, save tiles at paths from this template, e.g. `{z}/{x}/{y}.mvt`,
+    /// rather than the TileStash layout. See `fileio::templated_tile_path`.
+    pub path_template: Option<String>,
+
+    /// When reading several data.yml files, a layer in a later file replaces one with the same id
+    /// or name in an earlier file, rather than being an error.
+    pub allow_layer_override: bool,
+
+    /// For directory output, store tiles smaller than this (uncompressed) many bytes as they are,
+    /// since gzip would make them bigger. Readers must check if each tile is gzipped.
+    pub compress_min_bytes: Option<usize>,
+
+    /// How much to gzip tiles, from 0 (fastest) to 9 (smallest). `None` means gzip's default (6).
+    pub compression_level: Option<u32>,
+
+    /// Never query these layers (by id or name) above these zooms, whatever their `maxzoom` in the
+    /// data.yml is.
+    pub max_zoom_per_layer: Vec<(String, u8)>,
+
+    /// Where the tiles are, if they aren't the standard web mercator ones (e.g. a different
+    /// origin). `None` means web mercator.
+    pub tile_matrix: Option<TileMatrix>,
+
+    /// Stop starting new metatiles once this long has passed. The ones already started are
+    /// finished & written.
+    pub max_runtime: Option<Duration>,
+
+    /// When `max_runtime` runs out, write the metatiles which weren't generated to this file, in
+    /// the tile list format, so a later run can carry on with them.
+    pub remaining_tiles: Option<PathBuf>,
+
+    /// Stored as the `attribution` in the MBTiles/directory metadata, along with the `generator`
+    /// (tileigi & its version) & `generated_at`, which are always stored.
+    pub attribution: Option<String>,
+
+    /// Only generate these zooms (e.g. 0, 5 & 10 for an overview), rather than every zoom from the
+    /// minzoom to the maxzoom. They must be between the minzoom & maxzoom, with no duplicates.
+    pub zooms: Option<Vec<u8>>,
+
+    /// Log the SQL (with the parameters' values) of each layer's query for the first tile, or for
+    /// every tile at trace level. For finding out why features are missing.
+    pub dump_sql: bool,
+
+    /// Save every geometry which couldn't be made valid (and so was left out of the tiles) to this
+    /// GeoJSON file, with its layer & tile.
+    pub dump_invalid: Option<PathBuf>,
+
+    /// How to round source coordinates to whole tile units. Whichever is used, a coordinate is
+    /// always rounded to the same place in neighbouring tiles.
+    pub coordinate_rounding: CoordinateRounding,
+
+    /// For MBTiles output, insert the tiles of each metatile sorted by zoom, column & row, so
+    /// SQLite writes its pages more sequentially.
+    pub sorted_writes: bool,
+
+    /// When making polygons valid, simplify rings with more vertices than this first, since
+    /// adding points at all the crossings of very dense rings can take a very long time. The
+    /// result is less exact. `None` never simplifies them.
+    pub make_valid_vertex_budget: Option<usize>,
+
+    /// The highest zoom `make_valid_vertex_budget` is used on. On higher zooms, each tile has
+    /// fewer vertices, and the detail matters more. `None` is `DEFAULT_VERTEX_BUDGET_MAX_ZOOM`.
+    pub make_valid_vertex_budget_max_zoom: Option<u8>,
+}
+
+/// The highest zoom `GenerateOptions::make_valid_vertex_budget` is used on, unless it's set.
+pub const DEFAULT_VERTEX_BUDGET_MAX_ZOOM: u8 = 10;
+
+/// The settings from `GenerateOptions` for how geometries are made into tile geometries. They are
+/// passed to each step, rather than set globally, so 2 runs in one process (e.g. tests in
+/// parallel) can't change each other's settings.
+#[derive(Clone,Copy,Debug,Default)]
+struct GeometryOptions {
+    validity: ValidityOptions,
+    simplify_stage: SimplifyStage,
+    coordinate_rounding: CoordinateRounding,
+    clamp_coordinates: bool,
+    /// `validity.vertex_budget` is only used up to this zoom (see `for_zoom`)
+    vertex_budget_max_zoom: u8,
+}
+
+impl GeometryOptions {
+    /// The options for this zoom. The vertex budget is left out above its max zoom.
+    fn for_zoom(&self, zoom: u8) -> Self {
+        let mut options = *self;
+        if zoom > self.vertex_budget_max_zoom {
+            options.validity.vertex_budget = None;
+        }
+        options
+    }
+
+    fn from_options(options: &GenerateOptions) -> Self {
+        GeometryOptions{
+            validity: ValidityOptions{
+                level: options.validity_level,
+                keep_zero_length_lines: options.keep_zero_length_lines,
+                check_interior_nesting: options.check_interior_nesting,
+                vertex_budget: options.make_valid_vertex_budget,
+                // Tiles are always y down
+                y_axis: YAxis::Down,
+            },
+            simplify_stage: options.simplify_stage,
+            coordinate_rounding: options.coordinate_rounding,
+            clamp_coordinates: options.clamp_coordinates,
+            vertex_budget_max_zoom: options.make_valid_vertex_budget_max_zoom.unwrap_or(DEFAULT_VERTEX_BUDGET_MAX_ZOOM),
+        }
+    }
+}
+
+/// A function to change features before they are encoded, e.g. to offset roads. It is given the
+/// layer name, the geometry (in the tile's coordinates) and the feature's properties. If the
+/// geometry is left degenerate (e.g. no points, or a polygon with <4 points), the feature is
+/// dropped.
+#[derive(Clone)]
+pub struct FeatureHook(Arc<Fn(&str, &mut Geometry<i32>, &mut mapbox_vector_tile::Properties) + Send + Sync>);
+
+impl FeatureHook {
+    pub fn new<F>(f: F) -> Self
+        where F: Fn(&str, &mut Geometry<i32>, &mut mapbox_vector_tile::Properties) + Send + Sync + 'static
+    {
+        FeatureHook(Arc::new(f))
+    }
+
+    /// Run the hook on this feature. Returns None if the feature should be dropped.
+    fn apply(&self, layer_name: &str, mut geom: Geometry<i32>, properties: &Rc<mapbox_vector_tile::Properties>) -> Option<(Geometry<i32>, Rc<mapbox_vector_tile::Properties>)> {
+        let mut properties = (**properties).clone();
+        (self.0)(layer_name, &mut geom, &mut properties);
+
+        if geometry_bbox(&geom).is_none() || ! is_valid_skip_expensive(&geom) {
+            return None;
+        }
+
+        Some((geom, Rc::new(properties)))
+    }
+}
+
+impl std::fmt::Debug for FeatureHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "FeatureHook")
+    }
+}
+
+/// What happened when generating tiles, e.g. for monitoring. Returned by `generate_all`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GenerateStats {
+    /// Tiles sent to the destination (tiles which were skipped aren't included)
+    pub tiles_written: usize,
+    /// Total size of those tiles, as written (i.e. compressed)
+    pub bytes_written: usize,
+    /// Features in all the tiles. A feature in several tiles is counted once for each tile.
+    pub features_encoded: usize,
+    /// Features from the database which aren't in any tile because they couldn't be read, made
+    /// valid, or encoded. (Features outside the tile, or left out because of `max-features`,
+    /// aren't included)
+    pub features_dropped: usize,
+    /// How many features couldn't be encoded (see `EncodeError`)
+    pub encode_errors: usize,
+    /// With `clamp_coordinates`, how many vertices were clamped
+    pub clamped_vertices: usize,
+    /// With `against`, how many tiles were different from the previous tiles (incl. new tiles)
+    pub tiles_changed: usize,
+    /// With `against`, how many tiles were the same as the previous tiles
+    pub tiles_unchanged: usize,
+    /// With `max_runtime`, how many metatiles weren't generated because time ran out
+    pub metatiles_remaining: usize,
+    /// Metatiles which weren't generated because reading a layer's features failed (after any
+    /// retries). None of their tiles are written.
+    pub metatiles_failed: usize,
+    pub elapsed: Duration,
+}
+
+impl GenerateStats {
+    /// Add the counts from `other` to this. `elapsed` isn't changed.
+    pub fn add(&mut self, other: &GenerateStats) {
+        self.tiles_written += other.tiles_written;
+        self.bytes_written += other.bytes_written;
+        self.features_encoded += other.features_encoded;
+        self.features_dropped += other.features_dropped;
+        self.encode_errors += other.encode_errors;
+        self.clamped_vertices += other.clamped_vertices;
+        self.tiles_changed += other.tiles_changed;
+        self.tiles_unchanged += other.tiles_unchanged;
+        self.metatiles_remaining += other.metatiles_remaining;
+        self.metatiles_failed += other.metatiles_failed;
+    }
+
+    /// Count these (encoded) tiles as written.
+    fn add_written_tiles(&mut self, tiles: &[(slippy_map_tiles::Tile, Vec<u8>)]) {
+        self.tiles_written += tiles.len();
+        self.bytes_written += tiles.iter().map(|&(_, ref bytes)| bytes.len()).sum::<usize>();
+    }
+}
+
+impl std::fmt::Display for GenerateStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Wrote {} tiles ({} bytes) with {} features, {} features dropped, in {}",
+               self.tiles_written.separated_string(), self.bytes_written.separated_string(),
+               self.features_encoded.separated_string(), self.features_dropped.separated_string(),
+               fmt_duration(&self.elapsed))?;
+        if self.tiles_changed > 0 || self.tiles_unchanged > 0 {
+            write!(f, ". {} tiles changed, {} unchanged", self.tiles_changed.separated_string(), self.tiles_unchanged.separated_string())?;
+        }
+        if self.metatiles_remaining > 0 {
+            write!(f, ". Ran out of time with {} metatiles left", self.metatiles_remaining.separated_string())?;
+        }
+        if self.metatiles_failed > 0 {
+            write!(f, ". {} metatiles failed", self.metatiles_failed.separated_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// A feature which couldn't be put in a vector tile, e.g. because of an attribute value which
+/// can't be represented. The feature is left out of the tile.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodeError {
+    /// Id of the layer
+    pub layer: String,
+    /// Which row (from 0) of the layer's query results this is
+    pub feature_index: usize,
+    pub reason: String,
+}
+
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Layer {} feature {} couldn't be encoded: {}", self.layer, self.feature_index, self.reason)
+    }
+}
+
+/// Hash of these (uncompressed) tile bytes, as a lowercase hex SHA-256. The same bytes always
+/// give the same hash.
+///
+/// ```
+/// # use tileigi::tile_content_hash;
+/// assert_eq!(tile_content_hash(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+/// ```
+pub fn tile_content_hash(bytes: &[u8]) -> String {
+    use sha2::{Sha256, Digest};
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// The bytes of this tile, as they are saved. Gzipped (at `compression_level`, or the default
+/// level), unless `compress_min_bytes` is given, and the tile is smaller than that uncompressed
+/// (see `GenerateOptions::compress_min_bytes`).
+pub fn tile_bytes(mvt: &mapbox_vector_tile::Tile, compress_min_bytes: Option<usize>, compression_level: Option<u32>) -> Vec<u8> {
+    use std::io::Write as IoWrite;
+    use flate2::write::GzEncoder;
+
+    if compress_min_bytes.is_none() && compression_level.is_none() {
+        return mvt.to_compressed_bytes();
+    }
+    let bytes = mvt.to_bytes();
+    if let Some(min_bytes) = compress_min_bytes {
+        if bytes.len() < min_bytes {
+            return bytes;
+        }
+    }
+
+    let compression = compression_level.map(flate2::Compression::new).unwrap_or_default();
+    let mut encoder = GzEncoder::new(Vec::with_capacity(bytes.len()), compression);
+    encoder.write_all(&bytes).unwrap();
+    encoder.finish().unwrap()
 }
 
 pub struct ConnectionPool {
-    connections: HashMap<ConnectParams, Connection>,
+    connections: HashMap<ConnectParams, RefCell<Connection>>,
     layer_to_param: HashMap<String, ConnectParams>,
-
+    retry_policy: RetryPolicy,
+    /// The layers (by id) which are read from GeoPackage files
+    geopackages: HashMap<String, source::GeoPackage>,
+    /// The layers (by id) which are read from FlatGeobuf files
+    flatgeobufs: HashMap<String, source::FlatGeobuf>,
+    /// Log the SQL of each layer's query
+    dump_sql: bool,
+    /// The layers (by id) whose SQL has been logged (at info level) already
+    dumped_sql: RefCell<HashSet<String>>,
+    /// Save the geometries which couldn't be made valid here
+    invalid_dump: Option<Arc<InvalidGeometryDump>>,
 }
 
 impl ConnectionPool {
@@ -123,16 +564,104 @@ impl ConnectionPool {
         let mut connections = HashMap::with_capacity(params_to_layers.len());
         for (cp, layers) in params_to_layers.into_iter() {
             let connection = Connection::connect(cp.clone(), TlsMode::None).unwrap();
-            connections.insert(cp, connection);
+            connections.insert(cp, RefCell::new(connection));
+        }
+
+        ConnectionPool{ connections: connections, layer_to_param: layer_to_param, retry_policy: RetryPolicy::none(), geopackages: HashMap::new(), flatgeobufs: HashMap::new(), dump_sql: false, dumped_sql: RefCell::new(HashSet::new()), invalid_dump: None }
+    }
+
+    /// Open the GeoPackage or FlatGeobuf file of each of these layers which uses one.
+    pub fn open_files(&mut self, layers: &Layers) -> Result<()> {
+        for layer in layers.layers.iter() {
+            if let Some(ref geopackage) = layer.geopackage {
+                let source = source::GeoPackage::open(&geopackage.path, &geopackage.table).map_err(|e| format_err!("Layer {}: {}", layer.id, e))?;
+                self.geopackages.insert(layer.id.clone(), source);
+            }
+            if let Some(ref path) = layer.flatgeobuf {
+                let source = source::FlatGeobuf::open(path).map_err(|e| format_err!("Layer {}: {}", layer.id, e))?;
+                self.flatgeobufs.insert(layer.id.clone(), source);
+            }
+        }
+        Ok(())
+    }
+
+    /// Where this layer's features come from, the database, a GeoPackage or FlatGeobuf file, or
+    /// the data.yml itself.
+    fn source_for_layer(&self, layer: &Layer) -> &DataSource {
+        if layer.inline_features.is_some() {
+            return &source::InlineFeatures;
         }
+        if let Some(flatgeobuf) = self.flatgeobufs.get(&layer.id) {
+            return flatgeobuf;
+        }
+        match self.geopackages.get(&layer.id) {
+            Some(geopackage) => geopackage as &DataSource,
+            None => self,
+        }
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Log the SQL (with the parameters' values) of each layer's query for the first tile, or for
+    /// every tile at trace level.
+    pub fn set_dump_sql(&mut self, dump_sql: bool) {
+        self.dump_sql = dump_sql;
+    }
+
+    /// Save the geometries which couldn't be made valid (and so were left out) to this.
+    pub fn set_invalid_dump(&mut self, invalid_dump: Option<Arc<InvalidGeometryDump>>) {
+        self.invalid_dump = invalid_dump;
+    }
+
+    fn invalid_dump(&self) -> Option<&InvalidGeometryDump> {
+        self.invalid_dump.as_ref().map(|d| d.as_ref())
+    }
 
-        ConnectionPool{ connections: connections, layer_to_param: layer_to_param }
+    /// Log this layer's query, if `dump_sql` is on.
+    fn log_sql(&self, layer: &Layer, query: &source::FeatureQuery) {
+        if ! self.dump_sql {
+            return;
+        }
+        let first = self.dumped_sql.borrow_mut().insert(layer.id.clone());
+        if first || log_enabled!(log::Level::Trace) {
+            let sql = layer.table.query_with_params(&query.bbox, query.pixel_width, query.pixel_height, query.zoom as i32, query.scale_denominator);
+            if first {
+                info!("Layer {} SQL for zoom {}: {}", layer.id, query.zoom, sql);
+            } else {
+                trace!("Layer {} SQL for zoom {}: {}", layer.id, query.zoom, sql);
+            }
+        }
     }
 
-    fn connection_for_layer<'a>(&'a self, layer_id: &str) -> &'a Connection {
+    fn connection_for_layer<'a>(&'a self, layer_id: &str) -> Ref<'a, Connection> {
         let cp = &self.layer_to_param[layer_id];
         
-        &self.connections[cp]
+        self.connections[cp].borrow()
+    }
+
+    /// Replace the connection for this layer with a new one. e.g. after the old one was reset.
+    fn reconnect_layer(&self, layer_id: &str) {
+        let cp = &self.layer_to_param[layer_id];
+        match Connection::connect(cp.clone(), TlsMode::None) {
+            Ok(connection) => { *self.connections[cp].borrow_mut() = connection; },
+            Err(e) => { warn!("Could not reconnect to the database for layer {}: {}", layer_id, e); },
+        }
+    }
+
+    /// Run the query for this layer, retrying (and reconnecting if needed) if it fails for
+    /// temporary reasons.
+    fn query_for_layer(&self, layer_id: &str, query: &str, params: &[&ToSql]) -> std::result::Result<postgres::rows::Rows, postgres::Error> {
+        self.retry_policy.run(
+            &format!("Query for layer {}", layer_id),
+            retry::is_transient_db_error,
+            |e| if e.as_io().is_some() { self.reconnect_layer(layer_id) },
+            || {
+                let conn = self.connection_for_layer(layer_id);
+                let stmt = conn.prepare_cached(query)?;
+                stmt.query(params)
+            })
     }
 
 }
@@ -141,6 +670,27 @@ fn fmt_duration(dur: &std::time::Duration) -> String {
     format!("{:.2}s", duration_to_float_secs(dur))
 }
 
+/// Parse a duration like `90s`, `30m` or `2h`. A number on its own is seconds.
+///
+/// ```
+/// # use tileigi::parse_duration;
+/// # use std::time::Duration;
+/// assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(30*60));
+/// assert_eq!(parse_duration("45").unwrap(), Duration::from_secs(45));
+/// assert!(parse_duration("soon").is_err());
+/// ```
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let (number, multiplier) = match s.chars().last() {
+        Some('s') => (&s[..s.len()-1], 1),
+        Some('m') => (&s[..s.len()-1], 60),
+        Some('h') => (&s[..s.len()-1], 60*60),
+        _ => (s, 1),
+    };
+    let number: u64 = number.parse().map_err(|_| format_err!("Invalid duration {:?}, it should be like 90s, 30m or 2h", s))?;
+    Ok(Duration::from_secs(number * multiplier))
+}
+
 #[inline]
 fn duration_to_float_secs(dur: &std::time::Duration) -> f64 {
     (dur.as_secs() as f64) + (dur.subsec_nanos() as f64 / 1e9)
@@ -167,25 +717,156 @@ fn scale_denominator_for_zoom(zoom: u8) -> f32 {
         16 => 12500.,
         17 => 5000.,
         18 => 2500.,
-        _ => {
-            eprintln!("Unsupported zoom ({})", zoom);
-            unimplemented!();
-        }
+        // Each zoom after that is half the previous one
+        z => 2500. / 2f32.powi(z as i32 - 18),
     }
 }
 
-pub fn generate_all(filename: &str, min_zoom: u8, max_zoom: u8, bbox: &Option<BBox>, dest: &TileDestinationType, if_not_exists: bool, compress: bool, metatile_scale: u8, num_threads: usize, tile_list: Option<String>, file_writer_buffer: usize, quiet: bool) -> Result<()> {
-    let layers = Layers::from_file(filename)?;
+/// The highest zoom we can generate. Tiles past this are less than 10 cm wide, and coordinates
+/// start to lose precision.
+pub const MAX_ZOOM: u8 = 22;
+
+/// Half the width of the world in EPSG:3857 (spherical/web mercator) metres.
+const MERCATOR_HALF_WIDTH: f64 = 20037508.34;
+
+/// Radius of the sphere used by web mercator, in metres.
+const EARTH_RADIUS: f64 = 6378137.;
+
+/// The highest latitude web mercator can show.
+const MAX_LATITUDE: f64 = 85.0511287798;
+
+/// The bounding box of a tile in EPSG:3857 metres, as `(minx, miny, maxx, maxy)`.
+///
+/// Tiles are numbered the usual (XYZ/slippy map) way, y = 0 is the top (north) row.
+///
+/// ```
+/// # use tileigi::tile_bbox_mercator;
+/// // At zoom 0, the one tile covers the whole world
+/// let (minx, miny, maxx, maxy) = tile_bbox_mercator(0, 0, 0);
+/// assert!((minx - -20037508.34).abs() < 1e-6);
+/// assert!((miny - -20037508.34).abs() < 1e-6);
+/// assert!((maxx - 20037508.34).abs() < 1e-6);
+/// assert!((maxy - 20037508.34).abs() < 1e-6);
+///
+/// // Paris (2.3522, 48.8566) is ~(261848, 6250566) in EPSG:3857
+/// let (minx, miny, maxx, maxy) = tile_bbox_mercator(12, 2074, 1409);
+/// assert!(minx < 261848. && 261848. < maxx);
+/// assert!(miny < 6250566. && 6250566. < maxy);
+/// ```
+pub fn tile_bbox_mercator(z: u8, x: u32, y: u32) -> (f64, f64, f64, f64) {
+    TileMatrix::web_mercator().tile_bbox(z, x, y)
+}
+
+/// The `(x, y)` of the tile at zoom `z` which contains this longitude & latitude (in degrees).
+///
+/// Latitudes outside the range web mercator can show (about ±85.0511°) are clamped to the top
+/// or bottom row, and longitude 180 is in the last column.
+///
+/// ```
+/// # use tileigi::lonlat_to_tile;
+/// // At zoom 0 everything is in the one tile
+/// assert_eq!(lonlat_to_tile(0., 0., 0), (0, 0));
+/// assert_eq!(lonlat_to_tile(-179.9, 85., 0), (0, 0));
+///
+/// // Paris
+/// assert_eq!(lonlat_to_tile(2.3522, 48.8566, 12), (2074, 1409));
+/// ```
+pub fn lonlat_to_tile(lon: f64, lat: f64, z: u8) -> (u32, u32) {
+    TileMatrix::web_mercator().tile_for_lonlat(z, lon, lat)
+}
+
+pub fn generate_all(filenames: &[&str], min_zoom: u8, max_zoom: u8, bbox: &Option<BBox>, dest: &TileDestinationType, if_not_exists: bool, compress: bool, metatile_scale: u8, num_threads: usize, tile_list: Option<String>, file_writer_buffer: usize, quiet: bool, options: &GenerateOptions) -> Result<GenerateStats> {
+    let started = Instant::now();
+    if max_zoom > MAX_ZOOM {
+        return Err(format_err!("maxzoom {} is too high, the maximum is {}", max_zoom, MAX_ZOOM));
+    }
+    if min_zoom > max_zoom {
+        return Err(format_err!("minzoom {} is greater than maxzoom {}", min_zoom, max_zoom));
+    }
+    let zooms: Vec<u8> = match options.zooms {
+        None => (min_zoom..max_zoom+1).collect(),
+        Some(ref zooms) => {
+            if zooms.is_empty() {
+                return Err(format_err!("No zooms to generate"));
+            }
+            if let Some(z) = zooms.iter().find(|&&z| z < min_zoom || z > max_zoom) {
+                return Err(format_err!("Can't generate zoom {}, it must be between {} and {}", z, min_zoom, max_zoom));
+            }
+            let mut sorted = zooms.clone();
+            sorted.sort();
+            if let Some(w) = sorted.windows(2).find(|w| w[0] == w[1]) {
+                return Err(format_err!("Zoom {} is in the list of zooms more than once", w[0]));
+            }
+            sorted
+        },
+    };
+    if ! options.split_zoom_at.windows(2).all(|w| w[0] < w[1]) {
+        return Err(format_err!("Zooms to split at must be in increasing order: {:?}", options.split_zoom_at));
+    }
+    if let Some(z) = options.split_zoom_at.iter().find(|&&z| z <= min_zoom || z > max_zoom) {
+        return Err(format_err!("Can't split at zoom {}, it must be between {} and {}", z, min_zoom+1, max_zoom));
+    }
+    if let Some(ref path_template) = options.path_template {
+        fileio::check_path_template(path_template)?;
+        if let TileDestinationType::TileStashDirectory(_) = *dest {} else {
+            warn!("Only directory output can use a path template, ignoring it");
+        }
+    }
+    // Mixing gzipped & uncompressed tiles isn't allowed in MBTiles
+    let compress_min_bytes = match (options.compress_min_bytes, dest) {
+        (Some(b), &TileDestinationType::TileStashDirectory(_)) => Some(b),
+        (Some(_), _) => {
+            warn!("Only directory output can leave small tiles uncompressed, compressing all tiles");
+            None
+        },
+        (None, _) => None,
+    };
+    if let Some(level) = options.compression_level {
+        if level > 9 {
+            return Err(format_err!("Compression level must be from 0 to 9, not {}", level));
+        }
+    }
+    let compression_level = options.compression_level;
+    if options.debug_density == Some(0) {
+        return Err(format_err!("The density grid must have at least 1 cell"));
+    }
+    let tile_matrix = options.tile_matrix.clone().unwrap_or_default();
+    tile_matrix.check(max_zoom)?;
+    let deadline = options.max_runtime.map(|max_runtime| started + max_runtime);
+    if options.remaining_tiles.is_some() && deadline.is_none() {
+        warn!("The remaining tiles are only written with a maximum runtime, ignoring it");
+    }
+    // Rather than a worker panicing later on
+    match *dest {
+        TileDestinationType::TileStashDirectory(ref path) | TileDestinationType::ModTileDirectory(ref path) => fileio::check_dir_writable(path)?,
+        TileDestinationType::MBTiles(ref path) => fileio::check_mbtiles_writable(path)?,
+        TileDestinationType::Null => {},
+    }
+    if let Some(ref against) = options.against {
+        // Check it can be read now, rather than in every worker
+        fileio::MBTilesReader::open(against)?;
+    }
+
+    let mut layers = Layers::from_files(filenames, options.allow_layer_override)?;
+    layers.cap_layer_zooms(&options.max_zoom_per_layer)?;
     if layers.layers.len() == 0 {
         warn!("Zero layers defined. Possible configuration error?");
-        return Ok(());
+        return Ok(GenerateStats::default());
     }
 
-    let connection_pool = ConnectionPool::new(layers.get_all_connections());
+    let mut connection_pool = ConnectionPool::new(layers.get_all_connections());
+    connection_pool.open_files(&layers)?;
 
+    type BoxedMetatiles = Box<Iterator<Item=Metatile>+Send>;
     let (metatile_iterator, total_num_of_metatiles) = match tile_list {
+        None if options.tile_matrix.is_some() && bbox.is_some() => {
+            // The bbox covers different tiles than usual
+            let metatiles: Vec<Metatile> = zooms.iter().flat_map(|&z| tile_matrix.metatiles_for_bbox(metatile_scale, bbox.as_ref().unwrap(), z, z)).collect();
+            let total_num_of_metatiles = Some(metatiles.len());
+            (Box::new(metatiles.into_iter()) as BoxedMetatiles, total_num_of_metatiles)
+        },
         None => {
-            let total_num_of_metatiles: Option<usize> = (min_zoom..max_zoom+1).map(|z| {
+            let total_num_of_metatiles: Option<usize> = zooms.iter().map(|&z| {
                 match *bbox {
                     None => {
                         let scale = (metatile_scale.trailing_zeros()) as u32;
@@ -208,17 +889,29 @@ pub fn generate_all(filename: &str, min_zoom: u8, max_zoom: u8, bbox: &Option<BB
                     }
                 });
 
-            let metatile_iterator = MetatilesIterator::new_for_bbox_zoom(metatile_scale, &bbox, min_zoom, max_zoom);
+            let metatile_iterator = if options.zooms.is_none() {
+                Box::new(MetatilesIterator::new_for_bbox_zoom(metatile_scale, &bbox, min_zoom, max_zoom)) as BoxedMetatiles
+            } else {
+                // One zoom after the other
+                let iterators: Vec<MetatilesIterator> = zooms.iter().map(|&z| MetatilesIterator::new_for_bbox_zoom(metatile_scale, &bbox, z, z)).collect();
+                Box::new(iterators.into_iter().flat_map(|i| i)) as BoxedMetatiles
+            };
 
             (metatile_iterator, total_num_of_metatiles)
         },
         Some(tile_list) => {
+            if options.zooms.is_some() {
+                warn!("Generating every metatile in the tile list, ignoring the list of zooms");
+            }
             let mt_file_list = MetatilesIterator::new_from_filelist(tile_list);
             let total_num_of_metatiles = mt_file_list.total();
-            (mt_file_list, total_num_of_metatiles)
+            (Box::new(mt_file_list) as BoxedMetatiles, total_num_of_metatiles)
         }
     };
 
+    // The workers take the next metatile from this whenever they finish one, rather than each
+    // having a fixed share, so a thread which gets slow (dense) metatiles does fewer of them,
+    // and none are idle while there are metatiles left.
     let metatile_iterator = Arc::new(Mutex::new(metatile_iterator));
 
 
@@ -232,63 +925,181 @@ pub fn generate_all(filename: &str, min_zoom: u8, max_zoom: u8, bbox: &Option<BB
 
     let (fileio_tx, fileio_rx) = sync_channel(file_writer_buffer);
 
+    let scheme = options.scheme.unwrap_or_else(|| TileScheme::default_for(dest));
+    let generator_metadata = generator_metadata(options.attribution.as_ref().map(|a| a.as_str()), SystemTime::now());
+
     let mut fileio_thread = match dest {
         &TileDestinationType::TileStashDirectory(ref path) => {
-            let tile_dest = fileio::TileStashDirectory::new(&path);
-            write_tilejson(&layers, &connection_pool, &path)?;
+            let mut tile_dest = fileio::TileStashDirectory::new(&path);
+            tile_dest.set_scheme(scheme);
+            tile_dest.set_path_template(options.path_template.clone());
+            tile_dest.set_flush_every(options.flush_every);
+            write_tilejson(&path, &layers, tilejson_vector_layers(&layers, &connection_pool)?, min_zoom, max_zoom, mbtiles_bounds(bbox, &layers), scheme, &generator_metadata)?;
+            thread::spawn(move || { fileio::fileio_thread(fileio_rx, Box::new(tile_dest)) })
+        },
+        &TileDestinationType::MBTiles(ref path) if ! options.split_zoom_at.is_empty() => {
+            let mut tile_dest = fileio::SplitMBTiles::new_split(&path, min_zoom, max_zoom, &options.split_zoom_at);
+            tile_dest.set_scheme(scheme);
+            tile_dest.set_tilejson_vector_layers(tilejson_vector_layers(&layers, &connection_pool)?);
+            tile_dest.set_store_tile_hashes(options.store_tile_hashes);
+            tile_dest.set_sorted_writes(options.sorted_writes);
+            if let Some(bounds) = mbtiles_bounds(bbox, &layers) {
+                tile_dest.set_bounds(bounds);
+            }
+            for &(name, ref value) in generator_metadata.iter() {
+                tile_dest.set_metadata(name, value);
+            }
             thread::spawn(move || { fileio::fileio_thread(fileio_rx, Box::new(tile_dest)) })
         },
         &TileDestinationType::MBTiles(ref path) => {
             let mut tile_dest = fileio::MBTiles::new(&path);
+            tile_dest.set_scheme(scheme);
             tile_dest.set_tilejson_vector_layers(tilejson_vector_layers(&layers, &connection_pool)?);
+            tile_dest.set_store_tile_hashes(options.store_tile_hashes);
+            tile_dest.set_sorted_writes(options.sorted_writes);
+            tile_dest.set_zoom_range(min_zoom, max_zoom);
+            if let Some(bounds) = mbtiles_bounds(bbox, &layers) {
+                tile_dest.set_bounds(bounds);
+            }
+            for &(name, ref value) in generator_metadata.iter() {
+                tile_dest.set_metadata(name, value);
+            }
             thread::spawn(move || { fileio::fileio_thread(fileio_rx, Box::new(tile_dest)) })
         },
         &TileDestinationType::ModTileDirectory(ref path) => {
-            write_tilejson(&layers, &connection_pool, &path)?;
-            let tile_dest = fileio::ModTileMetatileDirectory::new(&path);
+            if scheme != TileScheme::Xyz {
+                warn!("mod_tile metatiles are always in the XYZ scheme, ignoring the {} scheme", scheme.as_str());
+            }
+            write_tilejson(&path, &layers, tilejson_vector_layers(&layers, &connection_pool)?, min_zoom, max_zoom, mbtiles_bounds(bbox, &layers), TileScheme::Xyz, &generator_metadata)?;
+            let mut tile_dest = fileio::ModTileMetatileDirectory::new(&path);
+            tile_dest.set_flush_every(options.flush_every);
             thread::spawn(move || { fileio::fileio_thread(fileio_rx, Box::new(tile_dest)) })
         },
+        &TileDestinationType::Null => {
+            let tile_dest = fileio::NullDestination::new(&PathBuf::new());
+            thread::spawn(move || { fileio::fileio_thread(fileio_rx, Box::new(tile_dest)) })
+        },
+    };
+
+
+
+    if ! options.split_zoom_at.is_empty() {
+        if let TileDestinationType::MBTiles(_) = *dest {} else {
+            warn!("Only MBTiles output can be split by zoom, writing everything to one destination");
+        }
+    }
+
+    let store_tile_hashes = match *dest {
+        TileDestinationType::MBTiles(_) => options.store_tile_hashes,
+        _ => {
+            if options.store_tile_hashes {
+                warn!("Tile hashes can only be stored in MBTiles, not storing them");
+            }
+            false
+        },
+    };
+
+    let copy_unchanged = match *dest {
+        TileDestinationType::ModTileDirectory(_) if options.against.is_some() && ! options.copy_unchanged => {
+            warn!("mod_tile metatiles are written whole, so unchanged tiles are copied from the previous tiles");
+            true
+        },
+        _ => options.copy_unchanged,
+    };
+
+    let empty_tiles = if options.prune_empty_descendants {
+        Some(Arc::new(EmptyTiles::new(&layers, max_zoom)))
+    } else {
+        None
+    };
+
+    let encode_errors = Arc::new(AtomicUsize::new(0));
+
+    // Metatiles at the edge of the bbox can have tiles outside it, which aren't written. mod_tile
+    // metatiles are written whole though, so a tile from earlier isn't blanked.
+    let tiles_bbox = match *dest {
+        TileDestinationType::ModTileDirectory(_) => None,
+        _ => bbox.clone(),
     };
 
+    let invalid_dump = match options.dump_invalid {
+        None => None,
+        Some(ref path) => Some(Arc::new(InvalidGeometryDump::create(path)?)),
+    };
 
+    let context = WorkerContext{
+        layers: layers.clone(),
+        store_tile_hashes: store_tile_hashes,
+        feature_hook: options.per_feature_hook.clone(),
+        empty_tiles: empty_tiles,
+        buffer_only_counts_as_empty: options.buffer_only_counts_as_empty,
+        encode_errors: Arc::clone(&encode_errors),
+        fail_on_encode_error: options.fail_on_encode_error,
+        debug_density: options.debug_density,
+        previous_tiles: options.against.clone(),
+        copy_unchanged: copy_unchanged,
+        compress_min_bytes: compress_min_bytes,
+        compression_level: compression_level,
+        tile_matrix: tile_matrix.clone(),
+        geometry: GeometryOptions::from_options(options),
+        deadline: deadline,
+        tiles_bbox: tiles_bbox,
+    };
 
     let mut workers = Vec::with_capacity(num_threads);
     for _ in 0..num_threads {
-        // TODO do I need all these clones?
-        let my_connection_pool = ConnectionPool::new(layers.get_all_connections());
+        let mut my_connection_pool = ConnectionPool::new(layers.get_all_connections());
+        my_connection_pool.set_retry_policy(options.db_retry);
+        my_connection_pool.set_dump_sql(options.dump_sql);
+        my_connection_pool.set_invalid_dump(invalid_dump.clone());
+        my_connection_pool.open_files(&layers)?;
         let my_printer_tx = printer_tx.clone();
         let my_fileio_tx = fileio_tx.clone();
         let my_metatile_iterator = Arc::clone(&metatile_iterator);
-        let my_layers = layers.clone();
+        let my_context = context.clone();
         let my_dest = dest.clone();
+        let my_path_template = options.path_template.clone();
 
-        let should_do_metatile = move |mt: &slippy_map_tiles::Metatile| {
-            if if_not_exists {
-                match my_dest {
-                    TileDestinationType::TileStashDirectory(ref path) => {
-                        !fileio::TileStashDirectory::does_metatile_exist(&path, &mt)
-                    },
-                    TileDestinationType::ModTileDirectory(ref path) => {
-                        !fileio::ModTileMetatileDirectory::does_metatile_exist(&path, &mt)
+        let split_mbtiles = ! options.split_zoom_at.is_empty();
+
+        let handle = thread::spawn(move || {
+            // The MBTiles files (as they are when the worker starts) are opened once
+            let existing_mbtiles = match my_dest {
+                TileDestinationType::MBTiles(ref path) if if_not_exists && split_mbtiles => Some(fileio::ExistingMBTiles::open_split(path)),
+                TileDestinationType::MBTiles(ref path) if if_not_exists => Some(fileio::ExistingMBTiles::open(path)),
+                _ => None,
+            };
+            let should_do_metatile = |mt: &slippy_map_tiles::Metatile| {
+                if if_not_exists {
+                    match my_dest {
+                        TileDestinationType::TileStashDirectory(ref path) => {
+                            !fileio::TileStashDirectory::does_metatile_exist_with_template(&path, scheme, my_path_template.as_ref().map(|t| t.as_str()), &mt)
+                        },
+                        TileDestinationType::ModTileDirectory(ref path) => {
+                            !fileio::ModTileMetatileDirectory::does_metatile_exist(&path, TileScheme::Xyz, &mt)
+                        }
+                        TileDestinationType::MBTiles(_) => {
+                            !existing_mbtiles.as_ref().map_or(false, |existing| existing.does_metatile_exist(&mt))
+                        },
+                        TileDestinationType::Null => true,
                     }
-                    TileDestinationType::MBTiles(ref path) => {
-                        unimplemented!();
-                    },
+                } else {
+                    true
                 }
-            } else {
-                true
-            }
-        };
+            };
 
-        let handle = thread::spawn(move || {
-            worker_all_layers(my_printer_tx, my_fileio_tx, my_metatile_iterator, &my_connection_pool, &my_layers, should_do_metatile);
+            worker_all_layers(my_printer_tx, my_fileio_tx, my_metatile_iterator, &my_connection_pool, should_do_metatile, &my_context)
         });
         workers.push(handle);
     }
 
+    let mut stats = GenerateStats::default();
+    let mut panicked_workers = 0;
     for worker in workers {
-        // If one of our worker threads has panic'ed, then this main programme should fail too
-        worker.join().ok();
+        match worker.join() {
+            Ok(worker_stats) => { stats.add(&worker_stats); },
+            Err(_) => { panicked_workers += 1; },
+        }
     }
 
     printer_tx.send(printer::PrinterMessage::Quit).unwrap();
@@ -301,40 +1112,286 @@ pub fn generate_all(filename: &str, min_zoom: u8, max_zoom: u8, bbox: &Option<BB
 
     fileio_thread.join().unwrap();
 
-    memory!("Finished");
-    if ! quiet {
-        println!("Finished.");
+    if out_of_time(deadline) {
+        // Whatever the workers didn't get to
+        let mut metatile_iterator = metatile_iterator.lock().unwrap();
+        stats.metatiles_remaining = match options.remaining_tiles {
+            Some(ref path) => {
+                let num = write_tile_list(path, &mut **metatile_iterator)?;
+                warn!("Ran out of time, {} metatiles weren't generated, they are listed in {}", num.separated_string(), path.display());
+                num
+            },
+            None => {
+                let num = metatile_iterator.by_ref().count();
+                warn!("Ran out of time, {} metatiles weren't generated", num.separated_string());
+                num
+            },
+        };
     }
 
-    Ok(())
-}
+    if stats.clamped_vertices > 0 {
+        warn!("{} vertices were outside the tile (and buffer), and were clamped", stats.clamped_vertices.separated_string());
+    }
 
-fn worker_all_layers<F>(printer_tx: Sender<printer::PrinterMessage>, fileio_tx: SyncSender<FileIOMessage>, mut metatile_iterator: Arc<Mutex<Iterator<Item=Metatile>>>, connection_pool: &ConnectionPool, layers: &Layers, should_do_metatile: F)
-    where F: Fn(&slippy_map_tiles::Metatile) -> bool,
-{
-    loop {
-        let metatile = metatile_iterator.lock().unwrap().next();
-        if let None = metatile {
-            // The iterator is finished.
-            break;
+    if let (Some(dump), Some(path)) = (invalid_dump, options.dump_invalid.as_ref()) {
+        let num_invalid = dump.finish()?;
+        if num_invalid > 0 {
+            warn!("{} geometries couldn't be made valid, they are in {}", num_invalid.separated_string(), path.display());
         }
-        let metatile = metatile.unwrap();
+    }
 
-        if ! should_do_metatile(&metatile) {
-            continue;
-        }
+    // A bug, so the tiles of the metatiles it was doing could be missing
+    if panicked_workers > 0 {
+        return Err(format_err!("{} worker thread(s) panicked, some tiles may be missing", panicked_workers));
+    }
 
-        let tiles = single_metatile(&layers, &metatile, &connection_pool);
-        let num_tiles = tiles.len();
+    if stats.metatiles_failed > 0 {
+        warn!("{} metatiles failed, and their tiles weren't written", stats.metatiles_failed.separated_string());
+    }
 
-        let tiles: Vec<_> = tiles.into_iter().map(|(tile, mvt)| (tile, mvt.to_compressed_bytes())).collect();
+    if stats.encode_errors > 0 {
+        if options.fail_on_encode_error {
+            return Err(format_err!("{} features couldn't be encoded, stopped generating tiles", stats.encode_errors.separated_string()));
+        }
+        warn!("{} features couldn't be encoded, and were left out", stats.encode_errors.separated_string());
+    }
+
+    memory!("Finished");
+    if ! quiet {
+        println!("Finished.");
+    }
+
+    stats.elapsed = started.elapsed();
+    Ok(stats)
+}
+
+/// Has the time to generate tiles run out?
+fn out_of_time(deadline: Option<Instant>) -> bool {
+    deadline.map_or(false, |deadline| Instant::now() >= deadline)
+}
+
+/// Write these metatiles to `path`, in the tile list format (one `SCALE Z/X/Y` per line). Returns
+/// how many there were.
+fn write_tile_list(path: &Path, metatiles: &mut Iterator<Item=Metatile>) -> Result<usize> {
+    use std::io::Write as IoWrite;
+
+    let mut file = std::io::BufWriter::new(File::create(path)?);
+    let mut num = 0;
+    for metatile in metatiles {
+        writeln!(file, "{} {}/{}/{}", metatile.size(), metatile.zoom(), metatile.x(), metatile.y())?;
+        num += 1;
+    }
+    file.flush()?;
+
+    Ok(num)
+}
+
+/// The metadata about how the tiles were made: the `generator` (tileigi & its version), when they
+/// were `generated_at`, and the `attribution`, if there is one.
+fn generator_metadata(attribution: Option<&str>, now: SystemTime) -> Vec<(&'static str, String)> {
+    let mut metadata = vec![
+        ("generator", format!("tileigi {}", env!("CARGO_PKG_VERSION"))),
+        ("generated_at", iso8601_utc(now)),
+    ];
+    if let Some(attribution) = attribution {
+        metadata.push(("attribution", attribution.to_string()));
+    }
+    metadata
+}
+
+/// This time in UTC, e.g. `2017-07-14T02:40:00Z`.
+fn iso8601_utc(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, secs) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // The civil date from the days since 1970-01-01, with years starting in March, so the leap
+    // day is at the end. From Howard Hinnant's date algorithms.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era/1_460 + day_of_era/36_524 - day_of_era/146_096) / 365;
+    let day_of_year = day_of_era - (365*year_of_era + year_of_era/4 - year_of_era/100);
+    let mp = (5*day_of_year + 2) / 153;
+    let day = day_of_year - (153*mp + 2)/5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs / 3_600, (secs % 3_600) / 60, secs % 60)
+}
+
+/// The `bounds` for MBTiles (and directory) metadata, as `[minlon, minlat, maxlon, maxlat]`. From
+/// the bbox being generated, or the data.yml's bounds.
+fn mbtiles_bounds(bbox: &Option<BBox>, layers: &Layers) -> Option<[f64; 4]> {
+    match *bbox {
+        Some(ref bbox) => Some([bbox.left() as f64, bbox.bottom() as f64, bbox.right() as f64, bbox.top() as f64]),
+        None => layers.bounds,
+    }
+}
+
+/// What the workers of one `generate_all` run need, other than their own database connections
+/// & channels. Each worker has its own copy.
+#[derive(Clone)]
+struct WorkerContext {
+    layers: Layers,
+    /// Send the hash of each tile's contents to be stored
+    store_tile_hashes: bool,
+    feature_hook: Option<FeatureHook>,
+    /// With `prune_empty_descendants`, the tiles which had no features
+    empty_tiles: Option<Arc<EmptyTiles>>,
+    buffer_only_counts_as_empty: bool,
+    /// How many features couldn't be encoded, in all the workers
+    encode_errors: Arc<AtomicUsize>,
+    fail_on_encode_error: bool,
+    debug_density: Option<u32>,
+    /// Only write tiles which are different from the ones in this MBTiles file (`against`)
+    previous_tiles: Option<PathBuf>,
+    copy_unchanged: bool,
+    compress_min_bytes: Option<usize>,
+    compression_level: Option<u32>,
+    tile_matrix: TileMatrix,
+    geometry: GeometryOptions,
+    /// Don't start any metatiles after this
+    deadline: Option<Instant>,
+    /// Only write the tiles (of the edge metatiles) which are in this
+    tiles_bbox: Option<BBox>,
+}
+
+fn worker_all_layers<F>(printer_tx: Sender<printer::PrinterMessage>, fileio_tx: SyncSender<FileIOMessage>, mut metatile_iterator: Arc<Mutex<Iterator<Item=Metatile>>>, connection_pool: &ConnectionPool, should_do_metatile: F, context: &WorkerContext) -> GenerateStats
+    where F: Fn(&slippy_map_tiles::Metatile) -> bool,
+{
+    let mut stats = GenerateStats::default();
+    // Each worker has it's own connection
+    let previous_tiles = context.previous_tiles.as_ref().map(|path| fileio::MBTilesReader::open(path).unwrap());
+    loop {
+        if context.fail_on_encode_error && context.encode_errors.load(AtomicOrdering::SeqCst) > 0 {
+            // Another worker has had an error
+            break;
+        }
+        if out_of_time(context.deadline) {
+            break;
+        }
+
+        let metatile = metatile_iterator.lock().unwrap().next();
+        if let None = metatile {
+            // The iterator is finished.
+            break;
+        }
+        let metatile = metatile.unwrap();
+
+        if ! should_do_metatile(&metatile) {
+            continue;
+        }
+
+        if let Some(ref empty_tiles) = context.empty_tiles {
+            if empty_tiles.can_skip(&metatile) {
+                trace!("Skipping metatile {:?}, the tiles above it are empty", metatile);
+                empty_tiles.add_empty_metatile(&metatile);
+                continue;
+            }
+        }
+
+        let mut metatile_encode_errors = Vec::new();
+        let mut tiles = match single_metatile_without_backgrounds(&context.layers, &metatile, &connection_pool, context.feature_hook.as_ref(), &mut metatile_encode_errors, &mut stats, &context.tile_matrix, &context.geometry) {
+            Ok(tiles) => tiles,
+            Err(e) => {
+                // Carry on with the other metatiles
+                error!("Metatile {:?} failed, none of its tiles will be written: {}", metatile, e);
+                stats.metatiles_failed += 1;
+                printer_tx.send(printer::PrinterMessage::DoneTiles(metatile.zoom(), 1, 0)).unwrap();
+                continue;
+            },
+        };
+        if let Some(ref bbox) = context.tiles_bbox {
+            tiles.retain(|&(ref tile, _)| context.tile_matrix.tile_in_bbox(bbox, tile.zoom(), tile.x(), tile.y()));
+        }
+        let num_tiles = tiles.len();
+
+        if ! metatile_encode_errors.is_empty() {
+            stats.encode_errors += metatile_encode_errors.len();
+            context.encode_errors.fetch_add(metatile_encode_errors.len(), AtomicOrdering::SeqCst);
+            if context.fail_on_encode_error {
+                error!("Metatile {:?}: {}", metatile, metatile_encode_errors[0]);
+                break;
+            }
+        }
+
+        if let Some(ref empty_tiles) = context.empty_tiles {
+            for &(ref tile, ref mvt) in tiles.iter() {
+                if tile_is_empty(mvt, context.buffer_only_counts_as_empty) {
+                    empty_tiles.add_empty_tile(tile);
+                }
+            }
+        }
+
+        // A tile with only backgrounds is still empty, so they're added after checking
+        add_backgrounds(&context.layers, &mut tiles, context.geometry.validity.y_axis);
+
+        // The hashes are of the tiles' contents, not the debugging density layer
+        let mut hashes: Vec<_> = if context.store_tile_hashes {
+            tiles.iter().map(|&(tile, ref mvt)| (tile, tile_content_hash(&mvt.to_bytes()))).collect()
+        } else {
+            Vec::new()
+        };
+
+        if let Some(cells) = context.debug_density {
+            for &mut (_, ref mut mvt) in tiles.iter_mut() {
+                let layer = density_layer(mvt, cells);
+                mvt.add_layer(layer);
+            }
+        }
+
+        let tiles: Vec<_> = tiles.into_iter().map(|(tile, mvt)| (tile, tile_bytes(&mvt, context.compress_min_bytes, context.compression_level))).collect();
+        let tiles = match previous_tiles {
+            None => tiles,
+            Some(ref previous) => only_changed_tiles(tiles, previous, context.copy_unchanged, &mut stats),
+        };
+        stats.add_written_tiles(&tiles);
+
+        // Only for the tiles which are written
+        hashes.retain(|&(hashed_tile, _)| tiles.iter().any(|&(tile, _)| tile == hashed_tile));
+        if ! hashes.is_empty() {
+            fileio_tx.send(FileIOMessage::SaveTileHashes(hashes)).unwrap();
+        }
 
         printer_tx.send(printer::PrinterMessage::DoneTiles(metatile.zoom(), 1, num_tiles)).unwrap();
 
-        fileio_tx.send(FileIOMessage::SaveMetaTile(metatile, tiles)).unwrap();
+        if ! tiles.is_empty() {
+            fileio_tx.send(FileIOMessage::SaveMetaTile(metatile, tiles)).unwrap();
+        }
 
     }
 
+    stats
+}
+
+/// The tiles which are different from the `previous` tile there (or with `copy_unchanged`, the
+/// previous version of the unchanged tiles too). Tiles are the same if they have the same
+/// features, even if they're encoded differently. The changed & unchanged tiles are counted in
+/// `stats`.
+fn only_changed_tiles(tiles: Vec<(slippy_map_tiles::Tile, Vec<u8>)>, previous: &fileio::MBTilesReader, copy_unchanged: bool, stats: &mut GenerateStats) -> Vec<(slippy_map_tiles::Tile, Vec<u8>)> {
+    tiles.into_iter().filter_map(|(tile, bytes)| {
+        let previous_bytes = match previous.tile_data(&tile) {
+            Ok(b) => b,
+            Err(e) => {
+                warn!("Couldn't read previous tile {:?}: {}", tile, e);
+                None
+            },
+        };
+        match previous_bytes {
+            Some(ref previous_bytes) if *previous_bytes == bytes || tiles_geometrically_equal(previous_bytes, &bytes, 0) => {
+                stats.tiles_unchanged += 1;
+                if copy_unchanged {
+                    Some((tile, previous_bytes.clone()))
+                } else {
+                    None
+                }
+            },
+            _ => {
+                stats.tiles_changed += 1;
+                Some((tile, bytes))
+            },
+        }
+    }).collect()
 }
 
 fn worker_one_layer(printer_tx: Sender<printer::PrinterMessage>, fileio_tx: SyncSender<FileIOMessage>, mut metatile_iterator: Arc<Mutex<MetatilesIterator>>, connection_pool: &ConnectionPool, layer: &Layer, global_maxzoom: u8)
@@ -349,17 +1406,25 @@ fn worker_one_layer(printer_tx: Sender<printer::PrinterMessage>, fileio_tx: Sync
         let scale = metatile.size() as u32;
 
         let mut string_store = StringStore::new();
-        let tiles = single_layer(&layer, global_maxzoom, &metatile, &connection_pool, &mut string_store);
+        let sub_layers = match single_layer(&layer, global_maxzoom, &metatile, &connection_pool, &mut string_store, None, &mut Vec::new(), &mut GenerateStats::default(), &TileMatrix::default(), &GeometryOptions::default(), None) {
+            Ok(sub_layers) => sub_layers,
+            Err(e) => {
+                error!("Metatile {:?} failed, none of its tiles will be written: {}", metatile, e);
+                Vec::new()
+            },
+        };
 
-        let num_tiles = tiles.len();
+        let num_tiles = (scale * scale) as usize;
+
+        for tiles in sub_layers.into_iter() {
+            for (i, mvt_layer) in tiles.into_iter().enumerate() {
+                let i = i as u32;
+                let x = i / scale + metatile.x();
+                let y = i % scale + metatile.y();
+                let tile = slippy_map_tiles::Tile::new(metatile.zoom(), x, y).unwrap();
 
-        for (i, mvt_layer) in tiles.into_iter().enumerate() {
-            let i = i as u32;
-            let x = i / scale + metatile.x();
-            let y = i % scale + metatile.y();
-            let tile = slippy_map_tiles::Tile::new(metatile.zoom(), x, y).unwrap();
-            
-            fileio_tx.send(FileIOMessage::AppendToTile(tile, mvt_layer.to_bytes())).unwrap();
+                fileio_tx.send(FileIOMessage::AppendToTile(tile, mvt_layer.to_bytes())).unwrap();
+            }
         }
 
         printer_tx.send(printer::PrinterMessage::DoneTiles(metatile.zoom(), 1, num_tiles)).unwrap();
@@ -369,40 +1434,175 @@ fn worker_one_layer(printer_tx: Sender<printer::PrinterMessage>, fileio_tx: Sync
 }
 
 fn tilejson_vector_layers(layers: &Layers, connection_pool: &ConnectionPool) -> Result<serde_json::Value> {
-    Ok(json!(
-            layers.layers.iter().map(|layer| {
-                let layer_name = &layer.id;
-                let columns: Vec<(String, String)> = columns_for_layer(layer, connection_pool)?;
-                let minzoom = layer.minzoom;
-                let maxzoom = layer.maxzoom;
-                let maxzoom = if maxzoom > layers.global_maxzoom { layers.global_maxzoom } else { maxzoom };
-                Ok(json!({
-                    "id": layer_name,
-                    "description": "",
-                    "minzoom": minzoom,
-                    "maxzoom": maxzoom,
-                    "fields": columns.into_iter().collect::<HashMap<_, _>>(),
-                }))
-            }).collect::<Result<Vec<_>>>()?
-    ))
-}
-
-
-fn write_tilejson(layers: &Layers, connection_pool: &ConnectionPool, dest: &PathBuf) -> Result<()> {
-    let tilejson = json!({
+    vector_layers_with_columns(layers, |layer| columns_for_layer(layer, connection_pool))
+}
+
+/// The TileJSON `vector_layers` for these layers, with the fields of each from `columns_for_layer`.
+fn vector_layers_with_columns<F>(layers: &Layers, columns_for_layer: F) -> Result<serde_json::Value>
+    where F: Fn(&Layer) -> Result<Vec<(String, String)>>
+{
+    let mut vector_layers = Vec::with_capacity(layers.layers.len());
+    for layer in layers.layers.iter() {
+        let columns: Vec<(String, String)> = columns_for_layer(layer)?;
+        let fields = columns.into_iter().collect::<HashMap<_, _>>();
+        let minzoom = layer.minzoom;
+        let maxzoom = layer.maxzoom;
+        let maxzoom = if maxzoom > layers.global_maxzoom { layers.global_maxzoom } else { maxzoom };
+        // A layer with split-by is several vector tile layers, with the same fields
+        for layer_name in layer.tile_layer_names() {
+            vector_layers.push(json!({
+                "id": layer_name,
+                "description": "",
+                "minzoom": minzoom,
+                "maxzoom": maxzoom,
+                "fields": fields,
+            }));
+        }
+    }
+    Ok(json!(vector_layers))
+}
+
+/// The TileJSON `vector_layers` block (layer names, zooms, and the type of each field) for the
+/// layers in these data.yml files, as pretty printed JSON. Each layer's source is asked for its
+/// columns once, and no tiles are generated, so a data.yml can be checked before a long run.
+pub fn describe_layers(filenames: &[&str], options: &GenerateOptions) -> Result<String> {
+    let mut layers = Layers::from_files(filenames, options.allow_layer_override)?;
+    layers.cap_layer_zooms(&options.max_zoom_per_layer)?;
+
+    let mut connection_pool = ConnectionPool::new(layers.get_all_connections());
+    connection_pool.set_retry_policy(options.db_retry);
+    connection_pool.open_files(&layers)?;
+
+    vector_layers_block(tilejson_vector_layers(&layers, &connection_pool)?)
+}
+
+/// How valid a layer's source features are, in the tiles sampled by `validate_source`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceValidity {
+    /// Id of the layer
+    pub layer: String,
+    /// How many of the sampled tiles this layer was queried for
+    pub tiles: usize,
+    /// Features in those tiles (incl. the buffer). A feature in several tiles is counted for each,
+    /// and a GeometryCollection for each type of geometry in it.
+    pub features: usize,
+    /// Features whose WKB couldn't be read, or has z or m coordinates
+    pub unreadable: usize,
+    /// Features which weren't valid in the tile's coordinates, after being simplified & clipped
+    pub invalid: usize,
+    /// Invalid features which `make_valid` repaired
+    pub repaired: usize,
+    /// Invalid features which nothing was left of after repairing, and so wouldn't be in the tile
+    pub dropped: usize,
+}
+
+impl std::fmt::Display for SourceValidity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Layer {}: {} features in {} tiles, {} invalid ({} repaired, {} dropped)",
+               self.layer, self.features.separated_string(), self.tiles.separated_string(),
+               self.invalid.separated_string(), self.repaired.separated_string(), self.dropped.separated_string())?;
+        if self.unreadable > 0 {
+            write!(f, ", {} unreadable", self.unreadable.separated_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// Check how valid the source features of the layers in these data.yml files are, without
+/// writing any tiles. `sample` random tiles (on zooms `min_zoom`..=`max_zoom`, inside `bbox`) are
+/// queried, and each feature is put in the tile's coordinates & repaired like it would be when
+/// generating. The same `seed` samples the same tiles.
+pub fn validate_source(filenames: &[&str], min_zoom: u8, max_zoom: u8, bbox: &Option<BBox>, sample: usize, seed: u64, options: &GenerateOptions) -> Result<Vec<SourceValidity>> {
+    if min_zoom > max_zoom {
+        return Err(format_err!("minzoom {} is more than maxzoom {}", min_zoom, max_zoom));
+    }
+    let tile_matrix = options.tile_matrix.clone().unwrap_or_default();
+    tile_matrix.check(max_zoom)?;
+
+    let mut layers = Layers::from_files(filenames, options.allow_layer_override)?;
+    layers.cap_layer_zooms(&options.max_zoom_per_layer)?;
+
+    let mut connection_pool = ConnectionPool::new(layers.get_all_connections());
+    connection_pool.set_retry_policy(options.db_retry);
+    connection_pool.open_files(&layers)?;
+
+    sample_source_validity(&layers, &connection_pool, min_zoom, max_zoom, bbox, sample, seed, &tile_matrix, &GeometryOptions::from_options(options))
+}
+
+/// A small xorshift random number generator, so the sampled tiles can be repeated from a seed.
+struct SampleRng(u64);
+
+impl SampleRng {
+    fn new(seed: u64) -> Self {
+        // xorshift gets stuck at 0
+        SampleRng(if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed })
+    }
+
+    /// A number in `min..=max`
+    fn between(&mut self, min: u32, max: u32) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        min + (self.0 % ((max - min) as u64 + 1)) as u32
+    }
+}
+
+/// Check the source features in `sample` random tiles, by generating (but not writing) the tiles,
+/// so the features go through exactly the same steps (and repairs) as when generating.
+fn sample_source_validity(layers: &Layers, connection_pool: &ConnectionPool, min_zoom: u8, max_zoom: u8, bbox: &Option<BBox>, sample: usize, seed: u64, tile_matrix: &TileMatrix, geometry: &GeometryOptions) -> Result<Vec<SourceValidity>> {
+    let mut results: Vec<SourceValidity> = layers.layers.iter().map(|l| SourceValidity{ layer: l.id.clone(), ..Default::default() }).collect();
+    let mut rng = SampleRng::new(seed);
+    let mut string_store = StringStore::new();
+
+    for _ in 0..sample {
+        let zoom = rng.between(min_zoom as u32, max_zoom as u32) as u8;
+        let ((minx, miny), (maxx, maxy)) = match *bbox {
+            None => ((0, 0), (tile_matrix.max_tile_index(zoom), tile_matrix.max_tile_index(zoom))),
+            Some(ref bbox) => (tile_matrix.tile_for_lonlat(zoom, bbox.left() as f64, bbox.top() as f64), tile_matrix.tile_for_lonlat(zoom, bbox.right() as f64, bbox.bottom() as f64)),
+        };
+        let metatile = Metatile::new(1, zoom, rng.between(minx, maxx), rng.between(miny, maxy)).unwrap();
+        debug!("Sampling tile {:?}", metatile);
+
+        for (layer, result) in layers.layers.iter().zip(results.iter_mut()) {
+            if zoom < layer.minzoom || zoom > layer.maxzoom || zoom > layers.global_maxzoom {
+                continue;
+            }
+            result.tiles += 1;
+            single_layer(layer, layers.global_maxzoom, &metatile, connection_pool, &mut string_store, None, &mut Vec::new(), &mut GenerateStats::default(), tile_matrix, geometry, Some(result))?;
+        }
+    }
+
+    Ok(results)
+}
+
+fn vector_layers_block(vector_layers: serde_json::Value) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&json!({ "vector_layers": vector_layers }))?)
+}
+
+/// Write the TileJSON for a directory of tiles to `metadata.json` in it, with the same details as
+/// the MBTiles metadata (zooms, bounds, layers etc.), and these extra keys (see
+/// `generator_metadata`).
+fn write_tilejson(dest: &Path, layers: &Layers, vector_layers: serde_json::Value, min_zoom: u8, max_zoom: u8, bounds: Option<[f64; 4]>, scheme: TileScheme, metadata: &[(&str, String)]) -> Result<()> {
+    let mut tilejson = json!({
         "tilejson": "2.2.0",
         "tiles": [
             "http://www.example.com/{z}/{x}/{y}.pbf"
         ],
-        "bounds": layers.bounds,
+        "bounds": bounds,
         "center": layers.center,
         "name": layers.name,
         "description": layers.description,
-        "minzoom": 0,
-        "maxzoom": 14,
+        "minzoom": min_zoom,
+        "maxzoom": max_zoom,
         "format": "pbf",
-        "vector_layers": tilejson_vector_layers(layers, connection_pool)?,
+        "scheme": scheme.as_str(),
+        "vector_layers": vector_layers,
     });
+    if let serde_json::Value::Object(ref mut tilejson) = tilejson {
+        for &(name, ref value) in metadata.iter() {
+            tilejson.insert(name.to_string(), json!(value));
+        }
+    }
 
     fs::create_dir_all(&dest)?;
     let mut tilejson_file = File::create(dest.join("metadata.json"))?;
@@ -412,7 +1612,13 @@ fn write_tilejson(layers: &Layers, connection_pool: &ConnectionPool, dest: &Path
     
 }
 
+/// The name & TileJSON type of each attribute of this layer.
 fn columns_for_layer(layer: &Layer, connection_pool: &ConnectionPool) -> Result<Vec<(String, String)>> {
+    connection_pool.source_for_layer(layer).columns(layer)
+}
+
+/// The name & TileJSON type of each column of this (PostGIS) layer's query.
+fn postgis_columns(layer: &Layer, connection_pool: &ConnectionPool) -> Result<Vec<(String, String)>> {
     let layer_name = &layer.id;
 
     let conn = connection_pool.connection_for_layer(&layer_name);
@@ -461,7 +1667,29 @@ fn columns_for_layer(layer: &Layer, connection_pool: &ConnectionPool) -> Result<
     Ok(cols)
 }
 
-pub fn single_metatile(layers: &Layers, metatile: &slippy_map_tiles::Metatile, connection_pool: &ConnectionPool) -> Vec<(slippy_map_tiles::Tile, mapbox_vector_tile::Tile)> {
+/// Generate all the tiles of this metatile. Features which couldn't be encoded are added to
+/// `encode_errors`, and how many features were encoded & dropped to `stats`. It's an error if a
+/// layer's features couldn't be read (after any retries).
+pub fn single_metatile(layers: &Layers, metatile: &slippy_map_tiles::Metatile, connection_pool: &ConnectionPool, feature_hook: Option<&FeatureHook>, encode_errors: &mut Vec<EncodeError>, stats: &mut GenerateStats) -> Result<Vec<(slippy_map_tiles::Tile, mapbox_vector_tile::Tile)>> {
+    single_metatile_with_tile_matrix(layers, metatile, connection_pool, feature_hook, encode_errors, stats, &TileMatrix::default())
+}
+
+/// Generate all the tiles of this metatile, like `single_metatile`, where the tiles are from this
+/// `TileMatrix`, rather than the standard web mercator ones.
+pub fn single_metatile_with_tile_matrix(layers: &Layers, metatile: &slippy_map_tiles::Metatile, connection_pool: &ConnectionPool, feature_hook: Option<&FeatureHook>, encode_errors: &mut Vec<EncodeError>, stats: &mut GenerateStats, tile_matrix: &TileMatrix) -> Result<Vec<(slippy_map_tiles::Tile, mapbox_vector_tile::Tile)>> {
+    single_metatile_with_options(layers, metatile, connection_pool, feature_hook, encode_errors, stats, tile_matrix, &GeometryOptions::default())
+}
+
+/// Generate all the tiles of this metatile, making the geometries with these options.
+fn single_metatile_with_options(layers: &Layers, metatile: &slippy_map_tiles::Metatile, connection_pool: &ConnectionPool, feature_hook: Option<&FeatureHook>, encode_errors: &mut Vec<EncodeError>, stats: &mut GenerateStats, tile_matrix: &TileMatrix, geometry: &GeometryOptions) -> Result<Vec<(slippy_map_tiles::Tile, mapbox_vector_tile::Tile)>> {
+    let mut tiles = single_metatile_without_backgrounds(layers, metatile, connection_pool, feature_hook, encode_errors, stats, tile_matrix, geometry)?;
+    add_backgrounds(layers, &mut tiles, geometry.validity.y_axis);
+    Ok(tiles)
+}
+
+/// Generate all the tiles of this metatile, without the layers' backgrounds, so we can tell which
+/// tiles are empty.
+fn single_metatile_without_backgrounds(layers: &Layers, metatile: &slippy_map_tiles::Metatile, connection_pool: &ConnectionPool, feature_hook: Option<&FeatureHook>, encode_errors: &mut Vec<EncodeError>, stats: &mut GenerateStats, tile_matrix: &TileMatrix, geometry: &GeometryOptions) -> Result<Vec<(slippy_map_tiles::Tile, mapbox_vector_tile::Tile)>> {
     let empty_tile = mapbox_vector_tile::Tile::new();
     let scale = metatile.size() as u32;
 
@@ -469,18 +1697,13 @@ pub fn single_metatile(layers: &Layers, metatile: &slippy_map_tiles::Metatile, c
 
     let mut string_store = stringstore::StringStore::new();
 
-    for layer in layers.layers.iter() {
-        let minzoom = layer.minzoom;
-        let maxzoom = layer.maxzoom;
-        let maxzoom = if maxzoom > layers.global_maxzoom { layers.global_maxzoom } else { maxzoom };
-        // Skip layers which are not on this zoom
-        if metatile.zoom() < minzoom || metatile.zoom() > maxzoom {
-            continue;
-        }
-
-        let mvt_layers = single_layer(layer, layers.global_maxzoom, metatile, connection_pool, &mut string_store);
-        for (mvt_tile, mvt_layer) in results.iter_mut().zip(mvt_layers.into_iter()) {
-            mvt_tile.add_layer(mvt_layer);
+    // Layers which are not on this zoom are skipped, without querying them
+    for layer in layers.layers_for_zoom(metatile.zoom()) {
+        let sub_layers = single_layer(layer, layers.global_maxzoom, metatile, connection_pool, &mut string_store, feature_hook, encode_errors, stats, tile_matrix, geometry, None)?;
+        for mvt_layers in sub_layers.into_iter() {
+            for (mvt_tile, mvt_layer) in results.iter_mut().zip(mvt_layers.into_iter()) {
+                mvt_tile.add_layer(mvt_layer);
+            }
         }
         //memory!("Done layer {}", layer.id);
 
@@ -488,51 +1711,62 @@ pub fn single_metatile(layers: &Layers, metatile: &slippy_map_tiles::Metatile, c
 
 
     memory!("Metatile {:?} finished", metatile);
-    results.into_iter().enumerate().map(|(i, mvt_tile)| {
+    Ok(results.into_iter().enumerate().map(|(i, mvt_tile)| {
         let i = i as u32;
         let x = i / scale + metatile.x();
         let y = i % scale + metatile.y();
         (slippy_map_tiles::Tile::new(metatile.zoom(), x, y).unwrap(), mvt_tile)
-    }).collect()
+    }).collect())
 }
 
-fn single_layer(layer: &Layer, global_maxzoom: u8, metatile: &slippy_map_tiles::Metatile, connection_pool: &ConnectionPool, mut string_store: &mut StringStore) -> Vec<mapbox_vector_tile::Layer> {
+/// The bbox (in web mercator) which the query for this metatile uses as `!bbox!`, and to filter
+/// rows. It includes the buffer, in 'pixels' where the metatile is 256 × its size wide.
+fn query_bbox(metatile: &slippy_map_tiles::Metatile, buffer: i32, tile_matrix: &TileMatrix) -> LocalBBox<f64> {
+    let canvas_size = 256.*(metatile.size() as f64);
+    let metatile_bbox = tile_matrix.metatile_bbox(metatile);
+    let (ll, ur) = ((metatile_bbox.0, metatile_bbox.1), (metatile_bbox.2, metatile_bbox.3));
+
+    // calculate how much to expand the bbox to get the buffer.
+    let buffer_width = (((ur.0 - ll.0) as f64) / canvas_size)*(buffer as f64);
+    let buffer_height = (((ur.1 - ll.1) as f64) / canvas_size)*(buffer as f64);
+
+    LocalBBox((ll.0 as f64)-buffer_width, (ll.1 as f64)-buffer_height, (ur.0 as f64)+buffer_width, (ur.1 as f64)+buffer_height)
+}
+
+/// The vector tile layers of this layer for each tile of the metatile. There's a `Vec` of the
+/// tiles for each vector tile layer the features are split into (see `Layer::split_by`), or just
+/// one. It's an error if the features couldn't be read (after any retries). With
+/// `source_validity`, how valid the features were before being repaired is counted in it (see
+/// `validate_source`).
+fn single_layer(layer: &Layer, global_maxzoom: u8, metatile: &slippy_map_tiles::Metatile, connection_pool: &ConnectionPool, mut string_store: &mut StringStore, feature_hook: Option<&FeatureHook>, encode_errors: &mut Vec<EncodeError>, stats: &mut GenerateStats, tile_matrix: &TileMatrix, geometry: &GeometryOptions, mut source_validity: Option<&mut SourceValidity>) -> Result<Vec<Vec<mapbox_vector_tile::Layer>>> {
     let scale = metatile.size() as u32;
-    let layer_name = &layer.id;
+    let layer_name = &layer.name;
     debug!("Starting layer {} metatile {:?}", layer_name, metatile);
+    let geometry = &geometry.for_zoom(metatile.zoom());
 
     let new_layer = mapbox_vector_tile::Layer::new(layer_name.to_string());
-    let mut results: Vec<mapbox_vector_tile::Layer> = vec![mapbox_vector_tile::Layer::new(layer_name.to_string()); (scale*scale) as usize];
+    let sub_layer_names = layer.tile_layer_names();
+    let mut results: Vec<Vec<mapbox_vector_tile::Layer>> = sub_layer_names.iter()
+        .map(|name| vec![mapbox_vector_tile::Layer::new(name.clone()); (scale*scale) as usize])
+        .collect();
 
     // One 'pixel' of buffer space is actually 16 pixels of space now
     let buffer = (layer.buffer as i32) * 16;
 
     let layer_name = &layer.id;
 
-    let conn = connection_pool.connection_for_layer(&layer_name);
-    
     let table = &layer.table;
     // TODO should this be 4096??
     // TODO not confident about this calculation.
     let canvas_size = 256.*(metatile.size() as f64);
-    let ll = metatile.sw_corner().to_3857();
-    let ur = metatile.ne_corner().to_3857();
+    let metatile_bbox = tile_matrix.metatile_bbox(metatile);
+    let (ll, ur) = ((metatile_bbox.0, metatile_bbox.1), (metatile_bbox.2, metatile_bbox.3));
 
     // TODO vtiles have y positive going down, is this correct??
     let tile_width = (ur.0 - ll.0) as f64;
     let tile_height = (ur.1 - ll.1) as f64;
 
-    // calculate how much to expand the bbox to get the buffer.
-    // Similar calculation for the pixel size
-    let buffer_width = (tile_width / canvas_size)*(buffer as f64);
-    let buffer_height = (tile_height / canvas_size)*(buffer as f64);
-
-    let minx = ll.0 as f64;
-    let miny = ll.1 as f64;
-    let maxx = ur.0 as f64;
-    let maxy = ur.1 as f64;
-
-    let bbox = LocalBBox(minx-buffer_width, miny-buffer_height, maxx+buffer_width, maxy+buffer_height);
+    let bbox = query_bbox(metatile, buffer, tile_matrix);
     assert!(tile_height > 0.);
     assert!(tile_width > 0.);
 
@@ -540,39 +1774,111 @@ fn single_layer(layer: &Layer, global_maxzoom: u8, metatile: &slippy_map_tiles::
     let pixel_height = (tile_height / canvas_size) as f32;
 
     let scale_denominator = scale_denominator_for_zoom(metatile.zoom());
-    let stmt = conn.prepare_cached(&layer.table.query).unwrap();
-    let res = stmt.query(&table.params(&bbox, &pixel_width, &pixel_height, &(metatile.zoom() as i32), &scale_denominator)).unwrap();
+    let query = source::FeatureQuery{ bbox, zoom: metatile.zoom(), pixel_width, pixel_height, scale_denominator };
+    let source_features = connection_pool.source_for_layer(layer).features_in(layer, &query, string_store)
+        .map_err(|e| format_err!("Query for layer {} metatile {:?} failed: {}", layer_name, metatile, e))?;
 
-    if res.is_empty() {
-        return results;
+    if source_features.is_empty() {
+        return Ok(results);
     }
 
 
-    let extent = (new_layer.extent as f64)*(metatile.size() as f64);
+    // Each tile's own grid, which the features end up on after clipping (& downsampling)
+    let tile_extent = new_layer.extent as i32;
+    let extent = (tile_extent as f64)*(metatile.size() as f64);
 
-    let columns: Vec<_> = res.columns().iter().skip(1).filter(|c| c.name() != "way").collect();
+    // With oversampling, everything is done at a higher resolution, until the features are
+    // scaled down to each tile in finish_oversampled_tile_geometry
+    let oversample = layer.oversample as i32;
+    let extent = extent * (oversample as f64);
+    let oversampled_buffer = buffer * oversample;
+    let projection = Projection::for_metatile(tile_matrix, metatile, extent / (metatile.size() as f64), geometry);
 
-    let mut res = res.iter().enumerate();
+    let mut res = source_features.into_iter().enumerate();
 
     let mut num_objects = 0;
+    let mut warned_extra_dimensions = false;
+
+    // The features for each tile, which are added to the layers at the end, once we know how many
+    // there are.
+    let mut tile_features: Vec<Vec<Vec<(Geometry<i32>, Rc<mapbox_vector_tile::Properties>)>>> = sub_layer_names.iter()
+        .map(|_| (0..scale*scale).map(|_| Vec::new()).collect())
+        .collect();
+
+    // Only do the simplification if we're not at maxzoom (or the layer's simplify_max_zoom).
+    // We've already removed extra points in remove_unneeded_points below
+    let simplify_max_zoom = layer.simplify_max_zoom.map_or(global_maxzoom, |z| if z < global_maxzoom { z } else { global_maxzoom });
+    let preserve_below = if metatile.zoom() < simplify_max_zoom {
+        Some(layer.preserve_detail_below_length.map(|l| (l as i32) * 16 * oversample).unwrap_or(0))
+    } else {
+        None
+    };
 
-    for (i, row) in res {
+    // With simplify-topology, the features are simplified together once they're all clipped
+    let features_preserve_below = if layer.simplify_topology { None } else { preserve_below };
+    // Each feature has the index (in sub_layer_names) of the vector tile layer it goes in
+    let mut features: Vec<(Geometry<i32>, mapbox_vector_tile::Properties, usize)> = Vec::new();
+
+    for (i, source_feature) in res {
         num_objects += 1;
         let bad_obj = false && metatile.zoom() == 3 && i == 4_579;
         if i % 5_000 == 0 {
             //memory!("layer {} have done {} objects", layer_name, i.separated_string());
         }
 
-        // First object is the ST_AsBinary
-        // TODO Does this do any copies that we don't want?
-        let wkb_bytes: Vec<u8> = row.get(0);
+        let source::SourceFeature{ wkb: wkb_bytes, attributes } = source_feature;
+
+        if let Some(ref filter) = layer.filter {
+            let value_of = |name: &str| attributes.iter().find(|&&(ref n, _)| n == name).and_then(|&(_, ref v)| v.as_ref().ok().and_then(|v| v.as_ref()));
+            if ! filter.matches(metatile.zoom(), &value_of) {
+                continue;
+            }
+        }
+
+        // Features whose value isn't in the mapping, with no default layer, are left out (like
+        // the filter)
+        let sub_layer = match layer.split_by {
+            None => 0,
+            Some(ref split_by) => {
+                let value = attributes.iter().find(|&&(ref n, _)| *n == split_by.column).and_then(|&(_, ref v)| v.as_ref().ok().and_then(|v| v.as_ref()));
+                match split_by.layer_for(value).and_then(|name| sub_layer_names.iter().position(|n| n == name)) {
+                    None => { continue; },
+                    Some(s) => s,
+                }
+            },
+        };
 
         //println!("\nL {} bytes {:?}", line!(), wkb_bytes);
 
+        // Everything is 2D, so rather than silently losing the z/m values, say so
+        if wkb_has_extra_dimensions(&wkb_bytes) {
+            if ! warned_extra_dimensions {
+                warn!("Layer {}: geometries have z or m coordinates, which aren't supported, so they are left out. Use ST_Force2D(way) in the query to drop them", layer_name);
+                warned_extra_dimensions = true;
+            }
+            if let Some(dump) = connection_pool.invalid_dump() {
+                dump.add_unreadable(&layer.id, metatile, "geometry has z or m coordinates");
+            }
+            stats.features_dropped += 1;
+            if let Some(ref mut v) = source_validity {
+                v.features += 1;
+                v.unreadable += 1;
+            }
+            continue;
+        }
+
         let geom: geo::Geometry<f64> = match wkb::wkb_to_geom(&mut wkb_bytes.as_slice()) {
             Err(e) => {
                 // TODO investigate this more
                 //eprintln!("{}:{} Metatile: {:?} WKB reading error {:?}, layer {} row {:?}", file!(), line!(), metatile, e, layer_name, row);
+                if let Some(dump) = connection_pool.invalid_dump() {
+                    dump.add_unreadable(&layer.id, metatile, &format!("couldn't read the WKB: {:?}", e));
+                }
+                stats.features_dropped += 1;
+                if let Some(ref mut v) = source_validity {
+                    v.features += 1;
+                    v.unreadable += 1;
+                }
                 continue;
             },
             Ok(g) => g,
@@ -589,101 +1895,125 @@ fn single_layer(layer: &Layer, global_maxzoom: u8, metatile: &slippy_map_tiles::
         //    println!("\nL {} geom {:100}", line!(), format!("{:?}", geom));
         //    println!("\nL {} minx {} maxx {} miny {} maxy {} extent {}", line!(), minx, maxx, miny, maxy, extent);
         //}
-        let mut geom = match remap_geometry(geom, minx, maxx, miny, maxy, extent) {
-            None => { continue; }
-            Some(g) => g,
-        };
 
-        let geom = match simplify::remove_unneeded_points(geom) {
-            None => { continue; },
-            Some(g) => g,
-        };
-        //if bad_obj { println!("{}:{} geom {:100}", file!(), line!(), format!("{:?}", geom)); }
+        // A vector tile feature can only be points, lines or polygons, so a GeometryCollection is
+        // split into a feature for each type
+        let mut geoms = Vec::with_capacity(1);
+        let mut any_degenerate = false;
+        for geom in split_geometry_collection(geom) {
+            if ! include_geometry_at_zoom(layer, metatile.zoom(), &geom) {
+                continue;
+            }
 
-        //let geom = validity::make_valid(geom);
+            // Geometries over the antimeridian, or up to the poles, are outside the web mercator
+            // world, which would give absurd (or overflowing) tile coordinates.
+            let geom = match clip_to_mercator_bounds(geom) {
+                None => { continue; }
+                Some(g) => g,
+            };
 
-        //debug_assert!(is_valid(&geom), "L {} Geometry is invalid after remap: {:100}", line!(), format!("{:?}", geom));
-        //validity::ensure_polygon_orientation(&mut geom);
-        //if ! is_valid_skip_expensive(&geom) {
-        //    continue;
-        //}
+            let mut geom = match project_geometry(geom, &projection) {
+                None => { continue; }
+                Some(g) => g,
+            };
 
-        // Only do the simplification if we're not at maxzoom. We've already removed extra
-        //
-        // points in remove_unneeded_points above
-        //println!("{} L {}", file!(), line!());
-        let geom = if metatile.zoom() < global_maxzoom {
-                match simplify::simplify(geom, 8) {
-                    None => {
-                        continue;
-                    },
-                    Some(g) => g,
-                }
-        } else { geom };
-        //println!("{} L {}", file!(), line!());
-        //debug_assert!(is_valid(&geom), "L {} Geometry is invalid after remap: {:100}", line!(), format!("{:?}", geom));
-        //if bad_obj { println!("{}:{} geom {:102}", file!(), line!(), format!("{:?}", geom)); }
-        //if bad_obj {
-        //    let mut g2 = geom.clone();
-        //    validity::ensure_polygon_orientation(&mut g2);
-        //    print_geom_as_geojson(&g2, 4096.*8.);
-        //}
-        
-        // After simplifying a geometry, it's possible it becomes invalid. So we just skip the
-        // geometries in that case.
-        //if ! is_valid(&geom) {
-        //    continue;
-        //}
+            // Polygons with no area (and lines with no length) are dropped here, before they get
+            // to make_valid
+            let original = connection_pool.invalid_dump().map(|_| geom.clone());
+            let geom = match simplify::remove_unneeded_points(geom) {
+                None => {
+                    if let (Some(dump), Some(original)) = (connection_pool.invalid_dump(), original) {
+                        dump.add(&layer.id, metatile, oversample, &original, "nothing left after removing repeated & collinear points");
+                    }
+                    any_degenerate = true;
+                    if let Some(ref mut v) = source_validity {
+                        v.features += 1;
+                        v.invalid += 1;
+                        v.dropped += 1;
+                    }
+                    continue;
+                },
+                Some(g) => g,
+            };
+            //if bad_obj { println!("{}:{} geom {:100}", file!(), line!(), format!("{:?}", geom)); }
+
+            //let geom = validity::make_valid(geom);
+
+            //debug_assert!(is_valid(&geom), "L {} Geometry is invalid after remap: {:100}", line!(), format!("{:?}", geom));
+            //validity::ensure_polygon_orientation(&mut geom);
+            //if ! is_valid_skip_expensive(&geom) {
+            //    continue;
+            //}
+
+            // clip geometry, so no part of it goes outside the bbox. PostgreSQL will return
+            // anything that overlaps.
+            let clip_bbox = geo::Bbox{ xmin: -oversampled_buffer, xmax: extent as i32 + oversampled_buffer, ymin: -oversampled_buffer, ymax: extent as i32 + oversampled_buffer };
+            let geom = match simplify_and_clip(geom, features_preserve_below, geometry.simplify_stage, &clip_bbox) {
+                None => {
+                    // geometry is outside the bbox (or simplified away), so skip
+                    continue;
+                },
+                Some(g) => g,
+            };
 
-        // clip geometry, so no part of it goes outside the bbox. PostgreSQL will return
-        // anything that overlaps.
-        let geom = match clip_to_bbox(Cow::Owned(geom), &geo::Bbox{ xmin: -(buffer as i32), xmax: extent as i32 + buffer as i32, ymin: -(buffer as i32), ymax: extent as i32 + buffer as i32 }) {
-            None => {
-                // geometry is outside the bbox, so skip
-                continue;
-            },
-            Some(g) => g,
-        };
+            geoms.push(geom);
+        }
+        if geoms.is_empty() {
+            // Not just outside this metatile, but couldn't be made valid
+            if any_degenerate {
+                stats.features_dropped += 1;
+            }
+            continue;
+        }
 
         //let geom = validity::make_valid(geom);
         //debug_assert!(is_valid(&geom), "L {} Geometry is invalid after clip_to_bbox: {:100}", line!(), format!("{:?}", geom));
                 
-        let mut properties = mapbox_vector_tile::Properties::new();
-
-        for column in columns.iter() {
-            let name = column.name();
-
-            // Sometimes a NULL value can be returned, hence the dance with Option<Value>
-            let value: Option<mapbox_vector_tile::Value> = match column.type_().name() {
-                "float4" => row.get_opt(name).map(|x| x.ok().map(mapbox_vector_tile::Value::Float)).unwrap_or(None),
-                "float8" => row.get_opt(name).map(|x| x.ok().map(mapbox_vector_tile::Value::Double)).unwrap_or(None),
-
-                "text" =>  row.get_opt(name).map(|x| x.ok().map(|s: String| mapbox_vector_tile::Value::String(string_store.get_string(s)))).unwrap_or(None),
-                "varchar" =>  row.get_opt(name).map(|x| x.ok().map(|s: String| mapbox_vector_tile::Value::String(string_store.get_string(s)))).unwrap_or(None),
-
-                "int4" => row.get_opt(name).map(|x| x.ok().map(|y| { let val: i32 = y; mapbox_vector_tile::Value::Int(val as i64) })).unwrap_or(None),
-                "int8" => row.get_opt(name).map(|x| x.ok().map(|y| { let val: i64 = y; mapbox_vector_tile::Value::Int(val as i64) })).unwrap_or(None),
-                
-                // TODO not 100% sure numeric is correct here
-                "numeric" => row.get_opt(name).map(|x| x.ok().map(mapbox_vector_tile::Value::Double)).unwrap_or(None),
-                "bool" => row.get_opt(name).map(|x| x.ok().map(mapbox_vector_tile::Value::Boolean)).unwrap_or(None),
-                
-                // why is there unknown?
-                "unknown" => None,
-                x => {
-                    error!("Postgres type {:?} not known", x);
-                    unimplemented!()
-                },
-            };
-
+        // Attributes which are only read for the filter aren't in the tiles
+        let values: Vec<_> = attributes.iter()
+            .filter(|&&(ref name, _)| layer.includes_attribute(name))
+            .map(|&(ref name, ref value)| (name.as_str(), value.clone()))
+            .collect();
+        let properties = match feature_properties(layer_name, i, values, string_store) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("Metatile {:?}: {}", metatile, e);
+                if let Some(dump) = connection_pool.invalid_dump() {
+                    for geom in geoms.iter() {
+                        dump.add(&layer.id, metatile, oversample, geom, &format!("attributes couldn't be encoded: {}", e));
+                    }
+                }
+                encode_errors.push(e);
+                stats.features_dropped += 1;
+                continue;
+            },
+        };
 
-            if let Some(v) = value {
-                properties.insert(string_store.get_string(name.to_string()), v);
-            }
+        let last = geoms.pop().unwrap();
+        for geom in geoms {
+            features.push((geom, properties.clone(), sub_layer));
         }
-        properties.0.shrink_to_fit();
+        features.push((last, properties, sub_layer));
+    }
+
+    let features = match preserve_below {
+        Some(preserve_below) if layer.simplify_topology => {
+            let (geoms, properties): (Vec<_>, Vec<_>) = features.into_iter().map(|(geom, properties, sub_layer)| (geom, (properties, sub_layer))).unzip();
+            simplify::simplify_topology(geoms, 8, preserve_below).into_iter().zip(properties.into_iter())
+                .filter_map(|(geom, (properties, sub_layer))| geom.map(|geom| (geom, properties, sub_layer)))
+                .collect()
+        },
+        _ => features,
+    };
 
-        let mut geoms: Vec<_> = clip_geometry_to_tiles(&metatile, geom, buffer).into_iter().filter_map(
+    let mut clamped_vertices = 0;
+    for (geom, properties, sub_layer) in features.into_iter() {
+        let pieces = clip_geometry_to_tiles(&metatile, geom, oversampled_buffer, tile_extent * oversample);
+        let num_pieces = pieces.iter().filter(|&&(_, ref g)| g.is_some()).count();
+        let mut any_invalid_piece = false;
+        let mut offscreen_pieces = 0;
+        let check_validity = source_validity.is_some();
+        let mut geoms: Vec<_> = pieces.into_iter().filter_map(
             |(t, g)| match g {
                 None => None,
                 // TODO probably could use .map/.and_then here
@@ -691,21 +2021,55 @@ fn single_layer(layer: &Layer, global_maxzoom: u8, metatile: &slippy_map_tiles::
                     //debug_assert!(is_valid(&g), "L {} Geometry is invalid after clip_geometry_to_tiles: {:?}", line!(), g);
 
                     trace!("About to call make_valid");
-                    match validity::make_valid(g) {
-                        None => None,
-                        Some(mut g) => {
-                            if is_valid(&g) {
-                                validity::ensure_polygon_orientation(&mut g);
-                                Some((t, g))
-                            } else {
-                                warn!("make_valid returned an invalid geometry: {:?}", g);
-                                None
-                            }
-                        },
+                    let tile_bbox = {
+                        let i = (t.x() - metatile.x()) as i32;
+                        let j = (t.y() - metatile.y()) as i32;
+                        Bbox{ xmin: i*tile_extent - buffer, xmax: (i+1)*tile_extent + buffer, ymin: j*tile_extent - buffer, ymax: (j+1)*tile_extent + buffer }
+                    };
+                    let original = connection_pool.invalid_dump().map(|_| g.clone());
+                    if check_validity && ! validity::is_valid_with_y_axis(&g, geometry.validity.y_axis) {
+                        any_invalid_piece = true;
                     }
+                    let finished = if oversample == 1 {
+                        finish_tile_geometry(g, &tile_bbox, layer.min_hole_area, layer.max_vertices_per_feature, geometry, &mut clamped_vertices).map(|g| (t, g))
+                    } else {
+                        finish_oversampled_tile_geometry(g, &tile_bbox, oversample, layer.min_hole_area, layer.max_vertices_per_feature, geometry, &mut clamped_vertices).map(|g| (t, g))
+                    };
+                    if let (Some(dump), Some(original)) = (connection_pool.invalid_dump(), original) {
+                        // Valid geometries can be dropped for being (e.g.) only in the buffer
+                        if finished.is_none() && ! validity::is_valid_with_y_axis(&original, geometry.validity.y_axis) {
+                            dump.add(&layer.id, metatile, oversample, &original, "couldn't be made valid");
+                        }
+                    }
+
+                    // Repairing can leave slivers which are only in the buffer, and so invisible
+                    let (i, j) = ((t.x() - metatile.x()) as i32, (t.y() - metatile.y()) as i32);
+                    let visible_bbox = Bbox{ xmin: i*tile_extent, xmax: (i+1)*tile_extent, ymin: j*tile_extent, ymax: (j+1)*tile_extent };
+                    finished.and_then(|(t, g)| match validity::drop_polygons_outside(g, &visible_bbox) {
+                        None => { offscreen_pieces += 1; None },
+                        Some(g) => Some((t, g)),
+                    })
                 },
             }).collect();
 
+        if geoms.is_empty() && num_pieces > 0 && offscreen_pieces == 0 {
+            // It's in some tiles, but couldn't be made valid in any
+            stats.features_dropped += 1;
+        }
+        if let Some(ref mut v) = source_validity {
+            if num_pieces > 0 {
+                v.features += 1;
+                if any_invalid_piece {
+                    v.invalid += 1;
+                    if geoms.is_empty() && offscreen_pieces == 0 {
+                        v.dropped += 1;
+                    } else {
+                        v.repaired += 1;
+                    }
+                }
+            }
+        }
+
         // If there are >1 tiles, then we don't want to clone the properties everytime. So share
         // the data between all mapbox_vector_tile::Features using a Rc.
         // This is only a small speed up.
@@ -716,55 +2080,574 @@ fn single_layer(layer: &Layer, global_maxzoom: u8, metatile: &slippy_map_tiles::
             let i = (tile.x() - metatile.x()) as i32;
             let j = (tile.y() - metatile.y()) as i32;
 
-            let xoff = i*4096;
-            let yoff = j*4096;
+            let xoff = i*tile_extent;
+            let yoff = j*tile_extent;
 
             geom.map_coords_inplace(&|&(x, y)| ( (x - xoff), (y - yoff)));
 
-            let feature = mapbox_vector_tile::Feature::new(geom, properties.clone());
+            let geom = match layer.snap_tile_edges {
+                None => geom,
+                Some(distance) => {
+                    // Back in the metatile's coordinates, which are no longer oversampled
+                    let original = connection_pool.invalid_dump().map(|_| geom.map_coords(&|&(x, y)| (x + xoff, y + yoff)));
+                    match snap_and_revalidate(geom, tile_extent, distance as i32, &geometry.validity) {
+                        None => {
+                            if let (Some(dump), Some(original)) = (connection_pool.invalid_dump(), original) {
+                                dump.add(&layer.id, metatile, 1, &original, "couldn't be made valid after snapping to the tile edges");
+                            }
+                            stats.features_dropped += 1;
+                            continue;
+                        },
+                        Some(g) => g,
+                    }
+                },
+            };
+
+            let (geom, properties) = match feature_hook {
+                None => (geom, properties.clone()),
+                Some(hook) => match hook.apply(&sub_layer_names[sub_layer], geom, &properties) {
+                    None => { continue; },
+                    Some(x) => x,
+                },
+            };
+
             let n = (i*(scale as i32) + j) as usize;
-            results.get_mut(n).unwrap().add_feature(feature);
+            tile_features[sub_layer][n].push((geom, properties));
 
         };
 
     }
+    stats.clamped_vertices += clamped_vertices;
+    for (mvt_layers, tile_features) in results.iter_mut().zip(tile_features.into_iter()) {
+        for (n, (mvt_layer, mut features)) in mvt_layers.iter_mut().zip(tile_features.into_iter()).enumerate() {
+            if let Some(max_features) = layer.max_features {
+                let num_features = features.len();
+                let dropped = limit_features(&mut features, max_features as usize);
+                if dropped > 0 {
+                    debug!("Layer {} metatile {:?} tile {}: dropped {} of {} features, max-features is {}", layer_name, metatile, n, dropped, num_features, max_features);
+                }
+            }
+
+            stats.features_encoded += features.len();
+            for (geom, properties) in features.into_iter() {
+                mvt_layer.add_feature(mapbox_vector_tile::Feature::new(geom, properties));
+            }
+        }
+    }
+
     debug!("Finished layer {}, there were {} object", layer_name, num_objects.separated_string());
     memory!("Finished layer {}, there were {} object", layer_name, num_objects.separated_string());
 
-    results
+    Ok(results)
 
 }
 
 
-fn remap_linestring(ls: LineString<f64>, minx: f64, maxx: f64, miny: f64, maxy: f64, size: f64, should_be_ring: bool) -> Option<LineString<i32>> {
-    
-    let remap_xy = |x: f64, y: f64| -> (i32, i32) {
-        let x: f64 = ((x - minx) / (maxx - minx))*size;
-        let x = x.round();
-        debug_assert!(x <= i32::max_value() as f64);
-        debug_assert!(x >= i32::min_value() as f64);
-        let x: i32 = x as i32;
+/// Does this WKB geometry have z or m coordinates, as well as x & y? Both ISO WKB (e.g. type 1003
+/// for a PolygonZ) and PostGIS's EWKB flags are checked.
+fn wkb_has_extra_dimensions(wkb: &[u8]) -> bool {
+    use byteorder::{BigEndian, LittleEndian, ByteOrder};
 
-        // y axies goes down, hence different ordering for y
-        let y: f64 = ((maxy - y) / (maxy - miny))*size;
-        let y = y.round();
-        debug_assert!(y <= i32::max_value() as f64);
-        debug_assert!(y >= i32::min_value() as f64);
-        let y: i32 = y as i32;
+    if wkb.len() < 5 {
+        return false;
+    }
+    let geom_type = if wkb[0] == 0 { BigEndian::read_u32(&wkb[1..5]) } else { LittleEndian::read_u32(&wkb[1..5]) };
+    // EWKB has flags in the top bits, and ISO adds 1000 (z), 2000 (m) or 3000 (zm) to the type
+    (geom_type & 0xC000_0000 != 0) || (geom_type & 0x0FFF_FFFF) >= 1000
+}
+
+/// The value of this column of the row, for a vector tile, or `None` if it's NULL. An error if
+/// the column's type can't be put in a vector tile.
+fn column_value(row: &postgres::rows::Row, name: &str, type_name: &str, string_store: &mut StringStore) -> std::result::Result<Option<mapbox_vector_tile::Value>, String> {
+    // Sometimes a NULL value can be returned, hence the dance with Option<Value>
+    Ok(match type_name {
+        "float4" => row.get_opt(name).map(|x| x.ok().map(mapbox_vector_tile::Value::Float)).unwrap_or(None),
+        "float8" => row.get_opt(name).map(|x| x.ok().map(mapbox_vector_tile::Value::Double)).unwrap_or(None),
+
+        "text" =>  row.get_opt(name).map(|x| x.ok().map(|s: String| mapbox_vector_tile::Value::String(string_store.get_string(s)))).unwrap_or(None),
+        "varchar" =>  row.get_opt(name).map(|x| x.ok().map(|s: String| mapbox_vector_tile::Value::String(string_store.get_string(s)))).unwrap_or(None),
+
+        "int4" => row.get_opt(name).map(|x| x.ok().map(|y| { let val: i32 = y; mapbox_vector_tile::Value::Int(val as i64) })).unwrap_or(None),
+        "int8" => row.get_opt(name).map(|x| x.ok().map(|y| { let val: i64 = y; mapbox_vector_tile::Value::Int(val as i64) })).unwrap_or(None),
+
+        // TODO not 100% sure numeric is correct here
+        "numeric" => row.get_opt(name).map(|x| x.ok().map(mapbox_vector_tile::Value::Double)).unwrap_or(None),
+        "bool" => row.get_opt(name).map(|x| x.ok().map(mapbox_vector_tile::Value::Boolean)).unwrap_or(None),
+
+        // why is there unknown?
+        "unknown" => None,
+        x => {
+            return Err(format!("column {} has PostgreSQL type {}, which can't be put in a vector tile", name, x));
+        },
+    })
+}
+
+/// The properties of feature `feature_index` of this layer, from the value of each column (see
+/// `column_value`). If any value can't be encoded, the whole feature can't be.
+fn feature_properties(layer: &str, feature_index: usize, values: Vec<(&str, std::result::Result<Option<mapbox_vector_tile::Value>, String>)>, string_store: &mut StringStore) -> std::result::Result<mapbox_vector_tile::Properties, EncodeError> {
+    let error = |reason: String| EncodeError{ layer: layer.to_string(), feature_index: feature_index, reason: reason };
+
+    let mut properties = mapbox_vector_tile::Properties::new();
+    for (name, value) in values.into_iter() {
+        let value = match value {
+            Err(reason) => { return Err(error(reason)); },
+            Ok(None) => { continue; },
+            Ok(Some(v)) => v,
+        };
+
+        // The protobuf can hold these, but clients can't turn them into JSON (e.g. for styling)
+        let non_finite = match value {
+            mapbox_vector_tile::Value::Float(f) if ! f.is_finite() => Some(f as f64),
+            mapbox_vector_tile::Value::Double(f) if ! f.is_finite() => Some(f),
+            _ => None,
+        };
+        if let Some(f) = non_finite {
+            return Err(error(format!("column {} has a value which isn't a finite number ({})", name, f)));
+        }
+
+        properties.insert(string_store.get_string(name.to_string()), value);
+    }
+    properties.0.shrink_to_fit();
+
+    Ok(properties)
+}
+
+/// How big is this geometry? The area of polygons, the length of lines, and 0 for points.
+fn geometry_size(geom: &Geometry<i32>) -> f64 {
+    fn polygon_area(p: &Polygon<i32>) -> f64 {
+        validity::ring_area(&p.exterior) - p.interiors.iter().map(|i| validity::ring_area(i)).sum::<f64>()
+    }
+    fn line_length(ls: &LineString<i32>) -> f64 {
+        ls.0.windows(2).map(|w| ((w[1].x() - w[0].x()) as f64).hypot((w[1].y() - w[0].y()) as f64)).sum()
+    }
+
+    match *geom {
+        Geometry::Polygon(ref p) => polygon_area(p),
+        Geometry::MultiPolygon(ref mp) => mp.0.iter().map(polygon_area).sum(),
+        Geometry::LineString(ref ls) => line_length(ls),
+        Geometry::MultiLineString(ref mls) => mls.0.iter().map(line_length).sum(),
+        Geometry::GeometryCollection(ref gc) => gc.0.iter().map(geometry_size).sum(),
+        _ => 0.,
+    }
+}
+
+/// All the coordinates of this geometry, in order.
+fn geometry_coords(geom: &Geometry<i32>) -> Vec<(i32, i32)> {
+    let coords = RefCell::new(Vec::new());
+    geom.map_coords(&|&(x, y)| { coords.borrow_mut().push((x, y)); (x, y) });
+    coords.into_inner()
+}
+
+/// If there are more than `max_features` features, keep only the largest ones (by
+/// `geometry_size`). Features the same size are ordered by their coordinates, so the same
+/// features are always kept, whatever order they came from the database in. Returns how many
+/// were dropped.
+fn limit_features<P>(features: &mut Vec<(Geometry<i32>, P)>, max_features: usize) -> usize {
+    if features.len() <= max_features {
+        return 0;
+    }
+
+    let mut sized: Vec<(f64, Vec<(i32, i32)>, (Geometry<i32>, P))> = features.drain(..).map(|f| (geometry_size(&f.0), geometry_coords(&f.0), f)).collect();
+    sized.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.1.cmp(&b.1)));
+
+    let dropped = sized.len() - max_features;
+    features.extend(sized.into_iter().take(max_features).map(|(_, _, f)| f));
+    dropped
+}
+
+/// Does this tile have no features (in any layer)? With `buffer_only_counts_as_empty`, features
+/// which are only in the buffer (i.e. their bbox doesn't overlap the tile) don't count.
+fn tile_is_empty(tile: &mapbox_vector_tile::Tile, buffer_only_counts_as_empty: bool) -> bool {
+    if ! buffer_only_counts_as_empty {
+        return tile.layers.iter().all(|l| l.features.is_empty());
+    }
+
+    tile.layers.iter().all(|l| {
+        let extent = l.extent as i32;
+        l.features.iter().all(|f| match geometry_bbox(&f.geometry) {
+            None => true,
+            Some(bbox) => bbox.xmax < 0 || bbox.xmin >= extent || bbox.ymax < 0 || bbox.ymin >= extent,
+        })
+    })
+}
+
+/// Name of the debugging layer added with `GenerateOptions::debug_density`.
+pub const DENSITY_LAYER_NAME: &str = "_density";
+
+/// How many features (in all layers) are in each cell, when this tile is split into a
+/// `cells`×`cells` grid. A feature is counted in the cell with the centre of its bbox. Only cells
+/// with features are included, ordered by (x, y).
+fn feature_density(tile: &mapbox_vector_tile::Tile, cells: u32) -> Vec<((u32, u32), usize)> {
+    let mut counts: BTreeMap<(u32, u32), usize> = BTreeMap::new();
+    for layer in tile.layers.iter() {
+        let extent = layer.extent as i64;
+        let cell_for = |a: i32, b: i32| -> u32 {
+            let centre = ((a as i64 + b as i64) / 2).max(0).min(extent - 1);
+            (centre * (cells as i64) / extent) as u32
+        };
+        for feature in layer.features.iter() {
+            let cell = match geometry_bbox(&feature.geometry) {
+                None => (0, 0),
+                Some(bbox) => (cell_for(bbox.xmin, bbox.xmax), cell_for(bbox.ymin, bbox.ymax)),
+            };
+            *counts.entry(cell).or_insert(0) += 1;
+        }
+    }
+    counts.into_iter().collect()
+}
+
+/// A `_density` layer for this tile, with a square for each cell (of a `cells`×`cells` grid)
+/// which has features, and how many as `count`.
+pub fn density_layer(tile: &mapbox_vector_tile::Tile, cells: u32) -> mapbox_vector_tile::Layer {
+    let mut layer = mapbox_vector_tile::Layer::new(DENSITY_LAYER_NAME.to_string());
+    let extent = layer.extent as i32;
+    let count_key = Rc::new("count".to_string());
+
+    for ((x, y), count) in feature_density(tile, cells) {
+        let (x, y, cells) = (x as i32, y as i32, cells as i32);
+        let (xmin, xmax) = (x*extent/cells, (x+1)*extent/cells);
+        let (ymin, ymax) = (y*extent/cells, (y+1)*extent/cells);
+        let square: LineString<i32> = vec![(xmin, ymin), (xmin, ymax), (xmax, ymax), (xmax, ymin), (xmin, ymin)].into();
+
+        let mut properties = mapbox_vector_tile::Properties::new();
+        properties.insert(count_key.clone(), mapbox_vector_tile::Value::Int(count as i64));
+        layer.add_feature(mapbox_vector_tile::Feature::new(Polygon::new(square, vec![]).into(), Rc::new(properties)));
+    }
+
+    layer
+}
+
+/// Add the background of each layer which has one (see `Layer::background`) to its vector tile
+/// layers in these tiles.
+fn add_backgrounds(layers: &Layers, tiles: &mut [(slippy_map_tiles::Tile, mapbox_vector_tile::Tile)], y_axis: YAxis) {
+    let mut string_store = stringstore::StringStore::new();
+    let zoom = match tiles.first() {
+        None => { return; },
+        Some(&(ref tile, _)) => tile.zoom(),
+    };
+    for layer in layers.layers_for_zoom(zoom) {
+        let background = match layer.background {
+            None => { continue; },
+            Some(ref background) => background,
+        };
+        let names = layer.tile_layer_names();
+        for &mut (_, ref mut mvt_tile) in tiles.iter_mut() {
+            let mut mvt_layers: Vec<_> = mvt_tile.layers.iter_mut().filter(|l| names.contains(&l.name)).collect();
+            add_background(&mut mvt_layers, background, (layer.buffer as i32) * 16, &mut string_store, y_axis);
+        }
+    }
+}
+
+/// Add a polygon covering the whole tile (and `buffer`), with these attributes, to each of these
+/// layers which has no features, going the right way around when the y axis goes this way. It's
+/// not counted as an encoded feature.
+fn add_background(mvt_layers: &mut [&mut mapbox_vector_tile::Layer], attributes: &[(String, filter::Literal)], buffer: i32, string_store: &mut StringStore, y_axis: YAxis) {
+    let mut properties = mapbox_vector_tile::Properties::new();
+    for &(ref name, ref value) in attributes.iter() {
+        let value = match *value {
+            filter::Literal::String(ref s) => mapbox_vector_tile::Value::String(string_store.get_str(s)),
+            filter::Literal::Number(n) if n.fract() == 0. && n.abs() < (1u64 << 53) as f64 => mapbox_vector_tile::Value::Int(n as i64),
+            filter::Literal::Number(n) => mapbox_vector_tile::Value::Double(n),
+            filter::Literal::Boolean(b) => mapbox_vector_tile::Value::Boolean(b),
+        };
+        properties.insert(string_store.get_str(name), value);
+    }
+    let properties = Rc::new(properties);
 
+    for mvt_layer in mvt_layers.iter_mut().filter(|l| l.features.is_empty()) {
+        let (min, max) = (-buffer, mvt_layer.extent as i32 + buffer);
+        let geom = clip::bbox_polygon(&Bbox{ xmin: min, xmax: max, ymin: min, ymax: max }, y_axis);
+        mvt_layer.add_feature(mapbox_vector_tile::Feature::new(geom, properties.clone()));
+    }
+}
+
+/// Make this (clipped) geometry valid for a tile, whose area (with buffer) is `tile_bbox`, remove
+/// interiors smaller than `min_hole_area`, and simplify it to at most `max_vertices`. `None` if
+/// nothing is left, or it can't be made valid.
+fn finish_tile_geometry(g: Geometry<i32>, tile_bbox: &Bbox<i32>, min_hole_area: Option<u32>, max_vertices: Option<u32>, geometry: &GeometryOptions, clamped_vertices: &mut usize) -> Option<Geometry<i32>> {
+    // A polygon covering the whole tile (e.g. the sea) is always the same rectangle, whatever
+    // vertices clipping left on its edges
+    if clip::covers_whole_bbox(&g, tile_bbox) {
+        return Some(clip::bbox_polygon(tile_bbox, geometry.validity.y_axis));
+    }
+
+    let validity_level = geometry.validity.level;
+    let mut g = match validity::make_valid_within(g, tile_bbox, &geometry.validity).and_then(|g| clamp_and_revalidate(g, tile_bbox, geometry, clamped_vertices)) {
+        None => { return None; },
+        Some(g) => g,
+    };
+
+    if ! validity::is_valid_at_level(&g, validity_level, geometry.validity.y_axis) {
+        warn!("make_valid returned an invalid geometry: {:?}", g);
+        return None;
+    }
+
+    if let Some(min_hole_area) = min_hole_area {
+        validity::remove_small_interiors(&mut g, min_hole_area as f64);
+    }
+    validity::ensure_polygon_orientation(&mut g, geometry.validity.y_axis);
+    match max_vertices {
+        None => Some(g),
+        Some(max_vertices) => simplify::simplify_to_max_vertices(g, max_vertices as usize),
+    }
+}
+
+/// Like `finish_tile_geometry`, for a geometry which is `oversample` times the tile's resolution.
+/// It's made valid at that resolution, and then scaled down (rounding to the nearest unit) &
+/// finished at the tile's.
+fn finish_oversampled_tile_geometry(g: Geometry<i32>, tile_bbox: &Bbox<i32>, oversample: i32, min_hole_area: Option<u32>, max_vertices: Option<u32>, geometry: &GeometryOptions, clamped_vertices: &mut usize) -> Option<Geometry<i32>> {
+    let oversampled_bbox = Bbox{ xmin: tile_bbox.xmin * oversample, xmax: tile_bbox.xmax * oversample, ymin: tile_bbox.ymin * oversample, ymax: tile_bbox.ymax * oversample };
+    if clip::covers_whole_bbox(&g, &oversampled_bbox) {
+        return Some(clip::bbox_polygon(tile_bbox, geometry.validity.y_axis));
+    }
+    validity::make_valid_within(g, &oversampled_bbox, &geometry.validity)
+        .and_then(|g| simplify::remove_unneeded_points(downsample_geometry(g, oversample)))
+        .and_then(|g| finish_tile_geometry(g, tile_bbox, min_hole_area, max_vertices, geometry, clamped_vertices))
+}
+
+/// Divide all the coordinates of this geometry by `oversample`, rounding to the nearest whole
+/// number (halves up). Points can end up in the same place.
+fn downsample_geometry(mut geom: Geometry<i32>, oversample: i32) -> Geometry<i32> {
+    fn div_round(a: i32, b: i32) -> i32 {
+        let a = a + b / 2;
+        // Integer division rounds towards 0, but negative numbers need to round down
+        if a >= 0 { a / b } else { (a - b + 1) / b }
+    }
+    geom.map_coords_inplace(&|&(x, y)| (div_round(x, oversample), div_round(y, oversample)));
+    geom
+}
+
+/// Make one (uncompressed) vector tile from these features, without a database. `layers` is the
+/// name of each layer, and its features, with coordinates already in tile units (0 to `extent`).
+/// The features go through the same steps as ones from the database: they are simplified, clipped
+/// to the tile (& `buffer`), and made valid. Features which end up empty are left out.
+pub fn encode_tile(extent: i32, buffer: i32, layers: Vec<(String, Vec<(Geometry<i32>, mapbox_vector_tile::Properties)>)>) -> Vec<u8> {
+    let clip_bbox = Bbox{ xmin: -buffer, xmax: extent + buffer, ymin: -buffer, ymax: extent + buffer };
+    let mut tile = mapbox_vector_tile::Tile::new();
+
+    let geometry = GeometryOptions::default();
+
+    for (name, features) in layers.into_iter() {
+        let mut mvt_layer = mapbox_vector_tile::Layer::new(name);
+        mvt_layer.extent = extent as _;
+
+        for (geom, properties) in features.into_iter() {
+            let geom = simplify::remove_unneeded_points(geom)
+                .and_then(|g| simplify_and_clip(g, Some(0), geometry.simplify_stage, &clip_bbox))
+                .and_then(|g| finish_tile_geometry(g, &clip_bbox, None, None, &geometry, &mut 0));
+            if let Some(geom) = geom {
+                mvt_layer.add_feature(mapbox_vector_tile::Feature::new(geom, Rc::new(properties)));
+            }
+        }
+
+        tile.add_layer(mvt_layer);
+    }
+
+    tile.to_bytes()
+}
+
+/// If clamping is turned on, move any vertices outside `bbox` onto its edge. If that happened,
+/// make sure the geometry is still valid.
+fn clamp_and_revalidate(mut geom: Geometry<i32>, bbox: &Bbox<i32>, geometry: &GeometryOptions, clamped_vertices: &mut usize) -> Option<Geometry<i32>> {
+    if ! geometry.clamp_coordinates {
+        return Some(geom);
+    }
+
+    let clamped = clip::clamp_to_bbox(&mut geom, bbox);
+    if clamped > 0 {
+        debug!("Clamped {} vertices into {:?}", clamped, bbox);
+        *clamped_vertices += clamped;
+        validity::make_valid_with_options(geom, &geometry.validity)
+    } else {
+        Some(geom)
+    }
+}
+
+/// Move any vertices near the tile's edges onto them (see `clip::snap_to_tile_edges`). If that
+/// happened, make sure the geometry is still valid.
+fn snap_and_revalidate(mut geom: Geometry<i32>, extent: i32, distance: i32, options: &ValidityOptions) -> Option<Geometry<i32>> {
+    if clip::snap_to_tile_edges(&mut geom, extent, distance) == 0 {
+        return Some(geom);
+    }
+    let mut geom = validity::make_valid_with_options(geom, options)?;
+    validity::ensure_polygon_orientation(&mut geom, options.y_axis);
+    Some(geom)
+}
+
+/// Simplify this geometry (if `preserve_below` is `Some`, keeping segments shorter than that), and
+/// clip it to `bbox`, in the order given by `stage`.
+fn simplify_and_clip(geom: Geometry<i32>, preserve_below: Option<i32>, stage: SimplifyStage, bbox: &Bbox<i32>) -> Option<Geometry<i32>> {
+    let simplify = |g: Geometry<i32>| match preserve_below {
+        None => Some(g),
+        Some(preserve_below) => simplify::simplify_preserve_short_segments(g, 8, preserve_below),
+    };
+
+    match stage {
+        SimplifyStage::BeforeClip => simplify(geom).and_then(|g| clip_to_bbox(Cow::Owned(g), bbox)),
+        SimplifyStage::AfterClip => clip_to_bbox(Cow::Owned(geom), bbox).and_then(simplify),
+    }
+}
+
+/// The bounding box of all the points in this geometry (of any type, including
+/// GeometryCollections). None if there are no points.
+pub fn geometry_bbox<T: CoordinateType>(geom: &Geometry<T>) -> Option<Bbox<T>> {
+    fn add_points<T: CoordinateType>(bbox: &mut Option<Bbox<T>>, points: &[Point<T>]) {
+        for p in points {
+            let (x, y) = (p.x(), p.y());
+            *bbox = Some(match bbox.take() {
+                None => Bbox{ xmin: x, xmax: x, ymin: y, ymax: y },
+                Some(b) => Bbox{
+                    xmin: if x < b.xmin { x } else { b.xmin },
+                    xmax: if x > b.xmax { x } else { b.xmax },
+                    ymin: if y < b.ymin { y } else { b.ymin },
+                    ymax: if y > b.ymax { y } else { b.ymax },
+                },
+            });
+        }
+    }
+
+    let mut result = None;
+    match *geom {
+        Geometry::Point(ref p) => add_points(&mut result, &[*p]),
+        Geometry::Line(ref l) => add_points(&mut result, &[l.start, l.end]),
+        Geometry::MultiPoint(ref mp) => add_points(&mut result, &mp.0),
+        Geometry::LineString(ref ls) => add_points(&mut result, &ls.0),
+        Geometry::MultiLineString(ref mls) => for ls in mls.0.iter() { add_points(&mut result, &ls.0); },
+        Geometry::Polygon(ref p) => add_points(&mut result, &p.exterior.0),
+        Geometry::MultiPolygon(ref mp) => for p in mp.0.iter() { add_points(&mut result, &p.exterior.0); },
+        Geometry::GeometryCollection(ref gc) => for g in gc.0.iter() {
+            if let Some(b) = geometry_bbox(g) {
+                add_points(&mut result, &[Point::new(b.xmin, b.ymin), Point::new(b.xmax, b.ymax)]);
+            }
+        },
+    }
+
+    result
+}
+
+/// Should this geometry be in this layer at this zoom? Layers with `labels_only_below` only
+/// include points (i.e. labels) at lower zooms.
+fn include_geometry_at_zoom<T: CoordinateType>(layer: &Layer, zoom: u8, geom: &Geometry<T>) -> bool {
+    match layer.labels_only_below {
+        Some(labels_only_below) if zoom < labels_only_below => match *geom {
+            Geometry::Point(_) | Geometry::MultiPoint(_) => true,
+            _ => false,
+        },
+        _ => true,
+    }
+}
+
+/// The geometries of a feature, with a GeometryCollection split into its points, lines & polygons
+/// (one geometry of each type, which can be a multi-geometry). Nested collections are flattened.
+/// Other geometries are returned as they are.
+fn split_geometry_collection<T: CoordinateType>(geom: Geometry<T>) -> Vec<Geometry<T>> {
+    fn flatten<T: CoordinateType>(geom: Geometry<T>, points: &mut Vec<Point<T>>, lines: &mut Vec<LineString<T>>, polygons: &mut Vec<Polygon<T>>) {
+        match geom {
+            Geometry::Point(p) => points.push(p),
+            Geometry::MultiPoint(mp) => points.extend(mp.0),
+            Geometry::Line(l) => lines.push(LineString(vec![l.start, l.end])),
+            Geometry::LineString(ls) => lines.push(ls),
+            Geometry::MultiLineString(mls) => lines.extend(mls.0),
+            Geometry::Polygon(p) => polygons.push(p),
+            Geometry::MultiPolygon(mp) => polygons.extend(mp.0),
+            Geometry::GeometryCollection(gc) => for g in gc.0 {
+                flatten(g, points, lines, polygons);
+            },
+        }
+    }
 
-        (x, y)
+    let gc = match geom {
+        Geometry::GeometryCollection(gc) => gc,
+        g => { return vec![g]; },
     };
 
-    let mut new_points: Vec<Point<_>> = Vec::with_capacity(ls.0.len());
-    let last_xy = remap_xy(ls.0[0].x(), ls.0[0].y());
+    let mut points = Vec::new();
+    let mut lines = Vec::new();
+    let mut polygons = Vec::new();
+    for g in gc.0 {
+        flatten(g, &mut points, &mut lines, &mut polygons);
+    }
+
+    let mut geoms = Vec::with_capacity(3);
+    match points.len() {
+        0 => {},
+        1 => geoms.push(Geometry::Point(points.remove(0))),
+        _ => geoms.push(Geometry::MultiPoint(MultiPoint(points))),
+    }
+    match lines.len() {
+        0 => {},
+        1 => geoms.push(Geometry::LineString(lines.remove(0))),
+        _ => geoms.push(Geometry::MultiLineString(MultiLineString(lines))),
+    }
+    match polygons.len() {
+        0 => {},
+        1 => geoms.push(Geometry::Polygon(polygons.remove(0))),
+        _ => geoms.push(Geometry::MultiPolygon(MultiPolygon(polygons))),
+    }
+    geoms
+}
+
+/// How source coordinates (in metres) become whole tile units.
+///
+/// Coordinates are first rounded in the whole world's tile units, and then the tile's origin is
+/// subtracted. So a source coordinate on the edge
+/// between 2 tiles is at exactly the same place on that edge in both of them, however it's
+/// rounded.
+#[derive(Debug,Clone,Copy)]
+struct Projection {
+    /// The point which is (0, 0) before the offset
+    origin: (f64, f64),
+    width: f64,
+    height: f64,
+    /// How many units `width`/`height` are
+    size: f64,
+    /// Subtracted from the rounded coordinates
+    offset: (i64, i64),
+    rounding: CoordinateRounding,
+    /// Keep lines which are all one point after rounding, as a 2 point line
+    keep_zero_length_lines: bool,
+}
+
+impl Projection {
+    /// Each tile of this metatile is `tile_size` units, with (0, 0) at the top left of the
+    /// metatile.
+    fn for_metatile(tile_matrix: &TileMatrix, metatile: &slippy_map_tiles::Metatile, tile_size: f64, geometry: &GeometryOptions) -> Self {
+        let width = tile_matrix.tile_width(metatile.zoom());
+        let units = tile_size as i64;
+        Projection{ origin: tile_matrix.origin, width, height: width, size: tile_size, offset: (metatile.x() as i64 * units, metatile.y() as i64 * units), rounding: geometry.coordinate_rounding, keep_zero_length_lines: geometry.validity.keep_zero_length_lines }
+    }
+
+    fn project(&self, x: f64, y: f64) -> (i32, i32) {
+        let conv = |v: f64, offset: i64| -> i32 {
+            let v = self.rounding.round(v) as i64 - offset;
+            debug_assert!(v <= i32::max_value() as i64);
+            debug_assert!(v >= i32::min_value() as i64);
+            v as i32
+        };
+
+        // y axies goes down, hence different ordering for y
+        (
+            conv(((x - self.origin.0) / self.width)*self.size, self.offset.0),
+            conv(((self.origin.1 - y) / self.height)*self.size, self.offset.1)
+        )
+    }
+}
+
+fn project_linestring(ls: LineString<f64>, projection: &Projection, should_be_ring: bool) -> Option<LineString<i32>> {
+    if ls.0.is_empty() {
+        return None;
+    }
+
+    let num_points = ls.0.len();
+    let mut new_points: Vec<Point<_>> = Vec::with_capacity(num_points);
+    let last_xy = projection.project(ls.0[0].x(), ls.0[0].y());
     let mut last_x = last_xy.0;
     let mut last_y = last_xy.1;
     new_points.push(Point::new(last_x, last_y));
 
     // Remap all points, but don't add a point if it's the same location as the last point.
     for p in ls.0.into_iter().skip(1) {
-        let new_xy = remap_xy(p.x(), p.y());
+        let new_xy = projection.project(p.x(), p.y());
         if ! ( new_xy.0 == last_x && new_xy.1 == last_y ) {
             last_x = new_xy.0;
             last_y = new_xy.1;
@@ -781,32 +2664,32 @@ fn remap_linestring(ls: LineString<f64>, minx: f64, maxx: f64, miny: f64, maxy:
     } else {
         if new_points.len() >= 2 {
             Some(LineString(new_points))
+        } else if num_points >= 2 && projection.keep_zero_length_lines {
+            // All the points are in the same place
+            let p = new_points[0];
+            Some(LineString(vec![p, p]))
         } else {
             None
         }
     }
 }
 
-fn remap_geometry(geom: Geometry<f64>, minx: f64, maxx: f64, miny: f64, maxy: f64, size: f64) -> Option<Geometry<i32>> {
-
-    fn conv(x: f64) -> i32 {
-        let x = x.round();
-        debug_assert!(x <= i32::max_value() as f64);
-        debug_assert!(x >= i32::min_value() as f64);
-
-        x as i32
-    }
-    
-    let remap_xy = |x: f64, y: f64| -> (i32, i32) {
-        (
-            conv(((x - minx) / (maxx - minx))*size),
-            conv(((maxy - y) / (maxy - miny))*size)
-        )
+fn project_geometry(geom: Geometry<f64>, projection: &Projection) -> Option<Geometry<i32>> {
+    let project_polygon = |p: Polygon<f64>| -> Option<Polygon<i32>> {
+        let Polygon{ exterior, interiors } = p;
+        match project_linestring(exterior, projection, true) {
+            // Exterior gets simplified away
+            None => None,
+            Some(exterior) => {
+                let interiors: Vec<LineString<_>> = interiors.into_iter().filter_map(|int| project_linestring(int, projection, true)).collect();
+                Some(Polygon::new(exterior, interiors))
+            }
+        }
     };
 
     match geom {
         Geometry::Point(p) => {
-            let xy = remap_xy(p.x(), p.y());
+            let xy = projection.project(p.x(), p.y());
             Some(Geometry::Point(Point::new(xy.0, xy.1)))
         },
         Geometry::MultiPoint(mp) => {
@@ -814,17 +2697,17 @@ fn remap_geometry(geom: Geometry<f64>, minx: f64, maxx: f64, miny: f64, maxy: f6
                 None
             } else {
                 Some(Geometry::MultiPoint(MultiPoint(mp.0.into_iter().map(|p| {
-                        let xy = remap_xy(p.x(), p.y());
+                        let xy = projection.project(p.x(), p.y());
                         Point::new(xy.0, xy.1)
                     }
                     ).collect::<Vec<Point<_>>>())))
             }
         },
         Geometry::LineString(ls) => {
-            remap_linestring(ls, minx, maxx, miny, maxy, size, false).and_then(|ls| Some(Geometry::LineString(ls)))
+            project_linestring(ls, projection, false).and_then(|ls| Some(Geometry::LineString(ls)))
         },
         Geometry::MultiLineString(mls) => {
-            let mut res: Vec<LineString<_>> = mls.0.into_iter().filter_map(|ls| remap_linestring(ls, minx, maxx, miny, maxy, size, false)).collect();
+            let mut res: Vec<LineString<_>> = mls.0.into_iter().filter_map(|ls| project_linestring(ls, projection, false)).collect();
             match res.len() {
                 0 => None,
                 1 => Some(Geometry::LineString(res.remove(0))),
@@ -832,28 +2715,10 @@ fn remap_geometry(geom: Geometry<f64>, minx: f64, maxx: f64, miny: f64, maxy: f6
             }
         },
         Geometry::Polygon(p) => {
-            let Polygon{ exterior, interiors } = p;
-            match remap_linestring(exterior, minx, maxx, miny, maxy, size, true) {
-                // Exterior gets simplified away
-                None => None,
-                Some(exterior) => {
-                    let interiors: Vec<LineString<_>> = interiors.into_iter().filter_map(|int| remap_linestring(int, minx, maxx, miny, maxy, size, true)).collect();
-                    Some(Geometry::Polygon(Polygon::new(exterior, interiors)))
-                }
-            }
+            project_polygon(p).map(Geometry::Polygon)
         }
         Geometry::MultiPolygon(mp) => {
-            let mut res: Vec<Polygon<_>> = mp.0.into_iter().filter_map(|p| {
-                let Polygon{ exterior, interiors } = p;
-                match remap_linestring(exterior, minx, maxx, miny, maxy, size, true) {
-                        // Exterior gets simplified away
-                    None => None,
-                    Some(exterior) => {
-                        let interiors: Vec<LineString<_>> = interiors.into_iter().filter_map(|int| remap_linestring(int, minx, maxx, miny, maxy, size, true)).collect();
-                        Some(Polygon::new(exterior, interiors))
-                    }
-                }
-            }).collect();
+            let mut res: Vec<Polygon<_>> = mp.0.into_iter().filter_map(|p| project_polygon(p)).collect();
 
             match res.len() {
                 0 => None,
@@ -868,12 +2733,10 @@ fn remap_geometry(geom: Geometry<f64>, minx: f64, maxx: f64, miny: f64, maxy: f6
 }
 
 fn x_to_lon<T: CoordinateType+Into<f64>>(x: T, extent: f64) -> f64 {
-    let earth_radius = 6378137.;
     let x: f64 = x.into();
-    let x = (x/extent) * (2.*20037508.34) - 20037508.34;
-    //let x = self.lon() * 20037508.34 / 180.;
+    let x = (x/extent) * (2.*MERCATOR_HALF_WIDTH) - MERCATOR_HALF_WIDTH;
 
-    (x/earth_radius).to_degrees()
+    (x/EARTH_RADIUS).to_degrees()
 }
 
 fn y_to_lat<T: CoordinateType+Into<f64>>(y: T, extent: f64) -> f64 {
@@ -890,13 +2753,11 @@ fn print_geom_as_geojson<T: CoordinateType+Into<f64>>(geom: &Geometry<T>, extent
     println!("{}", geom_as_geojson(geom, extent));
 }
 
-fn geom_as_geojson<T: CoordinateType+Into<f64>>(geom: &Geometry<T>, extent: f64) -> String {
+/// This geometry (in tile coordinates, where the world is `extent` wide) as GeoJSON, for
+/// debugging.
+pub fn geom_as_geojson<T: CoordinateType+Into<f64>>(geom: &Geometry<T>, extent: f64) -> String {
     let mut output = String::new();
 
-    let geojson = |ls: &LineString<T>| -> String {
-        format!("[{}]", ls.0.iter().map(|p| format!("[{}, {}]", x_to_lon(p.x(), extent), y_to_lat(p.y(), extent))).collect::<Vec<_>>().join(", "))
-    };
-
     let points_numbered = |p: &Vec<Point<T>>| -> String {
         p.iter().enumerate().filter_map(|(i, p)| {
             let col = match i / 100 {
@@ -924,52 +2785,46 @@ fn geom_as_geojson<T: CoordinateType+Into<f64>>(geom: &Geometry<T>, extent: f64)
     };
 
     write!(output, "\n{{\"type\":\"FeatureCollection\", \"features\":[").unwrap();
+    write!(output, "{{\"type\": \"Feature\", \"properties\":{{}}, \"geometry\":{}}}", geometry_as_geojson(geom, extent)).unwrap();
     match *geom {
-        Geometry::Polygon(ref poly) => {
-            write!(output, "{{\"type\": \"Feature\", \"properties\":{{}}, \"geometry\":{{ \"type\":\"Polygon\", \"coordinates\": [").unwrap();
-            write!(output, "{}", geojson(&poly.exterior)).unwrap();
-            if !poly.interiors.is_empty() {
-                write!(output, ", ").unwrap();
-                write!(output, "{}", poly.interiors.iter().map(|int| geojson(&int)).collect::<Vec<_>>().join(", ")).unwrap();
-            }
-            write!(output, "]}}}}").unwrap();
-            write!(output, ", {}", points_numbered(&poly.exterior.0)).unwrap();
-        },
-        Geometry::MultiPolygon(ref mp) => {
-            write!(output, "{{\"type\": \"Feature\", \"properties\":{{}}, \"geometry\":{{ \"type\":\"MultiPolygon\", \"coordinates\": [").unwrap();
-            let mut first = true;
-            for poly in mp.0.iter() {
-                if !first { write!(output, ", ").unwrap(); }
-                write!(output, "[").unwrap();
-                write!(output, "{}", geojson(&poly.exterior)).unwrap();
-                if !poly.interiors.is_empty() {
-                    write!(output, ", ").unwrap();
-                    write!(output, "{}", poly.interiors.iter().map(|int| geojson(&int)).collect::<Vec<_>>().join(", ")).unwrap();
-                }
-                write!(output, "]").unwrap();
-                first = false;
-            }
-            write!(output, "]}}}}").unwrap();
-        },
-        Geometry::LineString(ref ls) => {
-            write!(output, "{{\"type\": \"Feature\", \"properties\":{{}}, \"geometry\":{{ \"type\":\"LineString\", \"coordinates\": ").unwrap();
-            write!(output, "{}", geojson(&ls)).unwrap();
-            write!(output, "}}}}").unwrap();
-            write!(output, ", {}", points_numbered(&ls.0)).unwrap();
-        },
-        Geometry::MultiLineString(ref mls) => {
-            write!(output, "{{\"type\": \"Feature\", \"properties\":{{}}, \"geometry\":{{ \"type\":\"MultiLineString\", \"coordinates\": [").unwrap();
-            write!(output, "{}", mls.0.iter().map(geojson).collect::<Vec<_>>().join(", ")).unwrap();
-            write!(output, "]}}}}").unwrap();
-        },
-
-        _ => unimplemented!(),
+        Geometry::Polygon(ref poly) => { write!(output, ", {}", points_numbered(&poly.exterior.0)).unwrap(); },
+        Geometry::LineString(ref ls) => { write!(output, ", {}", points_numbered(&ls.0)).unwrap(); },
+        _ => {},
     }
     write!(output, "]}}\n").unwrap();
 
     output
 }
 
+/// Just the GeoJSON geometry object (e.g. `{"type":"Polygon", "coordinates": ...}`) of this
+/// geometry, which is in tile coordinates, where the world is `extent` wide.
+pub fn geometry_as_geojson<T: CoordinateType+Into<f64>>(geom: &Geometry<T>, extent: f64) -> String {
+    let point = |p: &Point<T>| -> String {
+        format!("[{}, {}]", x_to_lon(p.x(), extent), y_to_lat(p.y(), extent))
+    };
+    let geojson = |ls: &LineString<T>| -> String {
+        format!("[{}]", ls.0.iter().map(&point).collect::<Vec<_>>().join(", "))
+    };
+    let polygon = |poly: &Polygon<T>| -> String {
+        format!("[{}]", Some(&poly.exterior).into_iter().chain(poly.interiors.iter()).map(&geojson).collect::<Vec<_>>().join(", "))
+    };
+
+    let (type_, coordinates) = match *geom {
+        Geometry::Point(ref p) => ("Point", point(p)),
+        Geometry::MultiPoint(ref mp) => ("MultiPoint", format!("[{}]", mp.0.iter().map(&point).collect::<Vec<_>>().join(", "))),
+        Geometry::LineString(ref ls) => ("LineString", geojson(ls)),
+        Geometry::MultiLineString(ref mls) => ("MultiLineString", format!("[{}]", mls.0.iter().map(&geojson).collect::<Vec<_>>().join(", "))),
+        Geometry::Polygon(ref poly) => ("Polygon", polygon(poly)),
+        Geometry::MultiPolygon(ref mp) => ("MultiPolygon", format!("[{}]", mp.0.iter().map(&polygon).collect::<Vec<_>>().join(", "))),
+        Geometry::Line(ref l) => ("LineString", format!("[{}, {}]", point(&l.start), point(&l.end))),
+        Geometry::GeometryCollection(ref gc) => {
+            let geometries = gc.0.iter().map(|g| geometry_as_geojson(g, extent)).collect::<Vec<_>>().join(", ");
+            return format!("{{ \"type\":\"GeometryCollection\", \"geometries\": [{}]}}", geometries);
+        },
+    };
+    format!("{{ \"type\":\"{}\", \"coordinates\": {}}}", type_, coordinates)
+}
+
 #[derive(Debug)]
 pub struct LocalBBox<T: num_traits::Float+Into<f64>>(T, T, T, T);
 //let bbox = format!("ST_SetSRID(ST_MakeBox2D(ST_Point({llx}, {lly}), ST_Point({urx}, {ury})), 3857)", llx=(minx-buffer_width), lly=(miny-buffer_height), urx=(maxx+buffer_width), ury=(maxy+buffer_height));
@@ -0,0 +1,57 @@
+//! Setting up logging for the binaries, so they all have the same `-v`/`-q` flags & output.
+use std::env;
+use std::io::Write;
+
+use env_logger;
+use env_logger::Color;
+use log::{Level, LevelFilter};
+
+/// What log level to use for these command line flags. Warnings & errors are shown by default.
+/// `-v` adds info, `-vv` debug, and `-vvv` trace. `-q` only shows errors.
+pub fn log_level_for_verbosity(verbose: u64, quiet: bool) -> LevelFilter {
+    if quiet {
+        return LevelFilter::Error;
+    }
+    match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Start the logger, at the level for these command line flags. `RUST_LOG` can still be used to
+/// override it, e.g. for one module.
+pub fn init_logging(verbose: u64, quiet: bool) {
+    let mut builder = env_logger::Builder::new();
+    builder.filter(None, log_level_for_verbosity(verbose, quiet));
+    if let Ok(rust_log) = env::var("RUST_LOG") {
+        builder.parse(&rust_log);
+    }
+
+    builder
+        .format(|buf, record| {
+            let level = record.level();
+            let mut level_style = buf.style();
+
+            match level {
+                Level::Trace => level_style.set_color(Color::White),
+                Level::Debug => level_style.set_color(Color::Blue),
+                Level::Info => level_style.set_color(Color::Green),
+                Level::Warn => level_style.set_color(Color::Yellow),
+                Level::Error => level_style.set_color(Color::Red).set_bold(true),
+            };
+
+            write!(buf, "{:>5} ", level_style.value(level)).unwrap();
+
+            let ts = buf.timestamp();
+            write!(buf, "{}: ", ts).unwrap();
+            write!(buf, "{}", record.module_path().unwrap_or("UNKNOWN_MOD")).unwrap();
+            write!(buf, ":L{} ", record.line().map(|l| format!("{}", l)).unwrap_or("UNKNOWN_LINE".to_string())).unwrap();
+
+            writeln!(buf, "{}", record.args()).unwrap();
+
+            Ok(())
+        })
+        .init();
+}
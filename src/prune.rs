@@ -0,0 +1,176 @@
+//! Skipping tiles whose parent tile was empty.
+//!
+//! Over the sea (or outside the area of the data), every high zoom tile would be empty, and there
+//! are millions of them. If a tile has no features, then (usually) all the tiles inside it on
+//! higher zooms will have none either, so we don't need to generate them.
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use slippy_map_tiles::{Metatile, Tile};
+
+use ::MAX_ZOOM;
+use input::Layers;
+
+/// The tiles we know to be empty, so their descendants can be skipped.
+pub struct EmptyTiles {
+    empty: Mutex<EmptyByZoom>,
+
+    /// Can we skip a tile on this zoom, if its parent was empty?
+    can_prune_zoom: Vec<bool>,
+
+    max_zoom: u8,
+}
+
+/// The (x, y) of the empty tiles on each zoom. Metatiles are generated in zoom order, so once
+/// one on zoom z is checked, the zooms below z-1 aren't needed any more, and are forgotten. Only
+/// 2 zooms are kept at a time.
+struct EmptyByZoom {
+    tiles: Vec<HashSet<(u32, u32)>>,
+
+    /// Zooms below this have been forgotten
+    forgotten_below: u8,
+}
+
+impl EmptyByZoom {
+    /// Forget the empty tiles on zooms below `zoom`.
+    fn forget_below(&mut self, zoom: u8) {
+        for z in self.forgotten_below..zoom {
+            // Replaced, rather than cleared, to free the memory
+            self.tiles[z as usize] = HashSet::new();
+        }
+        self.forgotten_below = self.forgotten_below.max(zoom);
+    }
+}
+
+impl EmptyTiles {
+    /// Start with no tiles known to be empty. Pruning isn't done on a zoom where a layer could
+    /// have data that its parent zoom doesn't have, i.e. on a layer's minzoom, when a layer starts
+    /// including lines & polygons (`labels_only_below`), or for a layer which turns pruning off.
+    pub fn new(layers: &Layers, max_zoom: u8) -> Self {
+        let can_prune_zoom = (0..MAX_ZOOM+1).map(|z| {
+            z > 0 && layers.layers.iter().all(|layer| {
+                let layer_maxzoom = layer.maxzoom.min(layers.global_maxzoom);
+                let active = |zoom: u8| layer.minzoom <= zoom && zoom <= layer_maxzoom;
+                let all_geometries = |zoom: u8| layer.labels_only_below.map(|b| zoom >= b).unwrap_or(true);
+
+                if ! active(z) {
+                    true
+                } else {
+                    layer.prune_empty_descendants && active(z-1) && (all_geometries(z-1) || ! all_geometries(z))
+                }
+            })
+        }).collect();
+
+        let empty = EmptyByZoom{ tiles: (0..MAX_ZOOM+1).map(|_| HashSet::new()).collect(), forgotten_below: 0 };
+        EmptyTiles{ empty: Mutex::new(empty), can_prune_zoom: can_prune_zoom, max_zoom: max_zoom }
+    }
+
+    /// Is every tile in this metatile inside a tile which was empty? The empty tiles of the zooms
+    /// below the parent zoom are forgotten, so this must be called for each metatile (in zoom
+    /// order) before it's generated. A metatile which is still being generated on a forgotten
+    /// zoom isn't skipped.
+    pub fn can_skip(&self, metatile: &Metatile) -> bool {
+        let zoom = metatile.zoom();
+        let mut empty = self.empty.lock().unwrap();
+        if zoom >= 1 {
+            empty.forget_below(zoom-1);
+        }
+
+        if ! self.can_prune_zoom.get(zoom as usize).cloned().unwrap_or(false) {
+            return false;
+        }
+
+        let parents = &empty.tiles[(zoom-1) as usize];
+        metatile.tiles().iter().all(|t| parents.contains(&(t.x()/2, t.y()/2)))
+    }
+
+    /// Record that this tile has no features.
+    pub fn add_empty_tile(&self, tile: &Tile) {
+        // Nothing is generated below the max zoom, so no need to remember these.
+        if tile.zoom() >= self.max_zoom {
+            return;
+        }
+        let mut empty = self.empty.lock().unwrap();
+        if tile.zoom() < empty.forgotten_below {
+            return;
+        }
+        empty.tiles[tile.zoom() as usize].insert((tile.x(), tile.y()));
+    }
+
+    /// Record that all the tiles in this metatile are empty, e.g. because it was skipped.
+    pub fn add_empty_metatile(&self, metatile: &Metatile) {
+        for tile in metatile.tiles().iter() {
+            self.add_empty_tile(tile);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use input::{Layer, TableSQL};
+
+    fn layers(layers: Vec<Layer>) -> Layers {
+        Layers{ layers: layers, global_minzoom: 0, global_maxzoom: 14, bounds: None, center: None, name: None, description: None }
+    }
+
+    fn layer(minzoom: u8) -> Layer {
+        Layer{
+            minzoom: minzoom, maxzoom: 14, buffer: 0, id: "water".to_string(), name: "water".to_string(),
+            labels_only_below: None, preserve_detail_below_length: None, min_hole_area: None,
+            prune_empty_descendants: true, max_features: None, max_vertices_per_feature: None, simplify_topology: false, oversample: 1, snap_tile_edges: None, simplify_max_zoom: None, background: None, split_by: None,
+            include_attributes: None, exclude_attributes: vec![], filter: None,
+            table: TableSQL::new("(select way from water) as t".to_string()), dbname: None, geopackage: None, flatgeobuf: None, inline_features: None,
+        }
+    }
+
+    #[test]
+    fn empty_parent_skips_descendants() {
+        let empty_tiles = EmptyTiles::new(&layers(vec![layer(0)]), 14);
+
+        // Metatile 6/0/0 had no data.
+        let z6 = Metatile::new(8, 6, 0, 0).unwrap();
+        assert!(!empty_tiles.can_skip(&z6));
+        empty_tiles.add_empty_metatile(&z6);
+
+        // So all 4 z7 metatiles inside it are skipped
+        let z7: Vec<_> = [(0, 0), (8, 0), (0, 8), (8, 8)].iter().map(|&(x, y)| Metatile::new(8, 7, x, y).unwrap()).collect();
+        for mt in z7.iter() {
+            assert!(empty_tiles.can_skip(mt));
+            empty_tiles.add_empty_metatile(mt);
+        }
+        // and so are their z8 ones
+        assert!(empty_tiles.can_skip(&Metatile::new(8, 8, 0, 0).unwrap()));
+        assert!(empty_tiles.can_skip(&Metatile::new(8, 8, 24, 24).unwrap()));
+
+        // Outside it isn't
+        assert!(!empty_tiles.can_skip(&Metatile::new(8, 7, 16, 0).unwrap()));
+        assert!(!empty_tiles.can_skip(&Metatile::new(8, 8, 32, 0).unwrap()));
+
+        // Once z8 has started, z6 is forgotten, and empty tiles on it aren't remembered
+        assert!(empty_tiles.empty.lock().unwrap().tiles[6].is_empty());
+        empty_tiles.add_empty_tile(&Tile::new(6, 1, 1).unwrap());
+        assert!(empty_tiles.empty.lock().unwrap().tiles[6].is_empty());
+        assert_eq!(empty_tiles.empty.lock().unwrap().tiles[7].len(), 16*16);
+
+        // Only partly empty parent
+        let empty_tiles = EmptyTiles::new(&layers(vec![layer(0)]), 14);
+        empty_tiles.add_empty_tile(&Tile::new(6, 0, 0).unwrap());
+        assert!(!empty_tiles.can_skip(&Metatile::new(8, 7, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn layers_starting_at_high_zoom() {
+        // A layer starting at z7 could have data under an empty z6 tile
+        let empty_tiles = EmptyTiles::new(&layers(vec![layer(0), layer(7)]), 14);
+        empty_tiles.add_empty_metatile(&Metatile::new(8, 6, 0, 0).unwrap());
+        assert!(!empty_tiles.can_skip(&Metatile::new(8, 7, 0, 0).unwrap()));
+
+        // Layers can turn it off
+        let mut no_pruning = layer(0);
+        no_pruning.prune_empty_descendants = false;
+        let empty_tiles = EmptyTiles::new(&layers(vec![no_pruning]), 14);
+        empty_tiles.add_empty_metatile(&Metatile::new(8, 6, 0, 0).unwrap());
+        assert!(!empty_tiles.can_skip(&Metatile::new(8, 7, 0, 0).unwrap()));
+    }
+}
@@ -0,0 +1,173 @@
+//! Retrying database queries which fail for temporary reasons.
+//!
+//! On a busy PostGIS server, queries can fail because of a deadlock, a lock timeout, or the
+//! connection being reset. Trying again (after a short wait) usually works, so we don't want to
+//! lose the whole metatile. Errors like SQL syntax errors will never work, so those fail at once.
+//! Neither will a query which hit `statement_timeout`: it'll be just as slow the next time.
+use std::thread;
+use std::time::Duration;
+use std::fmt::Display;
+
+use postgres;
+use postgres::error::SqlState;
+
+/// How many times to retry, and how long to wait between retries.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct RetryPolicy {
+    /// How many times to retry after the first attempt. 0 = never retry.
+    pub retries: u32,
+
+    /// How long to wait before the first retry. This doubles for every retry after that.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy{ retries: 3, backoff: Duration::from_millis(500) }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry.
+    pub fn none() -> Self {
+        RetryPolicy{ retries: 0, backoff: Duration::from_millis(0) }
+    }
+
+    /// How long to wait before retry number `attempt` (starting from 0).
+    fn backoff_for(&self, attempt: u32) -> Duration {
+        // Cap the shift so we can't overflow with silly numbers of retries
+        self.backoff * 2u32.pow(attempt.min(16))
+    }
+
+    /// Call `f` until it succeeds, it returns an error that `is_transient` says is not transient,
+    /// or we have run out of retries. `before_retry` is called (with the error) before each
+    /// retry, e.g. to reconnect.
+    pub fn run<T, E, F, C, B>(&self, what: &str, is_transient: C, mut before_retry: B, mut f: F) -> Result<T, E>
+        where F: FnMut() -> Result<T, E>,
+              C: Fn(&E) -> bool,
+              B: FnMut(&E),
+              E: Display,
+    {
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Ok(x) => { return Ok(x); },
+                Err(e) => {
+                    if attempt >= self.retries || ! is_transient(&e) {
+                        return Err(e);
+                    }
+                    let wait = self.backoff_for(attempt);
+                    warn!("{} failed (attempt {} of {}), retrying in {:?}: {}", what, attempt+1, self.retries+1, wait, e);
+                    thread::sleep(wait);
+                    before_retry(&e);
+                    attempt += 1;
+                },
+            }
+        }
+    }
+}
+
+/// Is this database error likely to go away if we try again?
+pub fn is_transient_db_error(err: &postgres::Error) -> bool {
+    if err.as_io().is_some() {
+        // Connection reset, broken pipe etc.
+        return true;
+    }
+
+    match err.code() {
+        None => false,
+        Some(code) => is_transient_sqlstate(code),
+    }
+}
+
+fn is_transient_sqlstate(code: &SqlState) -> bool {
+    use postgres::error::*;
+    let transient = [
+        SERIALIZATION_FAILURE, DEADLOCK_DETECTED, LOCK_NOT_AVAILABLE,
+        ADMIN_SHUTDOWN, CRASH_SHUTDOWN, CANNOT_CONNECT_NOW, TOO_MANY_CONNECTIONS,
+        CONNECTION_EXCEPTION, CONNECTION_FAILURE,
+    ];
+    transient.contains(code)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use std::fmt;
+
+    #[derive(Debug,PartialEq)]
+    enum MockError {
+        Timeout,
+        Syntax,
+    }
+
+    impl fmt::Display for MockError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{:?}", self)
+        }
+    }
+
+    fn is_transient(e: &MockError) -> bool {
+        *e == MockError::Timeout
+    }
+
+    /// A 'data source' which fails with this error this many times, then returns some rows
+    fn mock_source(failures: u32, error: MockError) -> impl FnMut() -> Result<Vec<u32>, MockError> {
+        let mut calls = 0;
+        let mut error = Some(error);
+        move || {
+            calls += 1;
+            if calls <= failures {
+                Err(if calls == failures { error.take().unwrap() } else { MockError::Timeout })
+            } else {
+                Ok(vec![1, 2, 3])
+            }
+        }
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        let policy = RetryPolicy{ retries: 3, backoff: Duration::from_millis(1) };
+        let retried = Cell::new(0);
+        let result = policy.run("mock", is_transient, |_| retried.set(retried.get()+1), mock_source(2, MockError::Timeout));
+        assert_eq!(result, Ok(vec![1, 2, 3]));
+        assert_eq!(retried.get(), 2);
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let policy = RetryPolicy{ retries: 1, backoff: Duration::from_millis(1) };
+        let result = policy.run("mock", is_transient, |_| {}, mock_source(2, MockError::Timeout));
+        assert_eq!(result, Err(MockError::Timeout));
+
+        let result = RetryPolicy::none().run("mock", is_transient, |_| {}, mock_source(1, MockError::Timeout));
+        assert_eq!(result, Err(MockError::Timeout));
+    }
+
+    #[test]
+    fn non_transient_errors_fail_fast() {
+        let policy = RetryPolicy{ retries: 3, backoff: Duration::from_millis(1) };
+        let retried = Cell::new(0);
+        let result = policy.run("mock", is_transient, |_| retried.set(retried.get()+1), mock_source(1, MockError::Syntax));
+        assert_eq!(result, Err(MockError::Syntax));
+        assert_eq!(retried.get(), 0);
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let policy = RetryPolicy{ retries: 3, backoff: Duration::from_millis(100) };
+        assert_eq!(policy.backoff_for(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff_for(1), Duration::from_millis(200));
+        assert_eq!(policy.backoff_for(2), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn statement_timeout_isnt_transient() {
+        use postgres::error::*;
+        assert!(is_transient_sqlstate(&DEADLOCK_DETECTED));
+        assert!(is_transient_sqlstate(&LOCK_NOT_AVAILABLE));
+        assert!(!is_transient_sqlstate(&QUERY_CANCELED));
+        assert!(!is_transient_sqlstate(&SYNTAX_ERROR));
+    }
+}
@@ -0,0 +1,69 @@
+//! How source coordinates are rounded to whole tile units.
+
+/// How to round a coordinate (in tile units) to a whole number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoordinateRounding {
+    /// To the nearest, with halves away from 0
+    Round,
+
+    /// Down, towards -∞
+    Floor,
+
+    /// To the nearest, with halves to the even number, so halves aren't all pushed the same way
+    RoundHalfEven,
+}
+
+impl Default for CoordinateRounding {
+    fn default() -> Self {
+        CoordinateRounding::Round
+    }
+}
+
+impl ::std::str::FromStr for CoordinateRounding {
+    type Err = ::failure::Error;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "round" => Ok(CoordinateRounding::Round),
+            "floor" => Ok(CoordinateRounding::Floor),
+            "round-half-even" => Ok(CoordinateRounding::RoundHalfEven),
+            x => Err(format_err!("Unknown coordinate rounding {:?}, must be 'round', 'floor' or 'round-half-even'", x)),
+        }
+    }
+}
+
+impl CoordinateRounding {
+    pub fn round(&self, x: f64) -> f64 {
+        match *self {
+            CoordinateRounding::Round => x.round(),
+            CoordinateRounding::Floor => x.floor(),
+            CoordinateRounding::RoundHalfEven => {
+                let floor = x.floor();
+                if x - floor != 0.5 {
+                    x.round()
+                } else if floor % 2. == 0. {
+                    floor
+                } else {
+                    floor + 1.
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rounding() {
+        let round = |mode: &str, x: f64| mode.parse::<CoordinateRounding>().unwrap().round(x);
+        for &(x, nearest, floor, half_even) in [(2.4, 2., 2., 2.), (2.5, 3., 2., 2.), (3.5, 4., 3., 4.), (2.6, 3., 2., 3.),
+                                                (-2.5, -3., -3., -2.), (-3.5, -4., -4., -4.), (-0.4, -0., -1., -0.), (7., 7., 7., 7.)].iter() {
+            assert_eq!(round("round", x), nearest, "{}", x);
+            assert_eq!(round("floor", x), floor, "{}", x);
+            assert_eq!(round("round-half-even", x), half_even, "{}", x);
+        }
+        assert!("ceil".parse::<CoordinateRounding>().is_err());
+    }
+}
@@ -4,6 +4,36 @@ use std::ops::{DivAssign,Rem,Mul,AddAssign};
 
 use geo::*;
 
+/// Whether to simplify geometries before, or after, they are clipped to the metatile.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum SimplifyStage {
+    /// Simplify the whole geometry, then clip it. Neighbouring (meta)tiles simplify the same
+    /// source geometry the same way, so there are no seams at the tile edges.
+    BeforeClip,
+
+    /// Clip the geometry, then simplify what's left. Faster for large geometries, but the
+    /// simplified lines might not meet up at the tile edges.
+    AfterClip,
+}
+
+impl Default for SimplifyStage {
+    fn default() -> Self {
+        SimplifyStage::BeforeClip
+    }
+}
+
+impl ::std::str::FromStr for SimplifyStage {
+    type Err = ::failure::Error;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "before-clip" => Ok(SimplifyStage::BeforeClip),
+            "after-clip" => Ok(SimplifyStage::AfterClip),
+            x => Err(format_err!("Unknown simplify stage {:?}, must be 'before-clip' or 'after-clip'", x)),
+        }
+    }
+}
+
 /// We have a fraction a²/b², but we currently only have a & b². We want to reduce this fraction by
 /// removing common multiples so that the fraction is the. It returns the new (a, b²).
 /// The results of this will be used later to make the fraction when we calculate a², and we want
@@ -154,26 +184,62 @@ fn rdp(mut points: Vec<Point<i32>>, epsilon: i32) -> Vec<Point<i32>> {
     return new_points;
 }
 
+/// Like `rdp`, but points at either end of a segment shorter than `preserve_below` are always
+/// kept. The line is split at those points, and each part simplified on its own.
+fn rdp_preserve_short_segments(points: Vec<Point<i32>>, epsilon: i32, preserve_below: i32) -> Vec<Point<i32>> {
+    if preserve_below <= 0 || points.len() <= 2 {
+        return rdp(points, epsilon);
+    }
+
+    let num_points = points.len();
+    let preserve_below_sqr = (preserve_below as i64).pow(2);
+    let is_short: Vec<bool> = points.windows(2).map(|p| distance_sqr(&p[0], &p[1]) < preserve_below_sqr).collect();
+    let keep = |i: usize| (i > 0 && is_short[i-1]) || (i < num_points-1 && is_short[i]);
+
+    let mut new_points = Vec::with_capacity(num_points);
+    let mut start = 0;
+    for i in 1..num_points {
+        if i == num_points-1 || keep(i) {
+            let mut part = rdp(points[start..i+1].to_vec(), epsilon);
+            if ! new_points.is_empty() {
+                // The first point is the same as the last point of the previous part
+                part.remove(0);
+            }
+            new_points.append(&mut part);
+            start = i;
+        }
+    }
+
+    new_points
+}
+
 pub fn simplify(geom: Geometry<i32>, epsilon: i32) -> Option<Geometry<i32>> {
+    simplify_preserve_short_segments(geom, epsilon, 0)
+}
+
+/// Simplify this geometry, but keep all the points of segments shorter than `preserve_below`,
+/// e.g. the small kinks in a railway line that a dash pattern needs. 0 means simplify
+/// everything.
+pub fn simplify_preserve_short_segments(geom: Geometry<i32>, epsilon: i32, preserve_below: i32) -> Option<Geometry<i32>> {
     match geom {
         // Can't simplify a Point. let's hope this doesn't do a copy or memory move or something
         Geometry::Point(p) => Some(Geometry::Point(p)),
         Geometry::MultiPoint(p) => Some(Geometry::MultiPoint(p)),
 
-        Geometry::LineString(ls) => simplify_linestring(ls, epsilon, false).map(|g| g.into()),
+        Geometry::LineString(ls) => simplify_linestring(ls, epsilon, preserve_below, false).map(|g| g.into()),
         Geometry::Line(_) => unimplemented!(),
-        Geometry::MultiLineString(mls) => simplify_multilinestring(mls, epsilon).map(|g| g.into()),
-        Geometry::Polygon(p) => simplify_polygon(p, epsilon).map(|g| g.into()),
-        Geometry::MultiPolygon(mp) => simplify_multipolygon(mp, epsilon).map(|g| g.into()),
+        Geometry::MultiLineString(mls) => simplify_multilinestring(mls, epsilon, preserve_below).map(|g| g.into()),
+        Geometry::Polygon(p) => simplify_polygon(p, epsilon, preserve_below).map(|g| g.into()),
+        Geometry::MultiPolygon(mp) => simplify_multipolygon(mp, epsilon, preserve_below).map(|g| g.into()),
 
         Geometry::GeometryCollection(_) => unimplemented!(),
     }
 }
 
-fn simplify_linestring(geom: LineString<i32>, epsilon: i32, should_be_ring: bool) -> Option<LineString<i32>> {
+fn simplify_linestring(geom: LineString<i32>, epsilon: i32, preserve_below: i32, should_be_ring: bool) -> Option<LineString<i32>> {
     //println!("{} L {}", file!(), line!());
     let LineString(points) = geom;
-    let new_points = rdp(points, epsilon);
+    let new_points = rdp_preserve_short_segments(points, epsilon, preserve_below);
 
     if should_be_ring {
         if new_points.len() >= 4 && new_points[0] == new_points[new_points.len()-1] { 
@@ -190,23 +256,23 @@ fn simplify_linestring(geom: LineString<i32>, epsilon: i32, should_be_ring: bool
     }
 }
 
-fn simplify_multilinestring(geom: MultiLineString<i32>, epsilon: i32) -> Option<MultiLineString<i32>> {
-    Some(MultiLineString(geom.0.into_iter().filter_map(|l| simplify_linestring(l, epsilon, false)).collect()))
+fn simplify_multilinestring(geom: MultiLineString<i32>, epsilon: i32, preserve_below: i32) -> Option<MultiLineString<i32>> {
+    Some(MultiLineString(geom.0.into_iter().filter_map(|l| simplify_linestring(l, epsilon, preserve_below, false)).collect()))
 }
 
-fn simplify_polygon(geom: Polygon<i32>, epsilon: i32) -> Option<Polygon<i32>> {
+fn simplify_polygon(geom: Polygon<i32>, epsilon: i32, preserve_below: i32) -> Option<Polygon<i32>> {
     let Polygon{ exterior, interiors } = geom;
-    match simplify_linestring(exterior, epsilon, true) {
+    match simplify_linestring(exterior, epsilon, preserve_below, true) {
         None => None,
         Some(new_exterior) => {
-            Some(Polygon::new(new_exterior, interiors.into_iter().filter_map(|l| simplify_linestring(l, epsilon, true)).collect()))
+            Some(Polygon::new(new_exterior, interiors.into_iter().filter_map(|l| simplify_linestring(l, epsilon, preserve_below, true)).collect()))
         }
     }
 
 }
 
-fn simplify_multipolygon(geom: MultiPolygon<i32>, epsilon: i32) -> Option<MultiPolygon<i32>> {
-    let new_polygons: Vec<_> = geom.0.into_iter().filter_map(|p| simplify_polygon(p, epsilon)).collect();
+fn simplify_multipolygon(geom: MultiPolygon<i32>, epsilon: i32, preserve_below: i32) -> Option<MultiPolygon<i32>> {
+    let new_polygons: Vec<_> = geom.0.into_iter().filter_map(|p| simplify_polygon(p, epsilon, preserve_below)).collect();
     if new_polygons.is_empty() {
         None
     } else {
@@ -214,6 +280,241 @@ fn simplify_multipolygon(geom: MultiPolygon<i32>, epsilon: i32) -> Option<MultiP
     }
 }
 
+/// How many vertices (points) this geometry has, in all its parts.
+pub fn num_vertices(geom: &Geometry<i32>) -> usize {
+    fn polygon_vertices(p: &Polygon<i32>) -> usize {
+        p.exterior.0.len() + p.interiors.iter().map(|i| i.0.len()).sum::<usize>()
+    }
+
+    match *geom {
+        Geometry::Point(_) => 1,
+        Geometry::MultiPoint(ref mp) => mp.0.len(),
+        Geometry::Line(_) => 2,
+        Geometry::LineString(ref ls) => ls.0.len(),
+        Geometry::MultiLineString(ref mls) => mls.0.iter().map(|ls| ls.0.len()).sum(),
+        Geometry::Polygon(ref p) => polygon_vertices(p),
+        Geometry::MultiPolygon(ref mp) => mp.0.iter().map(polygon_vertices).sum(),
+        Geometry::GeometryCollection(ref gc) => gc.0.iter().map(num_vertices).sum(),
+    }
+}
+
+/// If this geometry has more than `max_vertices` vertices, simplify it more & more (doubling the
+/// tolerance each time) until it has few enough. `None` if it can't be simplified that far and
+/// stay valid (e.g. a polygon simplified away, or lots of points).
+pub fn simplify_to_max_vertices(geom: Geometry<i32>, max_vertices: usize) -> Option<Geometry<i32>> {
+    if num_vertices(&geom) <= max_vertices {
+        return Some(geom);
+    }
+
+    let mut epsilon = 8;
+    // A tolerance of a whole tile leaves (almost) nothing, so there's no point going further
+    while epsilon <= 4096 {
+        match simplify(geom.clone(), epsilon) {
+            None => { return None; },
+            Some(simplified) => {
+                if num_vertices(&simplified) <= max_vertices && ::validity::is_valid(&simplified) {
+                    return Some(simplified);
+                }
+            },
+        }
+        epsilon *= 2;
+    }
+
+    None
+}
+
+/// Simplify this (closed, maybe invalid) ring, more & more, until it has at most `max_vertices`
+/// points. It's never simplified so far that it stops being a ring (fewer than 4 points), so it
+/// can be left with more than `max_vertices`. Unlike `simplify_to_max_vertices`, the result isn't
+/// checked for validity.
+pub fn simplify_ring_to_max_vertices(ring: LineString<i32>, max_vertices: usize) -> LineString<i32> {
+    let mut ring = ring;
+    let mut epsilon = 1;
+    // A tolerance of a whole tile leaves (almost) nothing, so there's no point going further
+    while ring.0.len() > max_vertices && epsilon <= 4096 {
+        let simplified = rdp(ring.0.clone(), epsilon);
+        if simplified.len() < 4 {
+            break;
+        }
+        ring = LineString(simplified);
+        epsilon *= 2;
+    }
+
+    ring
+}
+
+/// An edge, with its ends in a fixed order, so it's the same whichever way a ring goes along it.
+type Edge = ((i32, i32), (i32, i32));
+
+fn edge(a: &Point<i32>, b: &Point<i32>) -> Edge {
+    let (a, b) = ((a.x(), a.y()), (b.x(), b.y()));
+    if a <= b { (a, b) } else { (b, a) }
+}
+
+/// All the polygon rings in this geometry, exteriors before their interiors.
+fn polygon_rings(geom: &Geometry<i32>) -> Vec<&LineString<i32>> {
+    let polygons: Vec<&Polygon<i32>> = match *geom {
+        Geometry::Polygon(ref p) => vec![p],
+        Geometry::MultiPolygon(ref mp) => mp.0.iter().collect(),
+        _ => vec![],
+    };
+    polygons.into_iter().flat_map(|p| ::std::iter::once(&p.exterior).chain(p.interiors.iter())).collect()
+}
+
+/// Simplify this arc (which keeps its ends), the same way whichever direction it goes in, and
+/// only once, no matter how many rings it's part of.
+fn simplify_arc(arc: &[Point<i32>], cache: &mut ::std::collections::HashMap<Vec<(i32, i32)>, Vec<(i32, i32)>>, epsilon: i32, preserve_below: i32) -> Vec<Point<i32>> {
+    let coords: Vec<(i32, i32)> = arc.iter().map(|p| (p.x(), p.y())).collect();
+    let mut reversed = coords.clone();
+    reversed.reverse();
+    let is_reversed = reversed < coords;
+    let key = if is_reversed { reversed } else { coords };
+
+    let mut simplified = cache.entry(key.clone()).or_insert_with(|| {
+        rdp_preserve_short_segments(key.iter().map(|&(x, y)| Point::new(x, y)).collect(), epsilon, preserve_below)
+            .into_iter().map(|p| (p.x(), p.y())).collect()
+    }).clone();
+    if is_reversed {
+        simplified.reverse();
+    }
+
+    simplified.into_iter().map(|(x, y)| Point::new(x, y)).collect()
+}
+
+/// Simplify this ring, ring number `ring_id`. It's split into arcs where the rings that share its
+/// edges change, and those points are kept.
+fn simplify_ring_topology(ring: LineString<i32>, ring_id: usize, owners: &::std::collections::HashMap<Edge, Vec<usize>>, cache: &mut ::std::collections::HashMap<Vec<(i32, i32)>, Vec<(i32, i32)>>, epsilon: i32, preserve_below: i32) -> Option<LineString<i32>> {
+    let LineString(points) = ring;
+    if points.len() < 4 {
+        return None;
+    }
+    let num_edges = points.len() - 1;
+
+    let nodes: Vec<usize> = {
+        let owners_of = |e: usize| owners.get(&edge(&points[e], &points[e+1]));
+        (0..num_edges).filter(|&j| {
+            let before = if j == 0 { num_edges - 1 } else { j - 1 };
+            owners_of(before) != owners_of(j)
+        }).collect()
+    };
+
+    if nodes.is_empty() {
+        // Doesn't share any of its boundary with another ring (or shares all of it), so there's
+        // nothing to keep in step
+        trace!("Ring {} has no shared arcs", ring_id);
+        return simplify_linestring(LineString(points), epsilon, preserve_below, true);
+    }
+
+    // Go around the ring from the first node, so every arc is a slice
+    let start = nodes[0];
+    let rotated: Vec<Point<i32>> = (0..num_edges+1).map(|k| points[(start + k) % num_edges]).collect();
+    let mut node_positions: Vec<usize> = nodes.iter().map(|&j| j - start).collect();
+    node_positions.push(num_edges);
+
+    let mut new_points = vec![rotated[0]];
+    for w in node_positions.windows(2) {
+        let arc = simplify_arc(&rotated[w[0]..w[1]+1], cache, epsilon, preserve_below);
+        new_points.extend(arc.into_iter().skip(1));
+    }
+
+    if new_points.len() >= 4 {
+        Some(LineString(new_points))
+    } else {
+        None
+    }
+}
+
+/// Simplify these geometries together (e.g. all the features of a layer in a tile), so that
+/// boundaries shared by polygons (e.g. neighbouring countries) are simplified the same way in
+/// both, and there are no gaps or overlaps between them afterwards.
+///
+/// Each ring is split into arcs where it starts or stops sharing edges with other rings. Those
+/// points are kept, and each distinct arc is simplified once, and that's used for every ring it's
+/// in. Geometries which aren't polygons are simplified as usual. The results are in the same
+/// order as `geoms`, `None` where a geometry was simplified away.
+pub fn simplify_topology(geoms: Vec<Geometry<i32>>, epsilon: i32, preserve_below: i32) -> Vec<Option<Geometry<i32>>> {
+    use std::collections::HashMap;
+
+    // Which rings use each edge
+    let mut owners: HashMap<Edge, Vec<usize>> = HashMap::new();
+    let mut ring_id = 0;
+    for geom in geoms.iter() {
+        for ring in polygon_rings(geom) {
+            for w in ring.0.windows(2) {
+                let ring_ids = owners.entry(edge(&w[0], &w[1])).or_insert_with(Vec::new);
+                if ring_ids.last() != Some(&ring_id) {
+                    ring_ids.push(ring_id);
+                }
+            }
+            ring_id += 1;
+        }
+    }
+
+    let mut cache = HashMap::new();
+    let mut ring_id = 0;
+    let mut simplify_polygon_topology = |p: Polygon<i32>, cache: &mut HashMap<_, _>| -> Option<Polygon<i32>> {
+        let Polygon{ exterior, interiors } = p;
+        let exterior = simplify_ring_topology(exterior, ring_id, &owners, cache, epsilon, preserve_below);
+        ring_id += 1;
+        let interiors: Vec<_> = interiors.into_iter().filter_map(|i| {
+            let i = simplify_ring_topology(i, ring_id, &owners, cache, epsilon, preserve_below);
+            ring_id += 1;
+            i
+        }).collect();
+        exterior.map(|e| Polygon::new(e, interiors))
+    };
+
+    geoms.into_iter().map(|geom| match geom {
+        Geometry::Polygon(p) => simplify_polygon_topology(p, &mut cache).map(|p| p.into()),
+        Geometry::MultiPolygon(mp) => {
+            let polygons: Vec<_> = mp.0.into_iter().filter_map(|p| simplify_polygon_topology(p, &mut cache)).collect();
+            if polygons.is_empty() { None } else { Some(MultiPolygon(polygons).into()) }
+        },
+        geom => simplify_preserve_short_segments(geom, epsilon, preserve_below),
+    }).collect()
+}
+
+/// Would `remove_unneeded_points` change this geometry? i.e. are there any duplicate points, or
+/// points in a straight line (or spike) with their neighbours. Doesn't allocate, so it's a cheap
+/// way to avoid rewriting geometries which are already clean.
+pub fn has_unneeded_points(geom: &Geometry<i32>) -> bool {
+    match *geom {
+        Geometry::LineString(ref ls) => linestring_has_unneeded_points(ls),
+        Geometry::MultiLineString(ref mls) => mls.0.iter().any(|ls| linestring_has_unneeded_points(ls)),
+        Geometry::Polygon(ref p) => polygon_has_unneeded_points(p),
+        Geometry::MultiPolygon(ref mp) => mp.0.iter().any(|p| polygon_has_unneeded_points(p)),
+        _ => false,
+    }
+}
+
+fn polygon_has_unneeded_points(p: &Polygon<i32>) -> bool {
+    linestring_has_unneeded_points(&p.exterior) || p.interiors.iter().any(|i| linestring_has_unneeded_points(i))
+}
+
+fn linestring_has_unneeded_points(ls: &LineString<i32>) -> bool {
+    fn zero_area(p1: &Point<i32>, p2: &Point<i32>, p3: &Point<i32>) -> bool {
+        let x1 = p1.x() as i64; let y1 = p1.y() as i64;
+        let x2 = p2.x() as i64; let y2 = p2.y() as i64;
+        let x3 = p3.x() as i64; let y3 = p3.y() as i64;
+        (x1 - x3)*(y2 - y1) == (x1 - x2)*(y3 - y1)
+    }
+
+    let points = &ls.0;
+    if points.len() < 2 {
+        return false;
+    }
+    if points.windows(2).any(|w| w[0] == w[1]) {
+        return true;
+    }
+    if points.windows(3).any(|w| zero_area(&w[0], &w[1], &w[2])) {
+        return true;
+    }
+
+    // For rings, the first/last point could be the point of a spike
+    let l = points.len();
+    l >= 4 && points[0] == points[l-1] && zero_area(&points[l-2], &points[0], &points[1])
+}
+
 pub fn remove_unneeded_points(mut geom: Geometry<i32>) -> Option<Geometry<i32>> {
     remove_duplicate_points(&mut geom);
     let geom = remove_spikes(geom);
@@ -221,6 +522,49 @@ pub fn remove_unneeded_points(mut geom: Geometry<i32>) -> Option<Geometry<i32>>
     geom
 }
 
+/// Like `remove_unneeded_points`, but every polygon ring left is closed (the first point is the
+/// same as the last) and has at least 4 points. Rings which can't be are dropped, and polygons
+/// without an exterior are dropped.
+pub fn remove_unneeded_points_keep_rings(geom: Geometry<i32>) -> Option<Geometry<i32>> {
+    fn closed_ring(ls: LineString<i32>) -> Option<LineString<i32>> {
+        let LineString(mut points) = ls;
+        if points.is_empty() {
+            return None;
+        }
+        if points[0] != points[points.len()-1] {
+            let first = points[0];
+            points.push(first);
+        }
+        if points.len() >= 4 {
+            Some(LineString(points))
+        } else {
+            None
+        }
+    }
+
+    fn closed_polygon(p: Polygon<i32>) -> Option<Polygon<i32>> {
+        let Polygon{ exterior, interiors } = p;
+        match closed_ring(exterior) {
+            None => None,
+            Some(exterior) => Some(Polygon::new(exterior, interiors.into_iter().filter_map(closed_ring).collect())),
+        }
+    }
+
+    match remove_unneeded_points(geom) {
+        None => None,
+        Some(Geometry::Polygon(p)) => closed_polygon(p).map(Geometry::Polygon),
+        Some(Geometry::MultiPolygon(mp)) => {
+            let polygons: Vec<_> = mp.0.into_iter().filter_map(closed_polygon).collect();
+            if polygons.is_empty() {
+                None
+            } else {
+                Some(Geometry::MultiPolygon(MultiPolygon(polygons)))
+            }
+        },
+        Some(g) => Some(g),
+    }
+}
+
 pub fn remove_duplicate_points<T: CoordinateType+Debug>(geom: &mut Geometry<T>) {
     match *geom {
         Geometry::LineString(ref mut ls) => remove_duplicate_points_linestring(ls),
@@ -466,6 +810,27 @@ mod test {
         assert_eq!(reduce_fraction_sqr(6, 30*30), (1, 5*5));
     }
 
+    #[test]
+    fn simplify_preserve_short_segments1() {
+        // Long, nearly straight, parts with a small zig-zag in the middle
+        let line: Geometry<i32> = LineString::from(vec![(0, 0), (1000, 1), (2000, 0), (2003, 4), (2006, 0), (2009, 4), (2012, 0), (3000, 0)]).into();
+
+        // Normally it's all simplified away
+        let simplified = simplify(line.clone(), 8).unwrap();
+        assert_eq!(simplified, LineString::from(vec![(0, 0), (3000, 0)]).into());
+
+        // Keeping the short segments keeps the zig-zag, but the long parts are still simplified
+        let preserved = simplify_preserve_short_segments(line.clone(), 8, 10).unwrap();
+        assert_eq!(preserved, LineString::from(vec![(0, 0), (2000, 0), (2003, 4), (2006, 0), (2009, 4), (2012, 0), (3000, 0)]).into());
+
+        let num_points = |g: &Geometry<i32>| match *g { Geometry::LineString(ref ls) => ls.0.len(), _ => unreachable!() };
+        assert!(num_points(&simplified) < num_points(&preserved));
+        assert!(num_points(&preserved) < num_points(&line));
+
+        // 0 is the same as plain simplify
+        assert_eq!(simplify_preserve_short_segments(line.clone(), 8, 0), simplify(line, 8));
+    }
+
     #[test]
     fn remove_spikes_linestring1() {
         // known good simple cases
@@ -557,5 +922,76 @@ mod test {
         let res = remove_spikes_linestring(ls);
     }
 
+    #[test]
+    fn remove_unneeded_points_keep_rings1() {
+        // A square with extra points along its edges, including one at the start/end
+        let square: Geometry<i32> = Polygon::new(vec![(5, 0), (10, 0), (10, 5), (10, 10), (5, 10), (0, 10), (0, 5), (0, 0), (5, 0)].into(), vec![]).into();
+        let num_points = |g: &Geometry<i32>| match *g { Geometry::Polygon(ref p) => p.exterior.0.len(), _ => unreachable!() };
+
+        let cleaned = remove_unneeded_points_keep_rings(square.clone()).unwrap();
+        assert!(num_points(&cleaned) < num_points(&square));
+        assert_eq!(num_points(&cleaned), 5);
+        match cleaned {
+            Geometry::Polygon(ref p) => assert_eq!(p.exterior.0[0], p.exterior.0[4]),
+            _ => unreachable!(),
+        }
+        assert!(is_valid(&cleaned));
+
+        // An interior which is all in a line is dropped, the rest is kept
+        let with_line_hole: Geometry<i32> = Polygon::new(vec![(0, 0), (0, 10), (10, 10), (10, 0), (0, 0)].into(), vec![vec![(2, 2), (4, 4), (6, 6), (2, 2)].into()]).into();
+        match remove_unneeded_points_keep_rings(with_line_hole).unwrap() {
+            Geometry::Polygon(p) => {
+                assert_eq!(p.exterior.0.len(), 5);
+                assert!(p.interiors.is_empty());
+            },
+            _ => unreachable!(),
+        }
+
+        // A polygon whose exterior is all in a line is dropped
+        let flat: Geometry<i32> = Polygon::new(vec![(0, 0), (5, 0), (10, 0), (0, 0)].into(), vec![]).into();
+        assert_eq!(remove_unneeded_points_keep_rings(flat), None);
+    }
+
+    #[test]
+    fn simplify_to_max_vertices1() {
+        // A long zig-zag line, with 10,000 vertices
+        let line: Geometry<i32> = LineString::from((0..10_000).map(|i| (i*10, (i%2)*20 + (i%7)*3)).collect::<Vec<_>>()).into();
+        assert_eq!(num_vertices(&line), 10_000);
+
+        let capped = simplify_to_max_vertices(line.clone(), 1_000).unwrap();
+        assert!(num_vertices(&capped) <= 1_000);
+        assert!(num_vertices(&capped) >= 2);
+        assert!(is_valid(&capped));
+
+        // Already under the cap, so not changed
+        assert_eq!(simplify_to_max_vertices(line.clone(), 10_000), Some(line));
+
+        // Polygons which can't get under the cap are dropped
+        let square: Geometry<i32> = Polygon::new(vec![(0, 0), (0, 100), (100, 100), (100, 0), (0, 0)].into(), vec![]).into();
+        assert_eq!(simplify_to_max_vertices(square, 3), None);
+    }
+
+    #[test]
+    fn simplify_topology1() {
+        // Two squares, which share a slightly wiggly edge along x=1000
+        let a: Geometry<i32> = Polygon::new(vec![(0, 0), (0, 1000), (1000, 1000), (1005, 750), (995, 500), (1005, 250), (1000, 0), (0, 0)].into(), vec![]).into();
+        let b: Geometry<i32> = Polygon::new(vec![(1000, 0), (1005, 250), (995, 500), (1005, 750), (1000, 1000), (2000, 1000), (2000, 0), (1000, 0)].into(), vec![]).into();
+
+        let simplified = simplify_topology(vec![a, b], 8, 0);
+        assert_eq!(simplified.len(), 2);
+        let a = simplified[0].clone().unwrap();
+        let b = simplified[1].clone().unwrap();
+
+        // The shared edge is simplified the same way in both, so there's no gap or overlap
+        assert_eq!(a, Polygon::new(vec![(1000, 1000), (1000, 0), (0, 0), (0, 1000), (1000, 1000)].into(), vec![]).into());
+        assert_eq!(b, Polygon::new(vec![(1000, 0), (1000, 1000), (2000, 1000), (2000, 0), (1000, 0)].into(), vec![]).into());
+        assert!(is_valid(&a));
+        assert!(is_valid(&b));
+
+        // Lines aren't changed
+        let line: Geometry<i32> = LineString::from(vec![(0, 0), (1000, 1), (2000, 0)]).into();
+        assert_eq!(simplify_topology(vec![line.clone()], 8, 0), vec![simplify(line, 8)]);
+    }
+
 
 }
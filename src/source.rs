@@ -0,0 +1,368 @@
+//! Where the features of a layer come from. Usually that's a PostGIS query (see
+//! `ConnectionPool`), but static data can be read from a GeoPackage or FlatGeobuf file instead, or
+//! be in the data.yml itself (`InlineFeatures`).
+
+use std::path::Path;
+
+use mapbox_vector_tile::Value;
+use rusqlite;
+
+use filter::Literal;
+use flatgeobuf::{self, PropertyValue};
+use input::{Layer, WEB_MERCATOR_SRID};
+use stringstore::StringStore;
+use wkt::write_wkb;
+use {ConnectionPool, LocalBBox};
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+/// What a layer's features are being fetched for.
+pub struct FeatureQuery {
+    /// Only features which overlap this (in web mercator, incl. the buffer) are needed. Sources
+    /// can return more, they are clipped away later.
+    pub bbox: LocalBBox<f64>,
+    pub zoom: u8,
+    pub pixel_width: f32,
+    pub pixel_height: f32,
+    pub scale_denominator: f32,
+}
+
+/// The value of each attribute of a feature (which the layer reads, see
+/// `Layer::reads_attribute`), or why it can't be in a vector tile.
+pub type AttributeMap = Vec<(String, std::result::Result<Option<Value>, String>)>;
+
+/// One feature from a data source.
+pub struct SourceFeature {
+    /// The geometry, as (2D) WKB in web mercator
+    pub wkb: Vec<u8>,
+    pub attributes: AttributeMap,
+}
+
+/// Something features can be read from.
+pub trait DataSource {
+    /// The features of this layer for this query.
+    fn features_in(&self, layer: &Layer, query: &FeatureQuery, string_store: &mut StringStore) -> Result<Vec<SourceFeature>>;
+
+    /// The name & TileJSON type (`Number`, `String` or `Boolean`) of each attribute of this
+    /// layer's features.
+    fn columns(&self, layer: &Layer) -> Result<Vec<(String, String)>>;
+}
+
+impl DataSource for ConnectionPool {
+    fn features_in(&self, layer: &Layer, query: &FeatureQuery, string_store: &mut StringStore) -> Result<Vec<SourceFeature>> {
+        let zoom = query.zoom as i32;
+        let params = layer.table.params(&query.bbox, &query.pixel_width, &query.pixel_height, &zoom, &query.scale_denominator);
+        self.log_sql(layer, query);
+        let rows = self.query_for_layer(&layer.id, &layer.table.query, &params)?;
+
+        // Leave out the attributes which aren't wanted now, so their values aren't even read
+        let columns: Vec<_> = rows.columns().iter().skip(1).filter(|c| c.name() != "way" && layer.reads_attribute(c.name())).collect();
+
+        let mut features = Vec::with_capacity(rows.len());
+        for row in rows.iter() {
+            // First object is the ST_AsBinary
+            let wkb: Vec<u8> = row.get(0);
+            let mut attributes = Vec::with_capacity(columns.len());
+            for column in columns.iter() {
+                attributes.push((column.name().to_string(), ::column_value(&row, column.name(), column.type_().name(), string_store)));
+            }
+            features.push(SourceFeature{ wkb, attributes });
+        }
+
+        Ok(features)
+    }
+
+    fn columns(&self, layer: &Layer) -> Result<Vec<(String, String)>> {
+        ::postgis_columns(layer, self)
+    }
+}
+
+/// A table of features in a GeoPackage file. They must be in web mercator (EPSG:3857).
+pub struct GeoPackage {
+    conn: rusqlite::Connection,
+    /// Selects the geometry, and then each attribute in `columns`
+    query: String,
+    /// Does `query` use the spatial index, with the bbox as parameters?
+    has_rtree: bool,
+    /// The name & TileJSON type of each attribute
+    columns: Vec<(String, Option<String>)>,
+}
+
+impl GeoPackage {
+    /// Open this table of the GeoPackage file at `path`.
+    pub fn open(path: &Path, table: &str) -> Result<Self> {
+        let conn = rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|e| format_err!("Can't open GeoPackage {}: {}", path.display(), e))?;
+
+        let (geometry_column, srid): (String, i32) = conn.query_row(
+            "SELECT column_name, srs_id FROM gpkg_geometry_columns WHERE table_name = ?1;", &[&table], |row| (row.get(0), row.get(1)))
+            .map_err(|_| format_err!("GeoPackage {} has no table {} with geometries", path.display(), table))?;
+        if srid != WEB_MERCATOR_SRID as i32 {
+            return Err(format_err!("GeoPackage {} table {} is in SRID {}, only {} (web mercator) is supported", path.display(), table, srid, WEB_MERCATOR_SRID));
+        }
+
+        // Every column except the geometry & the primary key (the feature id) is an attribute
+        let columns: Vec<(String, Option<String>)> = {
+            let mut stmt = conn.prepare(&format!("PRAGMA table_info({});", quote_identifier(table)))?;
+            let rows = stmt.query_map(&[], |row| (row.get::<_, String>(1), row.get::<_, String>(2), row.get::<_, i32>(5)))?;
+            let mut columns = Vec::new();
+            for row in rows {
+                let (name, type_name, pk) = row?;
+                if name != geometry_column && pk == 0 {
+                    let type_name = tilejson_type(&type_name);
+                    columns.push((name, type_name));
+                }
+            }
+            columns
+        };
+
+        let rtree = format!("rtree_{}_{}", table, geometry_column);
+        let has_rtree = conn.query_row("SELECT count(*) FROM sqlite_master WHERE name = ?1;", &[&rtree], |row| row.get::<_, i64>(0))? > 0;
+
+        let mut query = format!("SELECT {}", quote_identifier(&geometry_column));
+        for &(ref name, _) in columns.iter() {
+            query.push_str(", ");
+            query.push_str(&quote_identifier(name));
+        }
+        query.push_str(&format!(" FROM {}", quote_identifier(table)));
+        if has_rtree {
+            query.push_str(&format!(" WHERE rowid IN (SELECT id FROM {} WHERE minx <= ?3 AND maxx >= ?1 AND miny <= ?4 AND maxy >= ?2)", quote_identifier(&rtree)));
+        }
+
+        Ok(GeoPackage{ conn, query, has_rtree, columns })
+    }
+}
+
+impl DataSource for GeoPackage {
+    fn features_in(&self, layer: &Layer, query: &FeatureQuery, string_store: &mut StringStore) -> Result<Vec<SourceFeature>> {
+        let mut stmt = self.conn.prepare_cached(&self.query)?;
+        let bbox = &query.bbox;
+        let mut rows = if self.has_rtree {
+            stmt.query(&[&bbox.0, &bbox.1, &bbox.2, &bbox.3])?
+        } else {
+            stmt.query(&[])?
+        };
+
+        let mut features = Vec::new();
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let blob: Option<Vec<u8>> = row.get_checked(0)?;
+            let wkb = match blob {
+                None => { continue; },
+                Some(blob) => geopackage_wkb(&blob)?.to_vec(),
+            };
+
+            let mut attributes = Vec::with_capacity(self.columns.len());
+            for (i, &(ref name, _)) in self.columns.iter().enumerate() {
+                if ! layer.reads_attribute(name) {
+                    continue;
+                }
+                let value = match row.get_checked(i as i32 + 1)? {
+                    rusqlite::types::Value::Null => Ok(None),
+                    rusqlite::types::Value::Integer(i) => Ok(Some(Value::Int(i))),
+                    rusqlite::types::Value::Real(f) => Ok(Some(Value::Double(f))),
+                    rusqlite::types::Value::Text(s) => Ok(Some(Value::String(string_store.get_string(s)))),
+                    rusqlite::types::Value::Blob(_) => Err(format!("column {} is a BLOB, which can't be put in a vector tile", name)),
+                };
+                attributes.push((name.clone(), value));
+            }
+            features.push(SourceFeature{ wkb, attributes });
+        }
+
+        Ok(features)
+    }
+
+    fn columns(&self, layer: &Layer) -> Result<Vec<(String, String)>> {
+        Ok(self.columns.iter()
+           .filter_map(|&(ref name, ref type_name)| type_name.as_ref().map(|t| (name.clone(), t.clone())))
+           .collect())
+    }
+}
+
+/// The features of a FlatGeobuf file, which are all read when it's opened. They must be in web
+/// mercator (EPSG:3857).
+pub struct FlatGeobuf {
+    columns: Vec<flatgeobuf::Column>,
+    /// The bbox (minx, miny, maxx, maxy), WKB & properties of each feature
+    features: Vec<([f64; 4], Vec<u8>, Vec<(usize, PropertyValue)>)>,
+}
+
+impl FlatGeobuf {
+    /// Read the FlatGeobuf file at `path`.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = flatgeobuf::read_file(path).map_err(|e| format_err!("Can't read FlatGeobuf {}: {}", path.display(), e))?;
+        // Files which don't say are assumed to be in web mercator
+        if let Some(code) = file.epsg_code {
+            if code != WEB_MERCATOR_SRID as i32 {
+                return Err(format_err!("FlatGeobuf {} is in EPSG:{}, only {} (web mercator) is supported", path.display(), code, WEB_MERCATOR_SRID));
+            }
+        }
+
+        let features = file.features.into_iter().map(|feature| {
+            let mut wkb = Vec::new();
+            write_wkb(&feature.geometry, &mut wkb);
+            (feature.bbox, wkb, feature.properties)
+        }).collect();
+
+        Ok(FlatGeobuf{ columns: file.columns, features })
+    }
+}
+
+impl DataSource for FlatGeobuf {
+    fn features_in(&self, layer: &Layer, query: &FeatureQuery, string_store: &mut StringStore) -> Result<Vec<SourceFeature>> {
+        let query_bbox = &query.bbox;
+        let mut features = Vec::new();
+        for &(ref bbox, ref wkb, ref properties) in self.features.iter() {
+            if bbox[0] > query_bbox.2 || bbox[2] < query_bbox.0 || bbox[1] > query_bbox.3 || bbox[3] < query_bbox.1 {
+                continue;
+            }
+
+            let mut attributes = Vec::with_capacity(self.columns.len());
+            for (i, column) in self.columns.iter().enumerate() {
+                if ! layer.reads_attribute(&column.name) {
+                    continue;
+                }
+                // Columns without a value are NULL
+                let value = match properties.iter().find(|&&(c, _)| c == i).map(|&(_, ref v)| v) {
+                    None => Ok(None),
+                    Some(&PropertyValue::Int(x)) => Ok(Some(Value::Int(x))),
+                    Some(&PropertyValue::UInt(x)) if x <= i64::max_value() as u64 => Ok(Some(Value::Int(x as i64))),
+                    Some(&PropertyValue::UInt(x)) => Err(format!("column {} value {} is too big for a vector tile", column.name, x)),
+                    Some(&PropertyValue::Float(f)) => Ok(Some(Value::Float(f))),
+                    Some(&PropertyValue::Double(f)) => Ok(Some(Value::Double(f))),
+                    Some(&PropertyValue::Boolean(b)) => Ok(Some(Value::Boolean(b))),
+                    Some(&PropertyValue::String(ref s)) => Ok(Some(Value::String(string_store.get_str(s)))),
+                    Some(&PropertyValue::Binary(_)) => Err(format!("column {} is binary, which can't be put in a vector tile", column.name)),
+                };
+                attributes.push((column.name.clone(), value));
+            }
+            features.push(SourceFeature{ wkb: wkb.clone(), attributes });
+        }
+
+        Ok(features)
+    }
+
+    fn columns(&self, _layer: &Layer) -> Result<Vec<(String, String)>> {
+        Ok(self.columns.iter()
+           .filter_map(|column| column.type_.tilejson_type().map(|t| (column.name.clone(), t.to_string())))
+           .collect())
+    }
+}
+
+/// The features of a `wkt` datasource, which are in the layer itself (`Layer::inline_features`).
+/// Every feature is returned for every tile, they are clipped later.
+pub struct InlineFeatures;
+
+impl DataSource for InlineFeatures {
+    fn features_in(&self, layer: &Layer, _query: &FeatureQuery, string_store: &mut StringStore) -> Result<Vec<SourceFeature>> {
+        let features = match layer.inline_features {
+            None => { return Ok(Vec::new()); },
+            Some(ref features) => features,
+        };
+
+        Ok(features.iter().map(|feature| {
+            let attributes = feature.attributes.iter()
+                .filter(|&&(ref name, _)| layer.reads_attribute(name))
+                .map(|&(ref name, ref value)| (name.clone(), Ok(Some(match *value {
+                    Literal::String(ref s) => Value::String(string_store.get_str(s)),
+                    // Whole numbers are ints, as they would be from the database
+                    Literal::Number(n) if n.fract() == 0. && n.abs() < (1u64 << 53) as f64 => Value::Int(n as i64),
+                    Literal::Number(n) => Value::Double(n),
+                    Literal::Boolean(b) => Value::Boolean(b),
+                }))))
+                .collect();
+            SourceFeature{ wkb: feature.wkb.clone(), attributes }
+        }).collect())
+    }
+
+    fn columns(&self, layer: &Layer) -> Result<Vec<(String, String)>> {
+        let mut columns: Vec<(String, String)> = Vec::new();
+        for feature in layer.inline_features.iter().flat_map(|features| features.iter()) {
+            for &(ref name, ref value) in feature.attributes.iter() {
+                if ! columns.iter().any(|&(ref n, _)| n == name) {
+                    let type_name = match *value {
+                        Literal::String(_) => "String",
+                        Literal::Number(_) => "Number",
+                        Literal::Boolean(_) => "Boolean",
+                    };
+                    columns.push((name.clone(), type_name.to_string()));
+                }
+            }
+        }
+        Ok(columns)
+    }
+}
+
+/// The TileJSON type for a column with this (SQLite) type. `None` for BLOBs, which can't be in a
+/// vector tile.
+fn tilejson_type(type_name: &str) -> Option<String> {
+    let type_name = type_name.to_uppercase();
+    if type_name == "BOOLEAN" {
+        Some("Boolean".to_string())
+    } else if type_name.contains("INT") || type_name == "REAL" || type_name == "FLOAT" || type_name == "DOUBLE" {
+        Some("Number".to_string())
+    } else if type_name == "BLOB" {
+        None
+    } else {
+        // TEXT, DATE etc. are all strings in SQLite
+        Some("String".to_string())
+    }
+}
+
+/// Quote this table or column name for SQLite.
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace("\"", "\"\""))
+}
+
+/// The WKB in this GeoPackage geometry blob, i.e. without the GeoPackage header (`GP`, version,
+/// flags, SRID & an optional envelope).
+pub fn geopackage_wkb(blob: &[u8]) -> Result<&[u8]> {
+    if blob.len() < 8 || &blob[0..2] != b"GP" {
+        return Err(format_err!("Not a GeoPackage geometry"));
+    }
+    let flags = blob[3];
+    // Bit 5 is for extended geometry types, which aren't WKB
+    if flags & 0b0010_0000 != 0 {
+        return Err(format_err!("Extended GeoPackage geometries aren't supported"));
+    }
+    let envelope_len = match (flags >> 1) & 0b111 {
+        0 => 0,
+        1 => 32,
+        2 | 3 => 48,
+        4 => 64,
+        x => { return Err(format_err!("Invalid GeoPackage envelope type {}", x)); },
+    };
+    let start = 8 + envelope_len;
+    if blob.len() <= start {
+        return Err(format_err!("GeoPackage geometry is too short"));
+    }
+    // The SRID (bytes 4-7) is checked for the whole table, so the header's byte order doesn't
+    // matter
+    Ok(&blob[start..])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn geopackage_header() {
+        // POINT(1 2) as little endian WKB
+        let mut wkb = vec![1, 1, 0, 0, 0];
+        wkb.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0xf0, 0x3f, 0, 0, 0, 0, 0, 0, 0, 0x40]);
+
+        // No envelope, little endian, SRID 3857
+        let mut blob = vec![b'G', b'P', 0, 0b0000_0001, 0x11, 0x0f, 0, 0];
+        blob.extend_from_slice(&wkb);
+        assert_eq!(geopackage_wkb(&blob).unwrap(), &wkb[..]);
+
+        // With an xy envelope
+        let mut blob = vec![b'G', b'P', 0, 0b0000_0011, 0x11, 0x0f, 0, 0];
+        blob.extend_from_slice(&[0; 32]);
+        blob.extend_from_slice(&wkb);
+        assert_eq!(geopackage_wkb(&blob).unwrap(), &wkb[..]);
+
+        assert!(geopackage_wkb(&wkb).is_err());
+        assert!(geopackage_wkb(b"GP").is_err());
+    }
+}
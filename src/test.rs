@@ -1,4 +1,5 @@
 use super::*;
+use test_util::temp_path;
 
 #[test]
 fn remap() {
@@ -8,12 +9,10 @@ fn remap() {
 
     let geom = Geometry::LineString(LineString(vec![Point(Coordinate { x: -693741.39, y: 7049558.31 }), Point(Coordinate { x: -693886.45, y: 7049788.51 }), Point(Coordinate { x: -693905.81, y: 7049848.66 }), Point(Coordinate { x: -693923.15, y: 7049902.74 }), Point(Coordinate { x: -693956.59, y: 7050029.34 }), Point(Coordinate { x: -693985.26, y: 7050160.72 }), Point(Coordinate { x: -693997.2, y: 7050306.43 }), Point(Coordinate { x: -694009.15, y: 7050397.2 }), Point(Coordinate { x: -694022.23, y: 7050490.84 }), Point(Coordinate { x: -694037.39, y: 7050599.36 }), Point(Coordinate { x: -694166.75, y: 7051000.65 }), Point(Coordinate { x: -694400.88, y: 7051738.55 }), Point(Coordinate { x: -694427.16, y: 7051799.33 }), Point(Coordinate { x: -695009.99, y: 7052458.61 }), Point(Coordinate { x: -695055.37, y: 7052565.03 }), Point(Coordinate { x: -695093.59, y: 7052722.68 }), Point(Coordinate { x: -695103.15, y: 7053080.98 }), Point(Coordinate { x: -695072.09, y: 7054069.89 }), Point(Coordinate { x: -694990.43, y: 7054483.98 }), Point(Coordinate { x: -21474836.48, y: 20061906.38 })]));
 
-    let minx = -10018754.;
-    let maxx = 0.;
-    let miny = 0.;
-    let maxy = 10018756.;
-    let extent = 32768.;
-    let new_geom = remap_geometry(geom, minx, maxx, miny, maxy, extent).unwrap();
+    // The z2 tile which is minx -10018754 maxx 0 miny 0 maxy 10018756
+    let metatile = slippy_map_tiles::Metatile::new(1, 2, 1, 1).unwrap();
+    let projection = Projection::for_metatile(&TileMatrix::default(), &metatile, 32768., &GeometryOptions::default());
+    let new_geom = project_geometry(geom, &projection).unwrap();
 
     let expected = Geometry::LineString(LineString(vec![Point(Coordinate { x: 30499, y: 9711 }), Point(Coordinate { x: 30499, y: 9710 }), Point(Coordinate { x: 30498, y: 9710 }), Point(Coordinate { x: 30498, y: 9709 }), Point(Coordinate { x: 30498, y: 9708 }), Point(Coordinate { x: 30498, y: 9707 }), Point(Coordinate { x: 30497, y: 9704 }), Point(Coordinate { x: 30495, y: 9702 }), Point(Coordinate { x: 30495, y: 9701 }), Point(Coordinate { x: 30495, y: 9700 }), Point(Coordinate { x: 30495, y: 9696 }), Point(Coordinate { x: 30495, y: 9695 }), Point(Coordinate { x: -37469, y: -32848 })]));
 
@@ -31,3 +30,2251 @@ fn xy_to_latlon() {
     assert!(y_to_lat(2048, 4096.) - 0. < 1e-7);
     assert!(y_to_lat(4096, 4096.) - -85.05112 < 1e-7);
 }
+
+#[test]
+fn labels_only_below() {
+    let layers = Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+bounds: [-180, -85.0511, 180, 85.0511]
+center: [0, 0, 2]
+name: test
+description: test
+Layer:
+- id: places
+  Datasource:
+    type: postgis
+    table: (select way from places) as t
+  properties:
+    labels-only-below: 10
+").unwrap();
+    let layer = &layers.layers[0];
+    assert_eq!(layer.labels_only_below, Some(10));
+
+    let point: Geometry<f64> = Point::new(0., 0.).into();
+    let points: Geometry<f64> = MultiPoint(vec![Point::new(0., 0.), Point::new(1., 1.)]).into();
+    let polygon: Geometry<f64> = Polygon::new(vec![(0., 0.), (0., 1.), (1., 1.), (1., 0.), (0., 0.)].into(), vec![]).into();
+    let line: Geometry<f64> = LineString(vec![Point::new(0., 0.), Point::new(1., 1.)]).into();
+
+    // Only points at z3
+    assert!(include_geometry_at_zoom(layer, 3, &point));
+    assert!(include_geometry_at_zoom(layer, 3, &points));
+    assert!(!include_geometry_at_zoom(layer, 3, &polygon));
+    assert!(!include_geometry_at_zoom(layer, 3, &line));
+
+    // Everything at z12
+    assert!(include_geometry_at_zoom(layer, 12, &point));
+    assert!(include_geometry_at_zoom(layer, 12, &polygon));
+    assert!(include_geometry_at_zoom(layer, 12, &line));
+}
+
+#[test]
+fn log_levels() {
+    use log::{Level, LevelFilter};
+    assert_eq!(log_level_for_verbosity(0, false), LevelFilter::Warn);
+    assert_eq!(log_level_for_verbosity(1, false), LevelFilter::Info);
+    assert_eq!(log_level_for_verbosity(2, false), LevelFilter::Debug);
+    assert_eq!(log_level_for_verbosity(3, false), LevelFilter::Trace);
+    assert_eq!(log_level_for_verbosity(10, false), LevelFilter::Trace);
+    assert_eq!(log_level_for_verbosity(0, true), LevelFilter::Error);
+
+    // make_valid's warnings are shown by default, and its debug output with -vv
+    assert!(Level::Warn <= log_level_for_verbosity(0, false));
+    assert!(Level::Debug > log_level_for_verbosity(1, false));
+    assert!(Level::Debug <= log_level_for_verbosity(2, false));
+    assert!(Level::Warn > log_level_for_verbosity(0, true));
+}
+
+#[test]
+fn max_zoom_tiles() {
+    // A one point bbox at z22
+    let (x, y) = lonlat_to_tile(2.3522, 48.8566, MAX_ZOOM);
+    let (minx, miny, maxx, maxy) = tile_bbox_mercator(MAX_ZOOM, x, y);
+    assert!(maxx > minx && maxy > miny);
+    assert!(scale_denominator_for_zoom(MAX_ZOOM) > 0.);
+
+    let metatile = slippy_map_tiles::Metatile::new(1, MAX_ZOOM, x, y).unwrap();
+    let projection = Projection::for_metatile(&TileMatrix::default(), &metatile, 4096., &GeometryOptions::default());
+    let point: Geometry<f64> = Point::new((minx + maxx) / 2., (miny + maxy) / 2.).into();
+    assert_eq!(project_geometry(point, &projection), Some(Point::new(2048, 2048).into()));
+    let empty_line: Geometry<f64> = LineString(vec![]).into();
+    assert_eq!(project_geometry(empty_line, &projection), None);
+}
+
+#[test]
+fn geometry_bboxes() {
+    let as_tuple = |g: &Geometry<i32>| geometry_bbox(g).map(|b| (b.xmin, b.xmax, b.ymin, b.ymax));
+
+    // The interior can't make it bigger
+    let polygon = Geometry::Polygon(Polygon::new(vec![(0, 0), (0, 20), (30, 20), (30, 0), (0, 0)].into(), vec![vec![(5, 5), (10, 5), (10, 10), (5, 5)].into()]));
+    assert_eq!(as_tuple(&polygon), Some((0, 30, 0, 20)));
+
+    let lines = Geometry::MultiLineString(MultiLineString(vec![vec![(0, 0), (10, 5)].into(), vec![(-3, 8), (2, 2)].into()]));
+    assert_eq!(as_tuple(&lines), Some((-3, 10, 0, 8)));
+
+    let collection = Geometry::GeometryCollection(GeometryCollection(vec![lines, Geometry::Point(Point::new(40, -1))]));
+    assert_eq!(as_tuple(&collection), Some((-3, 40, -1, 8)));
+
+    assert_eq!(as_tuple(&Geometry::MultiLineString(MultiLineString(vec![]))), None);
+    assert_eq!(as_tuple(&Geometry::GeometryCollection(GeometryCollection(vec![]))), None);
+    assert_eq!(as_tuple(&Geometry::LineString(LineString(vec![]))), None);
+}
+
+#[test]
+fn zoom_too_high() {
+    let dest = TileDestinationType::TileStashDirectory(PathBuf::from("/nonexistant"));
+    let err = generate_all(&["/nonexistant.yml"], 0, MAX_ZOOM+1, &None, &dest, false, true, 8, 1, None, 10, true, &GenerateOptions::default()).unwrap_err();
+    assert!(err.to_string().contains("maxzoom"));
+
+    let err = generate_all(&["/nonexistant.yml"], 10, 5, &None, &dest, false, true, 8, 1, None, 10, true, &GenerateOptions::default()).unwrap_err();
+    assert!(err.to_string().contains("minzoom"));
+}
+
+#[test]
+fn unwritable_destination() {
+    // A directory can't be made inside a file (even by root)
+    let not_a_dir = temp_path("unwritable_destination");
+    fs::write(&not_a_dir, b"").unwrap();
+
+    let dest = TileDestinationType::TileStashDirectory(not_a_dir.join("tiles"));
+    let err = generate_all(&["/nonexistant.yml"], 0, 5, &None, &dest, false, true, 8, 1, None, 10, true, &GenerateOptions::default()).unwrap_err();
+    assert_eq!(err.downcast_ref::<DestinationError>().map(|e| e.path.clone()), Some(not_a_dir.join("tiles")), "{}", err);
+
+    let dest = TileDestinationType::MBTiles(not_a_dir.join("tiles.mbtiles"));
+    let err = generate_all(&["/nonexistant.yml"], 0, 5, &None, &dest, false, true, 8, 1, None, 10, true, &GenerateOptions::default()).unwrap_err();
+    assert_eq!(err.downcast_ref::<DestinationError>().map(|e| e.path.clone()), Some(not_a_dir.clone()), "{}", err);
+
+    // A writable directory is fine, so the error is from reading the data.yml
+    let dir = temp_path("writable_destination");
+    let dest = TileDestinationType::TileStashDirectory(dir.clone());
+    let err = generate_all(&["/nonexistant.yml"], 0, 5, &None, &dest, false, true, 8, 1, None, 10, true, &GenerateOptions::default()).unwrap_err();
+    assert!(err.downcast_ref::<DestinationError>().is_none(), "{}", err);
+    assert!(dir.is_dir());
+
+    fs::remove_file(&not_a_dir).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn directory_tilejson() {
+    let layers = Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+bounds: [-180, -85.0511, 180, 85.0511]
+center: [0, 0, 2]
+name: test
+Layer:
+- id: water
+  Datasource:
+    type: postgis
+    table: (select way from water) as t
+").unwrap();
+    let vector_layers = json!([{"id": "water", "description": "", "minzoom": 0, "maxzoom": 14, "fields": {}}]);
+
+    let dir = temp_path("directory_tilejson");
+    let bbox = BBox::new(53.5, -6.5, 53.25, -6.0).unwrap();
+    let metadata = generator_metadata(Some("© OpenStreetMap contributors"), UNIX_EPOCH + Duration::from_secs(1_500_000_000));
+    write_tilejson(&dir, &layers, vector_layers.clone(), 2, 10, mbtiles_bounds(&Some(bbox), &layers), TileScheme::Xyz, &metadata).unwrap();
+
+    let tilejson: serde_json::Value = serde_json::from_reader(File::open(dir.join("metadata.json")).unwrap()).unwrap();
+    assert_eq!(tilejson["bounds"], json!([-6.5, 53.25, -6.0, 53.5]));
+    assert_eq!(tilejson["minzoom"], json!(2));
+    assert_eq!(tilejson["maxzoom"], json!(10));
+    assert_eq!(tilejson["name"], json!("test"));
+    assert_eq!(tilejson["scheme"], json!("xyz"));
+    assert_eq!(tilejson["vector_layers"], vector_layers);
+    assert_eq!(tilejson["generator"], json!(format!("tileigi {}", env!("CARGO_PKG_VERSION"))));
+    assert_eq!(tilejson["generated_at"], json!("2017-07-14T02:40:00Z"));
+    assert_eq!(tilejson["attribution"], json!("© OpenStreetMap contributors"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn iso8601_timestamps() {
+    assert_eq!(iso8601_utc(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+    assert_eq!(iso8601_utc(UNIX_EPOCH + Duration::from_secs(1_500_000_000)), "2017-07-14T02:40:00Z");
+    assert_eq!(iso8601_utc(UNIX_EPOCH + Duration::from_secs(951_782_400 + 3_661)), "2000-02-29T01:01:01Z");
+}
+
+#[test]
+fn mbtiles_generator_metadata() {
+    let name = "mbtiles_generator_metadata";
+    let gpkg = temp_path(&format!("{}.gpkg", name));
+    create_parks_geopackage(&gpkg);
+    let data_yml = temp_path(&format!("{}.yml", name));
+    fs::write(&data_yml, format!("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  Datasource:
+    type: geopackage
+    file: {}
+    table: parks
+", gpkg.display())).unwrap();
+    let mbtiles = temp_path(&format!("{}.mbtiles", name));
+    let _ = fs::remove_file(&mbtiles);
+
+    let mut options = GenerateOptions::default();
+    options.attribution = Some("© OpenStreetMap contributors".to_string());
+    let dest = TileDestinationType::MBTiles(mbtiles.clone());
+    generate_all(&[data_yml.to_str().unwrap()], 0, 0, &None, &dest, false, true, 1, 1, None, 10, true, &options).unwrap();
+
+    let conn = rusqlite::Connection::open(&mbtiles).unwrap();
+    let metadata = |name: &str| -> String { conn.query_row("SELECT value FROM metadata WHERE name = ?1;", &[&name], |r| r.get(0)).unwrap() };
+    assert_eq!(metadata("generator"), format!("tileigi {}", env!("CARGO_PKG_VERSION")));
+    let generated_at = metadata("generated_at");
+    assert_eq!(generated_at.len(), "2017-07-14T02:40:00Z".len());
+    assert!(generated_at.ends_with('Z'), "{}", generated_at);
+    assert_eq!(metadata("attribution"), "© OpenStreetMap contributors");
+
+    fs::remove_file(&gpkg).unwrap();
+    fs::remove_file(&data_yml).unwrap();
+    fs::remove_file(&mbtiles).unwrap();
+}
+
+#[test]
+fn only_listed_zooms() {
+    let name = "only_listed_zooms";
+    let gpkg = temp_path(&format!("{}.gpkg", name));
+    create_parks_geopackage(&gpkg);
+    let data_yml = temp_path(&format!("{}.yml", name));
+    fs::write(&data_yml, format!("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  Datasource:
+    type: geopackage
+    file: {}
+    table: parks
+", gpkg.display())).unwrap();
+    let mbtiles = temp_path(&format!("{}.mbtiles", name));
+    let _ = fs::remove_file(&mbtiles);
+    let dest = TileDestinationType::MBTiles(mbtiles.clone());
+
+    let mut options = GenerateOptions::default();
+    options.zooms = Some(vec![3, 0, 3]);
+    let err = generate_all(&[data_yml.to_str().unwrap()], 0, 3, &None, &dest, false, true, 1, 1, None, 10, true, &options).unwrap_err();
+    assert!(err.to_string().contains("more than once"), "{}", err);
+    options.zooms = Some(vec![0, 5]);
+    let err = generate_all(&[data_yml.to_str().unwrap()], 0, 3, &None, &dest, false, true, 1, 1, None, 10, true, &options).unwrap_err();
+    assert!(err.to_string().contains("between 0 and 3"), "{}", err);
+
+    options.zooms = Some(vec![3, 0]);
+    generate_all(&[data_yml.to_str().unwrap()], 0, 3, &None, &dest, false, true, 1, 1, None, 10, true, &options).unwrap();
+
+    let conn = rusqlite::Connection::open(&mbtiles).unwrap();
+    let mut stmt = conn.prepare("SELECT DISTINCT zoom_level FROM tiles ORDER BY zoom_level;").unwrap();
+    let zooms: Vec<i64> = stmt.query_map(&[], |r| r.get(0)).unwrap().map(|z| z.unwrap()).collect();
+    assert_eq!(zooms, vec![0, 3]);
+    let num_tiles: i64 = conn.query_row("SELECT count(*) FROM tiles;", &[], |r| r.get(0)).unwrap();
+    assert_eq!(num_tiles, 1 + 64);
+
+    fs::remove_file(&gpkg).unwrap();
+    fs::remove_file(&data_yml).unwrap();
+    fs::remove_file(&mbtiles).unwrap();
+}
+
+#[test]
+fn slow_metatile_doesnt_hold_up_others() {
+    // One point in each of the 4 metatiles of zoom 1, each in its own layer, so the hook knows
+    // which metatile it's in. The hook for the north west one waits until the other 3 are done.
+    // The workers take metatiles one at a time from a shared queue, so the other thread does all
+    // 3. If each thread had a fixed share, the waiting thread would still have one of them to do,
+    // and it would time out.
+    let mut layers = String::new();
+    for &(name, x, y) in [("nw", -10e6, 10e6), ("ne", 10e6, 10e6), ("sw", -10e6, -10e6), ("se", 10e6, -10e6)].iter() {
+        layers.push_str(&format!("- id: {}\n  Datasource:\n    type: wkt\n    features:\n    - wkt: POINT({} {})\n", name, x, y));
+    }
+    let data_yml = temp_path("slow_metatile.yml");
+    File::create(&data_yml).unwrap().write_all(format!("minzoom: 0\nmaxzoom: 14\nLayer:\n{}", layers).as_bytes()).unwrap();
+
+    // Which thread did each metatile (by layer)
+    let done = Arc::new((Mutex::new(Vec::new()), std::sync::Condvar::new()));
+    let hook_done = Arc::clone(&done);
+    let mut options = GenerateOptions::default();
+    options.per_feature_hook = Some(FeatureHook::new(move |layer_name, _geom, _properties| {
+        let (ref lock, ref condvar) = *hook_done;
+        let mut done = lock.lock().unwrap();
+        done.push((layer_name.to_string(), thread::current().id()));
+        condvar.notify_all();
+        if layer_name == "nw" {
+            let give_up = Instant::now() + Duration::from_secs(10);
+            while done.len() < 4 && Instant::now() < give_up {
+                done = condvar.wait_timeout(done, Duration::from_millis(100)).unwrap().0;
+            }
+        }
+    }));
+    let stats = generate_all(&[data_yml.to_str().unwrap()], 1, 1, &None, &TileDestinationType::Null, false, true, 1, 2, None, 10, true, &options).unwrap();
+    assert_eq!(stats.features_encoded, 4);
+
+    let done = done.0.lock().unwrap();
+    let mut layers: Vec<_> = done.iter().map(|&(ref layer, _)| layer.as_str()).collect();
+    layers.sort();
+    assert_eq!(layers, vec!["ne", "nw", "se", "sw"]);
+    let waiting_thread = done.iter().find(|&&(ref layer, _)| layer == "nw").unwrap().1;
+    for &(ref layer, thread) in done.iter().filter(|&&(ref layer, _)| layer != "nw") {
+        assert!(thread != waiting_thread, "{} was done by the thread which was waiting", layer);
+    }
+
+    fs::remove_file(&data_yml).unwrap();
+}
+
+#[test]
+fn describe_layers_fields() {
+    /// Only knows the columns of each layer
+    struct ColumnsSource;
+    impl DataSource for ColumnsSource {
+        fn features_in(&self, _layer: &Layer, _query: &source::FeatureQuery, _string_store: &mut StringStore) -> Result<Vec<source::SourceFeature>> {
+            Ok(Vec::new())
+        }
+
+        fn columns(&self, layer: &Layer) -> Result<Vec<(String, String)>> {
+            match layer.id.as_str() {
+                "roads" => Ok(vec![("name".to_string(), "String".to_string()), ("lanes".to_string(), "Number".to_string()), ("oneway".to_string(), "Boolean".to_string())]),
+                _ => Ok(Vec::new()),
+            }
+        }
+    }
+
+    let layers = Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: roads
+  properties:
+    minzoom: 6
+  Datasource:
+    type: postgis
+    table: (select way, name, lanes, oneway from roads) as t
+- id: water
+  Datasource:
+    type: postgis
+    table: (select way from water) as t
+").unwrap();
+
+    let vector_layers = vector_layers_with_columns(&layers, |layer| ColumnsSource.columns(layer)).unwrap();
+    assert_eq!(vector_layers, json!([
+        {"id": "roads", "description": "", "minzoom": 6, "maxzoom": 14, "fields": {"lanes": "Number", "name": "String", "oneway": "Boolean"}},
+        {"id": "water", "description": "", "minzoom": 0, "maxzoom": 14, "fields": {}},
+    ]));
+
+    let printed = vector_layers_block(vector_layers).unwrap();
+    let printed: serde_json::Value = serde_json::from_str(&printed).unwrap();
+    let fields: Vec<&String> = printed["vector_layers"][0]["fields"].as_object().unwrap().keys().collect();
+    assert_eq!(fields, vec!["lanes", "name", "oneway"]);
+}
+
+#[test]
+fn wkb_extra_dimensions() {
+    // POINT(1 2), little endian
+    let mut point = vec![1, 1, 0, 0, 0];
+    point.extend_from_slice(&[0; 16]);
+    assert!(!wkb_has_extra_dimensions(&point));
+
+    // ISO POINT Z (type 1001), big endian
+    let point_z = vec![0, 0, 0, 0x03, 0xe9];
+    assert!(wkb_has_extra_dimensions(&point_z));
+    // ISO POLYGON M (type 2003)
+    assert!(wkb_has_extra_dimensions(&[1, 0xd3, 0x07, 0, 0]));
+    // EWKB POLYGON with a z flag, and with only an SRID
+    assert!(wkb_has_extra_dimensions(&[1, 3, 0, 0, 0x80]));
+    assert!(!wkb_has_extra_dimensions(&[1, 3, 0, 0, 0x20]));
+
+    assert!(!wkb_has_extra_dimensions(&[]));
+}
+
+#[test]
+fn max_runtime() {
+    assert!(!out_of_time(None));
+    assert!(out_of_time(Some(Instant::now())));
+    assert!(!out_of_time(Some(Instant::now() + Duration::from_secs(60*60))));
+
+    let path = temp_path("max_runtime.txt");
+    let as_tuple = |mt: Metatile| (mt.zoom(), mt.x(), mt.y());
+    let all: Vec<_> = MetatilesIterator::new_for_bbox_zoom(8, &None, 0, 4).map(&as_tuple).collect();
+    assert_eq!(all.len(), 1+1+1+1+4);
+
+    // The first few were done before time ran out
+    let mut metatiles = MetatilesIterator::new_for_bbox_zoom(8, &None, 0, 4);
+    for _ in 0..3 {
+        metatiles.next();
+    }
+    assert_eq!(write_tile_list(&path, &mut metatiles).unwrap(), all.len() - 3);
+
+    // It can be read back as a tile list, with only the ones not done
+    let remaining: Vec<_> = MetatilesIterator::new_from_filelist(path.to_str().unwrap().to_string()).map(&as_tuple).collect();
+    assert_eq!(remaining, &all[3..]);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn max_runtime_stops_early() {
+    let name = "max_runtime_stops_early";
+    let data_yml = temp_path(&format!("{}.yml", name));
+    File::create(&data_yml).unwrap().write_all(b"
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: land
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POLYGON((-19000000 -19000000, 19000000 -19000000, 19000000 19000000, -19000000 19000000, -19000000 -19000000))
+").unwrap();
+    let mbtiles = temp_path(&format!("{}.mbtiles", name));
+    let remaining = temp_path(&format!("{}.txt", name));
+    let _ = fs::remove_file(&mbtiles);
+
+    // Time runs out while the first metatile is being generated, so (at most) that one is done
+    let mut options = GenerateOptions::default();
+    options.max_runtime = Some(Duration::from_millis(100));
+    options.remaining_tiles = Some(remaining.clone());
+    let slept = std::sync::atomic::AtomicBool::new(false);
+    options.per_feature_hook = Some(FeatureHook::new(move |_layer_name, _geom, _properties| {
+        if ! slept.swap(true, AtomicOrdering::SeqCst) {
+            thread::sleep(Duration::from_millis(500));
+        }
+    }));
+    let stats = generate_all(&[data_yml.to_str().unwrap()], 0, 2, &None, &TileDestinationType::MBTiles(mbtiles.clone()), false, true, 1, 1, None, 10, true, &options).unwrap();
+    assert!(stats.metatiles_remaining > 0);
+    assert!(stats.metatiles_remaining < 1 + 4 + 16);
+
+    // The tiles listed are exactly the ones which weren't written
+    let listed: HashSet<(u8, u32, u32)> = MetatilesIterator::new_from_filelist(remaining.to_str().unwrap().to_string())
+        .flat_map(|mt| mt.tiles())
+        .map(|t| (t.zoom(), t.x(), t.y()))
+        .collect();
+    assert_eq!(listed.len(), stats.metatiles_remaining);
+    let reader = fileio::MBTilesReader::open(&mbtiles).unwrap();
+    let mut num_written = 0;
+    for z in 0..3 {
+        for x in 0..(1 << z) {
+            for y in 0..(1 << z) {
+                let written = reader.tile_data(&slippy_map_tiles::Tile::new(z, x, y).unwrap()).unwrap().is_some();
+                assert_eq!(written, !listed.contains(&(z, x, y)), "tile {}/{}/{}", z, x, y);
+                if written {
+                    num_written += 1;
+                }
+            }
+        }
+    }
+    assert_eq!(num_written, stats.tiles_written);
+    drop(reader);
+
+    fs::remove_file(&mbtiles).unwrap();
+    fs::remove_file(&remaining).unwrap();
+    fs::remove_file(&data_yml).unwrap();
+}
+
+/// Create a GeoPackage at `path`, with a `table` of `geometry_type` geometries (in EPSG:3857), and
+/// these other `columns`. Each feature is the WKB of its geometry, and the SQL values of its other
+/// columns.
+fn create_geopackage(path: &Path, table: &str, geometry_type: &str, columns: &str, features: &[(Vec<u8>, &str)]) {
+    let conn = rusqlite::Connection::open(path).unwrap();
+    conn.execute_batch(&format!("
+        CREATE TABLE gpkg_geometry_columns (table_name TEXT, column_name TEXT, geometry_type_name TEXT, srs_id INTEGER, z TINYINT, m TINYINT);
+        INSERT INTO gpkg_geometry_columns VALUES ('{0}', 'geom', '{1}', 3857, 0, 0);
+        CREATE TABLE {0} (fid INTEGER PRIMARY KEY, geom BLOB, {2});
+    ", table, geometry_type, columns)).unwrap();
+
+    for &(ref wkb, values) in features.iter() {
+        // GeoPackage header: no envelope, little endian, SRID 3857
+        let mut blob = vec![b'G', b'P', 0, 1, 0x11, 0x0f, 0, 0];
+        blob.extend_from_slice(wkb);
+        conn.execute(&format!("INSERT INTO {} VALUES (NULL, ?1, {});", table, values), &[&blob]).unwrap();
+    }
+}
+
+fn to_wkb(geom: &Geometry<f64>) -> Vec<u8> {
+    let mut wkb = Vec::new();
+    wkb::write_geom_to_wkb(geom, &mut wkb);
+    wkb
+}
+
+/// Create a GeoPackage at `path`, with a `parks` table with one park (in tile 1/0/0).
+fn create_parks_geopackage(path: &Path) {
+    // In tile 1/0/0 (the north west quarter of the world)
+    let polygon: Geometry<f64> = Polygon::new(vec![(-15e6, 5e6), (-5e6, 5e6), (-5e6, 15e6), (-15e6, 15e6), (-15e6, 5e6)].into(), vec![]).into();
+    create_geopackage(path, "parks", "POLYGON", "name TEXT, area INTEGER", &[(to_wkb(&polygon), "'Phoenix Park', 707")]);
+}
+
+#[test]
+fn geopackage_source() {
+    let path = temp_path("geopackage_source.gpkg");
+    create_parks_geopackage(&path);
+
+    let layers = Layers::from_tmsource_str(&format!("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  Datasource:
+    type: geopackage
+    file: {}
+    table: parks
+", path.display())).unwrap();
+    assert_eq!(layers.layers[0].geopackage, Some(input::GeoPackageTable{ path: path.clone(), table: "parks".to_string() }));
+
+    // No database is needed
+    let mut connection_pool = ConnectionPool::new(layers.get_all_connections());
+    connection_pool.open_files(&layers).unwrap();
+    assert_eq!(connection_pool.source_for_layer(&layers.layers[0]).columns(&layers.layers[0]).unwrap(),
+               vec![("name".to_string(), "String".to_string()), ("area".to_string(), "Number".to_string())]);
+
+    let mut stats = GenerateStats::default();
+    let tiles = single_metatile(&layers, &Metatile::new(1, 1, 0, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+    assert_eq!(tiles.len(), 1);
+    assert_eq!(stats.features_encoded, 1);
+    let (_, ref tile) = *tiles.iter().find(|&&(ref t, _)| (t.x(), t.y()) == (0, 0)).unwrap();
+    let bytes = tile.to_bytes();
+    assert_eq!(tile_layer_names(&bytes).unwrap(), vec!["parks".to_string()]);
+
+    // Both attributes are there (Layer.keys is field 3)
+    let mvt_layers: Vec<_> = protobuf_fields(&bytes).into_iter().filter(|&(f, _)| f == 3).collect();
+    let mut keys: Vec<_> = protobuf_fields(mvt_layers[0].1).into_iter().filter(|&(f, _)| f == 3).map(|(_, k)| k).collect();
+    keys.sort();
+    assert_eq!(keys, vec![&b"area"[..], &b"name"[..]]);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn flatgeobuf_source() {
+    use flatgeobuf::{ColumnType, PropertyValue};
+
+    // The same park as create_parks_geopackage, in tile 1/0/0, and one in tile 1/1/1
+    let path = temp_path("flatgeobuf_source.fgb");
+    let park: Geometry<f64> = Polygon::new(vec![(-15e6, 5e6), (-5e6, 5e6), (-5e6, 15e6), (-15e6, 15e6), (-15e6, 5e6)].into(), vec![]).into();
+    let other_park: Geometry<f64> = Polygon::new(vec![(5e6, -15e6), (15e6, -15e6), (15e6, -5e6), (5e6, -5e6), (5e6, -15e6)].into(), vec![]).into();
+    let columns = [("name", ColumnType::String), ("area", ColumnType::Long), ("photo", ColumnType::Binary)];
+    File::create(&path).unwrap().write_all(&flatgeobuf::write::file(&columns, &[
+        (park, vec![(0, PropertyValue::String("Phoenix Park".to_string())), (1, PropertyValue::Int(707))]),
+        (other_park, vec![(1, PropertyValue::Int(1))]),
+    ])).unwrap();
+
+    let layers = Layers::from_tmsource_str(&format!("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  source:
+    type: flatgeobuf
+    path: {}
+", path.display())).unwrap();
+
+    // No database is needed
+    let mut connection_pool = ConnectionPool::new(layers.get_all_connections());
+    connection_pool.open_files(&layers).unwrap();
+    // Binary columns can't be in a vector tile
+    assert_eq!(connection_pool.source_for_layer(&layers.layers[0]).columns(&layers.layers[0]).unwrap(),
+               vec![("name".to_string(), "String".to_string()), ("area".to_string(), "Number".to_string())]);
+
+    let mut stats = GenerateStats::default();
+    let tiles = single_metatile(&layers, &Metatile::new(1, 1, 0, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+    // Only the park in this tile
+    assert_eq!(stats.features_encoded, 1);
+    let (_, ref tile) = *tiles.iter().find(|&&(ref t, _)| (t.x(), t.y()) == (0, 0)).unwrap();
+
+    let decoded = decode::decode_tile_layers(&tile.to_bytes()).unwrap();
+    assert_eq!(decoded.len(), 1);
+    assert_eq!(decoded[0].name, "parks");
+    assert_eq!(decoded[0].features.len(), 1);
+    let feature = &decoded[0].features[0];
+    // 1/4 & 3/4 of the way across the tile
+    let bbox = geometry_bbox(&feature.geometry().unwrap()).unwrap();
+    for &(actual, expected) in [(bbox.xmin, 1030), (bbox.xmax, 3074), (bbox.ymin, 1030), (bbox.ymax, 3074)].iter() {
+        assert!((actual - expected).abs() <= 1, "{:?}", bbox);
+    }
+    let mut properties = feature.properties.clone();
+    properties.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(properties, vec![("area".to_string(), decode::DecodedValue::Int(707)), ("name".to_string(), decode::DecodedValue::String("Phoenix Park".to_string()))]);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn failed_metatiles_are_counted() {
+    // A geometry which isn't a GeoPackage geometry, so reading the layer fails for every metatile
+    let name = "failed_metatiles_are_counted";
+    let gpkg = temp_path(&format!("{}.gpkg", name));
+    create_parks_geopackage(&gpkg);
+    rusqlite::Connection::open(&gpkg).unwrap().execute("INSERT INTO parks (geom, name, area) VALUES (?1, 'Broken', 0);", &[&b"not a geometry".to_vec()]).unwrap();
+    let tmsource = format!("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  Datasource:
+    type: geopackage
+    file: {}
+    table: parks
+", gpkg.display());
+
+    let layers = Layers::from_tmsource_str(&tmsource).unwrap();
+    let mut connection_pool = ConnectionPool::new(layers.get_all_connections());
+    connection_pool.open_files(&layers).unwrap();
+    let err = single_metatile(&layers, &Metatile::new(1, 1, 0, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut GenerateStats::default()).unwrap_err();
+    assert!(err.to_string().contains("Query for layer parks"), "{}", err);
+
+    // The run carries on, and every failed metatile is counted
+    let data_yml = temp_path(&format!("{}.yml", name));
+    fs::write(&data_yml, &tmsource).unwrap();
+    let stats = generate_all(&[data_yml.to_str().unwrap()], 0, 1, &None, &TileDestinationType::Null, false, true, 1, 2, None, 10, true, &GenerateOptions::default()).unwrap();
+    assert_eq!(stats.metatiles_failed, 1 + 4);
+    assert_eq!(stats.tiles_written, 0);
+
+    fs::remove_file(&gpkg).unwrap();
+    fs::remove_file(&data_yml).unwrap();
+}
+
+#[test]
+fn wkt_source() {
+    // The same park as create_parks_geopackage, in tile 1/0/0
+    let layers = Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POLYGON((-15e6 5e6, -5e6 5e6, -5e6 15e6, -15e6 15e6, -15e6 5e6))
+      attrs: {name: Phoenix Park, area: 707, fee: null}
+").unwrap();
+    assert_eq!(layers.layers[0].inline_features.as_ref().unwrap().len(), 1);
+    assert!(layers.get_all_connections().is_empty());
+
+    let connection_pool = ConnectionPool::new(layers.get_all_connections());
+    assert_eq!(connection_pool.source_for_layer(&layers.layers[0]).columns(&layers.layers[0]).unwrap(),
+               vec![("area".to_string(), "Number".to_string()), ("name".to_string(), "String".to_string())]);
+
+    let mut stats = GenerateStats::default();
+    let tiles = single_metatile(&layers, &Metatile::new(1, 1, 0, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+    assert_eq!(stats.features_encoded, 1);
+    let (_, ref tile) = *tiles.iter().find(|&&(ref t, _)| (t.x(), t.y()) == (0, 0)).unwrap();
+
+    let decoded = decode::decode_tile_layers(&tile.to_bytes()).unwrap();
+    assert_eq!(decoded.len(), 1);
+    assert_eq!(decoded[0].name, "parks");
+    assert_eq!(decoded[0].features.len(), 1);
+    let feature = &decoded[0].features[0];
+    let geom = feature.geometry().unwrap();
+    match geom {
+        Geometry::Polygon(ref p) => {
+            assert_eq!(p.exterior.0.len(), 5);
+            assert!(p.interiors.is_empty());
+        },
+        ref x => panic!("{:?}", x),
+    }
+    // 1/4 & 3/4 of the way across the tile
+    let bbox = geometry_bbox(&geom).unwrap();
+    for &(actual, expected) in [(bbox.xmin, 1030), (bbox.xmax, 3074), (bbox.ymin, 1030), (bbox.ymax, 3074)].iter() {
+        assert!((actual - expected).abs() <= 1, "{:?}", bbox);
+    }
+    let mut properties = feature.properties.clone();
+    properties.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(properties, vec![("area".to_string(), decode::DecodedValue::Int(707)), ("name".to_string(), decode::DecodedValue::String("Phoenix Park".to_string()))]);
+
+    // The same feature as hex WKB
+    let mut wkb = Vec::new();
+    wkb::write_geom_to_wkb(&Geometry::Polygon(Polygon::new(vec![(-15e6, 5e6), (-5e6, 5e6), (-5e6, 15e6), (-15e6, 15e6), (-15e6, 5e6)].into(), vec![])), &mut wkb);
+    let hex: String = wkb.iter().map(|b| format!("{:02x}", b)).collect();
+    let layers_from_wkb = Layers::from_tmsource_str(&format!("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  Datasource:
+    type: wkt
+    features:
+    - wkb: {}
+      attrs: {{name: Phoenix Park, area: 707}}
+", hex)).unwrap();
+    assert_eq!(layers_from_wkb.layers[0].inline_features, layers.layers[0].inline_features);
+
+    let err = Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POLYGON((0 0, 1 1)
+").unwrap_err().to_string();
+    assert!(err.contains("parks") && err.contains("Invalid WKT"), "{}", err);
+}
+
+#[test]
+fn dump_invalid() {
+    let path = temp_path("dump_invalid.geojson");
+    let layers = Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POLYGON((-15e6 5e6, -5e6 5e6, -5e6 15e6, -15e6 15e6, -15e6 5e6))
+    # All the points are on one line, so it can't be made valid
+    - wkt: POLYGON((-15e6 5e6, -10e6 5e6, -5e6 5e6, -15e6 5e6))
+").unwrap();
+    let dump = Arc::new(InvalidGeometryDump::create(&path).unwrap());
+    let mut connection_pool = ConnectionPool::new(layers.get_all_connections());
+    connection_pool.set_invalid_dump(Some(Arc::clone(&dump)));
+
+    let mut stats = GenerateStats::default();
+    single_metatile(&layers, &Metatile::new(1, 1, 0, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+    assert_eq!(stats.features_encoded, 1);
+    assert_eq!(dump.finish().unwrap(), 1);
+
+    let geojson: serde_json::Value = serde_json::from_reader(File::open(&path).unwrap()).unwrap();
+    assert_eq!(geojson["type"], json!("FeatureCollection"));
+    let features = geojson["features"].as_array().unwrap();
+    assert_eq!(features.len(), 1);
+    assert_eq!(features[0]["properties"]["layer"], json!("parks"));
+    assert_eq!((&features[0]["properties"]["zoom"], &features[0]["properties"]["x"], &features[0]["properties"]["y"]), (&json!(1), &json!(0), &json!(0)));
+    assert_eq!(features[0]["geometry"]["type"], json!("Polygon"));
+    // In degrees, about where it is in the data.yml
+    let coordinates = features[0]["geometry"]["coordinates"][0].as_array().unwrap();
+    assert_eq!(coordinates.len(), 4);
+    let lon = coordinates[0][0].as_f64().unwrap();
+    assert!((lon - -134.7).abs() < 0.1, "{}", lon);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn vertex_budget_only_on_low_zooms() {
+    let mut options = GenerateOptions::default();
+    options.make_valid_vertex_budget = Some(200);
+    let geometry = GeometryOptions::from_options(&options);
+    assert_eq!(geometry.for_zoom(DEFAULT_VERTEX_BUDGET_MAX_ZOOM).validity.vertex_budget, Some(200));
+    assert_eq!(geometry.for_zoom(DEFAULT_VERTEX_BUDGET_MAX_ZOOM+1).validity.vertex_budget, None);
+
+    options.make_valid_vertex_budget_max_zoom = Some(4);
+    let geometry = GeometryOptions::from_options(&options);
+    assert_eq!(geometry.for_zoom(4).validity.vertex_budget, Some(200));
+    assert_eq!(geometry.for_zoom(5).validity.vertex_budget, None);
+}
+
+#[test]
+fn dump_invalid_closed_when_dropped() {
+    let path = temp_path("dump_invalid_closed_when_dropped.geojson");
+    let metatile = Metatile::new(1, 1, 0, 0).unwrap();
+    let dump = InvalidGeometryDump::create(&path).unwrap();
+    dump.add("roads", &metatile, 1, &Geometry::Line(geo::Line::new(Point::new(0, 0), Point::new(10, 10))), "test");
+    dump.add("roads", &metatile, 1, &Geometry::GeometryCollection(GeometryCollection(vec![Point::new(0, 0).into(), LineString(vec![Point::new(0, 0), Point::new(10, 0)]).into()])), "test");
+    dump.add_unreadable("roads", &metatile, "test");
+    // Not finished, e.g. generating failed
+    drop(dump);
+
+    let geojson: serde_json::Value = serde_json::from_reader(File::open(&path).unwrap()).unwrap();
+    let features = geojson["features"].as_array().unwrap();
+    assert_eq!(features.len(), 3);
+    assert_eq!(features[0]["geometry"]["type"], json!("LineString"));
+    assert_eq!(features[0]["geometry"]["coordinates"].as_array().unwrap().len(), 2);
+    assert_eq!(features[1]["geometry"]["type"], json!("GeometryCollection"));
+    let types: Vec<_> = features[1]["geometry"]["geometries"].as_array().unwrap().iter().map(|g| g["type"].clone()).collect();
+    assert_eq!(types, vec![json!("Point"), json!("LineString")]);
+    assert_eq!(features[2]["geometry"], serde_json::Value::Null);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn per_layer_buffer() {
+    // Everything crosses the right edge of tile 1/0/0, at x=0
+    let layers = Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+buffer-size: 8
+Layer:
+- id: roads
+  properties:
+    buffer-size: 64
+  Datasource:
+    type: wkt
+    features:
+    - wkt: LINESTRING(-10e6 10e6, 10e6 10e6)
+- id: parks
+  properties:
+    buffer-size: 0
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POLYGON((-10e6 5e6, 10e6 5e6, 10e6 15e6, -10e6 15e6, -10e6 5e6))
+- id: water
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POLYGON((-10e6 5e6, 10e6 5e6, 10e6 15e6, -10e6 15e6, -10e6 5e6))
+").unwrap();
+    assert_eq!(layers.layers.iter().map(|l| l.buffer).collect::<Vec<_>>(), vec![64, 0, 8]);
+
+    let connection_pool = ConnectionPool::new(layers.get_all_connections());
+    let mut stats = GenerateStats::default();
+    let tiles = single_metatile(&layers, &Metatile::new(1, 1, 0, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+    let (_, ref tile) = *tiles.iter().find(|&&(ref t, _)| (t.x(), t.y()) == (0, 0)).unwrap();
+
+    let max_x = |name: &str| -> i32 {
+        let decoded = decode::decode_tile_layers(&tile.to_bytes()).unwrap();
+        let layer = decoded.iter().find(|l| l.name == name).unwrap();
+        assert_eq!(layer.features.len(), 1);
+        geometry_bbox(&layer.features[0].geometry().unwrap()).unwrap().xmax
+    };
+    // 'Pixels' of buffer are 16 tile units
+    assert_eq!(max_x("roads"), 4096 + 64*16);
+    assert_eq!(max_x("parks"), 4096);
+    assert_eq!(max_x("water"), 4096 + 8*16);
+}
+
+#[test]
+fn full_tile_polygon() {
+    // Bigger than the whole metatile, so each of its 4 tiles (& buffer) is covered
+    let layers = Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: sea
+  properties:
+    buffer-size: 4
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POLYGON((-19e6 -19e6, 19e6 -19e6, 19e6 19e6, -19e6 19e6, -19e6 -19e6))
+").unwrap();
+    let connection_pool = ConnectionPool::new(layers.get_all_connections());
+
+    let mut stats = GenerateStats::default();
+    let tiles = single_metatile(&layers, &Metatile::new(2, 2, 0, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+    assert_eq!(tiles.len(), 4);
+    let corners = [(-64, -64), (-64, 4160), (4160, 4160), (4160, -64)];
+    for &(ref tile, ref mvt) in tiles.iter() {
+        let decoded = decode::decode_tile_layers(&mvt.to_bytes()).unwrap();
+        assert_eq!(decoded[0].features.len(), 1, "{:?}", tile);
+        let geom = decoded[0].features[0].geometry().unwrap();
+        assert!(validity::is_valid_with_y_axis(&geom, validity::YAxis::Down), "{:?} {:?}", tile, geom);
+        match geom {
+            Geometry::Polygon(ref p) => {
+                assert!(p.interiors.is_empty());
+                let mut points: Vec<(i32, i32)> = p.exterior.0.iter().map(|p| (p.x(), p.y())).collect();
+                if points.first() == points.last() {
+                    points.pop();
+                }
+                assert_eq!(points.len(), 4, "{:?} {:?}", tile, points);
+                assert!(points.iter().all(|p| corners.contains(p)), "{:?} {:?}", tile, points);
+            },
+            ref x => panic!("{:?} {:?}", tile, x),
+        }
+    }
+}
+
+#[test]
+fn repaired_slivers_in_buffer_are_dropped() {
+    // A bowtie over the right edge of tile 1/0/0. Making it valid gives 2 triangles, meeting at
+    // (4100, 1100), and the right one is only in the buffer, so is left out.
+    let unit = MERCATOR_HALF_WIDTH / 4096.;
+    let point = |x: f64, y: f64| format!("{} {}", -MERCATOR_HALF_WIDTH + x * unit, MERCATOR_HALF_WIDTH - y * unit);
+    let layers = Layers::from_tmsource_str(&format!("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  properties:
+    buffer-size: 8
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POLYGON(({}, {}, {}, {}, {}))
+", point(4000., 1000.), point(4200., 1200.), point(4200., 1000.), point(4000., 1200.), point(4000., 1000.))).unwrap();
+    let connection_pool = ConnectionPool::new(layers.get_all_connections());
+
+    let mut stats = GenerateStats::default();
+    let tiles = single_metatile(&layers, &Metatile::new(1, 1, 0, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+    assert_eq!(stats.features_encoded, 1);
+    let decoded = decode::decode_tile_layers(&tiles[0].1.to_bytes()).unwrap();
+    assert_eq!(decoded[0].features.len(), 1);
+    let geom = decoded[0].features[0].geometry().unwrap();
+    let bbox = geometry_bbox(&geom).unwrap();
+    assert!(bbox.xmin >= 3999 && bbox.xmax <= 4101, "{:?}", geom);
+}
+
+#[test]
+fn simplify_max_zoom() {
+    // A line in tile 14/8192/8191 (just north east of 0,0), zigzagging by 3 metres, which is 5
+    // units on zoom 14, so less than the simplification tolerance
+    let points: Vec<String> = (0..11).map(|i| format!("{} {}", 200 + i * 200, if i % 2 == 0 { 1197 } else { 1203 })).collect();
+    let layers = Layers::from_tmsource_str(&format!("
+minzoom: 0
+maxzoom: 16
+Layer:
+- id: exact
+  properties:
+    simplify-max-zoom: 14
+  Datasource:
+    type: wkt
+    features:
+    - wkt: LINESTRING({points})
+- id: simplified
+  Datasource:
+    type: wkt
+    features:
+    - wkt: LINESTRING({points})
+", points=points.join(", "))).unwrap();
+    assert_eq!(layers.layers[0].simplify_max_zoom, Some(14));
+    assert_eq!(layers.layers[1].simplify_max_zoom, None);
+    let connection_pool = ConnectionPool::new(layers.get_all_connections());
+
+    let vertices = |zoom: u8, x: u32, y: u32, layer_name: &str| -> usize {
+        let mut stats = GenerateStats::default();
+        let tiles = single_metatile(&layers, &Metatile::new(1, zoom, x, y).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+        let decoded = decode::decode_tile_layers(&tiles[0].1.to_bytes()).unwrap();
+        let layer = decoded.iter().find(|l| l.name == layer_name).unwrap();
+        assert_eq!(layer.features.len(), 1);
+        layer.features[0].parts.iter().map(|p| p.len()).sum()
+    };
+
+    assert_eq!(vertices(14, 8192, 8191, "exact"), 11);
+    assert!(vertices(14, 8192, 8191, "simplified") < 11);
+    assert!(vertices(10, 512, 511, "exact") < 11);
+}
+
+#[test]
+fn split_by() {
+    let layers = Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: roads
+  properties:
+    split-by:
+      column: class
+      mapping: {motorway: roads_major, primary: roads_major}
+      default: roads_minor
+  Datasource:
+    type: wkt
+    features:
+    - {wkt: 'POINT(1e6 1e6)', attrs: {class: motorway}}
+    - {wkt: 'POINT(2e6 1e6)', attrs: {class: primary}}
+    - {wkt: 'POINT(3e6 1e6)', attrs: {class: residential}}
+    - {wkt: 'POINT(4e6 1e6)', attrs: {name: Unclassified}}
+- id: road_labels
+  properties:
+    split-by:
+      column: class
+      mapping: {motorway: motorway_labels}
+  Datasource:
+    type: wkt
+    features:
+    - {wkt: 'POINT(1e6 1e6)', attrs: {class: motorway}}
+    - {wkt: 'POINT(3e6 1e6)', attrs: {class: residential}}
+").unwrap();
+    assert_eq!(layers.layers[0].tile_layer_names(), vec!["roads_major".to_string(), "roads_minor".to_string()]);
+    assert_eq!(layers.layers[1].tile_layer_names(), vec!["motorway_labels".to_string()]);
+    let connection_pool = ConnectionPool::new(layers.get_all_connections());
+
+    let mut stats = GenerateStats::default();
+    let tiles = single_metatile(&layers, &Metatile::new(1, 0, 0, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+    let decoded = decode::decode_tile_layers(&tiles[0].1.to_bytes()).unwrap();
+    assert!(decoded.iter().all(|l| l.name != "roads" && l.name != "road_labels"));
+
+    let classes = |layer_name: &str| -> Vec<String> {
+        let layer = decoded.iter().find(|l| l.name == layer_name).unwrap();
+        let mut classes: Vec<String> = layer.features.iter().map(|f| match f.properties.iter().find(|&&(ref k, _)| k == "class") {
+            Some(&(_, decode::DecodedValue::String(ref s))) => s.clone(),
+            Some(x) => panic!("{:?}", x),
+            None => String::new(),
+        }).collect();
+        classes.sort();
+        classes
+    };
+    assert_eq!(classes("roads_major"), vec!["motorway", "primary"]);
+    assert_eq!(classes("roads_minor"), vec!["", "residential"]);
+    // There's no default, so the residential road's label is left out
+    assert_eq!(classes("motorway_labels"), vec!["motorway"]);
+}
+
+#[test]
+fn snap_tile_edges() {
+    // A polygon over the edge between tiles 1/0/0 & 1/1/0 (at x=0), with a vertex about 1.3 tile
+    // units right of it, and one 1.6 units left of it
+    let unit = MERCATOR_HALF_WIDTH / 4096.;
+    // With oversampling, snapping still happens on the tile's own grid, after downsampling
+    for &oversample in &[1, 2] {
+        let layers = Layers::from_tmsource_str(&format!("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  properties:
+    buffer-size: 4
+    snap-tile-edges: 4
+    oversample: {}
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POLYGON((-5e6 5e6, {} 7e6, 5e6 5e6, 5e6 15e6, {} 13e6, -5e6 15e6, -5e6 5e6))
+", oversample, 1.3 * unit, -1.6 * unit)).unwrap();
+        let connection_pool = ConnectionPool::new(layers.get_all_connections());
+
+        // Each tile is generated on its own, so nothing is shared between them
+        let edge_ys = |x: u32, edge_x: i32| -> Vec<i32> {
+            let mut stats = GenerateStats::default();
+            let tiles = single_metatile(&layers, &Metatile::new(1, 1, x, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+            let decoded = decode::decode_tile_layers(&tiles[0].1.to_bytes()).unwrap();
+            assert_eq!(decoded[0].features.len(), 1);
+            let geom = decoded[0].features[0].geometry().unwrap();
+            assert!(validity::is_valid_with_y_axis(&geom, validity::YAxis::Down));
+            let ys = RefCell::new(Vec::new());
+            geom.map_coords(&|&(x, y)| { if x == edge_x { ys.borrow_mut().push(y); } (x, y) });
+            let mut ys = ys.into_inner();
+            ys.sort();
+            ys.dedup();
+            ys
+        };
+        let left = edge_ys(0, 4096);
+        let right = edge_ys(1, 0);
+        assert_eq!(left.len(), 2, "oversample {}: {:?}", oversample, left);
+        assert_eq!(left, right, "oversample {}", oversample);
+        assert!(left.iter().all(|y| y % 4 == 0), "oversample {}: {:?}", oversample, left);
+    }
+}
+
+#[test]
+fn tile_boundary_point() {
+    // A point on the edge between 2 tiles in Dublin, a bit off a whole tile unit vertically
+    let tile_matrix = TileMatrix::default();
+    let (x, y) = tile_matrix.tile_for_point(14, -695000., 7049000.);
+    let (edge_x, miny, _, maxy) = tile_matrix.tile_bbox(14, x + 1, y);
+    let point_y = miny + (maxy - miny) * 0.3;
+    let layers = Layers::from_tmsource_str(&format!("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: pois
+  properties:
+    buffer-size: 4
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POINT({} {})
+", edge_x, point_y)).unwrap();
+    let connection_pool = ConnectionPool::new(layers.get_all_connections());
+
+    // Each tile is generated on its own, so nothing is shared between them
+    let point_in_tile = |x: u32| -> (i32, i32) {
+        let mut stats = GenerateStats::default();
+        let tiles = single_metatile(&layers, &Metatile::new(1, 14, x, y).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+        let decoded = decode::decode_tile_layers(&tiles[0].1.to_bytes()).unwrap();
+        assert_eq!(decoded[0].features.len(), 1);
+        match decoded[0].features[0].geometry().unwrap() {
+            Geometry::Point(p) => (p.x(), p.y()),
+            g => panic!("{:?}", g),
+        }
+    };
+    let left = point_in_tile(x);
+    let right = point_in_tile(x + 1);
+    assert_eq!(left.0, 4096);
+    assert_eq!(right.0, 0);
+    assert_eq!(left.1, right.1);
+
+    // Every rounding puts it in the same place on the edge
+    let point = Geometry::Point(Point::new(edge_x, point_y));
+    for &rounding in [CoordinateRounding::Round, CoordinateRounding::Floor, CoordinateRounding::RoundHalfEven].iter() {
+        let project = |x: u32| -> Geometry<i32> {
+            let projection = Projection{ rounding, ..Projection::for_metatile(&tile_matrix, &Metatile::new(1, 14, x, y).unwrap(), 4096., &GeometryOptions::default()) };
+            project_geometry(point.clone(), &projection).unwrap()
+        };
+        match (project(x), project(x + 1)) {
+            (Geometry::Point(left), Geometry::Point(right)) => {
+                assert_eq!(left.x() - 4096, right.x(), "{:?}", rounding);
+                assert_eq!(left.y(), right.y(), "{:?}", rounding);
+            },
+            g => panic!("{:?}", g),
+        }
+    }
+}
+
+#[test]
+fn validate_source_report() {
+    // All in tile 1/0/0, so in the one tile on zoom 0
+    let layers = Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POLYGON((-15e6 5e6, -5e6 5e6, -5e6 15e6, -15e6 15e6, -15e6 5e6))
+    # A bowtie, which make_valid turns into 2 triangles
+    - wkt: POLYGON((-15e6 5e6, -5e6 15e6, -5e6 5e6, -15e6 15e6, -15e6 5e6))
+    # No area, so nothing is left after repairing
+    - wkt: POLYGON((-15e6 5e6, -10e6 5e6, -5e6 5e6, -15e6 5e6))
+    - wkt: LINESTRING(-15e6 5e6, -5e6 15e6)
+- id: labels
+  properties:
+    minzoom: 5
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POINT(-10e6 10e6)
+").unwrap();
+    let connection_pool = ConnectionPool::new(layers.get_all_connections());
+
+    let report = sample_source_validity(&layers, &connection_pool, 0, 0, &None, 3, 42, &TileMatrix::default(), &GeometryOptions::default()).unwrap();
+    assert_eq!(report, vec![
+        SourceValidity{ layer: "parks".to_string(), tiles: 3, features: 12, unreadable: 0, invalid: 6, repaired: 3, dropped: 3 },
+        SourceValidity{ layer: "labels".to_string(), tiles: 0, features: 0, unreadable: 0, invalid: 0, repaired: 0, dropped: 0 },
+    ]);
+    assert_eq!(report[0].to_string(), "Layer parks: 12 features in 3 tiles, 6 invalid (3 repaired, 3 dropped)");
+
+    // Only the labels layer is on zoom 5, and the point is only in one of the tiles in this bbox
+    let bbox = BBox::new(89., -180., -89., 180.).unwrap();
+    let report = sample_source_validity(&layers, &connection_pool, 5, 5, &Some(bbox), 20, 1, &TileMatrix::default(), &GeometryOptions::default()).unwrap();
+    assert_eq!(report[0].tiles, 0);
+    assert_eq!(report[1].tiles, 20);
+    assert_eq!(report[1].invalid, 0);
+    assert!(report[1].features < 20);
+}
+
+#[test]
+fn layer_name_override() {
+    let layers = Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: bldg
+  name: building
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POLYGON((-15e6 5e6, -5e6 5e6, -5e6 15e6, -15e6 15e6, -15e6 5e6))
+").unwrap();
+    let connection_pool = ConnectionPool::new(layers.get_all_connections());
+    let mut stats = GenerateStats::default();
+    let tiles = single_metatile(&layers, &Metatile::new(1, 1, 0, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+    assert_eq!(stats.features_encoded, 1);
+
+    // The tile & the TileJSON have the published name, not the id
+    let decoded = decode::decode_tile_layers(&tiles[0].1.to_bytes()).unwrap();
+    assert_eq!(decoded.len(), 1);
+    assert_eq!(decoded[0].name, "building");
+    assert_eq!(decoded[0].features.len(), 1);
+    let vector_layers = vector_layers_with_columns(&layers, |_| Ok(vec![])).unwrap();
+    assert_eq!(vector_layers[0]["id"], "building");
+}
+
+#[test]
+fn layer_filter() {
+    let path = temp_path("layer_filter.gpkg");
+    create_parks_geopackage(&path);
+    rusqlite::Connection::open(&path).unwrap().execute("INSERT INTO parks (geom, name, area) SELECT geom, 'Pocket Park', 1 FROM parks;", &[]).unwrap();
+
+    let layers = Layers::from_tmsource_str(&format!("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  properties:
+    filter: area > 100 and zoom >= 1
+    include-attributes: [name]
+  Datasource:
+    type: geopackage
+    file: {}
+    table: parks
+", path.display())).unwrap();
+    let mut connection_pool = ConnectionPool::new(layers.get_all_connections());
+    connection_pool.open_files(&layers).unwrap();
+
+    // No parks on zoom 0
+    let mut stats = GenerateStats::default();
+    single_metatile(&layers, &Metatile::new(1, 0, 0, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+    assert_eq!(stats.features_encoded, 0);
+
+    // Only the big park on zoom 1
+    let mut stats = GenerateStats::default();
+    let tiles = single_metatile(&layers, &Metatile::new(1, 1, 0, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+    assert_eq!(stats.features_encoded, 1);
+    let bytes = tiles[0].1.to_bytes();
+    let mvt_layers: Vec<_> = protobuf_fields(&bytes).into_iter().filter(|&(f, _)| f == 3).collect();
+    // Layer.keys is field 3, Layer.values is 4, and a string value is field 1 of that
+    let keys: Vec<_> = protobuf_fields(mvt_layers[0].1).into_iter().filter(|&(f, _)| f == 3).map(|(_, k)| k).collect();
+    assert_eq!(keys, vec![&b"name"[..]], "area is only for the filter");
+    let values: Vec<_> = protobuf_fields(mvt_layers[0].1).into_iter().filter(|&(f, _)| f == 4).flat_map(|(_, v)| protobuf_fields(v)).map(|(_, v)| v).collect();
+    assert_eq!(values, vec![&b"Phoenix Park"[..]]);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn split_geometry_collection1() {
+    let polygon = Polygon::new(LineString(vec![Point::new(0., 0.), Point::new(10., 0.), Point::new(10., 10.), Point::new(0., 0.)]), vec![]);
+    let line = LineString(vec![Point::new(20., 0.), Point::new(30., 10.)]);
+    let gc = Geometry::GeometryCollection(GeometryCollection(vec![polygon.clone().into(), line.clone().into()]));
+    assert_eq!(split_geometry_collection(gc), vec![Geometry::LineString(line.clone()), Geometry::Polygon(polygon.clone())]);
+
+    // Nested collections are flattened, and parts of the same type go together
+    let gc = Geometry::GeometryCollection(GeometryCollection(vec![
+        line.clone().into(),
+        Geometry::GeometryCollection(GeometryCollection(vec![Point::new(1., 1.).into(), line.clone().into()])),
+    ]));
+    assert_eq!(split_geometry_collection(gc), vec![
+        Geometry::Point(Point::new(1., 1.)),
+        Geometry::MultiLineString(MultiLineString(vec![line.clone(), line.clone()])),
+    ]);
+
+    assert_eq!(split_geometry_collection(line.clone().into()), vec![Geometry::LineString(line)]);
+    assert_eq!(split_geometry_collection::<f64>(Geometry::GeometryCollection(GeometryCollection(vec![]))), vec![]);
+}
+
+#[test]
+fn geometry_collection_feature() {
+    let path = temp_path("geometry_collection_feature.gpkg");
+    let tile_matrix = TileMatrix::default();
+    let (x, y) = tile_matrix.tile_for_point(14, -695000., 7049000.);
+    let (minx, miny, maxx, maxy) = tile_matrix.tile_bbox(14, x, y);
+    let (cx, cy) = ((minx + maxx) / 2., (miny + maxy) / 2.);
+    let square: Geometry<f64> = Polygon::new(LineString(vec![
+        Point::new(cx - 200., cy - 200.), Point::new(cx + 200., cy - 200.), Point::new(cx + 200., cy + 200.), Point::new(cx - 200., cy + 200.), Point::new(cx - 200., cy - 200.),
+    ]), vec![]).into();
+    let line: Geometry<f64> = LineString(vec![Point::new(cx - 500., cy + 400.), Point::new(cx + 500., cy + 500.)]).into();
+
+    // GEOMETRYCOLLECTION(POLYGON(...), LINESTRING(...)) as little endian WKB
+    let mut collection = vec![1, 7, 0, 0, 0, 2, 0, 0, 0];
+    collection.extend(to_wkb(&square));
+    collection.extend(to_wkb(&line));
+    create_geopackage(&path, "things", "GEOMETRYCOLLECTION", "name TEXT", &[(collection, "'Park & path'")]);
+
+    let layers = Layers::from_tmsource_str(&format!("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: things
+  Datasource:
+    type: geopackage
+    file: {}
+    table: things
+", path.display())).unwrap();
+    let mut connection_pool = ConnectionPool::new(layers.get_all_connections());
+    connection_pool.open_files(&layers).unwrap();
+
+    let mut stats = GenerateStats::default();
+    let tiles = single_metatile(&layers, &Metatile::new(1, 14, x, y).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+    assert_eq!(stats.features_encoded, 2);
+    assert_eq!(stats.features_dropped, 0);
+
+    // A line & a polygon feature (Layer.features is field 2), which both have the collection's
+    // attributes
+    let bytes = tiles[0].1.to_bytes();
+    let mvt_layers: Vec<_> = protobuf_fields(&bytes).into_iter().filter(|&(f, _)| f == 3).collect();
+    assert_eq!(protobuf_fields(mvt_layers[0].1).into_iter().filter(|&(f, _)| f == 2).count(), 2);
+    let values: Vec<_> = protobuf_fields(mvt_layers[0].1).into_iter().filter(|&(f, _)| f == 4).flat_map(|(_, v)| protobuf_fields(v)).map(|(_, v)| v).collect();
+    assert_eq!(values, vec![&b"Park & path"[..]]);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn downsample() {
+    let geom: Geometry<i32> = LineString(vec![Point::new(0, 0), Point::new(1, 3), Point::new(-1, -3), Point::new(8192, -8192)]).into();
+    assert_eq!(downsample_geometry(geom, 2), LineString(vec![Point::new(0, 0), Point::new(1, 2), Point::new(0, -1), Point::new(4096, -4096)]).into());
+
+    // A sliver which is less than a unit wide at the tile's resolution is dropped
+    let sliver: Geometry<i32> = Polygon::new(vec![(0, 0), (4000, 0), (4000, 1), (0, 0)].into(), vec![]).into();
+    let tile_bbox = Bbox{ xmin: -64, xmax: 4096 + 64, ymin: -64, ymax: 4096 + 64 };
+    assert_eq!(finish_oversampled_tile_geometry(sliver, &tile_bbox, 4, None, None), None);
+}
+
+#[test]
+fn layer_oversample() {
+    let path = temp_path("layer_oversample.gpkg");
+    create_parks_geopackage(&path);
+
+    let tile = |oversample: u8| {
+        let layers = Layers::from_tmsource_str(&format!("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  properties:
+    buffer-size: 4
+    oversample: {}
+  Datasource:
+    type: geopackage
+    file: {}
+    table: parks
+", oversample, path.display())).unwrap();
+        let mut connection_pool = ConnectionPool::new(layers.get_all_connections());
+        connection_pool.open_files(&layers).unwrap();
+        let mut stats = GenerateStats::default();
+        let tiles = single_metatile(&layers, &Metatile::new(1, 1, 0, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+        assert_eq!(stats.features_encoded, 1);
+        tiles[0].1.to_bytes()
+    };
+
+    // The park's corners are at about 1029.8 & 3073.9, so rounding at 2× gives the same tile
+    let direct = tile(1);
+    let oversampled = tile(2);
+    assert!(tiles_geometrically_equal(&direct, &oversampled, 1));
+
+    // A 0.1 unit wide notch at the tile's right edge is rounded to a 1 unit wide one directly, but
+    // closes up at 2×, so the oversampled tile is just the rectangle
+    let wkt_tile = |wkt: &str, oversample: u8| {
+        let layers = Layers::from_tmsource_str(&format!("
+minzoom: 0
+maxzoom: 1
+Layer:
+- id: parks
+  properties:
+    buffer-size: 4
+    oversample: {}
+  Datasource:
+    type: wkt
+    features:
+    - wkt: {}
+", oversample, wkt)).unwrap();
+        let connection_pool = ConnectionPool::new(layers.get_all_connections());
+        let mut stats = GenerateStats::default();
+        let tiles = single_metatile(&layers, &Metatile::new(1, 1, 0, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+        assert_eq!(stats.features_encoded, 1);
+        tiles[0].1.to_bytes()
+    };
+    let num_vertices = |bytes: &[u8]| -> usize {
+        decode::decode_tile_layers(bytes).unwrap().iter()
+            .flat_map(|l| l.features.iter())
+            .flat_map(|f| f.parts.iter())
+            .map(|p| p.len())
+            .sum()
+    };
+    let rectangle = "POLYGON((-15028131.25 15028131.25, 4422340.71 15028131.25, 4422340.71 5009377.09, -15028131.25 5009377.09, -15028131.25 15028131.25))";
+    let notched = "POLYGON((-15028131.25 15028131.25, 2201.39 15028131.25, 2201.39 12523442.71, 2690.58 12523442.71, 2690.58 15028131.25, 4422340.71 15028131.25, 4422340.71 5009377.09, -15028131.25 5009377.09, -15028131.25 15028131.25))";
+    let direct = wkt_tile(notched, 1);
+    let oversampled = wkt_tile(notched, 2);
+    assert!(tiles_geometrically_equal(&wkt_tile(rectangle, 1), &oversampled, 1));
+    assert_eq!(num_vertices(&oversampled), num_vertices(&wkt_tile(rectangle, 1)));
+    assert_eq!(num_vertices(&direct), num_vertices(&oversampled) + 4);
+
+    assert!(Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  properties:
+    oversample: 0
+  Datasource:
+    type: postgis
+    table: parks
+").is_err());
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn background_layer() {
+    let path = temp_path("background_layer.gpkg");
+    create_parks_geopackage(&path);
+
+    let layers = Layers::from_tmsource_str(&format!("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  properties:
+    background: {{class: grass, height: 0}}
+  Datasource:
+    type: geopackage
+    file: {}
+    table: parks
+", path.display())).unwrap();
+    assert_eq!(layers.layers[0].background, Some(vec![
+        ("class".to_string(), filter::Literal::String("grass".to_string())),
+        ("height".to_string(), filter::Literal::Number(0.)),
+    ]));
+    let mut connection_pool = ConnectionPool::new(layers.get_all_connections());
+    connection_pool.open_files(&layers).unwrap();
+
+    // The park is only in 1/0/0
+    let mut stats = GenerateStats::default();
+    let tiles = single_metatile(&layers, &Metatile::new(2, 1, 0, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut stats).unwrap();
+    assert_eq!(tiles.len(), 4);
+    // The backgrounds aren't counted
+    assert_eq!(stats.features_encoded, 1);
+    for &(tile, ref mvt_tile) in tiles.iter() {
+        let bytes = mvt_tile.to_bytes();
+        let mvt_layers: Vec<_> = protobuf_fields(&bytes).into_iter().filter(|&(f, _)| f == 3).collect();
+        let layer_fields = protobuf_fields(mvt_layers[0].1);
+        assert_eq!(layer_fields.iter().filter(|&&(f, _)| f == 2).count(), 1);
+        let mut keys: Vec<_> = layer_fields.iter().filter(|&&(f, _)| f == 3).map(|&(_, k)| k).collect();
+        keys.sort();
+        if (tile.x(), tile.y()) == (0, 0) {
+            // The real park, not the background
+            assert!(!keys.contains(&&b"class"[..]), "{:?}", tile);
+        } else {
+            assert_eq!(keys, vec![&b"class"[..], &b"height"[..]], "{:?}", tile);
+        }
+    }
+
+    assert!(Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  properties:
+    background: {class: [grass]}
+  Datasource:
+    type: postgis
+    table: parks
+").is_err());
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn background_tiles_are_pruned() {
+    let data_yml = temp_path("background_tiles_are_pruned.yml");
+    let tiles_written = |background: &str| -> usize {
+        // In tile 1/0/0
+        File::create(&data_yml).unwrap().write_all(format!("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  properties:
+    {}
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POLYGON((-15000000 5000000, -5000000 5000000, -5000000 15000000, -15000000 15000000, -15000000 5000000))
+", background).as_bytes()).unwrap();
+        let mut options = GenerateOptions::default();
+        options.prune_empty_descendants = true;
+        generate_all(&[data_yml.to_str().unwrap()], 0, 3, &None, &TileDestinationType::Null, false, true, 1, 1, None, 10, true, &options).unwrap().tiles_written
+    };
+
+    let without_background = tiles_written("buffer-size: 0");
+    assert!(without_background < 1 + 4 + 16 + 64, "{}", without_background);
+    assert_eq!(tiles_written("background: {class: grass}"), without_background);
+
+    fs::remove_file(&data_yml).unwrap();
+}
+
+#[test]
+fn partial_metatile_bbox() {
+    let name = "partial_metatile_bbox";
+    let gpkg = temp_path(&format!("{}.gpkg", name));
+    create_parks_geopackage(&gpkg);
+    let data_yml = temp_path(&format!("{}.yml", name));
+    fs::write(&data_yml, format!("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  Datasource:
+    type: geopackage
+    file: {}
+    table: parks
+", gpkg.display())).unwrap();
+    let dir = temp_path(name);
+
+    // A strip of 3 tiles (5/5, 6/5 & 7/5) on zoom 4, which is in one 8×8 metatile. The park is in
+    // lots of the other tiles of that metatile.
+    let bbox = BBox::new(54., -67., 42., -1.).unwrap();
+    let dest = TileDestinationType::TileStashDirectory(dir.clone());
+    generate_all(&[data_yml.to_str().unwrap()], 4, 4, &Some(bbox), &dest, false, true, 8, 1, None, 10, true, &GenerateOptions::default()).unwrap();
+
+    fn pbf_files(dir: &Path, files: &mut Vec<PathBuf>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                pbf_files(&path, files);
+            } else if path.extension().map_or(false, |e| e == "pbf") {
+                files.push(path);
+            }
+        }
+    }
+    let mut written = Vec::new();
+    pbf_files(&dir, &mut written);
+    written.sort();
+    let mut expected: Vec<PathBuf> = (5..8).map(|x| dir.join(slippy_map_tiles::Tile::new(4, x, 5).unwrap().ts_path("pbf"))).collect();
+    expected.sort();
+    assert_eq!(written, expected);
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_file(&data_yml).unwrap();
+    fs::remove_file(&gpkg).unwrap();
+}
+
+#[test]
+fn per_feature_hook() {
+    let layers = Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: roads
+  Datasource:
+    type: wkt
+    features:
+    - wkt: LINESTRING(0 0, 10000000 10000000)
+").unwrap();
+    let connection_pool = ConnectionPool::new(layers.get_all_connections());
+    let metatile = Metatile::new(1, 0, 0, 0).unwrap();
+
+    // The geometries of the roads in the generated tile
+    let generate = |hook: Option<&FeatureHook>| -> Vec<Geometry<i32>> {
+        let mut stats = GenerateStats::default();
+        let tiles = single_metatile(&layers, &metatile, &connection_pool, hook, &mut Vec::new(), &mut stats).unwrap();
+        let decoded = decode::decode_tile_layers(&tiles[0].1.to_bytes()).unwrap();
+        decoded.iter().filter(|l| l.name == "roads").flat_map(|l| l.features.iter().map(|f| f.geometry().unwrap())).collect()
+    };
+
+    let without_hook = generate(None);
+    assert_eq!(without_hook.len(), 1);
+
+    let hook = FeatureHook::new(|layer_name, geom, _properties| {
+        assert_eq!(layer_name, "roads");
+        geom.map_coords_inplace(&|&(x, y)| (x + 10, y + 20));
+    });
+    let expected: Geometry<i32> = without_hook[0].map_coords(&|&(x, y)| (x + 10, y + 20));
+    assert_eq!(generate(Some(&hook)), vec![expected]);
+
+    // Degenerate geometries are dropped
+    let hook = FeatureHook::new(|_, geom, _| { *geom = LineString(vec![Point::new(0, 0)]).into(); });
+    assert_eq!(generate(Some(&hook)), vec![]);
+}
+
+/// The field number & contents of every length delimited field in this protobuf message.
+fn protobuf_fields(bytes: &[u8]) -> Vec<(u64, &[u8])> {
+    decode::protobuf_fields(bytes).unwrap()
+}
+
+#[test]
+fn layer_values_are_deduplicated() {
+    let mut string_store = StringStore::new();
+    let categories = ["residential", "commercial", "industrial"];
+
+    let mut layer = mapbox_vector_tile::Layer::new("landuse".to_string());
+    for i in 0..1000 {
+        let mut properties = mapbox_vector_tile::Properties::new();
+        properties.insert(string_store.get_str(&"category".to_string()), mapbox_vector_tile::Value::String(string_store.get_string(categories[i % 3].to_string())));
+        layer.add_feature(mapbox_vector_tile::Feature::new(Point::new(i as i32, i as i32).into(), Rc::new(properties)));
+    }
+    let mut tile = mapbox_vector_tile::Tile::new();
+    tile.add_layer(layer);
+    let bytes = tile.to_bytes();
+
+    // Tile.layers is field 3. Layer.features is 2, keys 3, values 4.
+    let layers: Vec<_> = protobuf_fields(&bytes).into_iter().filter(|&(f, _)| f == 3).collect();
+    assert_eq!(layers.len(), 1);
+    let layer_fields = protobuf_fields(layers[0].1);
+    let count = |field| layer_fields.iter().filter(|&&(f, _)| f == field).count();
+    assert_eq!(count(2), 1000);
+    assert_eq!(count(3), 1);
+    assert_eq!(count(4), 3);
+}
+
+#[test]
+fn simplify_stage_seams() {
+    // A slightly wiggly line, with one real corner just over the edge between two tiles
+    let line: Geometry<i32> = LineString(vec![(0, 2000), (1000, 2003), (2000, 1998), (3000, 2002), (4100, 3000), (5000, 2001), (6000, 1999), (8192, 2000)].into_iter().map(|(x, y)| Point::new(x, y)).collect()).into();
+    let buffer = 64;
+    let left_tile = Bbox{ xmin: -buffer, xmax: 4096 + buffer, ymin: -buffer, ymax: 4096 + buffer };
+    let right_tile = Bbox{ xmin: 4096 - buffer, xmax: 8192 + buffer, ymin: -buffer, ymax: 4096 + buffer };
+
+    // The vertices in the area both tiles have (excluding where the buffers cut the line)
+    fn shared_vertices(geom: &Geometry<i32>) -> Vec<(i32, i32)> {
+        let points = match *geom {
+            Geometry::LineString(ref ls) => ls.0.clone(),
+            Geometry::MultiLineString(ref mls) => mls.0.iter().flat_map(|ls| ls.0.clone()).collect(),
+            _ => unreachable!(),
+        };
+        points.into_iter().map(|p| (p.x(), p.y())).filter(|&(x, _)| x > 4096 - 64 && x < 4096 + 64).collect()
+    }
+
+    let left = simplify_and_clip(line.clone(), Some(0), SimplifyStage::BeforeClip, &left_tile).unwrap();
+    let right = simplify_and_clip(line.clone(), Some(0), SimplifyStage::BeforeClip, &right_tile).unwrap();
+    assert_eq!(shared_vertices(&left), vec![(4100, 3000)]);
+    assert_eq!(shared_vertices(&left), shared_vertices(&right));
+
+    // After clipping, the wiggles are still simplified away
+    let left = simplify_and_clip(line.clone(), Some(0), SimplifyStage::AfterClip, &left_tile).unwrap();
+    assert!(geometry_bbox(&left).unwrap().xmax <= 4096 + 64);
+
+    // At maxzoom, nothing is simplified
+    let left = simplify_and_clip(line, None, SimplifyStage::BeforeClip, &left_tile).unwrap();
+    assert_eq!(shared_vertices(&left), vec![(4100, 3000)]);
+    assert_eq!(geometry_bbox(&left).unwrap().ymin, 1998);
+
+    assert_eq!("after-clip".parse::<SimplifyStage>().unwrap(), SimplifyStage::AfterClip);
+    assert!("sometime".parse::<SimplifyStage>().is_err());
+}
+
+#[test]
+fn limit_features1() {
+    // 100 squares, of size 1..100
+    let squares: Vec<(Geometry<i32>, usize)> = (1..101).map(|size| {
+        let x = (size * 7) % 13;
+        let exterior: LineString<i32> = vec![(x, 0), (x, size), (x+size, size), (x+size, 0), (x, 0)].into();
+        (Polygon::new(exterior, vec![]).into(), size as usize)
+    }).collect();
+
+    let mut features = squares.clone();
+    assert_eq!(limit_features(&mut features, 10), 90);
+    let kept: Vec<usize> = features.iter().map(|f| f.1).collect();
+    assert_eq!(kept, (91..101).rev().collect::<Vec<_>>());
+
+    // Same result whatever order they're in
+    let mut reversed: Vec<_> = squares.into_iter().rev().collect();
+    limit_features(&mut reversed, 10);
+    assert_eq!(reversed, features);
+
+    // Lines by length, and same sized features by coordinates
+    let mut lines: Vec<(Geometry<i32>, usize)> = vec![
+        (LineString::from(vec![(5, 0), (5, 10)]).into(), 0),
+        (LineString::from(vec![(0, 0), (0, 20)]).into(), 1),
+        (LineString::from(vec![(1, 0), (1, 10)]).into(), 2),
+    ];
+    assert_eq!(limit_features(&mut lines, 2), 1);
+    assert_eq!(lines.iter().map(|f| f.1).collect::<Vec<_>>(), vec![1, 2]);
+
+    assert_eq!(limit_features(&mut lines, 5), 0);
+}
+
+#[test]
+fn query_has_bbox_filter() {
+    let layers = Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: water
+  Datasource:
+    type: postgis
+    table: (select way from water where way_area > !pixel_width!) as t
+- id: roads
+  Datasource:
+    type: postgis
+    table: (select way from roads) as t
+    srid: 4326
+- id: places
+  Datasource:
+    type: postgis
+    table: (select way from places where ST_DWithin(way, !bbox!, 100)) as t
+  properties:
+    bbox-filter: false
+- id: countries
+  Datasource:
+    type: postgis
+    table: (select way from countries) as t
+  properties:
+    bbox-filter: false
+").unwrap();
+    let bbox = LocalBBox(0., 0., 0., 0.);
+    let num_params = |table: &input::TableSQL| table.params(&bbox, &0., &0., &0, &0.).len();
+
+    let water = &layers.layers[0].table;
+    assert_eq!(water.query, "SELECT ST_AsBinary(way::geometry), * from (select way from water where way_area > $2) as t where way && $1");
+    assert_eq!(num_params(water), 2);
+
+    let roads = &layers.layers[1].table;
+    assert!(roads.query.ends_with("where way && ST_Transform(ST_SetSRID($1::geometry, 3857), 4326)"));
+    assert_eq!(num_params(roads), 1);
+
+    // Opted out, but still filters by !bbox! itself
+    let places = &layers.layers[2].table;
+    assert_eq!(places.query, "SELECT ST_AsBinary(way::geometry), * from (select way from places where ST_DWithin(way, $1, 100)) as t");
+    assert_eq!(num_params(places), 1);
+
+    let countries = &layers.layers[3].table;
+    assert!(!countries.query.contains("$1"));
+    assert_eq!(num_params(countries), 0);
+
+    // What --dump-sql logs
+    let bbox = LocalBBox(-100., -200., 300., 400.);
+    let sql = water.query_with_params(&bbox, 2.5, 2.5, 10, 1000.);
+    assert!(sql.contains("(select way from water where way_area > 2.5) as t"), "{}", sql);
+    assert!(sql.ends_with("where way && ST_MakeEnvelope(-100, -200, 300, 400, 3857)"), "{}", sql);
+    let sql = countries.query_with_params(&bbox, 2.5, 2.5, 10, 1000.);
+    assert_eq!(sql, countries.query);
+
+    // The bbox is the metatile's envelope in web mercator, plus the buffer
+    let metatile = slippy_map_tiles::Metatile::new(1, 1, 0, 0).unwrap();
+    let half_width = 20037508.342789244;
+    let LocalBBox(minx, miny, maxx, maxy) = query_bbox(&metatile, 0, &TileMatrix::default());
+    assert!((minx - -half_width).abs() < 1e-3);
+    assert!((miny - 0.).abs() < 1e-3);
+    assert!((maxx - 0.).abs() < 1e-3);
+    assert!((maxy - half_width).abs() < 1e-3);
+
+    // 64 is a quarter of the 256 'pixels' across
+    let LocalBBox(minx, miny, maxx, maxy) = query_bbox(&metatile, 64, &TileMatrix::default());
+    let buffer = half_width / 4.;
+    assert!((minx - (-half_width - buffer)).abs() < 1e-3);
+    assert!((miny - -buffer).abs() < 1e-3);
+    assert!((maxx - buffer).abs() < 1e-3);
+    assert!((maxy - (half_width + buffer)).abs() < 1e-3);
+}
+
+#[test]
+fn encode_errors() {
+    use mapbox_vector_tile::Value;
+
+    let mut string_store = StringStore::new();
+    let rows: Vec<Vec<(&str, std::result::Result<Option<Value>, String>)>> = vec![
+        vec![("name", Ok(Some(Value::String(Rc::new("a".to_string()))))), ("height", Ok(Some(Value::Double(10.))))],
+        vec![("name", Ok(Some(Value::String(Rc::new("b".to_string()))))), ("height", Ok(Some(Value::Double(std::f64::NAN))))],
+        vec![("name", Ok(None)), ("height", Ok(Some(Value::Int(3))))],
+        vec![("name", Ok(Some(Value::String(Rc::new("d".to_string()))))), ("tags", Err("column tags has PostgreSQL type json, which can't be put in a vector tile".to_string()))],
+    ];
+
+    let mut layer = mapbox_vector_tile::Layer::new("buildings".to_string());
+    let mut errors = Vec::new();
+    for (i, values) in rows.into_iter().enumerate() {
+        match feature_properties("buildings", i, values, &mut string_store) {
+            Ok(properties) => layer.add_feature(mapbox_vector_tile::Feature::new(Point::new(i as i32, 0).into(), Rc::new(properties))),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    assert_eq!(errors.len(), 2);
+    assert_eq!((errors[0].layer.as_str(), errors[0].feature_index), ("buildings", 1));
+    assert!(errors[0].reason.contains("height"));
+    assert_eq!(errors[1].feature_index, 3);
+    assert_eq!(errors[1].to_string(), "Layer buildings feature 3 couldn't be encoded: column tags has PostgreSQL type json, which can't be put in a vector tile");
+
+    // The other features are still in the tile
+    let mut tile = mapbox_vector_tile::Tile::new();
+    tile.add_layer(layer);
+    let bytes = tile.to_bytes();
+    let layers: Vec<_> = protobuf_fields(&bytes).into_iter().filter(|&(f, _)| f == 3).collect();
+    assert_eq!(protobuf_fields(layers[0].1).iter().filter(|&&(f, _)| f == 2).count(), 2);
+}
+
+#[test]
+fn generate_stats() {
+    let tile = |x| slippy_map_tiles::Tile::new(2, x, 0).unwrap();
+
+    let mut worker1 = GenerateStats::default();
+    worker1.add_written_tiles(&[(tile(0), vec![0; 10]), (tile(1), vec![0; 20])]);
+    worker1.features_encoded = 5;
+    let mut worker2 = GenerateStats::default();
+    worker2.add_written_tiles(&[(tile(2), vec![0; 5])]);
+    worker2.features_dropped = 1;
+
+    let mut stats = GenerateStats::default();
+    stats.add(&worker1);
+    stats.add(&worker2);
+    assert_eq!((stats.tiles_written, stats.bytes_written), (3, 35));
+    assert_eq!((stats.features_encoded, stats.features_dropped), (5, 1));
+
+    stats.elapsed = std::time::Duration::from_millis(1500);
+    assert_eq!(stats.to_string(), "Wrote 3 tiles (35 bytes) with 5 features, 1 features dropped, in 1.50s");
+}
+
+#[test]
+fn generate_stats_from_generate_all() {
+    let data_yml = temp_path("generate_stats_from_generate_all.yml");
+    File::create(&data_yml).unwrap().write_all(b"
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  Datasource:
+    type: wkt
+    features:
+    # In tile 1/0/0
+    - wkt: POLYGON((-15000000 5000000, -5000000 5000000, -5000000 15000000, -15000000 15000000, -15000000 5000000))
+    # All the points are on one line, so it can't be made valid
+    - wkt: POLYGON((-15000000 5000000, -10000000 5000000, -5000000 5000000, -15000000 5000000))
+").unwrap();
+
+    // 2 threads, so the stats from each are added up
+    let stats = generate_all(&[data_yml.to_str().unwrap()], 0, 1, &None, &TileDestinationType::Null, false, true, 1, 2, None, 10, true, &GenerateOptions::default()).unwrap();
+    // Every tile of zooms 0 & 1 (including the empty ones)
+    assert_eq!(stats.tiles_written, 1 + 4);
+    assert!(stats.bytes_written > 0);
+    // The park in 0/0/0 & 1/0/0
+    assert_eq!(stats.features_encoded, 2);
+    // Inline features are read for every metatile, so the invalid one is dropped from all 5
+    assert_eq!(stats.features_dropped, 5);
+
+    fs::remove_file(&data_yml).unwrap();
+}
+
+#[test]
+fn null_destination_stats() {
+    let name = "null_destination_stats";
+    let data_yml = temp_path(&format!("{}.yml", name));
+    File::create(&data_yml).unwrap().write_all(b"
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: parks
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POLYGON((-15000000 5000000, -5000000 5000000, -5000000 15000000, -15000000 15000000, -15000000 5000000))
+    - wkt: POLYGON((-15000000 5000000, -10000000 5000000, -5000000 5000000, -15000000 5000000))
+").unwrap();
+    let mbtiles = temp_path(&format!("{}.mbtiles", name));
+    let _ = fs::remove_file(&mbtiles);
+
+    let generate = |dest: &TileDestinationType| generate_all(&[data_yml.to_str().unwrap()], 0, 2, &None, dest, false, true, 1, 1, None, 10, true, &GenerateOptions::default()).unwrap();
+    let null_stats = generate(&TileDestinationType::Null);
+    let mbtiles_stats = generate(&TileDestinationType::MBTiles(mbtiles.clone()));
+
+    // The same stats, as if the tiles had been written
+    assert_eq!((null_stats.tiles_written, null_stats.bytes_written), (mbtiles_stats.tiles_written, mbtiles_stats.bytes_written));
+    assert_eq!((null_stats.features_encoded, null_stats.features_dropped), (mbtiles_stats.features_encoded, mbtiles_stats.features_dropped));
+    assert_eq!(null_stats.tiles_written, 1 + 4 + 16);
+    assert!(null_stats.features_encoded > 0);
+    assert!(null_stats.features_dropped > 0);
+
+    let conn = rusqlite::Connection::open(&mbtiles).unwrap();
+    let num_tiles: i64 = conn.query_row("SELECT count(*) FROM tiles;", &[], |r| r.get(0)).unwrap();
+    assert_eq!(num_tiles as usize, mbtiles_stats.tiles_written);
+    drop(conn);
+
+    fs::remove_file(&mbtiles).unwrap();
+    fs::remove_file(&data_yml).unwrap();
+}
+
+#[test]
+fn if_not_exists_mbtiles() {
+    let name = "if_not_exists_mbtiles";
+    let data_yml = temp_path(&format!("{}.yml", name));
+    File::create(&data_yml).unwrap().write_all(b"
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: land
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POLYGON((-19000000 -19000000, 19000000 -19000000, 19000000 19000000, -19000000 19000000, -19000000 -19000000))
+").unwrap();
+    let mbtiles = temp_path(&format!("{}.mbtiles", name));
+
+    // One file, and split into one file per zoom
+    for split_zoom_at in vec![vec![], vec![1]] {
+        let files = if split_zoom_at.is_empty() {
+            vec![mbtiles.clone()]
+        } else {
+            vec![fileio::SplitMBTiles::filename_for_band(&mbtiles, 0, 0), fileio::SplitMBTiles::filename_for_band(&mbtiles, 1, 1)]
+        };
+        for file in files.iter() {
+            let _ = fs::remove_file(file);
+        }
+
+        let mut options = GenerateOptions::default();
+        options.split_zoom_at = split_zoom_at.clone();
+        let generate = || generate_all(&[data_yml.to_str().unwrap()], 0, 1, &None, &TileDestinationType::MBTiles(mbtiles.clone()), true, true, 1, 2, None, 10, true, &options).unwrap();
+        assert_eq!(generate().tiles_written, 1 + 4);
+
+        // Nothing is generated again
+        assert_eq!(generate().tiles_written, 0, "split at {:?}", split_zoom_at);
+
+        // Only the tiles which are missing
+        let conn = rusqlite::Connection::open(files.last().unwrap()).unwrap();
+        conn.execute("DELETE FROM map WHERE zoom_level = 1 AND tile_column = 0;", &[]).unwrap();
+        drop(conn);
+        assert_eq!(generate().tiles_written, 2, "split at {:?}", split_zoom_at);
+
+        for file in files.iter() {
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    fs::remove_file(&data_yml).unwrap();
+}
+
+#[test]
+fn debug_density() {
+    let mut tile = mapbox_vector_tile::Tile::new();
+    let properties = Rc::new(mapbox_vector_tile::Properties::new());
+
+    // 3 points in the top left corner, 1 in the bottom right
+    let mut places = mapbox_vector_tile::Layer::new("places".to_string());
+    for &(x, y) in [(10, 10), (20, 30), (100, 5), (4000, 4000)].iter() {
+        places.add_feature(mapbox_vector_tile::Feature::new(Point::new(x, y).into(), properties.clone()));
+    }
+    tile.add_layer(places);
+
+    // A line which goes across the middle, counted where its bbox's centre is
+    let mut roads = mapbox_vector_tile::Layer::new("roads".to_string());
+    roads.add_feature(mapbox_vector_tile::Feature::new(LineString(vec![Point::new(0, 2000), Point::new(4096, 2100)]).into(), properties.clone()));
+    tile.add_layer(roads);
+
+    let density = feature_density(&tile, 4);
+    assert_eq!(density, vec![((0, 0), 3), ((2, 2), 1), ((3, 3), 1)]);
+    assert_eq!(density.iter().map(|&(_, count)| count).sum::<usize>(), 5);
+
+    // One feature per cell with features
+    let mut with_density = tile.clone();
+    with_density.add_layer(density_layer(&tile, 4));
+    let bytes = with_density.to_bytes();
+    assert_eq!(tile_layer_names(&bytes).unwrap(), vec!["places", "roads", DENSITY_LAYER_NAME]);
+    let layers: Vec<_> = protobuf_fields(&bytes).into_iter().filter(|&(f, _)| f == 3).collect();
+    assert_eq!(protobuf_fields(layers[2].1).iter().filter(|&&(f, _)| f == 2).count(), 3);
+}
+
+#[test]
+fn buffer_only_counts_as_empty() {
+    let properties = Rc::new(mapbox_vector_tile::Properties::new());
+    let tile_with = |geom: Geometry<i32>| {
+        let mut layer = mapbox_vector_tile::Layer::new("roads".to_string());
+        layer.add_feature(mapbox_vector_tile::Feature::new(geom, properties.clone()));
+        let mut tile = mapbox_vector_tile::Tile::new();
+        tile.add_layer(mapbox_vector_tile::Layer::new("water".to_string()));
+        tile.add_layer(layer);
+        tile
+    };
+
+    assert!(tile_is_empty(&mapbox_vector_tile::Tile::new(), false));
+    assert!(tile_is_empty(&mapbox_vector_tile::Tile::new(), true));
+
+    // Only in the gutter to the left of the tile
+    let in_gutter = tile_with(LineString(vec![Point::new(-50, 100), Point::new(-10, 200)]).into());
+    assert!(!tile_is_empty(&in_gutter, false));
+    assert!(tile_is_empty(&in_gutter, true));
+    let below = tile_with(Point::new(2000, 4100).into());
+    assert!(!tile_is_empty(&below, false));
+    assert!(tile_is_empty(&below, true));
+
+    // Goes into the tile
+    let crossing = tile_with(LineString(vec![Point::new(-50, 100), Point::new(10, 200)]).into());
+    assert!(!tile_is_empty(&crossing, false));
+    assert!(!tile_is_empty(&crossing, true));
+}
+
+#[test]
+fn only_changed_tiles_against_itself() {
+    let path = temp_path("only_changed_tiles.mbtiles");
+    let _ = fs::remove_file(&path);
+
+    fn mvt(points: &[(i32, i32)]) -> Vec<u8> {
+        let mut layer = mapbox_vector_tile::Layer::new("places".to_string());
+        for &(x, y) in points.iter() {
+            layer.add_feature(mapbox_vector_tile::Feature::new(Point::new(x, y).into(), Rc::new(mapbox_vector_tile::Properties::new())));
+        }
+        let mut tile = mapbox_vector_tile::Tile::new();
+        tile.add_layer(layer);
+        tile.to_compressed_bytes()
+    }
+    let tiles: Vec<(slippy_map_tiles::Tile, Vec<u8>)> = (0..4).map(|x| (slippy_map_tiles::Tile::new(2, x, 1).unwrap(), mvt(&[(x as i32, 10), (20, 20)]))).collect();
+
+    let mut mbtiles = fileio::MBTiles::new(&path);
+    for &(tile, ref bytes) in tiles.iter() {
+        mbtiles.save_tile(tile, bytes.clone());
+    }
+    mbtiles.finish();
+    drop(mbtiles);
+    let previous = fileio::MBTilesReader::open(&path).unwrap();
+
+    // The same tiles again, nothing is written
+    let mut stats = GenerateStats::default();
+    assert!(only_changed_tiles(tiles.clone(), &previous, false, &mut stats).is_empty());
+    assert_eq!((stats.tiles_changed, stats.tiles_unchanged), (0, 4));
+
+    // Or they're all copied
+    let mut stats = GenerateStats::default();
+    assert_eq!(only_changed_tiles(tiles.clone(), &previous, true, &mut stats), tiles);
+
+    // Features in a different order is the same. A new tile & a moved point are changes
+    let mut new_tiles = tiles.clone();
+    new_tiles[0].1 = mvt(&[(20, 20), (0, 10)]);
+    new_tiles[1].1 = mvt(&[(1, 11), (20, 20)]);
+    new_tiles.push((slippy_map_tiles::Tile::new(2, 0, 3).unwrap(), mvt(&[(0, 0)])));
+    let mut stats = GenerateStats::default();
+    let written = only_changed_tiles(new_tiles.clone(), &previous, false, &mut stats);
+    assert_eq!(written, vec![new_tiles[1].clone(), new_tiles[4].clone()]);
+    assert_eq!((stats.tiles_changed, stats.tiles_unchanged), (2, 3));
+    stats.elapsed = std::time::Duration::from_millis(100);
+    assert!(stats.to_string().ends_with(". 2 tiles changed, 3 unchanged"));
+
+    drop(previous);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn tile_hashes_of_unchanged_input() {
+    let name = "tile_hashes_of_unchanged_input";
+    let data_yml = temp_path(&format!("{}.yml", name));
+    File::create(&data_yml).unwrap().write_all(b"
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: places
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POINT(-10000000 10000000)
+").unwrap();
+
+    // The hashes stored in each run, by (zoom, column, row)
+    let path = |run_name: &str| temp_path(&format!("{}-{}.mbtiles", name, run_name));
+    let run = |run_name: &str, debug_density: Option<u32>, against: Option<PathBuf>| -> Vec<(i64, i64, i64, String)> {
+        let path = path(run_name);
+        let _ = fs::remove_file(&path);
+        let mut options = GenerateOptions::default();
+        options.store_tile_hashes = true;
+        options.debug_density = debug_density;
+        options.against = against;
+        generate_all(&[data_yml.to_str().unwrap()], 0, 2, &None, &TileDestinationType::MBTiles(path.clone()), false, true, 1, 1, None, 10, true, &options).unwrap();
+
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let mut stmt = conn.prepare("SELECT zoom_level, tile_column, tile_row, hash FROM tile_hashes ORDER BY zoom_level, tile_column, tile_row;").unwrap();
+        let hashes = stmt.query_map(&[], |r| (r.get(0), r.get(1), r.get(2), r.get(3))).unwrap().map(|h| h.unwrap()).collect();
+        hashes
+    };
+
+    let first = run("first", None, None);
+    assert_eq!(first.len(), 1 + 4 + 16);
+
+    // The same for the same input, and the debugging layer isn't included
+    assert_eq!(run("second", Some(4), None), first);
+
+    // Nothing has changed, so no tiles, and no hashes, are written
+    assert_eq!(run("unchanged", None, Some(path("first"))), vec![]);
+    let conn = rusqlite::Connection::open(path("unchanged")).unwrap();
+    let num_tiles: i64 = conn.query_row("SELECT count(*) FROM tiles;", &[], |r| r.get(0)).unwrap();
+    assert_eq!(num_tiles, 0);
+    drop(conn);
+
+    for run_name in ["first", "second", "unchanged"].iter() {
+        fs::remove_file(path(run_name)).unwrap();
+    }
+    fs::remove_file(&data_yml).unwrap();
+}
+
+#[test]
+fn several_data_ymls() {
+    let write = |name: &str, contents: &str| {
+        let path = temp_path(&format!("{}.yml", name));
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    };
+    let data_yml = |maxzoom: u8, name: &str, layers: &str| format!("minzoom: 0\nmaxzoom: {}\nname: {}\nLayer:\n{}", maxzoom, name, layers);
+
+    let water = write("water", &data_yml(12, "water", "
+- id: water
+  Datasource:
+    type: postgis
+    table: (select way from water) as t
+"));
+    let roads = write("roads", &data_yml(14, "roads", "
+- id: roads
+  Datasource:
+    type: postgis
+    table: (select way from roads) as t
+"));
+    let more_water = write("more_water", &data_yml(14, "more water", "
+- id: water
+  Datasource:
+    type: postgis
+    table: (select way from ocean) as t
+"));
+
+    // All the layers, in order, are in the tiles, and later settings win
+    let layers = Layers::from_files(&[&water, &roads], false).unwrap();
+    assert_eq!(layers.layers.iter().map(|l| l.name.as_str()).collect::<Vec<_>>(), vec!["water", "roads"]);
+    assert_eq!(layers.global_maxzoom, 14);
+    assert_eq!(layers.name, Some("roads".to_string()));
+
+    // The same layer twice is a mistake, unless it's meant to be overridden
+    let err = Layers::from_files(&[&water, &roads, &more_water], false).unwrap_err();
+    assert!(err.to_string().contains("Layer water is already defined"));
+
+    let layers = Layers::from_files(&[&water, &roads, &more_water], true).unwrap();
+    assert_eq!(layers.layers.iter().map(|l| l.name.as_str()).collect::<Vec<_>>(), vec!["water", "roads"]);
+    assert!(layers.layers[0].table.query.contains("from ocean"));
+
+    // Both files' layers are rendered into one tile
+    let wkt_water = write("wkt_water", &data_yml(14, "water", "
+- id: water
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POLYGON((-15000000 5000000, -5000000 5000000, -5000000 15000000, -15000000 15000000, -15000000 5000000))
+"));
+    let wkt_roads = write("wkt_roads", &data_yml(14, "roads", "
+- id: roads
+  Datasource:
+    type: wkt
+    features:
+    - wkt: LINESTRING(0 0, 10000000 10000000)
+"));
+    let layers = Layers::from_files(&[&wkt_water, &wkt_roads], false).unwrap();
+    let connection_pool = ConnectionPool::new(layers.get_all_connections());
+    let tiles = single_metatile(&layers, &Metatile::new(1, 0, 0, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut GenerateStats::default()).unwrap();
+    let decoded = decode::decode_tile_layers(&tiles[0].1.to_bytes()).unwrap();
+    assert_eq!(decoded.iter().map(|l| (l.name.as_str(), l.features.len())).collect::<Vec<_>>(), vec![("water", 1), ("roads", 1)]);
+    assert_eq!(decoded[0].features[0].geom_type, 3);
+    assert_eq!(decoded[1].features[0].geom_type, 2);
+
+    for path in [water, roads, more_water, wkt_water, wkt_roads].iter() {
+        fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn include_attributes() {
+    let layers = Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: places
+  Datasource:
+    type: postgis
+    table: (select way, name, population, wikidata from places) as t
+  properties:
+    include-attributes: [name, population]
+    exclude-attributes: [population]
+- id: roads
+  Datasource:
+    type: postgis
+    table: (select way, name, ref from roads) as t
+").unwrap();
+    let places = &layers.layers[0];
+    assert!(places.includes_attribute("name"));
+    assert!(!places.includes_attribute("population"));
+    assert!(!places.includes_attribute("wikidata"));
+    // By default, everything is included
+    assert!(layers.layers[1].includes_attribute("ref"));
+
+    // Generated, only the name is in the tile
+    let layers = Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: places
+  properties:
+    include-attributes: [name, population]
+    exclude-attributes: [population]
+  Datasource:
+    type: wkt
+    features:
+    - wkt: POINT(-10000000 10000000)
+      attrs: {name: Dublin, population: 544107, wikidata: Q1761}
+").unwrap();
+    let connection_pool = ConnectionPool::new(layers.get_all_connections());
+    let tiles = single_metatile(&layers, &Metatile::new(1, 0, 0, 0).unwrap(), &connection_pool, None, &mut Vec::new(), &mut GenerateStats::default()).unwrap();
+    let decoded = decode::decode_tile_layers(&tiles[0].1.to_bytes()).unwrap();
+    assert_eq!(decoded[0].features.len(), 1);
+    assert_eq!(decoded[0].features[0].properties, vec![("name".to_string(), decode::DecodedValue::String("Dublin".to_string()))]);
+}
+
+#[test]
+fn compress_min_bytes() {
+    fn mvt(num_points: i32) -> mapbox_vector_tile::Tile {
+        let mut layer = mapbox_vector_tile::Layer::new("places".to_string());
+        for i in 0..num_points {
+            layer.add_feature(mapbox_vector_tile::Feature::new(Point::new(i % 4096, i / 4096).into(), Rc::new(mapbox_vector_tile::Properties::new())));
+        }
+        let mut tile = mapbox_vector_tile::Tile::new();
+        tile.add_layer(layer);
+        tile
+    }
+    let is_gzipped = |bytes: &[u8]| bytes.starts_with(&[0x1f, 0x8b]);
+
+    // A tiny tile is left as it is
+    let small = mvt(1);
+    assert!(small.to_bytes().len() <= 50);
+    assert_eq!(tile_bytes(&small, Some(1024), None), small.to_bytes());
+
+    // A big one is still gzipped
+    let big = mvt(6_000);
+    assert!(big.to_bytes().len() >= 50_000);
+    let bytes = tile_bytes(&big, Some(1024), None);
+    assert!(is_gzipped(&bytes));
+    assert_eq!(decode::decompress_tile(&bytes).unwrap().into_owned(), big.to_bytes());
+
+    // By default, everything is gzipped
+    assert!(is_gzipped(&tile_bytes(&small, None, None)));
+}
+
+#[test]
+fn compression_level() {
+    let mut layer = mapbox_vector_tile::Layer::new("places".to_string());
+    for i in 0..6_000 {
+        layer.add_feature(mapbox_vector_tile::Feature::new(Point::new(i % 4096, i / 4096).into(), Rc::new(mapbox_vector_tile::Properties::new())));
+    }
+    let mut mvt = mapbox_vector_tile::Tile::new();
+    mvt.add_layer(layer);
+
+    let fastest = tile_bytes(&mvt, None, Some(1));
+    let smallest = tile_bytes(&mvt, None, Some(9));
+    assert!(smallest.len() <= fastest.len());
+    assert_eq!(decode::decompress_tile(&fastest).unwrap().into_owned(), mvt.to_bytes());
+    assert_eq!(decode::decompress_tile(&smallest).unwrap().into_owned(), mvt.to_bytes());
+
+    // Still left uncompressed if it's small
+    assert_eq!(tile_bytes(&mvt, Some(1_000_000), Some(9)), mvt.to_bytes());
+}
+
+#[test]
+fn max_zoom_per_layer() {
+    let mut layers = Layers::from_tmsource_str("
+minzoom: 0
+maxzoom: 14
+Layer:
+- id: hillshade_labels
+  Datasource:
+    type: postgis
+    table: (select way, name from hillshade_labels) as t
+- id: roads
+  Datasource:
+    type: postgis
+    table: (select way from roads) as t
+  properties:
+    maxzoom: 8
+").unwrap();
+    layers.cap_layer_zooms(&[("hillshade_labels".to_string(), 10), ("roads".to_string(), 12)]).unwrap();
+
+    // single_metatile only queries the layers for the metatile's zoom
+    {
+        let ids = |zoom: u8| layers.layers_for_zoom(zoom).map(|l| l.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids(8), vec!["hillshade_labels", "roads"]);
+        assert_eq!(ids(10), vec!["hillshade_labels"]);
+        assert!(ids(11).is_empty());
+    }
+    // A cap above the layer's maxzoom doesn't change it
+    assert_eq!(layers.layers[1].maxzoom, 8);
+
+    let err = layers.cap_layer_zooms(&[("hillshade".to_string(), 10)]).unwrap_err().to_string();
+    assert!(err.contains("hillshade"), "{}", err);
+}
+
+#[test]
+fn encode_tile1() {
+    use mapbox_vector_tile::Value;
+
+    let mut string_store = StringStore::new();
+    let mut properties = || {
+        let values: Vec<(&str, std::result::Result<Option<Value>, String>)> = vec![
+            ("name", Ok(Some(Value::String(Rc::new("Phoenix Park".to_string()))))),
+            ("area", Ok(Some(Value::Int(707)))),
+        ];
+        feature_properties("parks", 0, values, &mut string_store).unwrap()
+    };
+
+    // Already valid, and the right way around, so it should come out unchanged
+    let square: Geometry<i32> = Polygon::new(vec![(100, 100), (100, 200), (200, 200), (200, 100), (100, 100)].into(), vec![]).into();
+    // Nowhere near the tile
+    let far_away: Geometry<i32> = Polygon::new(vec![(9000, 9000), (9000, 9100), (9100, 9100), (9100, 9000), (9000, 9000)].into(), vec![]).into();
+
+    let bytes = encode_tile(4096, 64, vec![("parks".to_string(), vec![(square.clone(), properties()), (far_away, properties())])]);
+
+    let mut layer = mapbox_vector_tile::Layer::new("parks".to_string());
+    layer.add_feature(mapbox_vector_tile::Feature::new(square, Rc::new(properties())));
+    let mut expected = mapbox_vector_tile::Tile::new();
+    expected.add_layer(layer);
+
+    assert_eq!(tile_layer_names(&bytes).unwrap(), vec!["parks".to_string()]);
+    assert!(tiles_geometrically_equal(&bytes, &expected.to_bytes(), 0));
+}
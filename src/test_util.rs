@@ -0,0 +1,10 @@
+//! Helpers for the tests in several modules.
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+/// A path in the temporary directory for a test file called `name`, which won't clash with the
+/// files of another test run.
+pub fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("tileigi-test-{}-{}", process::id(), name))
+}
@@ -0,0 +1,211 @@
+//! Where tiles are, in projected coordinates.
+//!
+//! Normally tiles are the standard web mercator (XYZ/slippy map) ones, where tile 0/0/0 covers the
+//! whole world. Some in-house schemes put the origin somewhere else, or use a different world
+//! size, so the area each tile covers can be given instead. There are still 2ᶻ×2ᶻ tiles on zoom
+//! z, numbered from the top left.
+use slippy_map_tiles::{BBox, Metatile};
+
+use {EARTH_RADIUS, MAX_LATITUDE, MAX_ZOOM, MERCATOR_HALF_WIDTH};
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+/// The tiles of each zoom, like a WMTS tile matrix set. Coordinates are in web mercator (EPSG:3857)
+/// metres, the same as the data.
+#[derive(Clone,Debug,PartialEq)]
+pub struct TileMatrix {
+    /// The top left corner of the top left tile (on every zoom)
+    pub origin: (f64, f64),
+
+    /// Width & height of a tile, in pixels
+    pub tile_size: u32,
+
+    /// How many metres one pixel is, for each zoom (i.e. `resolutions[z]` is for zoom z)
+    pub resolutions: Vec<f64>,
+}
+
+impl Default for TileMatrix {
+    /// The standard web mercator tiles
+    fn default() -> Self {
+        TileMatrix::web_mercator()
+    }
+}
+
+impl TileMatrix {
+    /// The standard web mercator tiles, where tile 0/0/0 is the whole world.
+    pub fn web_mercator() -> Self {
+        let tile_size = 256;
+        let resolutions = (0..MAX_ZOOM+1).map(|z| (2. * MERCATOR_HALF_WIDTH) / (tile_size as f64) / 2f64.powi(z as i32)).collect();
+        TileMatrix{ origin: (-MERCATOR_HALF_WIDTH, MERCATOR_HALF_WIDTH), tile_size, resolutions }
+    }
+
+    /// Error if this can't be used for generating tiles up to `max_zoom`.
+    pub fn check(&self, max_zoom: u8) -> Result<()> {
+        if self.tile_size == 0 {
+            return Err(format_err!("Tile matrix tile size must be more than 0"));
+        }
+        if self.resolutions.len() <= max_zoom as usize {
+            return Err(format_err!("Tile matrix has no resolution for zoom {}", self.resolutions.len()));
+        }
+        if let Some(z) = self.resolutions.iter().position(|&r| !(r > 0.) || !r.is_finite()) {
+            return Err(format_err!("Tile matrix resolution for zoom {} must be more than 0", z));
+        }
+        Ok(())
+    }
+
+    /// Width & height of a tile on this zoom, in metres.
+    pub fn tile_width(&self, zoom: u8) -> f64 {
+        self.resolutions[zoom as usize] * (self.tile_size as f64)
+    }
+
+    /// The highest x (or y) of a tile on this zoom.
+    pub fn max_tile_index(&self, zoom: u8) -> u32 {
+        (1u32 << zoom) - 1
+    }
+
+    /// The bbox `(minx, miny, maxx, maxy)`, in metres, of tile `zoom`/`x`/`y`.
+    pub fn tile_bbox(&self, zoom: u8, x: u32, y: u32) -> (f64, f64, f64, f64) {
+        let width = self.tile_width(zoom);
+        let minx = self.origin.0 + (x as f64) * width;
+        let maxy = self.origin.1 - (y as f64) * width;
+
+        (minx, maxy - width, minx + width, maxy)
+    }
+
+    /// The bbox `(minx, miny, maxx, maxy)`, in metres, of all the tiles in this metatile.
+    pub fn metatile_bbox(&self, metatile: &Metatile) -> (f64, f64, f64, f64) {
+        let width = self.tile_width(metatile.zoom()) * (metatile.size() as f64);
+        let (minx, _, _, maxy) = self.tile_bbox(metatile.zoom(), metatile.x(), metatile.y());
+
+        (minx, maxy - width, minx + width, maxy)
+    }
+
+    /// The `(x, y)` of the tile on this zoom which contains this point (in metres). Points outside
+    /// all the tiles are in the nearest one.
+    pub fn tile_for_point(&self, zoom: u8, x: f64, y: f64) -> (u32, u32) {
+        let width = self.tile_width(zoom);
+        let max_index = self.max_tile_index(zoom) as f64;
+        let clamp = |v: f64| if v.is_nan() || v < 0. { 0. } else if v > max_index { max_index } else { v };
+
+        (clamp(((x - self.origin.0) / width).floor()) as u32, clamp(((self.origin.1 - y) / width).floor()) as u32)
+    }
+
+    /// The `(x, y)` of the tile on this zoom which contains this longitude & latitude (in
+    /// degrees).
+    pub fn tile_for_lonlat(&self, zoom: u8, lon: f64, lat: f64) -> (u32, u32) {
+        let (x, y) = lonlat_to_mercator(lon, lat);
+        self.tile_for_point(zoom, x, y)
+    }
+
+    /// True iff the tile `zoom`/`x`/`y` overlaps this bbox. A metatile at the edge of a bbox can
+    /// have tiles which don't.
+    pub fn tile_in_bbox(&self, bbox: &BBox, zoom: u8, x: u32, y: u32) -> bool {
+        let (minx, miny) = self.tile_for_lonlat(zoom, bbox.left() as f64, bbox.top() as f64);
+        let (maxx, maxy) = self.tile_for_lonlat(zoom, bbox.right() as f64, bbox.bottom() as f64);
+        minx <= x && x <= maxx && miny <= y && y <= maxy
+    }
+
+    /// All the metatiles (of this scale) on these zooms which overlap this bbox.
+    pub fn metatiles_for_bbox(&self, scale: u8, bbox: &BBox, min_zoom: u8, max_zoom: u8) -> Vec<Metatile> {
+        let mut metatiles = Vec::new();
+        for zoom in min_zoom..max_zoom+1 {
+            let (minx, miny) = self.tile_for_lonlat(zoom, bbox.left() as f64, bbox.top() as f64);
+            let (maxx, maxy) = self.tile_for_lonlat(zoom, bbox.right() as f64, bbox.bottom() as f64);
+            // On low zooms, a metatile can be smaller than the scale
+            let size = match Metatile::new(scale, zoom, 0, 0) {
+                None => { continue; },
+                Some(mt) => mt.size() as u32,
+            };
+            for x in minx/size..maxx/size+1 {
+                for y in miny/size..maxy/size+1 {
+                    metatiles.extend(Metatile::new(scale, zoom, x*size, y*size));
+                }
+            }
+        }
+
+        metatiles
+    }
+}
+
+/// Convert this longitude & latitude (in degrees) to web mercator metres. Latitudes past what web
+/// mercator can show are moved to the edge.
+fn lonlat_to_mercator(lon: f64, lat: f64) -> (f64, f64) {
+    let lat = lat.max(-MAX_LATITUDE).min(MAX_LATITUDE);
+    let x = lon.to_radians() * EARTH_RADIUS;
+    let y = (std::f64::consts::FRAC_PI_4 + lat.to_radians() / 2.).tan().ln() * EARTH_RADIUS;
+    (x, y)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Paris is (2.3522, 48.8566), ~(261848, 6250566) in web mercator
+
+    #[test]
+    fn web_mercator() {
+        let matrix = TileMatrix::default();
+        assert!(matrix.check(MAX_ZOOM).is_ok());
+        assert_eq!(matrix.tile_for_lonlat(12, 2.3522, 48.8566), (2074, 1409));
+        assert_eq!(matrix.tile_for_lonlat(0, -179.9, 85.), (0, 0));
+
+        let (minx, miny, maxx, maxy) = matrix.tile_bbox(12, 2074, 1409);
+        assert!(minx < 261848. && 261848. < maxx);
+        assert!(miny < 6250566. && 6250566. < maxy);
+        let (minx, miny, maxx, maxy) = matrix.tile_bbox(0, 0, 0);
+        assert!((minx - -MERCATOR_HALF_WIDTH).abs() < 1e-6 && (miny - -MERCATOR_HALF_WIDTH).abs() < 1e-6);
+        assert!((maxx - MERCATOR_HALF_WIDTH).abs() < 1e-6 && (maxy - MERCATOR_HALF_WIDTH).abs() < 1e-6);
+
+        let metatile = Metatile::new(8, 12, 2072, 1408).unwrap();
+        let (minx, miny, maxx, maxy) = matrix.metatile_bbox(&metatile);
+        assert!((minx - matrix.tile_bbox(12, 2072, 1408).0).abs() < 1e-6);
+        assert!((maxy - matrix.tile_bbox(12, 2072, 1408).3).abs() < 1e-6);
+        assert!((maxx - matrix.tile_bbox(12, 2079, 1415).2).abs() < 1e-6);
+        assert!((miny - matrix.tile_bbox(12, 2079, 1415).1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn shifted_origin() {
+        // The tiles start at Greenwich, rather than the antimeridian
+        let mut matrix = TileMatrix::default();
+        matrix.origin = (0., MERCATOR_HALF_WIDTH);
+
+        // One tile at zoom 12 is ~9784m wide, so Paris is 26 tiles east of Greenwich
+        assert_eq!(matrix.tile_for_lonlat(12, 2.3522, 48.8566), (26, 1409));
+        let (minx, miny, maxx, maxy) = matrix.tile_bbox(12, 26, 1409);
+        assert!(minx < 261848. && 261848. < maxx);
+        assert!(miny < 6250566. && 6250566. < maxy);
+
+        // West of the origin is in the first column
+        assert_eq!(matrix.tile_for_lonlat(12, -3.7038, 40.4168).0, 0);
+
+        let bbox = BBox::new(48.9, 2.3, 48.8, 2.4).unwrap();
+        let metatiles = matrix.metatiles_for_bbox(1, &bbox, 12, 12);
+        assert!(metatiles.iter().any(|mt| (mt.x(), mt.y()) == (26, 1409)));
+        assert!(metatiles.iter().all(|mt| mt.x() < 30));
+    }
+
+    #[test]
+    fn tile_in_bbox() {
+        let matrix = TileMatrix::default();
+        // Tiles 5, 6 & 7 of row 5 at zoom 4, which is one metatile (of 8×8 tiles)
+        let bbox = BBox::new(54., -67., 42., -1.).unwrap();
+        assert_eq!(matrix.metatiles_for_bbox(8, &bbox, 4, 4).len(), 1);
+
+        let in_bbox: Vec<(u32, u32)> = (0..8).flat_map(|x| (0..8).map(move |y| (x, y)))
+            .filter(|&(x, y)| matrix.tile_in_bbox(&bbox, 4, x, y))
+            .collect();
+        assert_eq!(in_bbox, vec![(5, 5), (6, 5), (7, 5)]);
+    }
+
+    #[test]
+    fn check() {
+        let mut matrix = TileMatrix::default();
+        assert!(matrix.check(MAX_ZOOM).is_ok());
+        matrix.resolutions.truncate(10);
+        assert!(matrix.check(9).is_ok());
+        assert!(matrix.check(10).is_err());
+        matrix.resolutions[3] = 0.;
+        assert!(matrix.check(9).is_err());
+    }
+}
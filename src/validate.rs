@@ -0,0 +1,176 @@
+//! Checking that generated tiles have the layers we expect, e.g. in CI.
+
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+use rusqlite;
+use serde_json;
+
+use ::decode::tile_layer_names;
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+/// Which layers tiles should have, for bands of zooms. Read from a JSON file like:
+///
+/// ```json
+/// [
+///   {"minzoom": 0, "maxzoom": 7, "layers": ["water", "boundaries"]},
+///   {"minzoom": 8, "maxzoom": 14, "layers": ["water", "boundaries", "roads"]}
+/// ]
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpectedLayers {
+    bands: Vec<ZoomBand>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct ZoomBand {
+    minzoom: u8,
+    maxzoom: u8,
+    layers: Vec<String>,
+}
+
+impl ExpectedLayers {
+    pub fn from_file(filename: &Path) -> Result<Self> {
+        let file = File::open(filename)?;
+        let bands: Vec<ZoomBand> = serde_json::from_reader(file).map_err(|e| format_err!("{}: {}", filename.display(), e))?;
+        Ok(ExpectedLayers{ bands: bands })
+    }
+
+    pub fn from_json_str(contents: &str) -> Result<Self> {
+        let bands: Vec<ZoomBand> = serde_json::from_str(contents)?;
+        Ok(ExpectedLayers{ bands: bands })
+    }
+
+    /// The layers which tiles on this zoom should have. No layers if no band includes this zoom.
+    pub fn layers_for_zoom(&self, zoom: u8) -> &[String] {
+        self.bands.iter().find(|b| b.minzoom <= zoom && zoom <= b.maxzoom).map(|b| b.layers.as_slice()).unwrap_or(&[])
+    }
+
+    /// What's wrong with this tile's layers, if anything?
+    pub fn check_tile(&self, zoom: u8, x: u32, y: u32, layer_names: &[String]) -> Option<LayerMismatch> {
+        let expected = self.layers_for_zoom(zoom);
+        let missing: Vec<String> = expected.iter().filter(|l| ! layer_names.contains(l)).cloned().collect();
+        let unexpected: Vec<String> = layer_names.iter().filter(|l| ! expected.contains(l)).cloned().collect();
+
+        if missing.is_empty() && unexpected.is_empty() {
+            None
+        } else {
+            Some(LayerMismatch{ zoom, x, y, missing, unexpected })
+        }
+    }
+}
+
+/// A tile which doesn't have the layers it should.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerMismatch {
+    pub zoom: u8,
+    pub x: u32,
+    /// In the XYZ scheme
+    pub y: u32,
+    /// Layers which should be in the tile, but aren't
+    pub missing: Vec<String>,
+    /// Layers which are in the tile, but shouldn't be
+    pub unexpected: Vec<String>,
+}
+
+impl fmt::Display for LayerMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}/{}:", self.zoom, self.x, self.y)?;
+        if ! self.missing.is_empty() {
+            write!(f, " missing {}", self.missing.join(", "))?;
+        }
+        if ! self.unexpected.is_empty() {
+            write!(f, " unexpected {}", self.unexpected.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Check every `sample`th tile in this MBTiles file (every tile if `sample` is 1) has the
+/// expected layers. Returns how many tiles were checked, and the ones which don't.
+pub fn validate_mbtiles_layers(filename: &Path, expected: &ExpectedLayers, sample: usize) -> Result<(usize, Vec<LayerMismatch>)> {
+    if sample == 0 {
+        return Err(format_err!("sample must be at least 1"));
+    }
+    let conn = rusqlite::Connection::open(filename)?;
+    let scheme: String = conn.query_row("SELECT value FROM metadata WHERE name = 'scheme';", &[], |r| r.get(0)).unwrap_or_else(|_| "tms".to_string());
+    let is_tms = scheme != "xyz";
+
+    let mut stmt = conn.prepare("SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles ORDER BY zoom_level, tile_column, tile_row;")?;
+    let mut rows = stmt.query(&[])?;
+
+    let mut num_checked = 0;
+    let mut mismatches = Vec::new();
+    let mut i = 0;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        i += 1;
+        if (i - 1) % sample != 0 {
+            continue;
+        }
+
+        let zoom: i64 = row.get(0);
+        let x: i64 = row.get(1);
+        let row_num: i64 = row.get(2);
+        let bytes: Vec<u8> = row.get(3);
+        let zoom = zoom as u8;
+        let y = if is_tms { (1i64 << zoom) - 1 - row_num } else { row_num };
+
+        let layer_names = tile_layer_names(&bytes).map_err(|e| format_err!("Tile {}/{}/{}: {}", zoom, x, y, e))?;
+        num_checked += 1;
+        if let Some(mismatch) = expected.check_tile(zoom, x as u32, y as u32, &layer_names) {
+            mismatches.push(mismatch);
+        }
+    }
+
+    Ok((num_checked, mismatches))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_util::temp_path;
+    use std::fs;
+    use slippy_map_tiles;
+    use mapbox_vector_tile;
+    use fileio::{MBTiles, TileDestination};
+
+    #[test]
+    fn missing_layer() {
+        let expected = ExpectedLayers::from_json_str(r#"[
+            {"minzoom": 0, "maxzoom": 1, "layers": ["water"]},
+            {"minzoom": 2, "maxzoom": 14, "layers": ["water", "roads"]}
+        ]"#).unwrap();
+        assert_eq!(expected.layers_for_zoom(1), &["water".to_string()]);
+        assert!(expected.layers_for_zoom(15).is_empty());
+
+        let path = temp_path("validate_missing_layer.mbtiles");
+        let _ = fs::remove_file(&path);
+
+        let tile_with = |layers: &[&str]| {
+            let mut tile = mapbox_vector_tile::Tile::new();
+            for l in layers.iter() {
+                tile.add_layer(mapbox_vector_tile::Layer::new(l.to_string()));
+            }
+            tile.to_compressed_bytes()
+        };
+
+        let mut mbtiles = MBTiles::new(&path);
+        mbtiles.save_tile(slippy_map_tiles::Tile::new(0, 0, 0).unwrap(), tile_with(&["water"]));
+        mbtiles.save_tile(slippy_map_tiles::Tile::new(2, 1, 0).unwrap(), tile_with(&["water", "roads"]));
+        // No roads, and an extra layer
+        mbtiles.save_tile(slippy_map_tiles::Tile::new(2, 1, 1).unwrap(), tile_with(&["water", "buildings"]));
+        mbtiles.finish();
+        drop(mbtiles);
+
+        let (num_checked, mismatches) = validate_mbtiles_layers(&path, &expected, 1).unwrap();
+        assert_eq!(num_checked, 3);
+        assert_eq!(mismatches, vec![LayerMismatch{ zoom: 2, x: 1, y: 1, missing: vec!["roads".to_string()], unexpected: vec!["buildings".to_string()] }]);
+        assert_eq!(mismatches[0].to_string(), "2/1/1: missing roads unexpected buildings");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
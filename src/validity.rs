@@ -6,7 +6,7 @@ use geo::prelude::BoundingBox;
 use geo::winding_order::Winding;
 use std::cmp::{min, max, Ord, Ordering};
 use std::ops::{Add, Sub, DivAssign,Rem,Mul,AddAssign};
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
 use num_traits::Signed;
 use std::fmt::Debug;
 use std::hash::Hash;
@@ -14,12 +14,123 @@ use log;
 
 use ::simplify;
 use ::geom_as_geojson;
+use ::geometry_bbox;
+use ::clip::clip_to_bbox;
+use std::borrow::Cow;
+
+/// How much checking (and repairing) of geometries to do while generating tiles.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ValidityLevel {
+    /// Check everything, including the (O(n²)) self-intersection check, and repair anything
+    /// invalid.
+    Full,
+
+    /// Only do the cheap checks (e.g. unclosed rings, too few points), and repair geometries which
+    /// fail them. For source data which is known to have no self-intersections.
+    SkipExpensive,
+
+    /// Trust the source data entirely, no checking or repairing.
+    None,
+}
+
+impl Default for ValidityLevel {
+    fn default() -> Self {
+        ValidityLevel::Full
+    }
+}
+
+impl ::std::str::FromStr for ValidityLevel {
+    type Err = ::failure::Error;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(ValidityLevel::Full),
+            "skip-expensive" => Ok(ValidityLevel::SkipExpensive),
+            "none" => Ok(ValidityLevel::None),
+            x => Err(format_err!("Unknown validity level {:?}, must be 'full', 'skip-expensive' or 'none'", x)),
+        }
+    }
+}
+
+/// How to check & repair geometries when generating tiles. Each run has its own, which is passed
+/// to `make_valid_with_options` etc., so runs with different settings (e.g. tests in parallel)
+/// don't change each other's.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub struct ValidityOptions {
+    /// How much checking (and repairing) to do
+    pub level: ValidityLevel,
+
+    /// Keep lines whose points are all the same (e.g. connectors in a routing graph) as a 2 point
+    /// line, rather than dropping them as invalid.
+    pub keep_zero_length_lines: bool,
+
+    /// Check, and log, any interiors that have been wrongly assigned to exteriors. Always done in
+    /// debug builds. This is slow.
+    pub check_interior_nesting: bool,
+
+    /// Simplify (lossily) rings with more points than this before the expensive adding of points
+    /// at all their crossings. `None` never simplifies them.
+    pub vertex_budget: Option<usize>,
+
+    /// Which way the y axis goes, which decides which way around polygon rings have to go. Tiles
+    /// are always y down (the default), so only change this when making other data valid.
+    pub y_axis: YAxis,
+}
+
+/// Which way the y axis goes. This decides which way around polygon rings have to go.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum YAxis {
+    /// y increases downwards, as in vector tiles. Exterior rings go clockwise (when drawn with y
+    /// up), and interiors anticlockwise.
+    Down,
+
+    /// y increases upwards, as in most projections. Exterior rings go anticlockwise, and
+    /// interiors clockwise.
+    Up,
+}
+
+impl Default for YAxis {
+    fn default() -> Self {
+        YAxis::Down
+    }
+}
+
+/// Does this ring go the right way around for an exterior ring?
+fn has_exterior_orientation(ls: &LineString<i32>, y_axis: YAxis) -> bool {
+    match y_axis {
+        YAxis::Down => is_cw(ls),
+        YAxis::Up => is_ccw(ls),
+    }
+}
+
+/// Does this ring go the right way around for an interior ring?
+fn has_interior_orientation(ls: &LineString<i32>, y_axis: YAxis) -> bool {
+    match y_axis {
+        YAxis::Down => is_ccw(ls),
+        YAxis::Up => is_cw(ls),
+    }
+}
 
+/// Is this geometry valid, when the y axis goes this way, only doing the checks for this level?
+pub fn is_valid_at_level(geom: &Geometry<i32>, level: ValidityLevel, y_axis: YAxis) -> bool {
+    match level {
+        ValidityLevel::Full => is_valid_with_y_axis(geom, y_axis),
+        ValidityLevel::SkipExpensive => is_valid_skip_expensive(geom),
+        ValidityLevel::None => true,
+    }
+}
+
+/// Is this geometry valid, as a tile geometry (i.e. y down)?
 pub fn is_valid(geom: &Geometry<i32>) -> bool {
+    is_valid_with_y_axis(geom, YAxis::Down)
+}
+
+/// Is this geometry valid, when the y axis goes this way?
+pub fn is_valid_with_y_axis(geom: &Geometry<i32>, y_axis: YAxis) -> bool {
     match *geom {
         Geometry::LineString(ref ls) => is_linestring_valid(ls),
-        Geometry::Polygon(ref p) => is_polygon_valid(p),
-        Geometry::MultiPolygon(ref mp) => mp.0.iter().all(|p| is_polygon_valid(p)),
+        Geometry::Polygon(ref p) => is_polygon_valid_with_y_axis(p, y_axis),
+        Geometry::MultiPolygon(ref mp) => mp.0.iter().all(|p| is_polygon_valid_with_y_axis(p, y_axis)),
         Geometry::MultiLineString(ref mls) => mls.0.iter().all(|ls| is_linestring_valid(ls)),
         _ => true,
     }
@@ -36,19 +147,17 @@ pub fn is_valid_skip_expensive(geom: &Geometry<i32>) -> bool {
 }
 
 fn is_linestring_valid<T: CoordinateType>(ls: &LineString<T>) -> bool {
-    if ls.0.len() < 2 {
-        return false;
-    }
-
-    if ls.0.len() == 2 && ls.0[0] == ls.0[1] {
-        return false;
-    }
-
-    true
+    // A line needs at least 2 different points, any number of the same point has no length
+    num_points_excl_duplicates(ls) >= 2
 }
 
+/// Is this polygon valid, as a tile geometry (i.e. y down)?
 pub fn is_polygon_valid(p: &Polygon<i32>) -> bool {
-    is_polygon_valid_skip_expensive(p) && is_polygon_valid_do_expensive(p)
+    is_polygon_valid_with_y_axis(p, YAxis::Down)
+}
+
+fn is_polygon_valid_with_y_axis(p: &Polygon<i32>, y_axis: YAxis) -> bool {
+    is_polygon_valid_skip_expensive(p) && is_polygon_valid_do_expensive(p, y_axis)
 }
 
 fn is_polygon_valid_skip_expensive<T: CoordinateType+Signed+Debug+Ord>(p: &Polygon<T>) -> bool {
@@ -74,6 +183,11 @@ fn is_polygon_valid_skip_expensive<T: CoordinateType+Signed+Debug+Ord>(p: &Polyg
         return false;
     }
 
+    // Spikes (e.g. A-B-C-B-A) aren't duplicates, but can still leave <3 real corners
+    if ring_effective_vertex_count(&p.exterior) < 3 {
+        return false;
+    }
+
 
     for i in p.interiors.iter() {
         if num_points_excl_duplicates(i) < 4 {
@@ -90,13 +204,17 @@ fn is_polygon_valid_skip_expensive<T: CoordinateType+Signed+Debug+Ord>(p: &Polyg
             return false;
         }
 
+        if ring_effective_vertex_count(i) < 3 {
+            return false;
+        }
+
     }
 
 
     true
 }
 
-fn is_polygon_valid_do_expensive(p: &Polygon<i32>) -> bool {
+fn is_polygon_valid_do_expensive(p: &Polygon<i32>, y_axis: YAxis) -> bool {
     if has_self_intersections(&p.exterior) {
         return false;
     }
@@ -105,9 +223,17 @@ fn is_polygon_valid_do_expensive(p: &Polygon<i32>) -> bool {
         return false;
     }
 
-    // In theory this is backwards. Ext rings should be CCW, and int rings CW. But in vector tiles
-    // the y goes down, so it's flipped.
-    if is_ccw(&p.exterior) || p.interiors.iter().any(|i| is_cw(i)) {
+    // Ext rings should be CCW, and int rings CW. But in vector tiles the y goes down, so it's
+    // flipped.
+    if has_interior_orientation(&p.exterior, y_axis) || p.interiors.iter().any(|i| has_exterior_orientation(i, y_axis)) {
+        return false;
+    }
+
+    if p.interiors.iter().any(|i| shares_edge_with(i, &p.exterior)) {
+        return false;
+    }
+
+    if has_overlapping_interiors(p) {
         return false;
     }
 
@@ -154,27 +280,74 @@ fn num_points_excl_duplicates<T: CoordinateType>(ls: &LineString<T>) -> usize {
 
 }
 
-pub fn ensure_polygon_orientation(geom: &mut Geometry<i32>) {
+/// The number of real corners in this ring, i.e. not counting duplicate points, points in a
+/// straight line with their neighbours, or spikes (A-B-A). A ring needs at least 3 to have any
+/// area.
+fn ring_effective_vertex_count<T: CoordinateType>(ring: &LineString<T>) -> usize {
+    let points = &ring.0;
+    let is_closed = points.len() >= 2 && points[0] == points[points.len()-1];
+    let corners = if is_closed { &points[..points.len()-1] } else { &points[..] };
+    let n = corners.len();
+    let corner = |i: usize| { let p = corners[i % n]; (p.x(), p.y()) };
+
+    // Common case, every point is a real corner, which we can check without allocating
+    if n >= 3 && (0..n).all(|i| corner(i) != corner(i+1) && ! collinear(corner(i), corner(i+1), corner(i+2))) {
+        return n;
+    }
+
+    // Otherwise remove those points, going around the ring once
+    let mut kept: Vec<(T, T)> = Vec::with_capacity(n);
+    for i in 0..n {
+        let p = corner(i);
+        if kept.last() == Some(&p) {
+            continue;
+        }
+        while kept.len() >= 2 && collinear(kept[kept.len()-2], kept[kept.len()-1], p) {
+            kept.pop();
+        }
+        kept.push(p);
+    }
+
+    // The ring wraps around, so points at the start/end can also be spikes
+    loop {
+        let len = kept.len();
+        if len < 3 {
+            break;
+        }
+        if kept[len-1] == kept[0] || collinear(kept[len-2], kept[len-1], kept[0]) {
+            kept.pop();
+        } else if collinear(kept[len-1], kept[0], kept[1]) {
+            kept.remove(0);
+        } else {
+            break;
+        }
+    }
+
+    kept.len()
+}
+
+/// Make all polygon rings go the right way around, when the y axis goes this way.
+pub fn ensure_polygon_orientation(geom: &mut Geometry<i32>, y_axis: YAxis) {
     match *geom {
         Geometry::Polygon(ref mut p) => {
-            // Y goes positive down, ergo the winding order is 'wrong way around' since the winding
-            // order code works with y up
-            if !is_cw(&p.exterior) {
+            // When y goes positive down, the winding order is 'wrong way around' since the
+            // winding order code works with y up
+            if !has_exterior_orientation(&p.exterior, y_axis) {
                 p.exterior.0.reverse();
             }
             for i in p.interiors.iter_mut() {
-                if !is_ccw(i) {
+                if !has_interior_orientation(i, y_axis) {
                     i.0.reverse();
                 }
             }
         },
         Geometry::MultiPolygon(ref mut mp) => {
             for p in mp.0.iter_mut() {
-                if !is_cw(&p.exterior) {
+                if !has_exterior_orientation(&p.exterior, y_axis) {
                     p.exterior.0.reverse();
                 }
                 for i in p.interiors.iter_mut() {
-                    if !is_ccw(i) {
+                    if !has_interior_orientation(i, y_axis) {
                         i.0.reverse();
                     }
                 }
@@ -184,7 +357,13 @@ pub fn ensure_polygon_orientation(geom: &mut Geometry<i32>) {
     }
 }
 
+#[cfg(test)]
+thread_local!(static SELF_INTERSECTION_CHECKS: ::std::cell::Cell<usize> = ::std::cell::Cell::new(0));
+
 fn has_self_intersections(ls: &LineString<i32>) -> bool {
+    #[cfg(test)]
+    SELF_INTERSECTION_CHECKS.with(|c| c.set(c.get()+1));
+
     if ls.0.len() <= 4 {
         // cannot have a self intersection with this few members. (There shouldn't be <4 anyway)
         // With 4 points, it's a orientation, not self-intersection thing really
@@ -260,8 +439,21 @@ enum Intersection<T> {
 //}
 
 
+/// An `Overlapping` with the 2 end points always in the same (lexicographic) order, no matter
+/// what order (or direction) the segments were in.
+fn overlapping(a: (i32, i32), b: (i32, i32)) -> Intersection<i32> {
+    if a <= b {
+        Intersection::Overlapping(a, b)
+    } else {
+        Intersection::Overlapping(b, a)
+    }
+}
+
 /// True iff the segments |p1p2| and |p3p4| intersect at any point, and the intersection point is
 /// not on both end points. i.e. 2 lines can join end-to-end in this, but not touch anywhere else.
+/// For `Overlapping`, the end points are sorted.
+/// Neither segment can be a single point. Coordinates must be within ±2¹⁹ (far more than a tile &
+/// buffer), so the calculations don't overflow.
 fn intersection(x1: i32, y1: i32, x2: i32, y2: i32, x3: i32, y3: i32, x4: i32, y4: i32) -> Intersection<i32> {
     if max(x1, x2) < min(x3, x4) || min(x1, x2) > max(x3, x4)
         || max(y1, y2) < min(y3, y4) || min(y1, y2) > max(y3, y4)
@@ -295,7 +487,7 @@ fn intersection(x1: i32, y1: i32, x2: i32, y2: i32, x3: i32, y3: i32, x4: i32, y
         // The lines are the same (if we ignore direction). One lies totally on top of the
         // other
         if ((x1, y1) == (x3, y3) && (x2, y2) == (x4, y4)) || ((x1, y1) == (x4, y4) && (x2, y2) == (x3, y3)) {
-            return Intersection::Overlapping((x1 as i32, y1 as i32), (x2 as i32, y2 as i32));
+            return overlapping((x1 as i32, y1 as i32), (x2 as i32, y2 as i32));
         }
         
 
@@ -323,8 +515,9 @@ fn intersection(x1: i32, y1: i32, x2: i32, y2: i32, x3: i32, y3: i32, x4: i32, y
 
             (true, false) | (false, true) => {
                 // One point is on the line and the other is? But the slopes are the same, so this
-                // should be impossible
-                unreachable!();
+                // should be impossible. Don't stop generating tiles if it happens anyway.
+                warn!("Parallel segments {:?}-{:?} & {:?}-{:?} with only one point collinear, assuming they don't intersect", (x1, y1), (x2, y2), (x3, y3), (x4, y4));
+                return Intersection::None;
             },
 
             (true, true) => {
@@ -345,7 +538,7 @@ fn intersection(x1: i32, y1: i32, x2: i32, y2: i32, x3: i32, y3: i32, x4: i32, y
                 match (p3_on_12, p4_on_12) {
                     (true, true) => {
                         // both on the line
-                        return Intersection::Overlapping((x3 as i32, y3 as i32), (x4 as i32, y4 as i32));
+                        return overlapping((x3 as i32, y3 as i32), (x4 as i32, y4 as i32));
                     },
                     (true, false) => {
                         // p3 is on the line 12, but which of p1 & p2 is the other point
@@ -357,7 +550,7 @@ fn intersection(x1: i32, y1: i32, x2: i32, y2: i32, x3: i32, y3: i32, x4: i32, y
                             debug_assert!(point_on_line_incl_end((x3, y3), (x4, y4), (x2, y2)));
                             (x2 as i32, y2 as i32)
                         };
-                        return Intersection::Overlapping((x3 as i32, y3 as i32), other_point);
+                        return overlapping((x3 as i32, y3 as i32), other_point);
                     },
                     (false, true) => {
                         // p4 is on the line 12, but which of p1 & p2 is the other point
@@ -369,12 +562,12 @@ fn intersection(x1: i32, y1: i32, x2: i32, y2: i32, x3: i32, y3: i32, x4: i32, y
                             debug_assert!(point_on_line_incl_end((x3, y3), (x4, y4), (x2, y2)));
                             (x2 as i32, y2 as i32)
                         };
-                        return Intersection::Overlapping((x4 as i32, y4 as i32), other_point);
+                        return overlapping((x4 as i32, y4 as i32), other_point);
                     },
                     (false, false) => {
                         // This can happen when 12 is a subset of 34
                         debug_assert!(point_on_line_incl_end((x3, y3), (x4, y4), (x1, y1)) && point_on_line_incl_end((x3, y3), (x4, y4), (x2, y2)));
-                        return Intersection::Overlapping((x1 as i32, y1 as i32), (x2 as i32, y2 as i32));
+                        return overlapping((x1 as i32, y1 as i32), (x2 as i32, y2 as i32));
                     }
                 }
             },
@@ -403,21 +596,19 @@ fn intersection(x1: i32, y1: i32, x2: i32, y2: i32, x3: i32, y3: i32, x4: i32, y
     if (td == determinate || td == 0) && (sd == 0 || sd == determinate) {
         // endpoints overlap
         return Intersection::EndToEnd;
-    } else if (td == determinate || td == 0) && (sd > 0 || sd < determinate) {
+    } else if td == determinate || td == 0 {
+        // sd is strictly between 0 & determinate
         if td == 0 {
             return Intersection::Touching((x1 as i32, y1 as i32));
-        } else if td == determinate {
-            return Intersection::Touching((x2 as i32, y2 as i32));
         } else {
-            unreachable!();
+            return Intersection::Touching((x2 as i32, y2 as i32));
         }
-    } else if (td < determinate || td > 0) && (sd == 0 || sd == determinate) {
+    } else if sd == 0 || sd == determinate {
+        // td is strictly between 0 & determinate
         if sd == 0 {
             return Intersection::Touching((x3 as i32, y3 as i32));
-        } else if sd == determinate {
-            return Intersection::Touching((x4 as i32, y4 as i32));
         } else {
-            unreachable!();
+            return Intersection::Touching((x4 as i32, y4 as i32));
         }
     } else if td > 0 && td < determinate && sd > 0 && sd < determinate {
         // This will do some roundingin on integers
@@ -457,77 +648,324 @@ fn intersection(x1: i32, y1: i32, x2: i32, y2: i32, x3: i32, y3: i32, x4: i32, y
         return Intersection::Crossing((x as i32, y as i32));
     }
 
-    // Should have been caught above.
-    eprintln!("points {:?} {:?} {:?} {:?}", (x1, y1), (x2, y2), (x3, y3), (x4, y4));
-    eprintln!("det {:?} sd {:?} td {:?}", determinate, sd, td);
-    unreachable!();
+    // Should have been caught above. If not, treat it as not intersecting, rather than stopping
+    // tile generation.
+    warn!("Unexpected intersection case for segments {:?}-{:?} & {:?}-{:?}, assuming they don't intersect", (x1, y1), (x2, y2), (x3, y3), (x4, y4));
+    debug!("det {:?} sd {:?} td {:?}", determinate, sd, td);
+    Intersection::None
+}
+
+/// What happened when making a geometry valid (see `make_valid_outcome`).
+#[derive(Debug, Clone, PartialEq)]
+pub enum MakeValidOutcome {
+    /// The valid geometry
+    Valid(Geometry<i32>),
+    /// Nothing is left after repairing, e.g. a sliver polygon with no area, or a line whose
+    /// points are all the same. This is normal, and nothing has gone wrong.
+    EmptyAfterRepair,
+    /// The geometry couldn't be repaired, e.g. repairing it made something which is still
+    /// invalid. This is a bug (or unsupported case), and the geometry is lost.
+    Unrepairable,
+}
+
+impl MakeValidOutcome {
+    /// The valid geometry, if there is one, and `None` for both empty & unrepairable.
+    pub fn into_option(self) -> Option<Geometry<i32>> {
+        match self {
+            MakeValidOutcome::Valid(g) => Some(g),
+            MakeValidOutcome::EmptyAfterRepair | MakeValidOutcome::Unrepairable => None,
+        }
+    }
+}
+
+/// Make this geometry valid. `None` if there's nothing left, or it couldn't be repaired. Use
+/// `make_valid_outcome` to tell those apart. Geometries are only 2D, there is no z or m.
+pub fn make_valid(geom: Geometry<i32>) -> Option<Geometry<i32>> {
+    make_valid_outcome(geom).into_option()
+}
+
+/// Polygons with more vertices than this don't take the convex fast path in `make_valid_outcome`.
+const MAX_CONVEX_FAST_PATH_VERTICES: usize = 8;
+
+#[cfg(test)]
+thread_local!(static CONVEX_FAST_PATHS: ::std::cell::Cell<usize> = ::std::cell::Cell::new(0));
+
+/// Is this definitely a valid polygon, which `make_valid` wouldn't change, because it has no
+/// holes, a few (distinct) vertices, turns the same way (strictly) at every corner, goes around
+/// only once, and is the right way around? Anything else (e.g. collinear or duplicate points)
+/// is `false`, even if it's valid.
+fn is_small_convex_polygon(p: &Polygon<i32>, y_axis: YAxis) -> bool {
+    let points = &p.exterior.0;
+    if ! p.interiors.is_empty() || points.len() < 4 || points.len() > MAX_CONVEX_FAST_PATH_VERTICES + 1 || points[0] != points[points.len()-1] {
+        return false;
+    }
+
+    // Without the repeated last point
+    let n = points.len() - 1;
+    let mut turn_sign = 0;
+    let mut dx_sign_changes = 0;
+    let mut dy_sign_changes = 0;
+    for i in 0..n {
+        let (a, b, c) = (points[i], points[(i+1)%n], points[(i+2)%n]);
+        let (dx1, dy1) = ((b.x() - a.x()) as i64, (b.y() - a.y()) as i64);
+        let (dx2, dy2) = ((c.x() - b.x()) as i64, (c.y() - b.y()) as i64);
+        let cross = (dx1*dy2 - dy1*dx2).signum();
+        if cross == 0 || (turn_sign != 0 && cross != turn_sign) {
+            return false;
+        }
+        turn_sign = cross;
+        if dx1.signum() * dx2.signum() < 0 {
+            dx_sign_changes += 1;
+        }
+        if dy1.signum() * dy2.signum() < 0 {
+            dy_sign_changes += 1;
+        }
+    }
+
+    // Turning the same way at every corner can still go around more than once (e.g. a
+    // pentagram). Going around once, x & y each change direction exactly twice.
+    dx_sign_changes <= 2 && dy_sign_changes <= 2 && has_exterior_orientation(&p.exterior, y_axis)
+}
+
+/// Make this geometry valid, like `make_valid`, but say whether it was empty after repairing, or
+/// couldn't be repaired at all.
+pub fn make_valid_outcome(geom: Geometry<i32>) -> MakeValidOutcome {
+    make_valid_outcome_with_options(geom, &ValidityOptions::default())
 }
 
-pub fn make_valid(mut geom: Geometry<i32>) -> Option<Geometry<i32>> {
+/// `make_valid_outcome`, repairing polygons with these options. (The `level` isn't used, the
+/// geometry is always fully checked.)
+pub fn make_valid_outcome_with_options(geom: Geometry<i32>, options: &ValidityOptions) -> MakeValidOutcome {
     trace!("make_valid on {}", format!("{:?}", geom)[..20].to_string());
-    let mut geom = match simplify::remove_unneeded_points(geom) {
+
+    // Most polygons (e.g. buildings) are small & convex, which can be checked much more cheaply
+    // than the general validity checks.
+    if let Geometry::Polygon(ref p) = geom {
+        if is_small_convex_polygon(p, options.y_axis) {
+            #[cfg(test)]
+            CONVEX_FAST_PATHS.with(|c| c.set(c.get()+1));
+            trace!("input geometry is a small convex polygon, early return");
+            return MakeValidOutcome::Valid(geom);
+        }
+    }
+
+    // Fast path for the common case of an already clean geometry, which doesn't need to be
+    // rewritten (or even copied).
+    if ! simplify::has_unneeded_points(&geom) && is_valid_with_y_axis(&geom, options.y_axis) {
+        trace!("input geometry is already clean & valid, early return");
+        return MakeValidOutcome::Valid(geom);
+    }
+
+    let geom = match simplify::remove_unneeded_points(geom) {
         None => {
             trace!("After removing unneeded points, geom is None, early return");
-            return None;
+            return MakeValidOutcome::EmptyAfterRepair;
         },
         Some(g) => g,
     };
-    if is_valid(&geom) {
+    if is_valid_with_y_axis(&geom, options.y_axis) {
         trace!("input geometry is already valid, early return");
-        return Some(geom);
+        return MakeValidOutcome::Valid(geom);
     }
 
     trace!("geometry to make valid (geojson):\n{}", geom_as_geojson(&geom, 4096.*8.));
 
-    let valid_geom = match geom {
-        Geometry::Polygon(p) => make_polygon_valid(p).map(Geometry::MultiPolygon),
-        Geometry::MultiPolygon(mp) => make_multipolygon_valid(mp).map(Geometry::MultiPolygon),
+    let outcome = match geom {
+        Geometry::Polygon(p) => make_rings_valid_outcome(polygon_into_rings(p), options),
+        Geometry::MultiPolygon(mp) => make_rings_valid_outcome(mp.0.into_iter().flat_map(polygon_into_rings).collect(), options),
         Geometry::LineString(ls) => {
             if ls.0.len() < 2 {
-                None
+                MakeValidOutcome::EmptyAfterRepair
+            } else {
+                MakeValidOutcome::Valid(Geometry::LineString(ls))
+            }
+        },
+        Geometry::MultiLineString(mls) => {
+            let lines: Vec<_> = mls.0.into_iter().filter(|ls| is_linestring_valid(ls)).collect();
+            if lines.is_empty() {
+                MakeValidOutcome::EmptyAfterRepair
             } else {
-                Some(Geometry::LineString(ls))
+                MakeValidOutcome::Valid(Geometry::MultiLineString(MultiLineString(lines)))
             }
         },
-        x => Some(x),
+        x => MakeValidOutcome::Valid(x),
     };
 
     // Sanity checking
-    debug_assert_valid_geom(&valid_geom);
+    if let MakeValidOutcome::Valid(ref g) = outcome {
+        debug_assert_valid_geom(g, options.y_axis);
+    }
 
-    valid_geom
+    outcome
 }
 
-fn make_multipolygon_valid(mut mp: MultiPolygon<i32>) -> Option<MultiPolygon<i32>> {
-    trace!("making multipolygon valid, mp has {} inner polys", mp.0.len());
-    let MultiPolygon( polygons ) = mp;
+/// Make this geometry valid, only doing the checks for this level. A geometry which passes the
+/// checks is returned (almost) as is, anything which fails is fully repaired with `make_valid`.
+pub fn make_valid_at_level(geom: Geometry<i32>, level: ValidityLevel) -> Option<Geometry<i32>> {
+    make_valid_with_options(geom, &ValidityOptions{ level: level, ..ValidityOptions::default() })
+}
 
-    let rings: Vec<LineString<_>> = polygons.into_iter().flat_map(|p| {
-        let Polygon{ exterior, interiors } = p;
-        let mut these_rings = interiors;
-        these_rings.insert(0, exterior);
-        these_rings.into_iter()
-    }).collect();
+/// Make this geometry valid, like `make_valid_at_level`. If `keep_zero_length_lines`, lines (and
+/// lines in a MultiLineString) whose points are all the same are kept as a 2 point line, rather
+/// than dropped as invalid.
+pub fn make_valid_lines_at_level(geom: Geometry<i32>, level: ValidityLevel, keep_zero_length_lines: bool) -> Option<Geometry<i32>> {
+    make_valid_with_options(geom, &ValidityOptions{ level: level, keep_zero_length_lines: keep_zero_length_lines, ..ValidityOptions::default() })
+}
 
+/// Make this geometry valid, only doing the checks for `options.level`, and keeping zero length
+/// lines if `options.keep_zero_length_lines`.
+pub fn make_valid_with_options(geom: Geometry<i32>, options: &ValidityOptions) -> Option<Geometry<i32>> {
+    if ! options.keep_zero_length_lines {
+        return make_valid_checked(geom, options);
+    }
+
+    match geom {
+        Geometry::LineString(ls) => match zero_length_line(&ls) {
+            Some(ls) => Some(Geometry::LineString(ls)),
+            None => make_valid_checked(Geometry::LineString(ls), options),
+        },
+        Geometry::MultiLineString(mls) => {
+            let (zero_length, others): (Vec<_>, Vec<_>) = mls.0.into_iter().partition(|ls| zero_length_line(ls).is_some());
+            let mut lines = if others.is_empty() {
+                vec![]
+            } else {
+                match make_valid_checked(Geometry::MultiLineString(MultiLineString(others)), options) {
+                    Some(Geometry::MultiLineString(mls)) => mls.0,
+                    Some(Geometry::LineString(ls)) => vec![ls],
+                    _ => vec![],
+                }
+            };
+            lines.extend(zero_length.iter().filter_map(zero_length_line));
 
-    make_rings_valid(rings)
+            match lines.len() {
+                0 => None,
+                1 => Some(Geometry::LineString(lines.remove(0))),
+                _ => Some(Geometry::MultiLineString(MultiLineString(lines))),
+            }
+        },
+        geom => make_valid_checked(geom, options),
+    }
 }
 
-fn make_polygon_valid(mut p: Polygon<i32>) -> Option<MultiPolygon<i32>> {
-    trace!("make_polygon_valid p has {} interiors", p.interiors.len());
+/// Only do the checks for `options.level`, and fully repair the geometry if it fails them.
+fn make_valid_checked(geom: Geometry<i32>, options: &ValidityOptions) -> Option<Geometry<i32>> {
+    match options.level {
+        ValidityLevel::Full => make_valid_outcome_with_options(geom, options).into_option(),
+        ValidityLevel::SkipExpensive => {
+            if ! simplify::has_unneeded_points(&geom) && is_valid_skip_expensive(&geom) {
+                return Some(geom);
+            }
+            let geom = match simplify::remove_unneeded_points(geom) {
+                None => { return None; },
+                Some(g) => g,
+            };
+            if is_valid_skip_expensive(&geom) {
+                Some(geom)
+            } else {
+                make_valid_outcome_with_options(geom, options).into_option()
+            }
+        },
+        ValidityLevel::None => Some(geom),
+    }
+}
+
+/// If this line has >1 point, and they're all the same, the 2 point version of it.
+fn zero_length_line(ls: &LineString<i32>) -> Option<LineString<i32>> {
+    match ls.0.first() {
+        Some(&p) if ls.0.len() >= 2 && ls.0.iter().all(|q| *q == p) => Some(vec![p, p].into()),
+        _ => None,
+    }
+}
+
+/// Make this geometry valid, but only the part inside `bbox` (e.g. the tile plus the buffer)
+/// matters. Any parts outside the box are cut off first, so far-away segments (which would be
+/// clipped away afterwards anyway) don't go through the (slow) self-intersection repair.
+pub fn make_valid_within(geom: Geometry<i32>, bbox: &Bbox<i32>, options: &ValidityOptions) -> Option<Geometry<i32>> {
+    let geom_bbox = match geometry_bbox(&geom) {
+        None => { return None; },
+        Some(b) => b,
+    };
+
+    let inside = geom_bbox.xmin >= bbox.xmin && geom_bbox.xmax <= bbox.xmax && geom_bbox.ymin >= bbox.ymin && geom_bbox.ymax <= bbox.ymax;
+    if inside {
+        make_valid_with_options(geom, options)
+    } else {
+        trace!("make_valid_within: geometry goes outside {:?}, clipping first", bbox);
+        clip_to_bbox(Cow::Owned(geom), bbox).and_then(|g| make_valid_with_options(g, options))
+    }
+}
+
+/// Make this geometry valid, and then drop any polygons which are entirely outside the square
+/// `[min, max]` (in both x & y), e.g. slivers in the buffer left over from repairing. They would
+/// be invisible. Polygons which are partly inside are kept as they are.
+pub fn make_valid_in_extent(geom: Geometry<i32>, min: i32, max: i32) -> Option<Geometry<i32>> {
+    make_valid(geom).and_then(|g| drop_polygons_outside(g, &Bbox{ xmin: min, xmax: max, ymin: min, ymax: max }))
+}
+
+/// Drop the polygons of this geometry which are entirely outside `bbox` (touching it counts as
+/// outside). `None` if there are none left. Other geometries are returned as they are.
+pub fn drop_polygons_outside(geom: Geometry<i32>, bbox: &Bbox<i32>) -> Option<Geometry<i32>> {
+    let overlaps_bbox = |p: &Polygon<i32>| match p.exterior.bbox() {
+        None => false,
+        Some(b) => b.xmax > bbox.xmin && b.xmin < bbox.xmax && b.ymax > bbox.ymin && b.ymin < bbox.ymax,
+    };
+
+    match geom {
+        Geometry::Polygon(p) => if overlaps_bbox(&p) { Some(Geometry::Polygon(p)) } else { None },
+        Geometry::MultiPolygon(mp) => {
+            let num_polygons = mp.0.len();
+            let polygons: Vec<_> = mp.0.into_iter().filter(|p| overlaps_bbox(p)).collect();
+            if polygons.len() != num_polygons {
+                trace!("drop_polygons_outside: dropped {} polygon(s) outside {:?}", num_polygons - polygons.len(), bbox);
+            }
+            if polygons.is_empty() {
+                None
+            } else {
+                Some(Geometry::MultiPolygon(MultiPolygon(polygons)))
+            }
+        },
+        geom => Some(geom),
+    }
+}
+
+/// All the rings of this polygon, exterior first.
+fn polygon_into_rings(p: Polygon<i32>) -> Vec<LineString<i32>> {
     let Polygon{ exterior, interiors } = p;
     let mut rings = interiors;
     rings.insert(0, exterior);
+    rings
+}
 
-    make_rings_valid(rings)
+fn make_polygon_valid(p: Polygon<i32>) -> Option<MultiPolygon<i32>> {
+    trace!("make_polygon_valid p has {} interiors", p.interiors.len());
+    make_rings_valid(polygon_into_rings(p))
 }
 
-fn make_rings_valid(mut rings: Vec<LineString<i32>>) -> Option<MultiPolygon<i32>> {
-    trace!("make_rings_valid: function start with {} ring(s)", rings.len());
+/// Upper limit on how often make_rings_valid will go around the add points/dissolve loop for one
+/// ring.
+const MAX_ADD_POINTS_ITERATIONS: usize = 100;
 
+/// The (sorted) number of points in each ring. Used to detect when make_rings_valid is no longer
+/// making progress.
+fn ring_vertex_counts(rings: &[LineString<i32>]) -> Vec<usize> {
+    let mut counts: Vec<usize> = rings.iter().map(|r| r.0.len()).collect();
+    counts.sort();
+    counts
+}
+
+/// Add points at all the crossings in these rings, and split them at those points, until there are
+/// no crossings left. Rings with more than `vertex_budget` points are simplified first.
+fn dissolve_rings(rings: Vec<LineString<i32>>, vertex_budget: Option<usize>) -> Vec<LineString<i32>> {
     let mut new_rings: Vec<LineString<_>> = Vec::with_capacity(rings.len());
     for mut ring in rings.into_iter() {
         trace!("make_rings_valid: Processing ring w/ {} points", ring.0.len());
+        if let Some(vertex_budget) = vertex_budget {
+            if ring.0.len() > vertex_budget {
+                let num_points = ring.0.len();
+                ring = simplify::simplify_ring_to_max_vertices(ring, vertex_budget);
+                debug!("make_rings_valid: Ring has {} points, more than the budget of {}, simplified to {}", num_points, vertex_budget, ring.0.len());
+            }
+        }
         let mut rings_to_process = vec![ring];
 
         // Sometimes when adding points for crossing, we can make a linestring which has a self
@@ -537,8 +975,17 @@ fn make_rings_valid(mut rings: Vec<LineString<i32>>) -> Option<MultiPolygon<i32>
         // add_points_for_all_crossings so that we don't have to run it repeatidly
         // FIXME check if we need to run dissolve_into_rings a lot, or can we just run the inner
         // for loop?
+        // Rounding in intersection() can make this oscillate (add a point, dissolve, re-add the
+        // same point), so we stop if the ring sizes are the same as the last time around, or if
+        // we've gone around too often.
+        let mut prev_vertex_counts: Option<Vec<usize>> = None;
+        let mut iterations = 0;
         loop {
             let mut added_points = false;
+            iterations += 1;
+
+            let split_rings = rings_to_process.drain(..).flat_map(split_at_rounded_crossings).collect::<Vec<LineString<i32>>>();
+            rings_to_process = split_rings;
 
             for mut ring in rings_to_process.iter_mut() {
                 let old_num_points = ring.0.len();
@@ -563,27 +1010,83 @@ fn make_rings_valid(mut rings: Vec<LineString<i32>>) -> Option<MultiPolygon<i32>
             trace!("This ring has been dissolved into {} ring(s)", rings_to_process.len());
 
             if !added_points { break; }
+
+            let vertex_counts = ring_vertex_counts(&rings_to_process);
+            if prev_vertex_counts.as_ref() == Some(&vertex_counts) {
+                warn!("make_rings_valid: rings unchanged after adding points ({} iterations), stopping to avoid an infinite loop", iterations);
+                break;
+            }
+            if iterations >= MAX_ADD_POINTS_ITERATIONS {
+                warn!("make_rings_valid: still adding points after {} iterations, giving up", iterations);
+                break;
+            }
+            prev_vertex_counts = Some(vertex_counts);
         }
 
         new_rings.extend(rings_to_process.into_iter());
     }
 
-    let rings = new_rings;
+    new_rings
+}
+
+/// The rings that `make_valid` splits this (multi)polygon into, before they are assigned as
+/// exteriors & interiors and made into polygons. For debugging, e.g. dump them with
+/// `geom_as_geojson` to see why a polygon was split the way it was. Other geometry types have no
+/// rings.
+pub fn make_valid_rings(geom: Geometry<i32>) -> Vec<LineString<i32>> {
+    let geom = match simplify::remove_unneeded_points(geom) {
+        None => { return vec![]; },
+        Some(g) => g,
+    };
+
+    let rings: Vec<LineString<i32>> = match geom {
+        Geometry::Polygon(p) => polygon_into_rings(p),
+        Geometry::MultiPolygon(mp) => mp.0.into_iter().flat_map(polygon_into_rings).collect(),
+        _ => { return vec![]; },
+    };
+
+    dissolve_rings(rings, None)
+}
+
+fn make_rings_valid(rings: Vec<LineString<i32>>) -> Option<MultiPolygon<i32>> {
+    match make_rings_valid_outcome(rings, &ValidityOptions::default()) {
+        MakeValidOutcome::Valid(Geometry::MultiPolygon(mp)) => Some(mp),
+        _ => None,
+    }
+}
+
+/// Make these rings into valid polygons. The outcome is always a MultiPolygon, if it's valid.
+/// Rings with more than `options.vertex_budget` points are simplified first.
+fn make_rings_valid_outcome(rings: Vec<LineString<i32>>, options: &ValidityOptions) -> MakeValidOutcome {
+    trace!("make_rings_valid: function start with {} ring(s)", rings.len());
+
+    let rings = dissolve_rings(rings, options.vertex_budget);
     trace!("Now have {} ring(s)", rings.len());
+    if rings.is_empty() {
+        return MakeValidOutcome::EmptyAfterRepair;
+    }
     
     let result = match convert_rings_to_polygons(rings) {
-        None => { return None; },
+        None => { return MakeValidOutcome::Unrepairable; },
         Some(r) => r,
     };
+    if cfg!(debug_assertions) || options.check_interior_nesting {
+        log_interior_nesting_problems(&result.0);
+    }
+    let result = MultiPolygon(result.0.into_iter().map(remove_overlapping_interiors).collect());
 
     // This takes a geom, so we do a dance
     let mut result = Geometry::MultiPolygon(result);
-    ensure_polygon_orientation(&mut result);
+    ensure_polygon_orientation(&mut result, options.y_axis);
 
     if let Geometry::MultiPolygon(mp) = result {
+        let mp = MultiPolygon(mp.0.into_iter().flat_map(|p| merge_coincident_interiors(p, options.y_axis)).collect());
         trace!("make_rings_valid: Finishing with a {} polygon MultiPolygon", mp.0.len());
+        if mp.0.is_empty() {
+            return MakeValidOutcome::EmptyAfterRepair;
+        }
 
-        let valid_polys =  mp.into_iter().enumerate().filter_map(|(i, p)| if is_polygon_valid(&p) {
+        let mut valid_polys =  mp.into_iter().enumerate().filter_map(|(i, p)| if is_polygon_valid_with_y_axis(&p, options.y_axis) {
             Some(p)
         } else {
             // FIXME fix the code so it doesn't return invalid polygons
@@ -593,12 +1096,486 @@ fn make_rings_valid(mut rings: Vec<LineString<i32>>) -> Option<MultiPolygon<i32>
             }
             None
         }).collect::<Vec<Polygon<_>>>();
-        return Some(MultiPolygon(valid_polys));
+
+        if valid_polys.is_empty() {
+            return MakeValidOutcome::Unrepairable;
+        }
+        sort_polygons_canonically(&mut valid_polys);
+        return MakeValidOutcome::Valid(Geometry::MultiPolygon(MultiPolygon(valid_polys)));
     } else {
         unreachable!()
     }
 }
 
+/// True iff the insides of these 2 interiors (of the same polygon) overlap, i.e. their boundaries
+/// cross, one is (partly) inside the other, or they're the same ring. They can touch, or run
+/// along each other.
+fn interiors_overlap(a: &LineString<i32>, b: &LineString<i32>) -> bool {
+    let (a_bbox, b_bbox) = match (a.bbox(), b.bbox()) {
+        (Some(a_bbox), Some(b_bbox)) => (a_bbox, b_bbox),
+        _ => { return false; },
+    };
+    if a_bbox.xmax < b_bbox.xmin || a_bbox.xmin > b_bbox.xmax || a_bbox.ymax < b_bbox.ymin || a_bbox.ymin > b_bbox.ymax {
+        return false;
+    }
+
+    let crosses = a.0.windows(2).filter(|s| s[0] != s[1]).any(|s1| {
+        b.0.windows(2).filter(|s| s[0] != s[1]).any(|s2| {
+            match intersection(s1[0].x(), s1[0].y(), s1[1].x(), s1[1].y(), s2[0].x(), s2[0].y(), s2[1].x(), s2[1].y()) {
+                Intersection::Crossing(_) => true,
+                _ => false,
+            }
+        })
+    });
+    if crosses {
+        return true;
+    }
+
+    // Are any vertices, or middles of segments (where they're on the grid), strictly inside the
+    // other ring?
+    fn partly_inside(ring: &LineString<i32>, other: &LineString<i32>) -> bool {
+        ring.0.iter().any(|p| point_in_ring(p, other) == Some(true))
+            || ring.0.windows(2).any(|s| {
+                let (x, y) = (s[0].x() as i64 + s[1].x() as i64, s[0].y() as i64 + s[1].y() as i64);
+                x % 2 == 0 && y % 2 == 0 && point_in_ring(&Point::new((x/2) as i32, (y/2) as i32), other) == Some(true)
+            })
+    }
+    if partly_inside(a, b) || partly_inside(b, a) {
+        return true;
+    }
+
+    // The same ring (maybe starting somewhere else, or going the other way)
+    let points = |ring: &LineString<i32>| {
+        let mut points: Vec<(i32, i32)> = ring.0.iter().map(|p| (p.x(), p.y())).collect();
+        points.sort();
+        points.dedup();
+        points
+    };
+    points(a) == points(b)
+}
+
+/// Do any 2 interiors of this polygon overlap (see `interiors_overlap`)? This is part of every
+/// full validity check, so only interiors whose bboxes overlap are compared. They're sorted by
+/// their left edge, so each is only compared with the ones after it which start before it ends.
+fn has_overlapping_interiors(p: &Polygon<i32>) -> bool {
+    if p.interiors.len() < 2 {
+        return false;
+    }
+
+    let mut interiors: Vec<(Bbox<i32>, &LineString<i32>)> = p.interiors.iter().filter_map(|i| i.bbox().map(|b| (b, i))).collect();
+    interiors.sort_by_key(|&(ref bbox, _)| bbox.xmin);
+    interiors.iter().enumerate().any(|(n, &(ref a_bbox, a))| {
+        interiors[n+1..].iter()
+            .take_while(|&&(ref b_bbox, _)| b_bbox.xmin <= a_bbox.xmax)
+            .any(|&(ref b_bbox, b)| b_bbox.ymin <= a_bbox.ymax && b_bbox.ymax >= a_bbox.ymin && interiors_overlap(a, b))
+    })
+}
+
+/// Holes which overlap each other aren't valid, so when 2 interiors of this polygon overlap, drop
+/// the smaller one. (If they're the same size, the one with the smaller vertices, so the result
+/// doesn't depend on the order of the interiors.)
+fn remove_overlapping_interiors(polygon: Polygon<i32>) -> Polygon<i32> {
+    let Polygon{ exterior, interiors } = polygon;
+    if interiors.len() < 2 {
+        return Polygon::new(exterior, interiors);
+    }
+
+    let keys: Vec<(i64, Vec<(i32, i32)>)> = interiors.iter()
+        .map(|i| ((twice_linestring_area(i) as i64).abs(), i.0.iter().map(|p| (p.x(), p.y())).collect()))
+        .collect();
+    let mut keep = vec![true; interiors.len()];
+    for i in 0..interiors.len() {
+        for j in i+1..interiors.len() {
+            if !keep[i] {
+                break;
+            }
+            if !keep[j] || !interiors_overlap(&interiors[i], &interiors[j]) {
+                continue;
+            }
+            trace!("Interiors {} & {} overlap, dropping the smaller", i, j);
+            if keys[j] < keys[i] {
+                keep[j] = false;
+            } else {
+                keep[i] = false;
+            }
+        }
+    }
+
+    Polygon::new(exterior, interiors.into_iter().zip(keep.into_iter()).filter_map(|(i, keep)| if keep { Some(i) } else { None }).collect())
+}
+
+/// Merge these polygons, where they share edges, into as few polygons as possible, e.g. many
+/// small adjacent landcover polygons into one. Edges which 2 polygons share (even if one has
+/// extra vertices along it) are removed, and what's left is made into valid polygons with
+/// `make_rings_valid`. Polygons which only touch at a point stay separate. Overlapping polygons
+/// aren't merged.
+pub fn union_touching_polygons(polys: Vec<Polygon<i32>>) -> MultiPolygon<i32> {
+    type Point2 = (i32, i32);
+
+    // With all the rings going the same way around, a shared edge is in one polygon one way, and
+    // in the other the other way.
+    let mut edges: Vec<(Point2, Point2)> = Vec::new();
+    for p in polys.into_iter() {
+        let mut geom = Geometry::Polygon(p);
+        ensure_polygon_orientation(&mut geom, YAxis::Down);
+        if let Geometry::Polygon(p) = geom {
+            for ring in polygon_into_rings(p).into_iter() {
+                edges.extend(ring.0.windows(2).filter(|s| s[0] != s[1]).map(|s| ((s[0].x(), s[0].y()), (s[1].x(), s[1].y()))));
+            }
+        }
+    }
+
+    // Split edges at any vertex which lies on them, so an edge shared with a polygon which has
+    // extra vertices along it is made of the same pieces in both.
+    let mut vertices: Vec<Point2> = edges.iter().map(|&(a, _)| a).collect();
+    vertices.sort();
+    vertices.dedup();
+    let mut split_edges: Vec<(Point2, Point2)> = Vec::with_capacity(edges.len());
+    for (a, b) in edges.into_iter() {
+        let (xmin, xmax) = (min(a.0, b.0), max(a.0, b.0));
+        let (ymin, ymax) = (min(a.1, b.1), max(a.1, b.1));
+        let start = vertices.binary_search(&(xmin, ymin)).unwrap_or_else(|i| i);
+        let mut on_edge: Vec<Point2> = vertices[start..].iter()
+            .take_while(|v| v.0 <= xmax)
+            .filter(|&&v| v != a && v != b && v.1 >= ymin && v.1 <= ymax)
+            .filter(|&&v| (b.0 as i64 - a.0 as i64)*(v.1 as i64 - a.1 as i64) == (b.1 as i64 - a.1 as i64)*(v.0 as i64 - a.0 as i64))
+            .cloned().collect();
+        on_edge.sort_by_key(|v| (v.0 as i64 - a.0 as i64).abs() + (v.1 as i64 - a.1 as i64).abs());
+        let mut prev = a;
+        for v in on_edge.into_iter().chain(::std::iter::once(b)) {
+            split_edges.push((prev, v));
+            prev = v;
+        }
+    }
+
+    // Shared edges cancel each other out
+    let mut counts: BTreeMap<(Point2, Point2), usize> = BTreeMap::new();
+    for (a, b) in split_edges.into_iter() {
+        let reversed = counts.get(&(b, a)).cloned().unwrap_or(0);
+        if reversed > 0 {
+            counts.insert((b, a), reversed - 1);
+        } else {
+            *counts.entry((a, b)).or_insert(0) += 1;
+        }
+    }
+    let mut outgoing: BTreeMap<Point2, Vec<Point2>> = BTreeMap::new();
+    for (&(a, b), &count) in counts.iter() {
+        for _ in 0..count {
+            outgoing.entry(a).or_insert_with(Vec::new).push(b);
+        }
+    }
+
+    // Every vertex has as many edges going in as out, so following the edges from any vertex
+    // always gets back to it.
+    let mut rings: Vec<LineString<i32>> = Vec::new();
+    loop {
+        let start = match outgoing.iter().find(|&(_, ends)| !ends.is_empty()) {
+            None => { break; },
+            Some((&start, _)) => start,
+        };
+        let mut ring = vec![start];
+        let mut current = start;
+        loop {
+            let next = match outgoing.get_mut(&current).and_then(|ends| ends.pop()) {
+                None => { break; },
+                Some(next) => next,
+            };
+            ring.push(next);
+            current = next;
+            if current == start {
+                break;
+            }
+        }
+
+        // Remove the vertices in the middle of straight lines, e.g. where the removed edges were
+        let mut i = 1;
+        while ring.len() > 4 && i < ring.len() - 1 {
+            if is_straight_through(ring[i-1], ring[i], ring[i+1]) {
+                ring.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        // The start can be in the middle of a straight line too
+        let l = ring.len();
+        if l > 4 && is_straight_through(ring[l-2], ring[0], ring[1]) {
+            ring.pop();
+            ring.remove(0);
+            let first = ring[0];
+            ring.push(first);
+        }
+
+        if ring.len() >= 4 {
+            rings.push(ring.into());
+        }
+    }
+
+    make_rings_valid(rings).unwrap_or_else(|| MultiPolygon(vec![]))
+}
+
+/// Does the line p→q→r go straight on through q (i.e. q isn't needed)?
+fn is_straight_through(p: (i32, i32), q: (i32, i32), r: (i32, i32)) -> bool {
+    let (dx1, dy1) = (q.0 as i64 - p.0 as i64, q.1 as i64 - p.1 as i64);
+    let (dx2, dy2) = (r.0 as i64 - q.0 as i64, r.1 as i64 - q.1 as i64);
+    dx1*dy2 == dy1*dx2 && dx1*dx2 + dy1*dy2 > 0
+}
+
+/// Put these polygons in a fixed order, so the same input always gives the same MultiPolygon,
+/// however the rings were found. Polygons are ordered by the smallest (x, then y) vertex of the
+/// exterior, then by all the exterior's vertices. The interiors of each polygon are ordered the
+/// same way.
+fn sort_polygons_canonically<T: CoordinateType+Ord>(polygons: &mut Vec<Polygon<T>>) {
+    fn key<T: CoordinateType+Ord>(ring: &LineString<T>) -> (Option<(T, T)>, Vec<(T, T)>) {
+        let coords: Vec<(T, T)> = ring.0.iter().map(|pt| (pt.x(), pt.y())).collect();
+        let min_vertex = coords.iter().cloned().min();
+        (min_vertex, coords)
+    }
+    for p in polygons.iter_mut() {
+        p.interiors.sort_by_cached_key(key);
+    }
+    polygons.sort_by_cached_key(|p| key(&p.exterior));
+}
+
+/// True iff any segment of `ring` runs along (i.e. is collinear with, and overlaps for more than a
+/// point) a segment of `other`. Touching at a single point doesn't count.
+fn shares_edge_with(ring: &LineString<i32>, other: &LineString<i32>) -> bool {
+    ring.0.windows(2).any(|seg| {
+        let (a, b) = ((seg[0].x(), seg[0].y()), (seg[1].x(), seg[1].y()));
+        if a == b {
+            return false;
+        }
+        other.0.windows(2).any(|other_seg| {
+            let (c, d) = ((other_seg[0].x(), other_seg[0].y()), (other_seg[1].x(), other_seg[1].y()));
+            if c == d || !collinear(c, d, a) || !collinear(c, d, b) {
+                return false;
+            }
+            // On the same line, so compare them along whichever axis the line isn't flat in
+            let (ab, cd) = if a.0 != b.0 { ((a.0, b.0), (c.0, d.0)) } else { ((a.1, b.1), (c.1, d.1)) };
+            min(max(ab.0, ab.1), max(cd.0, cd.1)) > max(min(ab.0, ab.1), min(cd.0, cd.1))
+        })
+    })
+}
+
+/// The points of `ring`, with any vertices of `other` which lie on its segments added, so that
+/// where the 2 rings run along each other, they have the same segments.
+fn add_points_on_ring(ring: &LineString<i32>, other: &LineString<i32>) -> Vec<(i32, i32)> {
+    let mut points = Vec::with_capacity(ring.0.len());
+    for seg in ring.0.windows(2) {
+        let (a, b) = ((seg[0].x(), seg[0].y()), (seg[1].x(), seg[1].y()));
+        points.push(a);
+        let mut on_segment: Vec<(i32, i32)> = other.0.iter().map(|p| (p.x(), p.y()))
+            .filter(|&p| p != a && p != b && collinear(a, b, p) && point_on_line_incl_end(a, b, p))
+            .collect();
+        on_segment.sort_by_key(|p| ((p.0 - a.0).abs(), (p.1 - a.1).abs()));
+        on_segment.dedup();
+        points.extend(on_segment);
+    }
+    if let Some(last) = ring.0.last() {
+        points.push((last.x(), last.y()));
+    }
+    points
+}
+
+/// Cut `interior` out of `exterior`, when they run along each other for part of their length. The
+/// rings go opposite ways around, so the shared segments cancel out, and the rest are joined up
+/// into the new exterior ring(s). None if the remaining segments don't join up into rings.
+fn merge_interior_into_exterior(exterior: &LineString<i32>, interior: &LineString<i32>) -> Option<Vec<LineString<i32>>> {
+    fn segments(points: &[(i32, i32)]) -> Vec<((i32, i32), (i32, i32))> {
+        points.windows(2).map(|w| (w[0], w[1])).filter(|&(a, b)| a != b).collect()
+    }
+    let ext_segments = segments(&add_points_on_ring(exterior, interior));
+    let int_segments = segments(&add_points_on_ring(interior, exterior));
+
+    let mut ext_index: HashMap<((i32, i32), (i32, i32)), Vec<usize>> = HashMap::new();
+    for (i, &seg) in ext_segments.iter().enumerate() {
+        ext_index.entry(seg).or_insert_with(Vec::new).push(i);
+    }
+    let mut ext_shared = vec![false; ext_segments.len()];
+    let mut int_shared = vec![false; int_segments.len()];
+    for (i, &(a, b)) in int_segments.iter().enumerate() {
+        if let Some(j) = ext_index.get_mut(&(b, a)).and_then(|js| js.pop()) {
+            ext_shared[j] = true;
+            int_shared[i] = true;
+        }
+    }
+
+    let remaining: Vec<((i32, i32), (i32, i32))> = ext_segments.iter().zip(ext_shared.into_iter())
+        .chain(int_segments.iter().zip(int_shared.into_iter()))
+        .filter(|&(_, shared)| !shared)
+        .map(|(&seg, _)| seg)
+        .collect();
+    let mut starting_at: HashMap<(i32, i32), Vec<usize>> = HashMap::new();
+    for (i, &(a, _)) in remaining.iter().enumerate() {
+        starting_at.entry(a).or_insert_with(Vec::new).push(i);
+    }
+
+    let mut used = vec![false; remaining.len()];
+    let mut rings = Vec::new();
+    for start in 0..remaining.len() {
+        if used[start] {
+            continue;
+        }
+        let (first, _) = remaining[start];
+        let mut points = vec![Point::new(first.0, first.1)];
+        let mut current = start;
+        loop {
+            used[current] = true;
+            let (_, end) = remaining[current];
+            points.push(Point::new(end.0, end.1));
+            if end == first {
+                break;
+            }
+            match starting_at.get(&end).and_then(|is| is.iter().cloned().find(|&i| !used[i])) {
+                Some(next) => { current = next; },
+                None => { return None; },
+            }
+        }
+        let ring = LineString(points);
+        if twice_linestring_area(&ring) != 0 {
+            rings.push(ring);
+        }
+    }
+
+    Some(rings)
+}
+
+/// After clipping, an interior can run along part of the exterior, which is drawn as a polygon
+/// with a slit. Cut any such interiors out of the exterior, which can split the polygon into
+/// several, so that each polygon is simple. If that can't be done, the interior is dropped.
+fn merge_coincident_interiors(polygon: Polygon<i32>, y_axis: YAxis) -> Vec<Polygon<i32>> {
+    let Polygon{ exterior, mut interiors } = polygon;
+
+    let mut i = 0;
+    while i < interiors.len() {
+        if !shares_edge_with(&interiors[i], &exterior) {
+            i += 1;
+            continue;
+        }
+
+        let interior = interiors.remove(i);
+        match merge_interior_into_exterior(&exterior, &interior) {
+            Some(ref exteriors) if exteriors.iter().all(|e| has_exterior_orientation(e, y_axis)) => {
+                trace!("merge_coincident_interiors: interior on the exterior, now have {} exterior(s)", exteriors.len());
+                if exteriors.is_empty() {
+                    // The interior was the whole polygon
+                    return vec![];
+                }
+                let mut polygons: Vec<Polygon<i32>> = exteriors.iter().cloned().map(|e| Polygon::new(e, vec![])).collect();
+                let unassigned = distribute_interiors(&mut polygons, interiors);
+                if ! unassigned.is_empty() {
+                    debug!("merge_coincident_interiors: {} interior(s) aren't in any of the new exteriors, dropping them", unassigned.len());
+                }
+                // The other interiors could be on the new exteriors
+                return polygons.into_iter().flat_map(|p| merge_coincident_interiors(p, y_axis)).collect();
+            },
+            _ => {
+                debug!("merge_coincident_interiors: Couldn't cut interior out of the exterior it touches, dropping it\n{}", geom_as_geojson(&Geometry::LineString(interior), 4096.*8.));
+            },
+        }
+    }
+
+    vec![Polygon::new(exterior, interiors)]
+}
+
+/// Where 2 (non-adjacent) segments of this ring cross, but the crossing point is rounded onto a
+/// vertex of one of them, e.g. the bowtie (0,0)-(1,1)-(1,0)-(0,1)-(0,0) 'crosses' at (0,0).
+/// Returns the index of that vertex, and the index of the other segment. Segments before `from`
+/// are only checked against later segments.
+fn rounded_crossing(ls: &LineString<i32>, from: usize) -> Option<(usize, usize)> {
+    if ls.0.len() <= 3 {
+        return None;
+    }
+
+    // Segments whose bboxes don't overlap can't cross, which is much quicker to check
+    let bboxes: Vec<Bbox<i32>> = ls.0.windows(2).map(|w| Bbox{
+        xmin: min(w[0].x(), w[1].x()), xmax: max(w[0].x(), w[1].x()),
+        ymin: min(w[0].y(), w[1].y()), ymax: max(w[0].y(), w[1].y()),
+    }).collect();
+
+    for (i, points12) in ls.0.windows(2).enumerate().skip(from) {
+        let (p1, p2) = (points12[0], points12[1]);
+        if p1 == p2 {
+            continue;
+        }
+        let bbox12 = &bboxes[i];
+        for (j, points34) in ls.0.windows(2).enumerate().skip(i+2) {
+            let bbox34 = &bboxes[j];
+            if bbox12.xmax < bbox34.xmin || bbox34.xmax < bbox12.xmin || bbox12.ymax < bbox34.ymin || bbox34.ymax < bbox12.ymin {
+                continue;
+            }
+            let (p3, p4) = (points34[0], points34[1]);
+            if p3 == p4 {
+                continue;
+            }
+            if let Intersection::Crossing(crosspoint) = intersection(p1.x(), p1.y(), p2.x(), p2.y(), p3.x(), p3.y(), p4.x(), p4.y()) {
+                let crosspoint = Point::new(crosspoint.0, crosspoint.1);
+                if crosspoint == p1 {
+                    return Some((i, j));
+                } else if crosspoint == p2 {
+                    return Some((i+1, j));
+                } else if crosspoint == p3 {
+                    return Some((j, i));
+                } else if crosspoint == p4 {
+                    return Some((j+1, i));
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Split this ring at every vertex which another segment crosses after rounding (see
+/// `rounded_crossing`). The ring really touches itself at that vertex, so it's cut into the 2
+/// rings on either side of it. `add_points_for_all_crossings` won't add a node at a point which
+/// is already a vertex, so `dissolve_into_rings` could be left with a bowtie it can't split.
+/// Rings with no area (3 or fewer points) are dropped.
+fn split_at_rounded_crossings(ring: LineString<i32>) -> Vec<LineString<i32>> {
+    // Each ring, and the first segment which could cross a later one. The segments before the
+    // split point are the same in the outer ring, and have been checked already.
+    let mut rings_to_process = vec![(ring, 0)];
+    let mut results = vec![];
+
+    while let Some((ring, from)) = rings_to_process.pop() {
+        let (vertex, segment) = match rounded_crossing(&ring, from) {
+            None => {
+                results.push(ring);
+                continue;
+            },
+            Some(x) => x,
+        };
+        trace!("split_at_rounded_crossings: splitting ring of {} points at vertex {}, which segment {} crosses", ring.0.len(), vertex, segment);
+
+        let LineString(points) = ring;
+        let point = points[vertex];
+        // `inner` goes from the vertex to the other segment (or vice versa), `outer` is the rest
+        // of the ring, and keeps its start point.
+        let (inner, outer) = if vertex < segment {
+            let mut inner = points[vertex..segment+1].to_vec();
+            inner.push(point);
+            let mut outer = points[..vertex+1].to_vec();
+            outer.extend_from_slice(&points[segment+1..]);
+            (inner, outer)
+        } else {
+            let mut inner = vec![point];
+            inner.extend_from_slice(&points[segment+1..vertex+1]);
+            let mut outer = points[..segment+1].to_vec();
+            outer.extend_from_slice(&points[vertex..]);
+            (inner, outer)
+        };
+
+        if outer.len() > 3 {
+            // The segment before the split point joins the new one, so check from there
+            let from = min(vertex, segment).saturating_sub(1);
+            rings_to_process.push((LineString(outer), from));
+        }
+        if inner.len() > 3 {
+            rings_to_process.push((LineString(inner), 0));
+        }
+    }
+
+    results
+}
 
 /// Modify the LineString, so that at all self-intersection places there is a node. i.e. if 2
 /// segments cross, add a node in the middle of each segment where they cross. After this all
@@ -609,7 +1586,7 @@ fn add_points_for_all_crossings(ls: &mut LineString<i32>) {
     }
 
     loop {
-        let mut coords_to_insert = HashMap::new();
+        let mut coords_to_insert = BTreeMap::new();
         // Keys are the point indexes.
         // Values are a Vec of new points to add after the point with that index.
         // So vec![(0, 0), (1, 0)] for key #3, means to insert those 2 points after ls.0[3]
@@ -707,7 +1684,7 @@ fn add_points_for_all_crossings(ls: &mut LineString<i32>) {
 
             let mut num_new_points_to_insert = 0;
 
-            // Turn hashmap into a sorted vec, sorted by index to add
+            // Turn map into a sorted vec, sorted by index to add
             let coords_to_insert = ls.0.windows(2).enumerate().filter_map(|(idx, points)| {
                 let (point1, point2) = (points[0], points[1]);
                 if let Some(mut new_points) = coords_to_insert.remove(&idx) {
@@ -759,7 +1736,9 @@ fn dissolve_into_rings(ls: LineString<i32>) -> Vec<LineString<i32>> {
     // where this point is the first point of that segment. e.g. (0, 0): [1, 5], means that the
     // line segment [1, 2] starts at point (0,0), i.e. points[1] == (0,0), likewise for segment [5,
     // 6]
-    let mut outgoing_segments = HashMap::with_capacity(points.len());
+    // It's a BTreeMap, so the loops are always in the same order, and the same input always gives
+    // the same rings
+    let mut outgoing_segments = BTreeMap::new();
 
     for (i, p) in points.iter().enumerate() {
         // TODO here we could assert that the existing vec is <=2, and generate the loops vec here,
@@ -931,12 +1910,55 @@ fn does_ray_cross<T: CoordinateType+Debug+Ord>(point: &Point<T>, p1: &Point<T>,
     //unreachable!();
 }
 
+/// Is this point inside this polygon, and not in one of its holes? Uses the same ray crossing
+/// rules as making polygons valid, so it agrees with how rings are nested there. Points on the
+/// boundary (of the exterior, or of a hole) are not inside.
+pub fn point_in_polygon(point: &Point<i32>, polygon: &Polygon<i32>) -> bool {
+    point_in_ring(point, &polygon.exterior) == Some(true)
+        && polygon.interiors.iter().all(|i| point_in_ring(point, i) == Some(false))
+}
+
+/// Is this point inside (`Some(true)`) or outside (`Some(false)`) this ring? `None` if it's on
+/// the ring itself.
+fn point_in_ring(point: &Point<i32>, ring: &LineString<i32>) -> Option<bool> {
+    let p = (point.x(), point.y());
+    let mut num_crossings = 0;
+
+    for segment in ring.0.iter().zip(ring.0.iter().skip(1)) {
+        let (p1, p2) = segment;
+        if p1 == p2 {
+            continue;
+        }
+        let (a, b) = ((p1.x(), p1.y()), (p2.x(), p2.y()));
+        if collinear(a, b, p) && point_on_line_incl_end(a, b, p) || p == b {
+            return None;
+        }
+
+        match does_ray_cross(point, p1, p2) {
+            // As in is_ring_ext_int, the ray going through an end point only counts if the other
+            // end is below.
+            Crossing::Yes | Crossing::OneEndOnOtherBelow => { num_crossings += 1; },
+            // Since the point isn't on this segment, it can only be a horizontal segment, which
+            // the ray runs along. The segments either side of it are what count.
+            Crossing::Touches => {},
+            Crossing::OneEndOnOtherAbove | Crossing::No => {},
+        }
+    }
+
+    Some(num_crossings % 2 == 1)
+}
 
 #[derive(PartialEq,Eq,Debug)]
 enum RingType { Exterior, Interior }
 
+#[cfg(test)]
+thread_local!(static RING_CLASSIFICATIONS: ::std::cell::Cell<usize> = ::std::cell::Cell::new(0));
+
 /// ring is at index `ring_type` in `all_rings`
 fn is_ring_ext_int<T: CoordinateType+Debug+Ord>(ring: &LineString<T>, ring_index: usize, all_rings: &Vec<LineString<T>>) -> RingType {
+    #[cfg(test)]
+    RING_CLASSIFICATIONS.with(|c| c.set(c.get()+1));
+
     trace!("is_ring_ext_int: all_rings.len() {:?} ring_index {:?}", all_rings.len(), ring_index);
     // Do an even/odd check on a point in `ring` on all rings in all_rings. except this one (that's
     // why we need ring_index. If the point is inside, then this is an interior ring, else
@@ -1016,7 +2038,9 @@ fn calc_rings_ext_int<T: CoordinateType+Debug+Ord>(rings: Vec<LineString<T>>) ->
 
 }
 
-/// This will look at what rings are inside other rings.
+/// This will look at what rings are inside other rings, and make them into polygons. The output
+/// is always in the same order for the same rings (whatever order they're in), see
+/// `sort_polygons_canonically`.
 fn convert_rings_to_polygons<T: CoordinateType+Debug+Ord+Into<f64>>(mut rings: Vec<LineString<T>>) -> Option<MultiPolygon<T>> {
     if rings.is_empty() {
         return None;
@@ -1024,11 +2048,52 @@ fn convert_rings_to_polygons<T: CoordinateType+Debug+Ord+Into<f64>>(mut rings: V
     if rings.len() == 1 {
         return Some(MultiPolygon(vec![Polygon::new(rings.remove(0), vec![])]));
     }
+    if rings.len() == 2 {
+        return convert_two_rings_to_polygons(rings);
+    }
+    convert_many_rings_to_polygons(rings)
+}
+
+/// The very common case of 2 rings, e.g. a shell & a hole. A ring can only be inside another if
+/// its bbox is, so usually only one ring needs a point-in-polygon test. The result is the same as
+/// `convert_many_rings_to_polygons`.
+fn convert_two_rings_to_polygons<T: CoordinateType+Debug+Ord+Into<f64>>(mut rings: Vec<LineString<T>>) -> Option<MultiPolygon<T>> {
+    debug_assert_eq!(rings.len(), 2);
+    let (bbox0, bbox1) = match (rings[0].bbox(), rings[1].bbox()) {
+        (Some(b0), Some(b1)) => (b0, b1),
+        _ => { return convert_many_rings_to_polygons(rings); },
+    };
+    let within = |a: &Bbox<T>, b: &Bbox<T>| a.xmin >= b.xmin && a.xmax <= b.xmax && a.ymin >= b.ymin && a.ymax <= b.ymax;
+
+    let exterior_index = if within(&bbox1, &bbox0) && is_ring_ext_int(&rings[1], 1, &rings) == RingType::Interior {
+        Some(0)
+    } else if within(&bbox0, &bbox1) && is_ring_ext_int(&rings[0], 0, &rings) == RingType::Interior {
+        Some(1)
+    } else {
+        None
+    };
+
+    match exterior_index {
+        Some(exterior_index) => {
+            let interior = rings.remove(1 - exterior_index);
+            let exterior = rings.remove(0);
+            Some(MultiPolygon(vec![Polygon::new(exterior, vec![interior])]))
+        },
+        None => {
+            // 2 separate polygons
+            let mut polygons: Vec<_> = rings.into_iter().map(|r| Polygon::new(r, vec![])).collect();
+            sort_polygons_canonically(&mut polygons);
+            Some(MultiPolygon(polygons))
+        },
+    }
+}
+
+/// Classify every ring as an exterior or interior, and put each interior in its exterior.
+fn convert_many_rings_to_polygons<T: CoordinateType+Debug+Ord+Into<f64>>(rings: Vec<LineString<T>>) -> Option<MultiPolygon<T>> {
     trace!("convert_rings_to_polygons: starting with {} rings", rings.len());
 
     let rings_with_type = calc_rings_ext_int(rings);
 
-    // Do a simple case when there are only 2 rings?
     let mut exteriors = Vec::new();
     let mut interiors = Vec::new();
 
@@ -1066,10 +2131,15 @@ fn convert_rings_to_polygons<T: CoordinateType+Debug+Ord+Into<f64>>(mut rings: V
             trace!("exteriors:\n{}", polygons.iter().map(|p| geom_as_geojson(&Geometry::Polygon(p.clone()), 4096.*8.)).collect::<Vec<String>>().join("\n"));
             trace!("interiors:\n{}", interiors.iter().map(|l| geom_as_geojson(&Geometry::LineString(l.clone()), 4096.*8.)).collect::<Vec<String>>().join("\n"));
 
-            distribute_interiors(&mut polygons, interiors);
+            let unassigned = distribute_interiors(&mut polygons, interiors);
+            if ! unassigned.is_empty() {
+                warn!("{} interior polygon(s) can't be allocated to any exterior polygon, dropping them", unassigned.len());
+                trace!("unassigned interiors:\n{}", unassigned.iter().map(|l| geom_as_geojson(&Geometry::LineString(l.clone()), 4096.*8.)).collect::<Vec<String>>().join("\n"));
+            }
         }
     }
 
+    sort_polygons_canonically(&mut polygons);
 
     Some(MultiPolygon(polygons))
 }
@@ -1163,6 +2233,30 @@ fn twice_linestring_area(ls: &LineString<i32>) -> i32 {
     }).sum::<i64>() as i32
 }
 
+/// The area of this (closed) ring, whichever way around it goes.
+pub fn ring_area(ls: &LineString<i32>) -> f64 {
+    let twice_area: i64 = ls.0.windows(2).map(|points| {
+        let (x1, y1) = (points[0].x() as i64, points[0].y() as i64);
+        let (x2, y2) = (points[1].x() as i64, points[1].y() as i64);
+        x1*y2 - x2*y1
+    }).sum();
+    (twice_area as f64).abs() / 2.
+}
+
+/// Remove all the interiors (holes) of the polygons which have an area less than `min_area`.
+/// The exteriors aren't changed, so a valid geometry stays valid.
+pub fn remove_small_interiors(geom: &mut Geometry<i32>, min_area: f64) {
+    fn remove(p: &mut Polygon<i32>, min_area: f64) {
+        p.interiors.retain(|i| ring_area(i) >= min_area);
+    }
+
+    match *geom {
+        Geometry::Polygon(ref mut p) => remove(p, min_area),
+        Geometry::MultiPolygon(ref mut mp) => for p in mp.0.iter_mut() { remove(p, min_area); },
+        _ => {},
+    }
+}
+
 fn is_cw(ls: &LineString<i32>) -> bool {
     twice_linestring_area(ls) < 0
 }
@@ -1175,18 +2269,38 @@ fn bbox_area<T: CoordinateType>(bbox: &Bbox<T>) -> T {
     (bbox.xmax - bbox.xmin)*(bbox.ymax - bbox.ymin)
 }
 
-fn distribute_interiors<T: CoordinateType+Debug+Ord+Into<f64>>(mut polygons: &mut Vec<Polygon<T>>, mut interiors: Vec<LineString<T>>) {
+/// Put each of these interiors into the polygon it's inside. An interior can't be bigger than the
+/// exterior it's in, so if it's bigger (by bbox) than every polygon which contains it, the ring was
+/// misclassified, and it becomes the exterior of a new polygon instead (see
+/// `add_promoted_polygons`). Returns the interiors which aren't in any polygon (and so aren't in
+/// `polygons`), for the caller to deal with.
+fn distribute_interiors<T: CoordinateType+Debug+Ord+Into<f64>>(mut polygons: &mut Vec<Polygon<T>>, mut interiors: Vec<LineString<T>>) -> Vec<LineString<T>> {
     debug!("[distribute_interiors] start. {} polygons {} interiors", polygons.len(), interiors.len());
     debug_assert!(polygons.iter().all(|p| p.interiors.len() == 0), "Invalid argument: polygons should have no interiors already");
     debug_assert!((polygons.is_empty() && interiors.is_empty()) || !polygons.is_empty(), "Invalid argument: Can't specify interiors without also polygons");
     if polygons.is_empty() || interiors.is_empty() {
-        return;
+        return interiors;
     }
     //debug_assert!(interiors.iter().all(|i| is_cw(i))); // figure out which is which
-    
+
+    let ring_bbox_area = |ring: &LineString<T>| ring.bbox().map(|b| bbox_area(&b));
+    let mut promoted: Vec<Polygon<T>> = Vec::new();
+    let promote = |mut interior: LineString<T>, promoted: &mut Vec<Polygon<T>>| {
+        warn!("Interior polygon is bigger than any exterior it could be in, using it as an exterior");
+        // It went the interior way around
+        interior.0.reverse();
+        promoted.push(Polygon::new(interior, vec![]));
+    };
+
     if polygons.len() == 1 {
-        ::std::mem::replace(&mut polygons[0].interiors, interiors);
-        return;
+        let exterior_area = ring_bbox_area(&polygons[0].exterior);
+        let (fits, too_big): (Vec<_>, Vec<_>) = interiors.into_iter().partition(|i| ring_bbox_area(i) <= exterior_area);
+        ::std::mem::replace(&mut polygons[0].interiors, fits);
+        for interior in too_big.into_iter() {
+            promote(interior, &mut promoted);
+        }
+        add_promoted_polygons(polygons, promoted);
+        return Vec::new();
     }
 
     // polygons with the largest bboxes to the front, so that the largest polygon (ie first) that
@@ -1203,32 +2317,180 @@ fn distribute_interiors<T: CoordinateType+Debug+Ord+Into<f64>>(mut polygons: &mu
     let mut polygons_f: Vec<Polygon<f64>> = polygons.iter().map(|p| p.map_coords(&|&(x, y)| (x.into(), y.into()))).collect();
     let mut interiors_f: Vec<LineString<f64>> = interiors.iter().map(|l| l.map_coords(&|&(x, y)| (x.into(), y.into()))).collect();
     
+    let exterior_areas: Vec<_> = polygons.iter().map(|p| ring_bbox_area(&p.exterior)).collect();
+    let mut unassigned: Vec<LineString<T>> = Vec::new();
+
     for (interior_f, interior) in interiors_f.into_iter().zip(interiors.into_iter()) {
+        let interior_area = ring_bbox_area(&interior);
         let mut been_assigned = false;
-        for (polygon_f, polygon) in polygons_f.iter_mut().zip(polygons.iter_mut()) {
+        let mut contained = false;
+        for ((polygon_f, polygon), exterior_area) in polygons_f.iter_mut().zip(polygons.iter_mut()).zip(exterior_areas.iter()) {
             if polygon_f.contains(&interior_f) {
-                polygon.interiors.push(interior);
-                been_assigned = true;
-                break;
+                contained = true;
+                if interior_area <= *exterior_area {
+                    polygon.interiors.push(interior);
+                    been_assigned = true;
+                    break;
+                }
+            }
+        }
+        if been_assigned {
+            continue;
+        }
+        if contained {
+            promote(interior, &mut promoted);
+            continue;
+        }
+
+        // `contains` says no if the interior touches the exterior, e.g. they share an edge
+        // after snapping to the grid. So look again, allowing that.
+        match exterior_for_touching_interior(&polygons_f, &interior_f) {
+            Some(idx) if interior_area <= exterior_areas[idx] => { polygons[idx].interiors.push(interior); },
+            Some(_) => { promote(interior, &mut promoted); },
+            // The polygons are sorted biggest first
+            None if interior_area > exterior_areas[0] => { promote(interior, &mut promoted); },
+            None => { unassigned.push(interior); },
+        }
+    }
+
+    add_promoted_polygons(polygons, promoted);
+    unassigned
+}
+
+/// Add these polygons, made from interiors which were too big to be holes, to `polygons`. Any of
+/// `polygons` inside one of them is really a hole in it, so its exterior becomes an interior of the
+/// new polygon, and its interiors become polygons (islands in that hole), so nothing overlaps.
+fn add_promoted_polygons<T: CoordinateType+Debug+Ord+Into<f64>>(polygons: &mut Vec<Polygon<T>>, promoted: Vec<Polygon<T>>) {
+    for mut promoted in promoted.into_iter() {
+        let promoted_f: Polygon<f64> = promoted.map_coords(&|&(x, y)| (x.into(), y.into()));
+        let mut islands = Vec::new();
+        let mut i = 0;
+        while i < polygons.len() {
+            // The rings don't cross, so if one vertex is strictly inside, it's all inside
+            let inside = polygons[i].exterior.0.iter().any(|p| {
+                let p: Point<f64> = Point::new(p.x().into(), p.y().into());
+                promoted_f.contains(&p)
+            });
+            if ! inside {
+                i += 1;
+                continue;
+            }
+            let Polygon{ mut exterior, interiors } = polygons.remove(i);
+            exterior.0.reverse();
+            promoted.interiors.push(exterior);
+            islands.extend(interiors.into_iter().map(|mut interior| { interior.0.reverse(); Polygon::new(interior, vec![]) }));
+        }
+        polygons.push(promoted);
+        polygons.extend(islands.into_iter());
+    }
+}
+
+/// Which of these polygons should this interior (which touches the exterior boundaries) go in?
+/// The interior must be inside or on the exterior, and at least partly strictly inside. If there
+/// are several, use the one it shares the most boundary with, and then the smallest.
+fn exterior_for_touching_interior(polygons: &[Polygon<f64>], interior: &LineString<f64>) -> Option<usize> {
+    polygons.iter().enumerate().filter_map(|(idx, polygon)| {
+        let exterior = &polygon.exterior;
+        let exterior_poly = Polygon::new(exterior.clone(), vec![]);
+        let mut strictly_inside = false;
+        let mut shared_segments = 0;
+
+        for seg in interior.0.windows(2) {
+            let (p1, p2) = (seg[0], seg[1]);
+            let midpoint = Point::new((p1.x()+p2.x())/2., (p1.y()+p2.y())/2.);
+            for p in [p1, midpoint].iter() {
+                if exterior_poly.contains(p) {
+                    strictly_inside = true;
+                } else if ! exterior.contains(p) {
+                    return None;
+                }
+            }
+            if exterior.contains(&p1) && exterior.contains(&midpoint) && exterior.contains(&p2) {
+                shared_segments += 1;
+            }
+        }
+
+        if strictly_inside {
+            Some((idx, shared_segments, bbox_area(&exterior.bbox().unwrap())))
+        } else {
+            None
+        }
+    })
+    .max_by(|&(_, shared1, area1), &(_, shared2, area2)| shared1.cmp(&shared2).then(area2.partial_cmp(&area1).unwrap_or(Ordering::Equal)))
+    .map(|(idx, _, _)| idx)
+}
+
+/// Something wrong with how the interiors of a polygon are arranged.
+#[derive(Debug,Clone,PartialEq,Eq)]
+enum NestingProblem {
+    /// Interior (polygon index, interior index) is not inside its exterior ring.
+    OutsideExterior(usize, usize),
+
+    /// Interior (polygon index, interior index) is inside another interior (the last index) of the
+    /// same polygon. Holes in holes aren't allowed.
+    InsideInterior(usize, usize, usize),
+}
+
+/// Log (as errors) any interiors of these polygons which are in the wrong place (see
+/// `interior_nesting_problems`).
+fn log_interior_nesting_problems<T: CoordinateType+Debug+Into<f64>>(polygons: &[Polygon<T>]) {
+    for problem in interior_nesting_problems(polygons) {
+        let (poly_idx, int_idx) = match problem {
+            NestingProblem::OutsideExterior(p, i) => (p, i),
+            NestingProblem::InsideInterior(p, i, _) => (p, i),
+        };
+        error!("convert_rings_to_polygons: interior wrongly assigned: {:?}\npolygon:\n{}\ninterior:\n{}",
+               problem,
+               geom_as_geojson(&Geometry::Polygon(Polygon::new(polygons[poly_idx].exterior.clone(), vec![])), 4096.*8.),
+               geom_as_geojson(&Geometry::LineString(polygons[poly_idx].interiors[int_idx].clone()), 4096.*8.));
+    }
+}
+
+/// Check that every interior of these polygons is inside its own exterior, and not inside any of
+/// its sibling interiors. Touching the boundary is OK.
+fn interior_nesting_problems<T: CoordinateType+Into<f64>>(polygons: &[Polygon<T>]) -> Vec<NestingProblem> {
+    // Is `inner` inside `outer`? All the points must be inside or on the outer ring, and at least
+    // one must be strictly inside.
+    fn ring_inside(inner: &LineString<f64>, outer: &LineString<f64>) -> bool {
+        let outer_poly = Polygon::new(outer.clone(), vec![]);
+        let mut one_strictly_inside = false;
+        for p in inner.0.iter() {
+            if outer_poly.contains(p) {
+                one_strictly_inside = true;
+            } else if ! outer.contains(p) {
+                return false;
             }
         }
-        if !been_assigned {
-            warn!("Interior polygon can't be allocated to any exterior polygon");
+        one_strictly_inside
+    }
+
+    let mut problems = Vec::new();
+    for (poly_idx, polygon) in polygons.iter().enumerate() {
+        if polygon.interiors.is_empty() {
+            continue;
+        }
+        let exterior: LineString<f64> = polygon.exterior.map_coords(&|&(x, y)| (x.into(), y.into()));
+        let interiors: Vec<LineString<f64>> = polygon.interiors.iter().map(|l| l.map_coords(&|&(x, y)| (x.into(), y.into()))).collect();
+
+        for (int_idx, interior) in interiors.iter().enumerate() {
+            if ! ring_inside(interior, &exterior) {
+                problems.push(NestingProblem::OutsideExterior(poly_idx, int_idx));
+            }
+            for (other_idx, other) in interiors.iter().enumerate() {
+                if other_idx != int_idx && ring_inside(interior, other) {
+                    problems.push(NestingProblem::InsideInterior(poly_idx, int_idx, other_idx));
+                }
+            }
         }
     }
 
+    problems
 }
 
 /// debug_assert that this geometry is valid, and if invalid, print out information on it.
-/// if None, then does nothing
 #[cfg(debug_assertions)]
-fn debug_assert_valid_geom(geom: &Option<Geometry<i32>>) {
-    let geom = match geom {
-        &None => return,
-        &Some(ref geom) => geom,
-    };
-
-    if !is_valid(&geom) {
+fn debug_assert_valid_geom(geom: &Geometry<i32>, y_axis: YAxis) {
+    if !is_valid_with_y_axis(&geom, y_axis) {
         let geom = geom.clone();
         error!("make_valid trying to return an invalid geometry");
         error!("geometry: {:?}", geom);
@@ -1238,7 +2500,7 @@ fn debug_assert_valid_geom(geom: &Option<Geometry<i32>>) {
         match geom {
             Geometry::MultiPolygon(mp) => {
                 for p in mp.0.into_iter().map(Geometry::Polygon) {
-                    if !is_valid(&p) {
+                    if !is_valid_with_y_axis(&p, y_axis) {
                         error!("invalid polygon in multipolygon:\n{:?}\n{}", p, geom_as_geojson(&p, 4096.*8.));
                     }
                 }
@@ -1252,12 +2514,13 @@ fn debug_assert_valid_geom(geom: &Option<Geometry<i32>>) {
 
 /// Stub
 #[cfg(not(debug_assertions))]
-fn debug_assert_valid_geom(geom: &Option<Geometry<i32>>) {}
+fn debug_assert_valid_geom(geom: &Geometry<i32>, y_axis: YAxis) {}
 
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use quickcheck::{Arbitrary, Gen};
 
     #[test]
     fn intersect1() {
@@ -1268,7 +2531,12 @@ mod test {
         assert_eq!(intersection(0, 0,  0, 10,  0, 0,  0, 10), Intersection::Overlapping((0, 0), (0, 10)));
         assert_eq!(intersection(0, 0,  0, 10,  0, 5,  0, 10), Intersection::Overlapping((0, 5), (0, 10)));
         assert_eq!(intersection(0, 0,  0, 10,  0, 5,  0, 15), Intersection::Overlapping((0, 5), (0, 10)));
-        assert_eq!(intersection(0, 0,  0, 10,  0, 0,  0, 5), Intersection::Overlapping((0, 5), (0, 0)));
+        assert_eq!(intersection(0, 0,  0, 10,  0, 0,  0, 5), Intersection::Overlapping((0, 0), (0, 5)));
+
+        // End points are always sorted, whatever the direction of the segments
+        assert_eq!(intersection(0, 10,  0, 0,  0, 5,  0, 0), Intersection::Overlapping((0, 0), (0, 5)));
+        assert_eq!(intersection(0, 0,  0, 10,  0, 8,  0, 2), Intersection::Overlapping((0, 2), (0, 8)));
+        assert_eq!(intersection(0, 8,  0, 2,  0, 10,  0, 0), Intersection::Overlapping((0, 2), (0, 8)));
         assert_eq!(intersection(0, 0,  0, 10,  0, 2,  0, 8), Intersection::Overlapping((0, 2), (0, 8)));
         assert_eq!(intersection(0,2, 0,8,  0,0, 0,10), Intersection::Overlapping((0, 2), (0, 8)));
 
@@ -1431,12 +2699,46 @@ mod test {
         assert_eq!(intersection(20480, 23619, 24576, 21764, 24576, 21328, 21328, 24576), Intersection::Crossing((23779, 22125)));
     }
 
+    #[test]
+    fn keep_zero_length_lines1() {
+        let line: Geometry<i32> = LineString::from(vec![(5, 5), (5, 5)]).into();
+        for &level in [ValidityLevel::Full, ValidityLevel::SkipExpensive].iter() {
+            assert_eq!(make_valid_lines_at_level(line.clone(), level, false), None);
+            assert_eq!(make_valid_lines_at_level(line.clone(), level, true), Some(line.clone()));
+        }
+        assert_eq!(make_valid(line.clone()), None);
+
+        // More than 2 points are made into 2
+        let line: Geometry<i32> = LineString::from(vec![(5, 5), (5, 5), (5, 5)]).into();
+        assert_eq!(make_valid_lines_at_level(line, ValidityLevel::Full, true), Some(LineString::from(vec![(5, 5), (5, 5)]).into()));
+
+        // In a MultiLineString, the other lines are still made valid
+        let lines: Geometry<i32> = MultiLineString(vec![
+            vec![(0, 0), (0, 0)].into(),
+            vec![(1, 1), (1, 1), (4, 4)].into(),
+        ]).into();
+        assert_eq!(make_valid_lines_at_level(lines.clone(), ValidityLevel::Full, false), Some(MultiLineString(vec![vec![(1, 1), (4, 4)].into()]).into()));
+        assert_eq!(make_valid_lines_at_level(lines, ValidityLevel::Full, true), Some(MultiLineString(vec![
+            vec![(1, 1), (4, 4)].into(),
+            vec![(0, 0), (0, 0)].into(),
+        ]).into()));
+    }
+
     #[test]
     fn validity_checks() {
         let geom: LineString<i32> = LineString(vec![]);
         assert!(!is_linestring_valid(&geom));
 
         assert!(!is_linestring_valid(&LineString(vec![(0i32, 0i32).into()])));
+        assert!(!is_linestring_valid(&LineString(vec![(0i32, 0i32).into(), (0, 0).into()])));
+        assert!(!is_linestring_valid(&LineString(vec![(0i32, 0i32).into(), (0, 0).into(), (0, 0).into()])));
+        assert!(is_linestring_valid(&LineString(vec![(0i32, 0i32).into(), (0, 0).into(), (1, 0).into()])));
+        assert!(is_linestring_valid(&vec![(0, 0), (4, 0)].into()));
+
+        // A line of 3 points in the same place is dropped from a MultiLineString too
+        let mls = MultiLineString(vec![vec![(0, 0), (10, 0)].into(), vec![(5, 5), (5, 5), (5, 5)].into()]);
+        assert!(!is_valid(&mls.clone().into()));
+        assert_eq!(make_valid(mls.into()), Some(Geometry::MultiLineString(MultiLineString(vec![vec![(0, 0), (10, 0)].into()]))));
 
         // Linestrings can self-intersect
         assert!(is_linestring_valid(&vec![(0, 0), (4, 0), (2, -1), (2, 1)].into()));
@@ -1489,6 +2791,33 @@ mod test {
         assert_eq!(new_geom, Polygon::new(vec![a, d, c, b, a].into(), vec![vec![g, h, i, j, g].into()]));
     }
 
+    #[test]
+    fn make_valid_rings1() {
+        // Same as make_valid2
+        // a-----b
+        // | g-h |
+        // e-f | |
+        // | j-i |
+        // d-----c
+        let a = Point::new(0, 0); let b = Point::new(6, 0);
+        let c = Point::new(6, 4); let d = Point::new(0, 4);
+        let e = Point::new(0, 2); let f = Point::new(2, 2);
+        let g = Point::new(2, 1); let h = Point::new(4, 1);
+        let i = Point::new(4, 3); let j = Point::new(2, 3);
+
+        let geom: Geometry<_> = Polygon::new(vec![a, b, c, d, e, f, j, i, h, g, f, e, a].into(), vec![]).into();
+        let rings = make_valid_rings(geom);
+
+        // The shell & the hole
+        assert_eq!(rings.len(), 2);
+        let mut ring_sizes: Vec<_> = rings.iter().map(|r| ring_area(r)).collect();
+        ring_sizes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(ring_sizes, vec![4., 24.]);
+
+        // Points & lines don't have rings
+        assert!(make_valid_rings(Point::new(0, 0).into()).is_empty());
+    }
+
     #[test]
     fn make_valid3() {
         // a-----b
@@ -1570,6 +2899,7 @@ mod test {
 
         let new_mp: MultiPolygon<_> = make_polygon_valid(poly).unwrap();
 
+        // Polygons are in canonical order, the one with g (0, 4), the smallest vertex, first
         assert_eq!(new_mp.0.len(), 2);
         assert_eq!(new_mp.0[0], Polygon::new(vec![d, g, f, e, d].into(), vec![]));
         assert!(is_polygon_valid(&new_mp.0[0]));
@@ -1586,6 +2916,7 @@ mod test {
         assert!(p.is_some());
         let p = p.unwrap();
         assert!(is_valid(&p));
+        // In canonical order, by the smallest vertex: (3026, 3314), (3031, 3316), (3041, 3310)
         assert_eq!(p, Geometry::MultiPolygon(MultiPolygon(vec![
                         Polygon::new(vec![(3033, 3312), (3039, 3304), (3026, 3314), (3033, 3312)].into(), vec![]),
                         Polygon::new(vec![(3041, 3310), (3033, 3312), (3031, 3316), (3041, 3310)].into(), vec![]),
@@ -1655,6 +2986,56 @@ mod test {
                          vec![(3045, 3309), (3044, 3308), (3041, 3310), (3031, 3316), (3033, 3312), (3039, 3304), (3026, 3314), (3033, 3312), (3041, 3310), (3045, 3309)].into() )
     }
 
+    #[test]
+    fn add_points_for_all_crossings_reversed() {
+        // The 'tail' (8,0)-(2,0) overlaps the first segment. Adding the points should give the
+        // same ring, whichever way around it goes.
+        let ring: LineString<i32> = vec![(0, 0), (10, 0), (10, 10), (8, 10), (8, 0), (2, 0), (2, 10), (0, 10), (0, 0)].into();
+
+        let mut forward = ring.clone();
+        add_points_for_all_crossings(&mut forward);
+
+        let mut reversed = LineString(ring.0.iter().rev().cloned().collect());
+        add_points_for_all_crossings(&mut reversed);
+        reversed.0.reverse();
+
+        assert_eq!(forward, reversed);
+        assert_eq!(forward, vec![(0, 0), (2, 0), (8, 0), (10, 0), (10, 10), (8, 10), (8, 0), (2, 0), (2, 10), (0, 10), (0, 0)].into());
+    }
+
+    #[test]
+    fn split_at_rounded_crossings1() {
+        // The diagonals cross at (0.5, 0.5), which is rounded onto (0, 0)
+        let bowtie: LineString<i32> = vec![(0, 0), (1, 1), (1, 0), (0, 1), (0, 0)].into();
+        assert_eq!(rounded_crossing(&bowtie, 0), Some((0, 2)));
+        // Only the segments after the first are checked against each other
+        assert_eq!(rounded_crossing(&bowtie, 1), None);
+        assert_eq!(split_at_rounded_crossings(bowtie), vec![vec![(0, 0), (1, 1), (1, 0), (0, 0)].into()]);
+
+        // No rounding here, so nothing to split
+        let bowtie: LineString<i32> = vec![(0, 0), (10, 10), (10, 0), (0, 10), (0, 0)].into();
+        assert_eq!(rounded_crossing(&bowtie, 0), None);
+        test_no_change_own_vec(split_at_rounded_crossings, bowtie);
+    }
+
+    #[test]
+    fn make_valid_subpixel_bowtie() {
+        // A bowtie whose crossing is less than a pixel from the vertices, in both directions &
+        // starting at different points, is still a (valid) polygon, not dropped
+        for ring in vec![
+            vec![(0, 0), (1, 1), (1, 0), (0, 1), (0, 0)],
+            vec![(0, 0), (0, 1), (1, 0), (1, 1), (0, 0)],
+            vec![(1, 0), (0, 1), (0, 0), (1, 1), (1, 0)],
+            vec![(1, 1), (0, 0), (0, 1), (1, 0), (1, 1)],
+        ] {
+            let bowtie: Geometry<i32> = Polygon::new(ring.clone().into(), vec![]).into();
+            match make_valid_outcome(bowtie) {
+                MakeValidOutcome::Valid(g) => assert!(is_valid(&g), "{:?} made into invalid {:?}", ring, g),
+                x => panic!("{:?} gave unexpected outcome {:?}", ring, x),
+            }
+        }
+    }
+
     #[test]
     fn dissolve_into_rings1() {
         test_no_change_own_vec(dissolve_into_rings, vec![(0, 0), (0, 1), (1, 1), (1, 0), (0, 0)].into());
@@ -1725,6 +3106,23 @@ mod test {
         assert_eq!(result[1], vec![a, b, e, f, c, a].into());
     }
 
+    #[test]
+    fn dissolve_into_rings_deterministic() {
+        let a = Point::new(0, 0); let b = Point::new(6, 0);
+        let c = Point::new(6, 4); let d = Point::new(0, 4);
+        let e = Point::new(0, 2); let f = Point::new(2, 2);
+        let g = Point::new(2, 1); let h = Point::new(4, 1);
+        let i = Point::new(4, 3); let j = Point::new(2, 3);
+
+        // Several points where the ring touches itself, so several loops
+        let ls = LineString(vec![a, b, c, i, h, c, d, e, f, g, f, j, f, e, a]);
+        let expected = dissolve_into_rings(ls.clone());
+        assert!(expected.len() > 1);
+        for _ in 0..100 {
+            assert_eq!(dissolve_into_rings(ls.clone()), expected);
+        }
+    }
+
     #[test]
     fn dissolve_into_rings4() {
         // a-----b
@@ -1929,7 +3327,56 @@ mod test {
         let inner: LineString<_> = vec![g, h, i, j, f, g].into();
         let rings = vec![ outer.clone(), inner.clone() ];
 
-        assert_eq!(convert_rings_to_polygons(rings), Some(MultiPolygon(vec![Polygon::new(outer, vec![inner])])));
+        assert_eq!(convert_rings_to_polygons(rings), Some(MultiPolygon(vec![Polygon::new(outer.clone(), vec![inner.clone()])])));
+        // The order of the rings doesn't matter
+        assert_eq!(convert_rings_to_polygons(vec![inner.clone(), outer.clone()]), Some(MultiPolygon(vec![Polygon::new(outer.clone(), vec![inner.clone()])])));
+
+        // Polygons are ordered by their smallest vertex
+        let k = Point::new(10, 0); let l = Point::new(12, 0); let m = Point::new(12, 2);
+        let other: LineString<_> = vec![k, l, m, k].into();
+        let expected = Some(MultiPolygon(vec![Polygon::new(outer.clone(), vec![inner.clone()]), Polygon::new(other.clone(), vec![])]));
+        assert_eq!(convert_rings_to_polygons(vec![other.clone(), inner.clone(), outer.clone()]), expected);
+        assert_eq!(convert_rings_to_polygons(vec![inner.clone(), other.clone(), outer.clone()]), expected);
+    }
+
+    #[test]
+    fn convert_two_rings_to_polygons1() {
+        fn ring_classifications() -> usize {
+            RING_CLASSIFICATIONS.with(|c| c.get())
+        }
+        fn square(x: i32, y: i32, size: i32) -> LineString<i32> {
+            vec![(x, y), (x, y+size), (x+size, y+size), (x+size, y), (x, y)].into()
+        }
+
+        // Donuts (with the hole first or last), squares side by side, and squares which touch
+        let mut inputs = Vec::new();
+        for i in 0..1000 {
+            let outer = square(i, 0, 100);
+            let hole = square(i+10+(i%50), 10, 20);
+            inputs.push(vec![outer.clone(), hole.clone()]);
+            inputs.push(vec![hole, outer.clone()]);
+            inputs.push(vec![outer.clone(), square(i+200, 50, 30)]);
+            inputs.push(vec![square(i+100, 0, 100), outer]);
+        }
+
+        let before = ring_classifications();
+        let fast: Vec<_> = inputs.iter().map(|rings| convert_two_rings_to_polygons(rings.clone())).collect();
+        let fast_classifications = ring_classifications() - before;
+
+        let before = ring_classifications();
+        let general: Vec<_> = inputs.iter().map(|rings| convert_many_rings_to_polygons(rings.clone())).collect();
+        let general_classifications = ring_classifications() - before;
+
+        assert_eq!(fast, general);
+        assert_eq!(fast[0], Some(MultiPolygon(vec![Polygon::new(square(0, 0, 100), vec![square(10, 10, 20)])])));
+        assert_eq!(fast[1], fast[0]);
+        assert_eq!(fast[2].as_ref().unwrap().0.len(), 2);
+        assert_eq!(fast[3].as_ref().unwrap().0.len(), 2);
+
+        // At most one point-in-polygon test per donut, and none for rings whose bboxes don't
+        // overlap, where the general path classifies every ring
+        assert_eq!(general_classifications, 2*inputs.len());
+        assert!(fast_classifications <= inputs.len() / 2, "{} ring classifications", fast_classifications);
     }
 
     #[test]
@@ -1947,8 +3394,10 @@ mod test {
         let inner1: LineString<_> = vec![f, g, h, f].into();
         let inner2: LineString<_> = vec![e, f, i, e].into();
         let outer: LineString<_> = vec![a, b, c, d, a].into();
-        let rings = vec![outer.clone(), inner1.clone(), inner2.clone()];
-        assert_eq!(convert_rings_to_polygons(rings), Some(MultiPolygon(vec![Polygon::new(outer, vec![inner1, inner2])])));
+        // Interiors are ordered by their smallest vertex, e is before f
+        let expected = Some(MultiPolygon(vec![Polygon::new(outer.clone(), vec![inner2.clone(), inner1.clone()])]));
+        assert_eq!(convert_rings_to_polygons(vec![outer.clone(), inner1.clone(), inner2.clone()]), expected);
+        assert_eq!(convert_rings_to_polygons(vec![inner2.clone(), outer.clone(), inner1.clone()]), expected);
     }
 
     #[test]
@@ -2137,6 +3586,28 @@ mod test {
         assert_eq!(order_points( ((0,0), (10, 0)), (10, 0), (5, 0) ), Ordering::Greater );
     }
 
+    #[test]
+    fn make_valid_outcome1() {
+        // A good polygon is returned as is
+        let square: Geometry<i32> = Polygon::new(vec![(0, 0), (0, 10), (10, 10), (10, 0), (0, 0)].into(), vec![]).into();
+        assert_eq!(make_valid_outcome(square.clone()), MakeValidOutcome::Valid(square.clone()));
+        assert_eq!(make_valid(square.clone()), Some(square));
+
+        // A sliver with no area collapses to nothing, which isn't a failure
+        let sliver: Geometry<i32> = Polygon::new(vec![(0, 0), (10, 0), (20, 0), (0, 0)].into(), vec![]).into();
+        assert_eq!(make_valid_outcome(sliver.clone()), MakeValidOutcome::EmptyAfterRepair);
+        assert_eq!(make_valid(sliver), None);
+
+        // A bowtie is repaired into 2 triangles
+        let bowtie: Geometry<i32> = Polygon::new(vec![(0, 0), (10, 10), (10, 0), (0, 10), (0, 0)].into(), vec![]).into();
+        match make_valid_outcome(bowtie) {
+            MakeValidOutcome::Valid(Geometry::MultiPolygon(mp)) => assert_eq!(mp.0.len(), 2),
+            x => panic!("Unexpected outcome {:?}", x),
+        }
+
+        assert_eq!(MakeValidOutcome::Unrepairable.into_option(), None);
+    }
+
     #[test]
     fn order_points2() {
         assert_eq!(order_points( ((29147, 10518), (17365, 10520)), (-16552, 10518), (-4238, 10518) ), Ordering::Equal );
@@ -2153,16 +3624,274 @@ mod test {
         let rings = vec![line];
         let valid = make_rings_valid(rings).unwrap();
         assert_eq!(valid.0.len(), 2);
-        assert_eq!(valid.0[0].exterior, vec![b, c, d, e, b].into());
+        // In canonical order, a is the smallest vertex
+        assert_eq!(valid.0[0].exterior, vec![a, h, g, b, a].into());
         assert_eq!(valid.0[0].interiors, vec![]);
 
-        assert_eq!(valid.0[1].exterior, vec![a, h, g, b, a].into());
+        assert_eq!(valid.0[1].exterior, vec![b, c, d, e, b].into());
         assert_eq!(valid.0[1].interiors, vec![]);
     }
 
+    #[test]
+    fn make_rings_valid_vertex_budget() {
+        // A figure of eight (which crosses itself in the middle), with 4000 points, which wobble
+        // by a unit
+        let points: Vec<(i32, i32)> = (0..4000).map(|i| {
+            let t = (i as f64) / 4000. * 2. * ::std::f64::consts::PI;
+            let x = 2000. + 1500. * t.cos();
+            let y = 2000. + 1500. * t.sin() * t.cos();
+            (x.round() as i32 + (i % 2), y.round() as i32)
+        }).collect();
+        let mut ring: LineString<i32> = points.into();
+        let first = ring.0[0];
+        ring.0.push(first);
+        assert!(!is_valid(&Geometry::Polygon(Polygon::new(ring.clone(), vec![]))));
+
+        let simplified = simplify::simplify_ring_to_max_vertices(ring.clone(), 200);
+        assert!(simplified.0.len() <= 200 && simplified.0.len() >= 4, "{}", simplified.0.len());
+        assert_eq!(simplified.0[0], simplified.0[simplified.0.len()-1]);
+
+        // The ring is simplified before the crossings are found, so that's done on <= 200 points
+        let dissolved = dissolve_rings(vec![ring.clone()], Some(200));
+        let num_points: usize = dissolved.iter().map(|r| r.0.len()).sum();
+        assert!(num_points <= 200 + 4, "{}", num_points);
+
+        let result = match make_rings_valid_outcome(vec![ring], &ValidityOptions{ vertex_budget: Some(200), ..ValidityOptions::default() }) {
+            MakeValidOutcome::Valid(g) => g,
+            x => panic!("Unexpected outcome {:?}", x),
+        };
+        assert!(is_valid(&result));
+        match result {
+            // Each loop of the 8
+            Geometry::MultiPolygon(ref mp) => {
+                assert_eq!(mp.0.len(), 2);
+                let num_points: usize = mp.0.iter().map(|p| p.exterior.0.len()).sum();
+                assert!(num_points <= 200 + 4, "{}", num_points);
+            },
+            ref x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn ring_vertex_counts1() {
+        let square: LineString<_> = vec![(0, 0), (0, 1), (1, 1), (1, 0), (0, 0)].into();
+        let triangle: LineString<_> = vec![(0, 0), (0, 1), (1, 1), (0, 0)].into();
+        assert_eq!(ring_vertex_counts(&[]), Vec::<usize>::new());
+        assert_eq!(ring_vertex_counts(&[square.clone(), triangle.clone()]), vec![4, 5]);
+        assert_eq!(ring_vertex_counts(&[triangle, square]), vec![4, 5]);
+    }
+
+    #[test]
+    fn make_valid_terminates() {
+        // Lots of small, spiky, self-intersecting rings in a small grid are the sort of input
+        // where rounding the intersection points can make make_rings_valid go around in circles.
+        // Whatever happens, it must finish, and either return a valid geometry or nothing.
+        let mut seed: u32 = 614;
+        let mut next = || {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            ((seed >> 16) % 20) as i32
+        };
+
+        for _ in 0..200 {
+            let num_points = 4 + (next() % 6) as usize;
+            let mut points: Vec<(i32, i32)> = (0..num_points).map(|_| (next(), next())).collect();
+            let first = points[0];
+            points.push(first);
+            let geom: Geometry<_> = Polygon::new(points.clone().into(), vec![]).into();
+
+            if let Some(g) = make_valid(geom) {
+                assert!(is_valid(&g), "input {:?} gave invalid output {:?}", points, g);
+            }
+        }
+    }
+
+    #[test]
+    fn make_valid_within1() {
+        // A square in the tile, with a long tail going way outside the tile, which crosses itself
+        // out there.
+        //
+        // a---b
+        // |   |      g-f
+        // |   |       X
+        // |   c------h-e
+        // d---------/
+        let a = Point::new(0, 0); let b = Point::new(100, 0);
+        let c = Point::new(100, 100); let d = Point::new(0, 110);
+        let e = Point::new(10100, 100); let f = Point::new(10000, 0);
+        let g = Point::new(10100, 0); let h = Point::new(10000, 100);
+        let geom: Geometry<_> = Polygon::new(vec![a, d, h, g, f, e, c, b, a].into(), vec![]).into();
+        assert!(!is_valid(&geom));
+
+        let bbox = Bbox{ xmin: -64, xmax: 4096+64, ymin: -64, ymax: 4096+64 };
+        let result = make_valid_within(geom, &bbox, &ValidityOptions::default()).unwrap();
+        assert!(is_valid(&result));
+
+        // Nothing is outside the buffered box
+        let result_bbox = geometry_bbox(&result).unwrap();
+        assert!(result_bbox.xmin >= bbox.xmin && result_bbox.xmax <= bbox.xmax);
+        assert!(result_bbox.ymin >= bbox.ymin && result_bbox.ymax <= bbox.ymax);
+
+        // Geometries inside the box are the same as make_valid
+        let p: Geometry<_> = Polygon::new(vec![(3045, 3309), (3044, 3308), (3031, 3316), (3039, 3304), (3026, 3314), (3045, 3309)].into(), vec![]).into();
+        assert_eq!(make_valid_within(p.clone(), &bbox, &ValidityOptions::default()), make_valid(p));
+    }
+
+    #[test]
+    fn make_valid_in_extent1() {
+        // A bowtie over the right edge of the tile. It's made into 2 triangles, meeting at (4100,
+        // 100), and the right one is entirely outside.
+        // a   c
+        // |\ /|
+        // | X |
+        // |/ \|
+        // d   b
+        let geom: Geometry<i32> = Polygon::new(vec![(4000, 0), (4200, 200), (4200, 0), (4000, 200), (4000, 0)].into(), vec![]).into();
+        match make_valid(geom.clone()) {
+            Some(Geometry::MultiPolygon(ref mp)) => assert_eq!(mp.0.len(), 2),
+            x => panic!("Wrong result {:?}", x),
+        }
+
+        let result = make_valid_in_extent(geom.clone(), 0, 4096).unwrap();
+        assert!(is_valid(&result));
+        let polygons = match result {
+            Geometry::MultiPolygon(mp) => mp.0,
+            x => panic!("Wrong result {:?}", x),
+        };
+        assert_eq!(polygons.len(), 1);
+        let result_bbox = geometry_bbox(&Geometry::Polygon(polygons[0].clone())).unwrap();
+        assert_eq!((result_bbox.xmin, result_bbox.xmax), (4000, 4100));
+
+        // Both are kept if they're both (partly) inside, and nothing if neither are
+        assert_eq!(make_valid_in_extent(geom.clone(), 0, 4150), make_valid(geom.clone()));
+        assert_eq!(make_valid_in_extent(geom, 0, 1000), None);
+    }
+
+    #[test]
+    fn convex_fast_path() {
+        fn convex_fast_paths() -> usize {
+            CONVEX_FAST_PATHS.with(|c| c.get())
+        }
+        fn self_intersection_checks() -> usize {
+            SELF_INTERSECTION_CHECKS.with(|c| c.get())
+        }
+
+        let square: Geometry<_> = Polygon::new(vec![(0, 0), (0, 20), (20, 20), (20, 0), (0, 0)].into(), vec![]).into();
+        assert!(is_valid(&square));
+        let before = (convex_fast_paths(), self_intersection_checks());
+        assert_eq!(make_valid(square.clone()), Some(square.clone()));
+        assert_eq!((convex_fast_paths(), self_intersection_checks()), (before.0 + 1, before.1));
+
+        // An L shape is valid, but not convex
+        let concave: Geometry<_> = Polygon::new(vec![(0, 0), (0, 20), (10, 20), (10, 10), (20, 10), (20, 0), (0, 0)].into(), vec![]).into();
+        assert!(is_valid(&concave));
+        let before = convex_fast_paths();
+        assert_eq!(make_valid(concave.clone()), Some(concave.clone()));
+        assert_eq!(convex_fast_paths(), before);
+
+        let not_convex = |points: Vec<(i32, i32)>| ! is_small_convex_polygon(&Polygon::new(points.into(), vec![]), YAxis::Down);
+        // The wrong way around
+        assert!(not_convex(vec![(0, 0), (20, 0), (20, 20), (0, 20), (0, 0)]));
+        // A collinear point, which make_valid would remove
+        assert!(not_convex(vec![(0, 0), (0, 10), (0, 20), (20, 20), (20, 0), (0, 0)]));
+        // A duplicate point
+        assert!(not_convex(vec![(0, 0), (0, 20), (0, 20), (20, 20), (20, 0), (0, 0)]));
+        // A pentagram turns the same way at every corner, but crosses itself
+        assert!(not_convex(vec![(0, 0), (20, 60), (40, 0), (-10, 40), (50, 40), (0, 0)]));
+        assert!(not_convex(vec![(0, 0), (50, 40), (-10, 40), (40, 0), (20, 60), (0, 0)]));
+        // A hole
+        assert!(! is_small_convex_polygon(&Polygon::new(vec![(0, 0), (0, 20), (20, 20), (20, 0), (0, 0)].into(), vec![vec![(5, 5), (10, 5), (10, 10), (5, 10), (5, 5)].into()]), YAxis::Down));
+    }
+
+    #[test]
+    fn validity_level_skip_expensive() {
+        fn self_intersection_checks() -> usize {
+            SELF_INTERSECTION_CHECKS.with(|c| c.get())
+        }
+
+        // A valid polygon with enough points that the self-intersection check does something
+        let square: Geometry<_> = Polygon::new(vec![(0, 0), (0, 10), (0, 20), (20, 20), (20, 0), (0, 0)].into(), vec![]).into();
+
+        let before = self_intersection_checks();
+        assert!(make_valid_at_level(square.clone(), ValidityLevel::Full).is_some());
+        assert!(self_intersection_checks() > before);
+
+        let before = self_intersection_checks();
+        assert!(make_valid_at_level(square.clone(), ValidityLevel::SkipExpensive).is_some());
+        assert!(is_valid_at_level(&square, ValidityLevel::SkipExpensive, YAxis::Down));
+        assert_eq!(self_intersection_checks(), before);
+
+        // Unclosed rings are still caught
+        let unclosed = Polygon::new(vec![(0, 0), (0, 10), (0, 20), (20, 20), (20, 0)].into(), vec![]).into();
+        assert!(!is_valid_at_level(&unclosed, ValidityLevel::SkipExpensive, YAxis::Down));
+        assert!(is_valid_at_level(&unclosed, ValidityLevel::None, YAxis::Down));
+        assert_eq!(make_valid_at_level(unclosed.clone(), ValidityLevel::None), Some(unclosed));
+    }
+
+    #[test]
+    fn remove_small_interiors1() {
+        let exterior: LineString<i32> = vec![(0, 0), (0, 100), (100, 100), (100, 0), (0, 0)].into();
+        let real_hole: LineString<i32> = vec![(10, 10), (20, 10), (20, 20), (10, 20), (10, 10)].into();
+        let tiny_hole: LineString<i32> = vec![(50, 50), (51, 50), (51, 51), (50, 51), (50, 50)].into();
+        assert_eq!(ring_area(&real_hole), 100.);
+        assert_eq!(ring_area(&tiny_hole), 1.);
+        assert_eq!(ring_area(&LineString(tiny_hole.0.iter().rev().cloned().collect())), 1.);
+
+        let mut geom: Geometry<i32> = Polygon::new(exterior.clone(), vec![real_hole.clone(), tiny_hole.clone()]).into();
+        remove_small_interiors(&mut geom, 4.);
+        assert_eq!(geom, Polygon::new(exterior.clone(), vec![real_hole.clone()]).into());
+
+        let mut geom: Geometry<i32> = MultiPolygon(vec![Polygon::new(exterior.clone(), vec![real_hole.clone(), tiny_hole.clone()])]).into();
+        remove_small_interiors(&mut geom, 1000.);
+        assert_eq!(geom, MultiPolygon(vec![Polygon::new(exterior.clone(), vec![])]).into());
+    }
+
+    #[test]
+    fn validity_level_from_str() {
+        assert_eq!("full".parse::<ValidityLevel>().unwrap(), ValidityLevel::Full);
+        assert_eq!("skip-expensive".parse::<ValidityLevel>().unwrap(), ValidityLevel::SkipExpensive);
+        assert_eq!("none".parse::<ValidityLevel>().unwrap(), ValidityLevel::None);
+        assert!("fast".parse::<ValidityLevel>().is_err());
+    }
+
+    #[test]
+    fn interior_nesting_problems1() {
+        // a-----------b
+        // | e-----f   |
+        // | | i-j |   |
+        // | | l-k |   |
+        // | h-----g   |
+        // d-----------c
+        let a = Point::new(0, 0); let b = Point::new(12, 0);
+        let c = Point::new(12, 10); let d = Point::new(0, 10);
+        let e = Point::new(2, 2); let f = Point::new(8, 2);
+        let g = Point::new(8, 8); let h = Point::new(2, 8);
+        let i = Point::new(4, 4); let j = Point::new(6, 4);
+        let k = Point::new(6, 6); let l = Point::new(4, 6);
+
+        let outer: LineString<_> = vec![a, d, c, b, a].into();
+        let hole: LineString<_> = vec![e, f, g, h, e].into();
+        let hole_in_hole: LineString<_> = vec![i, j, k, l, i].into();
+
+        // One hole is fine
+        assert_eq!(interior_nesting_problems(&[Polygon::new(outer.clone(), vec![hole.clone()])]), Vec::<NestingProblem>::new());
+
+        // The second hole is inside the first
+        assert_eq!(interior_nesting_problems(&[Polygon::new(outer.clone(), vec![hole.clone(), hole_in_hole.clone()])]),
+                   vec![NestingProblem::InsideInterior(0, 1, 0)]);
+
+        // A hole which is in a different polygon
+        let m = Point::new(20, 0); let n = Point::new(30, 0);
+        let o = Point::new(30, 10); let p = Point::new(20, 10);
+        let other_outer: LineString<_> = vec![m, p, o, n, m].into();
+        assert_eq!(interior_nesting_problems(&[Polygon::new(outer.clone(), vec![]), Polygon::new(other_outer, vec![hole.clone()])]),
+                   vec![NestingProblem::OutsideExterior(1, 0)]);
+    }
+
     #[test]
     fn distribute_interiors1() {
-        assert_eq!(distribute_interiors::<i32>(Vec::new(), Vec::new()), Vec::new());
+        let mut polygons: Vec<Polygon<i32>> = Vec::new();
+        assert_eq!(distribute_interiors(&mut polygons, Vec::new()), Vec::new());
+        assert_eq!(polygons, Vec::new());
         
         // a-----b
         // | g-h |
@@ -2179,7 +3908,162 @@ mod test {
         let unit_square: LineString<_> = vec![a, b, c, d, a].into();
         let inner_square: LineString<_> = vec![g, h, i, j, g].into();
 
-        assert_eq!(distribute_interiors::<i32>(vec![Polygon::new(unit_square.clone(), vec![])], vec![]), vec![Polygon::new(unit_square.clone(), vec![])]);
+        let mut polygons = vec![Polygon::new(unit_square.clone(), vec![])];
+        assert_eq!(distribute_interiors(&mut polygons, vec![]), Vec::new());
+        assert_eq!(polygons, vec![Polygon::new(unit_square.clone(), vec![])]);
+
+        let mut polygons = vec![Polygon::new(unit_square.clone(), vec![])];
+        assert_eq!(distribute_interiors(&mut polygons, vec![inner_square.clone()]), Vec::new());
+        assert_eq!(polygons, vec![Polygon::new(unit_square.clone(), vec![inner_square.clone()])]);
+    }
+
+    #[test]
+    fn distribute_interiors_unassigned() {
+        // The interior i-j-k-l isn't in either exterior (and is smaller than both), so it's
+        // returned, rather than lost
+        // a-b  e-f
+        // | |  | |  i-j
+        // d-c  h-g  l-k
+        let a = Point::new(0, 0); let b = Point::new(10, 0);
+        let c = Point::new(10, 10); let d = Point::new(0, 10);
+        let e = Point::new(20, 0); let f = Point::new(30, 0);
+        let g = Point::new(30, 10); let h = Point::new(20, 10);
+        let i = Point::new(40, 2); let j = Point::new(44, 2);
+        let k = Point::new(44, 6); let l = Point::new(40, 6);
+
+        let square: LineString<i32> = vec![a, d, c, b, a].into();
+        let other_square: LineString<i32> = vec![e, h, g, f, e].into();
+        let interior: LineString<i32> = vec![i, j, k, l, i].into();
+
+        let mut polygons = vec![Polygon::new(square.clone(), vec![]), Polygon::new(other_square.clone(), vec![])];
+        assert_eq!(distribute_interiors(&mut polygons, vec![interior.clone()]), vec![interior]);
+        assert_eq!(polygons.len(), 2);
+        assert!(polygons.iter().all(|p| p.interiors.is_empty()));
+    }
+
+    #[test]
+    fn distribute_interiors_touching_edge() {
+        // a-----b  i-j
+        // e     |  | |
+        // |\    |  l-k
+        // | g   |
+        // |/    |
+        // f     |
+        // d-----c
+        // The interior e-g-f shares part of the edge a-d
+        let a = Point::new(0, 0); let b = Point::new(10, 0);
+        let c = Point::new(10, 10); let d = Point::new(0, 10);
+        let e = Point::new(0, 2); let f = Point::new(0, 8); let g = Point::new(5, 5);
+        let i = Point::new(20, 0); let j = Point::new(30, 0);
+        let k = Point::new(30, 10); let l = Point::new(20, 10);
+
+        let square: LineString<i32> = vec![a, d, c, b, a].into();
+        let other_square: LineString<i32> = vec![i, l, k, j, i].into();
+        let interior: LineString<i32> = vec![e, g, f, e].into();
+
+        let mut polygons = vec![Polygon::new(other_square.clone(), vec![]), Polygon::new(square.clone(), vec![])];
+        distribute_interiors(&mut polygons, vec![interior.clone()]);
+
+        let with_square = polygons.iter().find(|p| p.exterior == square).unwrap();
+        assert_eq!(with_square.interiors, vec![interior]);
+        let with_other = polygons.iter().find(|p| p.exterior == other_square).unwrap();
+        assert!(with_other.interiors.is_empty());
+    }
+
+    #[test]
+    fn distribute_interiors_bigger_than_exterior() {
+        // A ring which was wrongly classified as an interior, and is bigger than the only
+        // exterior, becomes an exterior, rather than a hole in the smaller polygon. The smaller
+        // polygon is inside it, so is a hole in it.
+        let reversed = |ring: &LineString<i32>| { let mut ring = ring.clone(); ring.0.reverse(); ring };
+        let small: LineString<i32> = vec![(0, 0), (0, 10), (10, 10), (10, 0), (0, 0)].into();
+        let big_interior: LineString<i32> = vec![(-10, -10), (30, -10), (30, 30), (-10, 30), (-10, -10)].into();
+
+        let mut polygons = vec![Polygon::new(small.clone(), vec![])];
+        distribute_interiors(&mut polygons, vec![big_interior.clone()]);
+        assert_eq!(polygons, vec![Polygon::new(reversed(&big_interior), vec![reversed(&small)])]);
+        assert!(is_valid(&Geometry::MultiPolygon(MultiPolygon(polygons))));
+
+        // Same with several exteriors, which it contains rather than being in. The hole in one
+        // of them is an island in the new polygon's hole.
+        let other: LineString<i32> = vec![(15, 15), (15, 20), (20, 20), (20, 15), (15, 15)].into();
+        let hole: LineString<i32> = vec![(2, 2), (8, 2), (8, 8), (2, 8), (2, 2)].into();
+        let mut polygons = vec![Polygon::new(small.clone(), vec![]), Polygon::new(other.clone(), vec![])];
+        distribute_interiors(&mut polygons, vec![big_interior.clone(), hole.clone()]);
+        assert_eq!(polygons, vec![
+            Polygon::new(reversed(&big_interior), vec![reversed(&small), reversed(&other)]),
+            Polygon::new(reversed(&hole), vec![]),
+        ]);
+        assert!(is_valid(&Geometry::MultiPolygon(MultiPolygon(polygons))));
+    }
+
+    #[test]
+    fn y_axis_up() {
+        // A standard polygon with y up (e.g. GeoJSON), exterior anticlockwise & hole clockwise
+        let exterior: LineString<i32> = vec![(0, 0), (10, 0), (10, 10), (0, 10), (0, 0)].into();
+        let hole: LineString<i32> = vec![(2, 2), (2, 4), (4, 4), (4, 2), (2, 2)].into();
+        let polygon: Geometry<i32> = Polygon::new(exterior, vec![hole]).into();
+        assert!(is_valid_with_y_axis(&polygon, YAxis::Up));
+        assert!(!is_valid_with_y_axis(&polygon, YAxis::Down));
+
+        // Each call says which way y goes, so it's left alone for y up, while the (y down)
+        // default still fixes it
+        let up = ValidityOptions{ y_axis: YAxis::Up, ..ValidityOptions::default() };
+        assert_eq!(make_valid_with_options(polygon.clone(), &up), Some(polygon.clone()));
+        assert!(is_valid(&make_valid(polygon.clone()).unwrap()));
+
+        // Flipping it for y down, and back again
+        let mut flipped = polygon.clone();
+        ensure_polygon_orientation(&mut flipped, YAxis::Down);
+        assert_ne!(flipped, polygon);
+        assert!(is_valid_with_y_axis(&flipped, YAxis::Down));
+        assert!(!is_valid_with_y_axis(&flipped, YAxis::Up));
+        ensure_polygon_orientation(&mut flipped, YAxis::Up);
+        assert_eq!(flipped, polygon);
+    }
+
+    #[test]
+    fn interior_on_exterior_edge() {
+        // a-----------b
+        // e---f       |
+        // |   |       |
+        // h---g       |
+        // d-----------c
+        // The hole e-f-g-h's edge h-e lies on the shell's edge a-d
+        let shell: LineString<i32> = vec![(0, 0), (0, 10), (10, 10), (10, 0), (0, 0)].into();
+        let hole: LineString<i32> = vec![(0, 2), (4, 2), (4, 6), (0, 6), (0, 2)].into();
+        let polygon = Polygon::new(shell.clone(), vec![hole.clone()]);
+        assert!(shares_edge_with(&hole, &shell));
+        assert!(!is_polygon_valid(&polygon));
+
+        let result = make_valid(polygon.into()).unwrap();
+        let polygons = match result {
+            Geometry::MultiPolygon(mp) => mp.0,
+            _ => unreachable!(),
+        };
+        assert_eq!(polygons.len(), 1);
+        assert!(polygons[0].interiors.is_empty());
+        assert!(is_polygon_valid(&polygons[0]));
+        assert_eq!(ring_area(&polygons[0].exterior), 100. - 16.);
+
+        // The hole is now a notch in the exterior, which doesn't go along the old shared edge
+        let exterior: Vec<(i32, i32)> = polygons[0].exterior.0.iter().map(|p| (p.x(), p.y())).collect();
+        assert!(exterior.contains(&(4, 2)) && exterior.contains(&(4, 6)));
+        for seg in exterior.windows(2) {
+            let on_shell_edge = collinear((0, 0), (0, 10), seg[0]) && collinear((0, 0), (0, 10), seg[1]);
+            assert!(!on_shell_edge || seg[0].1.max(seg[1].1) <= 2 || seg[0].1.min(seg[1].1) >= 6, "{:?}", seg);
+        }
+
+        // Touching at one point is still fine
+        let touching: LineString<i32> = vec![(0, 5), (4, 2), (4, 6), (0, 5)].into();
+        assert!(!shares_edge_with(&touching, &shell));
+
+        // A hole from edge to edge splits it in two
+        let band: LineString<i32> = vec![(0, 4), (10, 4), (10, 6), (0, 6), (0, 4)].into();
+        let polygons = merge_coincident_interiors(Polygon::new(shell, vec![band]), YAxis::Down);
+        assert_eq!(polygons.len(), 2);
+        assert!(polygons.iter().all(|p| p.interiors.is_empty() && is_polygon_valid(p)));
+        assert_eq!(polygons.iter().map(|p| ring_area(&p.exterior)).collect::<Vec<_>>(), vec![40., 40.]);
     }
 
     #[test]
@@ -2190,7 +4074,7 @@ mod test {
         
         let unit_square: LineString<_> = vec![a, b, c, d, a].into();
 
-        distribute_interiors::<i32>(vec![], vec![unit_square.clone()]);
+        distribute_interiors::<i32>(&mut vec![], vec![unit_square.clone()]);
     }
 
     #[test]
@@ -2205,7 +4089,7 @@ mod test {
         let unit_square: LineString<_> = vec![a, b, c, d, a].into();
         let inner_square: LineString<_> = vec![g, h, i, j, g].into();
 
-        distribute_interiors::<i32>(vec![Polygon::new(unit_square, vec![inner_square])], vec![]);
+        distribute_interiors::<i32>(&mut vec![Polygon::new(unit_square, vec![inner_square])], vec![]);
     }
 
     #[test]
@@ -2225,7 +4109,9 @@ mod test {
         let unit_square: LineString<_> = vec![a, b, c, d, a].into();
         let inner_square: LineString<_> = vec![g, h, i, j, g].into();
 
-        assert_eq!(distribute_interiors::<i32>(vec![Polygon::new(unit_square.clone(), vec![])], vec![inner_square.clone()]), vec![Polygon::new(unit_square.clone(), vec![inner_square.clone()])]);
+        let mut polygons = vec![Polygon::new(unit_square.clone(), vec![])];
+        assert_eq!(distribute_interiors::<i32>(&mut polygons, vec![inner_square.clone()]), vec![]);
+        assert_eq!(polygons, vec![Polygon::new(unit_square.clone(), vec![inner_square.clone()])]);
     }
 
     #[test]
@@ -2250,8 +4136,287 @@ mod test {
         let inner_square: LineString<_> = vec![g, h, i, j, g].into();
         let square_on_right: LineString<_> = vec![k, l, n, m, k].into();
 
-        assert_eq!(distribute_interiors::<i32>(vec![Polygon::new(unit_square.clone(), vec![]), Polygon::new(square_on_right.clone(), vec![])], vec![inner_square.clone()]), vec![Polygon::new(unit_square.clone(), vec![inner_square.clone()]), Polygon::new(square_on_right.clone(), vec![])]);
+        let mut polygons = vec![Polygon::new(unit_square.clone(), vec![]), Polygon::new(square_on_right.clone(), vec![])];
+        assert_eq!(distribute_interiors::<i32>(&mut polygons, vec![inner_square.clone()]), vec![]);
+        assert_eq!(polygons, vec![Polygon::new(unit_square.clone(), vec![inner_square.clone()]), Polygon::new(square_on_right.clone(), vec![])]);
+    }
+
+    #[test]
+    fn union_touching_polygons1() {
+        let square = |x: i32, y: i32| Polygon::new(vec![(x, y), (x+1, y), (x+1, y+1), (x, y+1), (x, y)].into(), vec![]);
+        let corners = |p: &Polygon<i32>| {
+            let mut points: Vec<(i32, i32)> = p.exterior.0.iter().map(|p| (p.x(), p.y())).collect();
+            points.sort();
+            points.dedup();
+            points
+        };
+
+        // A 3×3 grid of squares is one square, with no seams
+        let grid: Vec<_> = (0..3).flat_map(|x| (0..3).map(move |y| square(x, y))).collect();
+        let union = union_touching_polygons(grid);
+        assert_eq!(union.0.len(), 1);
+        assert_eq!(union.0[0].exterior.0.len(), 5);
+        assert_eq!(corners(&union.0[0]), vec![(0, 0), (0, 3), (3, 0), (3, 3)]);
+        assert!(union.0[0].interiors.is_empty());
+        assert!(is_valid(&Geometry::MultiPolygon(union)));
+
+        // Without the middle one, there's a hole
+        let ring: Vec<_> = (0..3).flat_map(|x| (0..3).map(move |y| (x, y))).filter(|&(x, y)| (x, y) != (1, 1)).map(|(x, y)| square(x, y)).collect();
+        let union = union_touching_polygons(ring);
+        assert_eq!(union.0.len(), 1);
+        assert_eq!(corners(&union.0[0]), vec![(0, 0), (0, 3), (3, 0), (3, 3)]);
+        assert_eq!(union.0[0].interiors.len(), 1);
+
+        // A long rectangle along 2 squares shares the edge, even though it has no vertex in the
+        // middle of it
+        let rectangle = Polygon::new(vec![(0, 1), (2, 1), (2, 2), (0, 2), (0, 1)].into(), vec![]);
+        let union = union_touching_polygons(vec![square(0, 0), square(1, 0), rectangle]);
+        assert_eq!(union.0.len(), 1);
+        assert_eq!(corners(&union.0[0]), vec![(0, 0), (0, 2), (2, 0), (2, 2)]);
+
+        // Squares which only touch at a corner stay separate
+        assert_eq!(union_touching_polygons(vec![square(0, 0), square(1, 1)]).0.len(), 2);
+    }
+
+    #[test]
+    fn overlapping_interiors() {
+        // Two holes which overlap each other, one bigger than the other, and one apart from both
+        let exterior: LineString<i32> = vec![(0, 0), (0, 100), (100, 100), (100, 0), (0, 0)].into();
+        let hole1: LineString<i32> = vec![(10, 10), (50, 10), (50, 50), (10, 50), (10, 10)].into();
+        let hole2: LineString<i32> = vec![(30, 30), (75, 30), (75, 75), (30, 75), (30, 30)].into();
+        let hole3: LineString<i32> = vec![(80, 80), (90, 80), (90, 90), (80, 90), (80, 80)].into();
+        let polygon = Polygon::new(exterior.clone(), vec![hole1.clone(), hole2.clone(), hole3.clone()]);
+
+        assert!(interiors_overlap(&hole1, &hole2));
+        assert!(!interiors_overlap(&hole1, &hole3));
+        assert!(!is_valid(&polygon.clone().into()));
+        assert!(has_overlapping_interiors(&Polygon::new(exterior.clone(), vec![hole3.clone(), hole2.clone(), hole1.clone()])));
+        assert!(!has_overlapping_interiors(&Polygon::new(exterior.clone(), vec![hole3.clone(), hole1.clone()])));
+        // It's one of the expensive checks
+        assert!(is_valid_at_level(&polygon.clone().into(), ValidityLevel::SkipExpensive, YAxis::Down));
+
+        // The smaller one is dropped, whatever order they're in
+        assert_eq!(remove_overlapping_interiors(polygon.clone()), Polygon::new(exterior.clone(), vec![hole2.clone(), hole3.clone()]));
+        assert_eq!(remove_overlapping_interiors(Polygon::new(exterior.clone(), vec![hole3.clone(), hole2.clone(), hole1.clone()])), Polygon::new(exterior.clone(), vec![hole3.clone(), hole2.clone()]));
+
+        // One hole inside another, and the same hole twice
+        let inner: LineString<i32> = vec![(20, 20), (40, 20), (40, 40), (20, 40), (20, 20)].into();
+        assert!(interiors_overlap(&hole1, &inner));
+        let reversed = LineString(hole1.0.iter().rev().cloned().collect());
+        assert!(interiors_overlap(&hole1, &reversed));
+
+        // Holes which only touch, or share an edge, don't overlap
+        let touching: LineString<i32> = vec![(50, 50), (60, 50), (60, 60), (50, 60), (50, 50)].into();
+        let sharing_edge: LineString<i32> = vec![(50, 10), (60, 10), (60, 50), (50, 50), (50, 10)].into();
+        assert!(!interiors_overlap(&hole1, &touching));
+        assert!(!interiors_overlap(&hole1, &sharing_edge));
+
+        // make_valid leaves no overlapping holes
+        let fixed = make_valid(polygon.into()).unwrap();
+        assert!(is_valid(&fixed));
+        let polygons = match fixed {
+            Geometry::Polygon(p) => vec![p],
+            Geometry::MultiPolygon(mp) => mp.0,
+            _ => unreachable!(),
+        };
+        assert!(polygons.iter().all(|p| !has_overlapping_interiors(p)));
+    }
+
+    /// A random ring inside a 4096×4096 tile. Points are often put on a coarse grid, so that we
+    /// get lots of shared points, overlapping segments and spikes, which is where the problems are.
+    fn arbitrary_ring<G: Gen>(g: &mut G) -> LineString<i32> {
+        let num_points = g.gen_range(3, 12);
+        let coarse = g.gen();
+        let mut points: Vec<Point<i32>> = (0..num_points).map(|_| if coarse {
+            Point::new(g.gen_range(0, 9) * 512, g.gen_range(0, 9) * 512)
+        } else {
+            Point::new(g.gen_range(0, 4097), g.gen_range(0, 4097))
+        }).collect();
+        let first = points[0];
+        points.push(first);
+        LineString(points)
+    }
+
+    #[derive(Clone, Debug)]
+    struct ArbPolygon(Polygon<i32>);
+
+    impl Arbitrary for ArbPolygon {
+        fn arbitrary<G: Gen>(g: &mut G) -> ArbPolygon {
+            let exterior = arbitrary_ring(g);
+            let num_interiors = g.gen_range(0, 3);
+            let interiors = (0..num_interiors).map(|_| arbitrary_ring(g)).collect();
+            ArbPolygon(Polygon::new(exterior, interiors))
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    struct ArbMultiPolygon(MultiPolygon<i32>);
+
+    impl Arbitrary for ArbMultiPolygon {
+        fn arbitrary<G: Gen>(g: &mut G) -> ArbMultiPolygon {
+            let num_polygons = g.gen_range(1, 4);
+            ArbMultiPolygon(MultiPolygon((0..num_polygons).map(|_| ArbPolygon::arbitrary(g).0).collect()))
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    struct ArbMultiLineString(MultiLineString<i32>);
+
+    impl Arbitrary for ArbMultiLineString {
+        fn arbitrary<G: Gen>(g: &mut G) -> ArbMultiLineString {
+            let num_lines = g.gen_range(1, 4);
+            ArbMultiLineString(MultiLineString((0..num_lines).map(|_| {
+                let num_points = g.gen_range(1, 6);
+                let x = g.gen_range(0, 4097); let y = g.gen_range(0, 4097);
+                // Sometimes it's all the same point
+                let same = g.gen();
+                LineString((0..num_points).map(|_| if same { Point::new(x, y) } else { Point::new(g.gen_range(0, 4097), g.gen_range(0, 4097)) }).collect())
+            }).collect()))
+        }
+    }
+
+    /// Two (non-zero length) segments. Often on a small grid, so there are lots of collinear,
+    /// touching & overlapping segments.
+    #[derive(Clone, Debug)]
+    struct ArbSegments((i32, i32), (i32, i32), (i32, i32), (i32, i32));
+
+    impl Arbitrary for ArbSegments {
+        fn arbitrary<G: Gen>(g: &mut G) -> ArbSegments {
+            let small = g.gen();
+            let mut point = || if small {
+                (g.gen_range(0, 6), g.gen_range(0, 6))
+            } else {
+                (g.gen_range(-(1<<19), 1<<19), g.gen_range(-(1<<19), 1<<19))
+            };
+            let (p1, mut p2, p3, mut p4) = (point(), point(), point(), point());
+            if p1 == p2 { p2.0 += 1; }
+            if p3 == p4 { p4.0 += 1; }
+            ArbSegments(p1, p2, p3, p4)
+        }
+    }
+
+    fn valid_or_none(geom: Geometry<i32>) -> bool {
+        match make_valid(geom) {
+            None => true,
+            Some(g) => is_valid(&g),
+        }
+    }
+
+    quickcheck! {
+        fn make_valid_polygon_is_valid_or_none(p: ArbPolygon) -> bool {
+            valid_or_none(Geometry::Polygon(p.0))
+        }
+
+        fn make_valid_multipolygon_is_valid_or_none(mp: ArbMultiPolygon) -> bool {
+            valid_or_none(Geometry::MultiPolygon(mp.0))
+        }
+
+        fn make_valid_multilinestring_is_valid_or_none(mls: ArbMultiLineString) -> bool {
+            valid_or_none(Geometry::MultiLineString(mls.0))
+        }
+
+        fn intersection_never_panics(s: ArbSegments) -> bool {
+            let ArbSegments((x1, y1), (x2, y2), (x3, y3), (x4, y4)) = s;
+            match intersection(x1, y1, x2, y2, x3, y3, x4, y4) {
+                // Allow for rounding
+                Intersection::Crossing((x, y)) => x >= max(min(x1, x2), min(x3, x4)) - 1 && x <= min(max(x1, x2), max(x3, x4)) + 1
+                                               && y >= max(min(y1, y2), min(y3, y4)) - 1 && y <= min(max(y1, y2), max(y3, y4)) + 1,
+                _ => true,
+            }
+        }
+    }
+
+    #[test]
+    fn make_valid_multilinestring_degenerate() {
+        // One of the lines is only one point after removing duplicates
+        let mls = MultiLineString(vec![
+                    vec![(0, 0), (10, 0)].into(),
+                    vec![(5, 5), (5, 5)].into(),
+                    ]);
+        assert!(!is_valid(&mls.clone().into()));
+        assert_eq!(make_valid(mls.into()), Some(Geometry::MultiLineString(MultiLineString(vec![vec![(0, 0), (10, 0)].into()]))));
+
+        // All lines are degenerate
+        let mls = MultiLineString(vec![vec![(5, 5), (5, 5)].into()]);
+        assert_eq!(make_valid(mls.into()), None);
+    }
+
+    #[test]
+    fn make_valid_collapses_to_nothing() {
+        // A polygon which is just a line there and back again. There's nothing left after making
+        // it valid, so we should get None, not an empty MultiPolygon
+        let p: Geometry<_> = Polygon::new(vec![(0, 0), (1024, 0), (2048, 0), (1024, 0), (0, 0)].into(), vec![]).into();
+        assert_eq!(make_valid(p), None);
+    }
+
+
+    #[test]
+    fn make_valid_clean_geometry_is_unchanged() {
+        // That it doesn't allocate is checked in tests/make_valid_allocations.rs, which has its
+        // own allocator
+        let bbox = Bbox{ xmin: -64, xmax: 4096+64, ymin: -64, ymax: 4096+64 };
+        let square: Geometry<_> = Polygon::new(vec![(0, 0), (0, 20), (20, 20), (20, 0), (0, 0)].into(), vec![vec![(5, 5), (15, 5), (15, 15), (5, 15), (5, 5)].into()]).into();
+        let line: Geometry<_> = LineString::from(vec![(0, 0), (10, 5), (20, 0)]).into();
+        assert!(!simplify::has_unneeded_points(&square));
+        assert!(!simplify::has_unneeded_points(&line));
+
+        for geom in vec![square, line] {
+            for &level in &[ValidityLevel::Full, ValidityLevel::SkipExpensive] {
+                let result = make_valid_within(geom.clone(), &bbox, &ValidityOptions{ level: level, ..ValidityOptions::default() });
+                assert_eq!(result, Some(geom.clone()));
+            }
+        }
+
+        // Duplicate or collinear points still go through the full repair
+        let dupe: Geometry<_> = LineString::from(vec![(0, 0), (10, 5), (10, 5), (20, 0)]).into();
+        assert!(simplify::has_unneeded_points(&dupe));
+        let collinear: Geometry<_> = Polygon::new(vec![(0, 0), (0, 10), (0, 20), (20, 20), (20, 0), (0, 0)].into(), vec![]).into();
+        assert!(simplify::has_unneeded_points(&collinear));
+        assert_eq!(make_valid(collinear), Some(Polygon::new(vec![(0, 0), (0, 20), (20, 20), (20, 0), (0, 0)].into(), vec![]).into()));
     }
 
+    #[test]
+    fn spiky_ring_is_invalid() {
+        // A-B-C-B-A, i.e. a triangle, but C is a spike, so there's no area
+        let spike: Polygon<i32> = Polygon::new(vec![(0, 0), (10, 0), (10, 10), (10, 0), (0, 0)].into(), vec![]);
+        assert_eq!(num_points_excl_duplicates(&spike.exterior), 5);
+        assert_eq!(ring_effective_vertex_count(&spike.exterior), 2);
+        assert!(!is_polygon_valid_skip_expensive(&spike));
+        assert!(!is_polygon_valid(&spike));
+        assert!(!is_valid_at_level(&spike.into(), ValidityLevel::SkipExpensive, YAxis::Down));
+
+        // Spike at the start/end of the ring
+        let spike_at_end: LineString<i32> = vec![(10, 10), (10, 0), (0, 0), (10, 0), (10, 10)].into();
+        assert_eq!(ring_effective_vertex_count(&spike_at_end), 2);
+
+        let square: LineString<i32> = vec![(0, 0), (0, 10), (10, 10), (10, 0), (0, 0)].into();
+        assert_eq!(ring_effective_vertex_count(&square), 4);
+        let square_with_extra_point: LineString<i32> = vec![(0, 0), (0, 5), (0, 10), (10, 10), (10, 0), (0, 0)].into();
+        assert_eq!(ring_effective_vertex_count(&square_with_extra_point), 4);
+    }
 
+    #[test]
+    fn point_in_polygon1() {
+        let square_with_hole: Polygon<i32> = Polygon::new(
+            vec![(0, 0), (0, 10), (10, 10), (10, 0), (0, 0)].into(),
+            vec![vec![(4, 4), (6, 4), (6, 6), (4, 6), (4, 4)].into()]);
+
+        // In the shell
+        assert!(point_in_polygon(&Point::new(2, 2), &square_with_hole));
+        // On the same horizontal line as the hole's corners
+        assert!(point_in_polygon(&Point::new(8, 4), &square_with_hole));
+        assert!(point_in_polygon(&Point::new(2, 6), &square_with_hole));
+
+        // In the hole
+        assert!(!point_in_polygon(&Point::new(5, 5), &square_with_hole));
+        // Outside
+        assert!(!point_in_polygon(&Point::new(15, 5), &square_with_hole));
+        assert!(!point_in_polygon(&Point::new(-1, 0), &square_with_hole));
+
+        // On the boundary, of the shell or the hole
+        assert!(!point_in_polygon(&Point::new(0, 5), &square_with_hole));
+        assert!(!point_in_polygon(&Point::new(10, 10), &square_with_hole));
+        assert!(!point_in_polygon(&Point::new(4, 5), &square_with_hole));
+        assert!(!point_in_polygon(&Point::new(6, 6), &square_with_hole));
+    }
 }
@@ -0,0 +1,180 @@
+//! Just enough WKT (well known text) parsing to read features which are in the data.yml itself,
+//! e.g. `POLYGON((0 0, 0 10, 10 10, 10 0, 0 0))`. Only 2D geometries are supported, and not
+//! `EMPTY` ones. Also writing geometries as WKB, which is how sources give their features.
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use geo::{Geometry, Point, LineString, Polygon, MultiPoint, MultiLineString, MultiPolygon, GeometryCollection};
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+/// Write this geometry as (little endian) WKB. `wkb` can't write geometry collections, so they
+/// are done here.
+pub fn write_wkb(geom: &Geometry<f64>, out: &mut Vec<u8>) {
+    match *geom {
+        Geometry::GeometryCollection(ref gc) => {
+            out.extend_from_slice(&[1, 7, 0, 0, 0]);
+            out.write_u32::<LittleEndian>(gc.0.len() as u32).unwrap();
+            for g in gc.0.iter() {
+                write_wkb(g, out);
+            }
+        },
+        ref g => { ::wkb::write_geom_to_wkb(g, out); },
+    }
+}
+
+/// The geometry in this WKT string.
+pub fn parse_wkt(s: &str) -> Result<Geometry<f64>> {
+    let mut parser = Parser{ chars: s.chars().collect(), position: 0 };
+    let geom = parser.geometry().map_err(|e| format_err!("Invalid WKT {:?}: {}", s, e))?;
+    if let Some(c) = parser.peek() {
+        return Err(format_err!("Invalid WKT {:?}: unexpected {:?} after the geometry", s, c));
+    }
+    Ok(geom)
+}
+
+/// A recursive descent parser.
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    /// The next character which isn't whitespace, without using it up.
+    fn peek(&mut self) -> Option<char> {
+        while self.position < self.chars.len() && self.chars[self.position].is_whitespace() {
+            self.position += 1;
+        }
+        self.chars.get(self.position).cloned()
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        match self.peek() {
+            Some(next) if next == c => {
+                self.position += 1;
+                Ok(())
+            },
+            x => Err(format_err!("expected {:?}, not {:?}", c, x)),
+        }
+    }
+
+    /// The next word (e.g. the geometry type), in upper case.
+    fn word(&mut self) -> String {
+        self.peek();
+        let start = self.position;
+        while self.position < self.chars.len() && self.chars[self.position].is_alphabetic() {
+            self.position += 1;
+        }
+        self.chars[start..self.position].iter().collect::<String>().to_uppercase()
+    }
+
+    fn number(&mut self) -> Result<f64> {
+        self.peek();
+        let start = self.position;
+        while self.position < self.chars.len() && (self.chars[self.position].is_digit(10) || "+-.eE".contains(self.chars[self.position])) {
+            self.position += 1;
+        }
+        let number: String = self.chars[start..self.position].iter().collect();
+        number.parse().map_err(|_| format_err!("invalid number {:?}", number))
+    }
+
+    /// `(item, item, ...)`
+    fn list<T, F>(&mut self, mut item: F) -> Result<Vec<T>>
+        where F: FnMut(&mut Parser) -> Result<T>
+    {
+        self.expect('(')?;
+        let mut items = vec![item(self)?];
+        while self.peek() == Some(',') {
+            self.position += 1;
+            items.push(item(self)?);
+        }
+        self.expect(')')?;
+        Ok(items)
+    }
+
+    /// `x y`
+    fn point(&mut self) -> Result<Point<f64>> {
+        let x = self.number()?;
+        let y = self.number()?;
+        match self.peek() {
+            Some(c) if c.is_digit(10) || c == '-' || c == '.' => Err(format_err!("only 2D coordinates are supported")),
+            _ => Ok(Point::new(x, y)),
+        }
+    }
+
+    fn linestring(&mut self) -> Result<LineString<f64>> {
+        Ok(LineString(self.list(Parser::point)?))
+    }
+
+    fn polygon(&mut self) -> Result<Polygon<f64>> {
+        let mut rings = self.list(Parser::linestring)?;
+        let exterior = rings.remove(0);
+        Ok(Polygon::new(exterior, rings))
+    }
+
+    fn geometry(&mut self) -> Result<Geometry<f64>> {
+        let word = self.word();
+        match word.as_str() {
+            "POINT" => {
+                self.expect('(')?;
+                let point = self.point()?;
+                self.expect(')')?;
+                Ok(Geometry::Point(point))
+            },
+            "LINESTRING" => Ok(Geometry::LineString(self.linestring()?)),
+            "POLYGON" => Ok(Geometry::Polygon(self.polygon()?)),
+            // The points can be in brackets, or not: `MULTIPOINT((0 0), (1 1))` or
+            // `MULTIPOINT(0 0, 1 1)`
+            "MULTIPOINT" => Ok(Geometry::MultiPoint(MultiPoint(self.list(|p| {
+                if p.peek() == Some('(') {
+                    p.position += 1;
+                    let point = p.point()?;
+                    p.expect(')')?;
+                    Ok(point)
+                } else {
+                    p.point()
+                }
+            })?))),
+            "MULTILINESTRING" => Ok(Geometry::MultiLineString(MultiLineString(self.list(Parser::linestring)?))),
+            "MULTIPOLYGON" => Ok(Geometry::MultiPolygon(MultiPolygon(self.list(Parser::polygon)?))),
+            "GEOMETRYCOLLECTION" => Ok(Geometry::GeometryCollection(GeometryCollection(self.list(Parser::geometry)?))),
+            "EMPTY" => Err(format_err!("empty geometries aren't supported")),
+            "" => Err(format_err!("expected a geometry type, not {:?}", self.peek())),
+            x => Err(format_err!("unknown geometry type {}", x)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_wkt("POINT(1 -2.5)").unwrap(), Geometry::Point(Point::new(1., -2.5)));
+        assert_eq!(parse_wkt(" linestring (0 0, 10 1e3) ").unwrap(), Geometry::LineString(vec![(0., 0.), (10., 1000.)].into()));
+
+        let outer: LineString<f64> = vec![(0., 0.), (0., 10.), (10., 10.), (10., 0.), (0., 0.)].into();
+        let hole: LineString<f64> = vec![(2., 2.), (4., 2.), (4., 4.), (2., 2.)].into();
+        let donut = Polygon::new(outer.clone(), vec![hole.clone()]);
+        assert_eq!(parse_wkt("POLYGON((0 0, 0 10, 10 10, 10 0, 0 0), (2 2, 4 2, 4 4, 2 2))").unwrap(), Geometry::Polygon(donut.clone()));
+        assert_eq!(parse_wkt("MULTIPOLYGON(((0 0, 0 10, 10 10, 10 0, 0 0), (2 2, 4 2, 4 4, 2 2)), ((0 0, 0 10, 10 10, 10 0, 0 0)))").unwrap(),
+                   Geometry::MultiPolygon(MultiPolygon(vec![donut, Polygon::new(outer, vec![])])));
+
+        let points = Geometry::MultiPoint(MultiPoint(vec![Point::new(0., 0.), Point::new(1., 1.)]));
+        assert_eq!(parse_wkt("MULTIPOINT((0 0), (1 1))").unwrap(), points);
+        assert_eq!(parse_wkt("MULTIPOINT(0 0, 1 1)").unwrap(), points);
+
+        match parse_wkt("GEOMETRYCOLLECTION(POINT(0 0), MULTILINESTRING((0 0, 1 1), (2 2, 3 3)))").unwrap() {
+            Geometry::GeometryCollection(gc) => assert_eq!(gc.0.len(), 2),
+            x => panic!("{:?}", x),
+        }
+
+        assert!(parse_wkt("").is_err());
+        assert!(parse_wkt("POINT(0 0 0)").is_err());
+        assert!(parse_wkt("POINT EMPTY").is_err());
+        assert!(parse_wkt("POINT(0 0))").is_err());
+        assert!(parse_wkt("LINESTRING(0 0, 1 1").is_err());
+        assert!(parse_wkt("CIRCLE(0 0, 1)").is_err());
+        assert!(parse_wkt("POINT(a b)").is_err());
+    }
+}
@@ -0,0 +1,53 @@
+//! Checks the make_valid fast path for clean geometries doesn't allocate. It's on its own, since
+//! counting the allocations needs a global allocator, which would be used by every test in a
+//! binary.
+extern crate geo;
+extern crate tileigi;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use geo::{Bbox, Geometry, LineString, Polygon};
+use tileigi::{make_valid_within, ValidityLevel, ValidityOptions};
+
+/// Counts the allocations on each thread.
+struct CountingAllocator;
+
+thread_local!(static ALLOCATIONS: Cell<usize> = Cell::new(0));
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get()+1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|a| a.get())
+}
+
+#[test]
+fn make_valid_clean_geometry_doesnt_allocate() {
+    let bbox = Bbox{ xmin: -64, xmax: 4096+64, ymin: -64, ymax: 4096+64 };
+    let square: Geometry<i32> = Polygon::new(vec![(0, 0), (0, 20), (20, 20), (20, 0), (0, 0)].into(), vec![vec![(5, 5), (15, 5), (15, 15), (5, 15), (5, 5)].into()]).into();
+    let line: Geometry<i32> = LineString::from(vec![(0, 0), (10, 5), (20, 0)]).into();
+
+    for geom in vec![square, line] {
+        let expected = geom.clone();
+        for &level in &[ValidityLevel::Full, ValidityLevel::SkipExpensive] {
+            let geom = geom.clone();
+            let options = ValidityOptions{ level: level, ..ValidityOptions::default() };
+            let before = allocations();
+            let result = make_valid_within(geom, &bbox, &options);
+            assert_eq!(allocations(), before);
+            assert_eq!(result, Some(expected.clone()));
+        }
+    }
+}